@@ -0,0 +1,237 @@
+//! decides which cached page is displaced when a page is loaded while the
+//! map is full. Lru and fifo keep their order in the cache lru so they only
+//! differ in how an access updates it. Lfu keeps the pages in buckets of
+//! how often they were used on the side
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::str::FromStr;
+
+use lru::LruCache;
+
+use super::CachedPage;
+
+/// after that many accesses per cached page on average all use counts are
+/// halved, so pages that were used a lot a long time ago don't stay forever
+const DECAY_AFTER: usize = 8;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    /// the least recently used page
+    #[default]
+    Lru,
+    /// the page that was loaded first, accesses don't change the order
+    Fifo,
+    /// the least frequently used page, of pages used as often the least
+    /// recently used one. Pages that are used a lot survive a scan, the
+    /// counts decay over time so they do not stay forever
+    Lfu,
+}
+
+/// the eviction policy with the state it needs to pick a victim. It has to
+/// be told about every page that is added to or dropped from the cache
+pub(super) struct Evictor {
+    policy: Eviction,
+    // only used by lfu
+    frequencies: Frequencies,
+}
+
+impl Evictor {
+    /// evictor for the pages already in cache, they all count as used once
+    pub(super) fn new(policy: Eviction, cache: &LruCache<u32, CachedPage>) -> Self {
+        let mut evictor = Self {
+            policy,
+            frequencies: Frequencies::default(),
+        };
+
+        for (page, _) in cache.iter().rev() {
+            evictor.loaded(*page);
+        }
+
+        evictor
+    }
+
+    /// page was added to the cache
+    pub(super) fn loaded(&mut self, page: u32) {
+        if self.policy == Eviction::Lfu {
+            self.frequencies.insert(page);
+        }
+    }
+
+    /// page was dropped from the cache
+    pub(super) fn dropped(&mut self, page: u32) {
+        if self.policy == Eviction::Lfu {
+            self.frequencies.remove(page);
+        }
+    }
+
+    /// the cached page for an access to page
+    pub(super) fn touch<'a>(
+        &mut self,
+        cache: &'a mut LruCache<u32, CachedPage>,
+        page: u32,
+    ) -> Option<&'a mut CachedPage> {
+        match self.policy {
+            Eviction::Fifo => cache.peek_mut(&page),
+            Eviction::Lru => cache.get_mut(&page),
+            Eviction::Lfu => {
+                let cached = cache.peek_mut(&page)?;
+                self.frequencies.touch(page);
+                Some(cached)
+            }
+        }
+    }
+
+    /// the cached pages as (page, address) in the order they are displaced
+    pub(super) fn order<'a>(
+        &'a self,
+        cache: &'a LruCache<u32, CachedPage>,
+    ) -> Box<dyn Iterator<Item = (u32, usize)> + 'a> {
+        match self.policy {
+            // least recently used (or loaded) first
+            Eviction::Lru | Eviction::Fifo => Box::new(
+                cache
+                    .iter()
+                    .rev()
+                    .map(|(page, cached)| (*page, cached.address)),
+            ),
+            Eviction::Lfu => Box::new(
+                self.frequencies
+                    .pages()
+                    .filter_map(move |page| cache.peek(&page).map(|cached| (page, cached.address))),
+            ),
+        }
+    }
+
+    /// the page to displace as (page, address)
+    pub(super) fn victim(&self, cache: &LruCache<u32, CachedPage>) -> Option<(u32, usize)> {
+        self.order(cache).next()
+    }
+}
+
+/// how often each cached page was used. Pages used as often are in the same
+/// bucket from the least to the most recently used one
+#[derive(Default)]
+struct Frequencies {
+    uses: HashMap<u32, u32>,
+    buckets: BTreeMap<u32, LruCache<u32, ()>>,
+    // accesses since the last decay
+    touches: usize,
+}
+
+impl Frequencies {
+    fn insert(&mut self, page: u32) {
+        self.remove(page);
+        self.put(page, 1);
+    }
+
+    fn remove(&mut self, page: u32) {
+        if let Some(uses) = self.uses.remove(&page) {
+            self.take(page, uses);
+        }
+    }
+
+    fn touch(&mut self, page: u32) {
+        let Some(uses) = self.uses.get(&page).copied() else {
+            return;
+        };
+
+        self.take(page, uses);
+        self.put(page, uses.saturating_add(1));
+
+        self.touches += 1;
+        if self.touches >= self.uses.len() * DECAY_AFTER {
+            self.decay();
+        }
+    }
+
+    /// halves all use counts, pages keep their order
+    fn decay(&mut self) {
+        self.touches = 0;
+        for (uses, bucket) in std::mem::take(&mut self.buckets) {
+            for (page, _) in bucket.iter().rev() {
+                self.put(*page, (uses / 2).max(1));
+            }
+        }
+    }
+
+    fn put(&mut self, page: u32, uses: u32) {
+        self.uses.insert(page, uses);
+        self.buckets
+            .entry(uses)
+            .or_insert_with(LruCache::unbounded)
+            .push(page, ());
+    }
+
+    fn take(&mut self, page: u32, uses: u32) {
+        if let Some(bucket) = self.buckets.get_mut(&uses) {
+            bucket.pop(&page);
+            if bucket.is_empty() {
+                self.buckets.remove(&uses);
+            }
+        }
+    }
+
+    /// least used first, of pages used as often the least recently used one
+    fn pages(&self) -> impl Iterator<Item = u32> + '_ {
+        self.buckets
+            .values()
+            .flat_map(|bucket| bucket.iter().rev().map(|(page, _)| *page))
+    }
+}
+
+impl FromStr for Eviction {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Self::Lru),
+            "fifo" => Ok(Self::Fifo),
+            "lfu" => Ok(Self::Lfu),
+            _ => Err(format!(
+                "invalid eviction policy '{s}', expected lru, fifo or lfu"
+            )),
+        }
+    }
+}
+
+impl Display for Eviction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Lru => f.write_str("lru"),
+            Self::Fifo => f.write_str("fifo"),
+            Self::Lfu => f.write_str("lfu"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frequencies() {
+        let mut frequencies = Frequencies::default();
+        for page in 0..3 {
+            frequencies.insert(page);
+        }
+        // used as often, least recently used first
+        assert_eq!(frequencies.pages().collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        frequencies.touch(0);
+        frequencies.touch(1);
+        frequencies.touch(1);
+        assert_eq!(frequencies.pages().collect::<Vec<_>>(), vec![2, 0, 1]);
+
+        frequencies.remove(2);
+        assert_eq!(frequencies.pages().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(frequencies.buckets.len(), 2);
+
+        // 16 touches with 2 pages halve the counts
+        for _ in 0..13 {
+            frequencies.touch(1);
+        }
+        assert_eq!(frequencies.touches, 0);
+        assert_eq!(frequencies.uses[&0], 1);
+        assert_eq!(frequencies.uses[&1], 8);
+        assert_eq!(frequencies.pages().collect::<Vec<_>>(), vec![0, 1]);
+    }
+}
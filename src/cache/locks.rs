@@ -0,0 +1,103 @@
+//! per page async locks so writers of the same page are serialized while
+//! writers of different pages are not. Pages are spread over a fixed number
+//! of shards, a range of pages locks all its shards in shard order so two
+//! ranges never deadlock whatever order they are requested in.
+use std::{ops::RangeInclusive, sync::Arc};
+
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// default number of shards
+pub const SHARDS: usize = 64;
+
+pub struct PageLocks {
+    shards: Vec<Arc<Mutex<()>>>,
+}
+
+/// holds the locks of a range of pages until dropped
+pub struct PagesGuard {
+    _guards: Vec<OwnedMutexGuard<()>>,
+}
+
+impl Default for PageLocks {
+    fn default() -> Self {
+        Self::new(SHARDS)
+    }
+}
+
+impl PageLocks {
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| Arc::default()).collect(),
+        }
+    }
+
+    /// waits until all pages in range are locked
+    pub async fn lock(&self, pages: RangeInclusive<u32>) -> PagesGuard {
+        let count = self.shards.len();
+        let len = (*pages.end() as usize).saturating_sub(*pages.start() as usize) + 1;
+
+        let mut shards: Vec<usize> = if len >= count {
+            (0..count).collect()
+        } else {
+            pages.map(|page| page as usize % count).collect()
+        };
+        shards.sort_unstable();
+        shards.dedup();
+
+        let mut guards = Vec::with_capacity(shards.len());
+        for shard in shards {
+            guards.push(Arc::clone(&self.shards[shard]).lock_owned().await);
+        }
+
+        PagesGuard { _guards: guards }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn stress() {
+        const PAGES: u32 = 32;
+        // few shards so ranges share shards a lot
+        let locks = Arc::new(PageLocks::new(8));
+        let held: Arc<Vec<AtomicBool>> =
+            Arc::new((0..PAGES).map(|_| AtomicBool::new(false)).collect());
+
+        let mut tasks = Vec::new();
+        for task in 0..16u32 {
+            let locks = Arc::clone(&locks);
+            let held = Arc::clone(&held);
+            tasks.push(tokio::spawn(async move {
+                for i in 0..200u32 {
+                    // overlapping ranges, some wrapping over the shards more than once
+                    let start = (task * 7 + i * 3) % PAGES;
+                    let end = (start + (task + i) % 12).min(PAGES - 1);
+
+                    let _guard = locks.lock(start..=end).await;
+                    for page in start..=end {
+                        assert!(!held[page as usize].swap(true, Ordering::SeqCst));
+                    }
+                    tokio::task::yield_now().await;
+                    for page in start..=end {
+                        held[page as usize].store(false, Ordering::SeqCst);
+                    }
+                }
+            }));
+        }
+
+        let all = async {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        };
+
+        // a deadlock shows as a timeout
+        tokio::time::timeout(Duration::from_secs(30), all)
+            .await
+            .unwrap();
+    }
+}
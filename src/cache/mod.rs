@@ -7,41 +7,149 @@
 //! 1MiB this maps to 4096TiB
 //!
 use std::{
+    future::Future,
     num::NonZeroUsize,
+    ops::RangeInclusive,
     path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
     time::{Duration, Instant},
 };
 
 use crate::{
-    map::{Flags, Page, PageMut},
+    map::{Flags, Page, PageMut, MAX_PAGE_COUNT},
     store::{Page as PageData, Store},
 };
 
-use super::map::PageMap;
+use super::map::{MultiPageMap, PageMap};
 use bytesize::ByteSize;
 use lazy_static::lazy_static;
 use lru::LruCache;
 use prometheus::{
-    register_histogram, register_int_counter, register_int_gauge, Histogram, IntCounter, IntGauge,
+    register_gauge, register_histogram, register_int_counter, register_int_gauge,
+    register_int_gauge_vec, Gauge, Histogram, IntCounter, IntGauge, IntGaugeVec,
 };
 
-use crate::{Error, Result};
+use crate::{
+    clock::{Clock, SystemClock},
+    metrics, Error, PolicyError, Result,
+};
+
+mod eviction;
+mod locks;
+mod thrash;
+pub use eviction::Eviction;
+use eviction::Evictor;
+use locks::PageLocks;
+pub use locks::PagesGuard;
+use thrash::Thrash;
 
 lazy_static! {
-    static ref PAGES_EVICTED: IntCounter =
-        register_int_counter!("nbd_pages_evicted", "number of pages evicted to backend").unwrap();
-    static ref PAGES_LOADED: IntCounter =
-        register_int_counter!("nbd_pages_loaded", "number of pages loaded from backend").unwrap();
-    static ref PAGES_CACHED: IntGauge =
-        register_int_gauge!("nbd_pages_cached", "number of pages available in cache").unwrap();
+    static ref PAGES_EVICTED: IntCounter = register_int_counter!(
+        metrics::name("pages_evicted"),
+        "number of pages evicted to backend"
+    )
+    .unwrap();
+    static ref PAGES_LOADED: IntCounter = register_int_counter!(
+        metrics::name("pages_loaded"),
+        "number of pages loaded from backend"
+    )
+    .unwrap();
+    static ref READ_FROM_RESIDENT: IntCounter = register_int_counter!(
+        metrics::name("read_from_resident"),
+        "number of page reads served by a page that was already in the cache"
+    )
+    .unwrap();
+    static ref READ_FROM_WARM: IntCounter = register_int_counter!(
+        metrics::name("read_from_warm"),
+        "number of page reads that had to load the page into the cache first"
+    )
+    .unwrap();
+    static ref PAGES_WARMED_AHEAD: IntCounter = register_int_counter!(
+        metrics::name("pages_warmed_ahead"),
+        "number of pages loaded into the cache after a miss on the page before them"
+    )
+    .unwrap();
+    static ref PAGES_PREFETCHED: IntCounter = register_int_counter!(
+        metrics::name("pages_prefetched"),
+        "number of pages loaded into the cache ahead of sequential reads"
+    )
+    .unwrap();
+    static ref WARM_AHEAD_ERR: IntCounter = register_int_counter!(
+        metrics::name("warm_ahead_err"),
+        "number of pages that failed to load ahead of a read"
+    )
+    .unwrap();
+    static ref STORE_SET_ERR: IntCounter = register_int_counter!(
+        metrics::name("store_set_err"),
+        "number of failed writes to backend"
+    )
+    .unwrap();
+    static ref PAGE_DISPLACEMENTS: IntCounter = register_int_counter!(
+        metrics::name("page_displacements"),
+        "number of cached pages dropped to make room for another page"
+    )
+    .unwrap();
+    static ref CACHE_THRASHING: IntGauge = register_int_gauge!(
+        metrics::name("cache_thrashing"),
+        "1 if most accesses displace a cached page, the cache is too small for the working set"
+    )
+    .unwrap();
+    static ref WRITEBACK_LAG: Gauge = register_gauge!(
+        metrics::name("writeback_lag_seconds"),
+        "age of the oldest dirty page, this is how much writes can be lost on a crash"
+    )
+    .unwrap();
+    static ref EVICT_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        metrics::name("evict_queue_depth"),
+        "number of dirty pages waiting to be evicted to the backend"
+    )
+    .unwrap();
+    static ref PAGES_QUARANTINED: IntGauge = register_int_gauge!(
+        metrics::name("pages_quarantined"),
+        "number of cache slots never used again after repeated bad crc"
+    )
+    .unwrap();
+    static ref SCRUB_REPAIRED: IntCounter = register_int_counter!(
+        metrics::name("scrub_repaired"),
+        "number of corrupted cached pages fetched again from the store by scrub"
+    )
+    .unwrap();
+    static ref SCRUB_DROPPED: IntCounter = register_int_counter!(
+        metrics::name("scrub_dropped"),
+        "number of corrupted cached pages scrub dropped without repairing them"
+    )
+    .unwrap();
+    static ref LRU_ENTRIES: IntGauge = register_int_gauge!(
+        metrics::name("lru_entries"),
+        "number of pages tracked by the lru"
+    )
+    .unwrap();
+    static ref LRU_BYTES: IntGauge = register_int_gauge!(
+        metrics::name("lru_bytes"),
+        "approximate memory used by the lru to track the cached pages"
+    )
+    .unwrap();
+    static ref PAGES_CACHED: IntGauge = register_int_gauge!(
+        metrics::name("pages_cached"),
+        "number of pages available in cache"
+    )
+    .unwrap();
+    static ref CACHE_FILE_BYTES: IntGaugeVec = register_int_gauge_vec!(
+        metrics::name("cache_file_bytes"),
+        "cache file size, logical is the mapped size, allocated is the actual disk usage",
+        &["kind"]
+    )
+    .unwrap();
     static ref EVICT_HISTOGRAM: Histogram = register_histogram!(
-        "nbd_evict_histogram",
+        metrics::name("evict_histogram"),
         "page eviction histogram",
         vec![0.001, 0.010, 0.050, 0.100, 0.250, 0.500]
     )
     .unwrap();
     static ref LOAD_HISTOGRAM: Histogram = register_histogram!(
-        "nbd_load_histogram",
+        metrics::name("load_histogram"),
         "load eviction histogram",
         vec![0.001, 0.010, 0.050, 0.100, 0.250, 0.500]
     )
@@ -52,24 +160,53 @@ lazy_static! {
 struct CachedPage {
     /// address of the block in underlying cache
     address: usize,
-    // in memory information
-    // about the block can be here
+    /// last time the page was handed out for writing while clean, so
+    /// while the page is dirty it's the time it became dirty
+    dirty_since: Instant,
 }
 
-/// Cache layer on top of BlockMap. This allows tracking what block is in what map location
-/// and make it easier to find which block in the map is least used so we can evict if needed
+/// Cache layer on top of a PageMap. This allows tracking what page is in what map location
+/// and make it easier to find which page in the map is least used so we can evict if needed
 pub struct Cache<S>
 where
     S: Store,
 {
     cache: LruCache<u32, CachedPage>,
-    map: PageMap,
+    map: MultiPageMap,
+    // addresses of free slots in the map, the lowest
+    // address is at the end so it's used first
+    free: Vec<usize>,
     store: S,
     // blocks is number of possible blocks
-    // in the store (store.size() / bs)
+    // in the store (store.size() / bs rounded up)
     pages: usize,
+    // bytes of the last page held by the store if its size is not a
+    // multiple of the page size, 0 otherwise
+    tail: usize,
+    // background eviction waits until at least that many
+    // pages are dirty
+    evict_batch: usize,
+    // pages after a missed page that are loaded with it
+    warm_batch: usize,
+    eviction: Evictor,
+    // pages after a sequential read that are loaded with it
+    read_ahead: usize,
+    last_read: Option<u32>,
+    on_displace: Option<DisplaceHook>,
+    // access counters used to detect thrashing
+    hits: u64,
+    displaced: u64,
+    thrash: Thrash,
+    // slots that are never used again, see [`Cache::scrub`]
+    quarantined: usize,
+    clock: Arc<dyn Clock>,
+    locks: PageLocks,
 }
 
+/// called with (loaded, displaced) page ids every time a cached page is
+/// dropped to make room for another page
+pub type DisplaceHook = Box<dyn Fn(u32, u32) + Send + Sync>;
+
 impl<S> Cache<S>
 where
     S: Store,
@@ -81,38 +218,171 @@ where
         page_size: ByteSize,
     ) -> Result<Self> {
         let map = PageMap::new(path, size, page_size)?;
-        let pc = size.as_u64() / page_size.as_u64();
+        Self::with_map(store, map)
+    }
+
+    /// create a cache over an already opened map. The lru tracks exactly
+    /// the pages in the map slots, its capacity can't be set on its own:
+    /// each entry is the slot of a cached page, so a bigger lru never fills
+    /// up and a smaller one forgets pages that still hold a slot
+    pub fn with_map<M: Into<MultiPageMap>>(store: S, map: M) -> Result<Self> {
+        let map = map.into();
+        let pc = map.page_count();
+        if pc == 0 {
+            return Err(Error::ZeroSize);
+        }
+
+        // pages are copied as is between the map and the store
+        if store.page_size() != map.page_size() {
+            return Err(Error::InvalidPageSize);
+        }
 
-        let mut cache = LruCache::new(NonZeroUsize::new(pc as usize).ok_or(Error::ZeroSize)?);
+        let mut cache = LruCache::new(NonZeroUsize::new(pc).ok_or(Error::ZeroSize)?);
 
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let mut free = Vec::new();
+        let mut quarantined = 0;
         for page in map.iter() {
             let header = page.header();
-            if header.flag(Flags::Occupied) {
+            if header.flag(Flags::Quarantined) {
+                quarantined += 1;
+            } else if header.flag(Flags::Occupied) {
                 cache.put(
                     header.page(),
                     CachedPage {
                         address: page.address(),
+                        // the real age of pages left dirty is unknown
+                        dirty_since: clock.now(),
                     },
                 );
+            } else {
+                free.push(page.address());
             }
         }
-
-        PAGES_CACHED.set(cache.len() as i64);
-        // to be able to check block boundaries
-        let pages = store.size().as_u64() / page_size.as_u64();
+        free.reverse();
+
+        update_lru_metrics(&cache);
+        PAGES_QUARANTINED.set(quarantined as i64);
+        // to be able to check block boundaries. A partial page at the end of
+        // the store is cached as a full page
+        let tail = (store.size().as_u64() % store.page_size() as u64) as usize;
+        let pages = store.page_count() + u64::from(tail != 0);
         log::debug!("device pages: {pages}");
+        let eviction = Evictor::new(Eviction::default(), &cache);
         Ok(Self {
             map,
             cache,
+            free,
             store,
             pages: pages as usize,
+            tail,
+            evict_batch: 1,
+            warm_batch: 0,
+            eviction,
+            read_ahead: 0,
+            last_read: None,
+            on_displace: None,
+            hits: 0,
+            displaced: 0,
+            thrash: Thrash::default(),
+            quarantined,
+            clock,
+            locks: PageLocks::default(),
         })
     }
 
+    /// set a hook that is called every time a page displaces another page
+    /// in the cache. A high rate of displacements means the working set does
+    /// not fit in the cache
+    pub fn with_displace_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(u32, u32) + Send + Sync + 'static,
+    {
+        self.on_displace = Some(Box::new(hook));
+        self
+    }
+
+    /// use clock instead of the system clock for everything that depends on
+    /// time, mainly for tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        for (_, cached) in self.cache.iter_mut() {
+            cached.dirty_since = now;
+        }
+
+        self.clock = clock;
+        self
+    }
+
+    /// the clock used by the cache, see [`Cache::with_clock`]
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
+
+    /// background eviction is skipped until at least batch pages
+    /// are dirty. This trades a bigger crash window for less but bigger
+    /// store writes. sync_all always writes everything.
+    pub fn with_evict_batch(mut self, batch: usize) -> Self {
+        self.evict_batch = batch.max(1);
+        self
+    }
+
+    /// a read that misses page p also loads the next batch pages (those
+    /// that are not cached yet) with concurrent store reads. This saves
+    /// round trips on sequential reads from slow stores at the cost of
+    /// reading pages that may never be used. 0 disables it
+    pub fn with_warm_batch(mut self, batch: usize) -> Self {
+        self.warm_batch = batch;
+        self
+    }
+
+    /// once a read is right after the previous one and the next page is not
+    /// cached, the next ahead pages are loaded with it in one batch, hit or
+    /// miss. Reads of the pages loaded ahead don't wait for the store.
+    /// Unlike [`Cache::with_warm_batch`] random reads load nothing more.
+    /// 0 disables it
+    pub fn with_read_ahead(mut self, ahead: usize) -> Self {
+        self.read_ahead = ahead;
+        self
+    }
+
+    /// which page is displaced to load another page once the map is full
+    /// (default is lru). Only the choice of the victim changes, dirty pages
+    /// are still written back the same way
+    pub fn with_eviction(mut self, eviction: Eviction) -> Self {
+        self.eviction = Evictor::new(eviction, &self.cache);
+        self
+    }
+
     pub fn inner(self) -> S {
         self.store
     }
 
+    /// swap the backend store with store and return the old one. All dirty
+    /// pages are written to the old store first, after that the cache only
+    /// holds clean pages which are never written to the new store until they
+    /// are modified again. So the new store must already hold the same data
+    /// as the old one (for example a copy of it), and it must have the same
+    /// geometry.
+    pub async fn replace_store(&mut self, store: S) -> Result<S> {
+        if store.page_size() != self.page_size() {
+            return Err(Error::InvalidPageSize);
+        }
+
+        if store.size() != self.store.size() {
+            return Err(PolicyError::StoresNotSameSize.into());
+        }
+
+        self.sync_all().await?;
+        log::info!(
+            "replacing store {} with {}",
+            self.store.describe(),
+            store.describe()
+        );
+
+        Ok(std::mem::replace(&mut self.store, store))
+    }
+
     pub fn page_size(&self) -> usize {
         self.map.page_size()
     }
@@ -121,12 +391,329 @@ where
         self.map.page_count()
     }
 
+    /// usable size of the backend store. A partial page at the end of the
+    /// store is cached as a full page but only its first bytes are usable.
+    pub fn size(&self) -> ByteSize {
+        ByteSize(self.store.size().as_u64())
+    }
+
+    /// number of bytes the store holds for page, only the last page of a
+    /// store that is not a multiple of the page size holds less than a page
+    fn page_len(&self, page: u32) -> usize {
+        if self.tail != 0 && page as usize == self.pages - 1 {
+            return self.tail;
+        }
+
+        self.page_size()
+    }
+
+    /// approximate memory used to track the cached pages in memory
+    pub fn lru_memory(&self) -> usize {
+        lru_memory(self.cache.len(), self.cache.cap().get())
+    }
+
     pub fn occupied(&self) -> usize {
         self.map
             .iter()
             .filter(|b| b.header().flag(Flags::Occupied))
             .count()
     }
+
+    /// update the cache file size metrics. with a sparse cache file the
+    /// allocated size grows as pages are written until it reaches the
+    /// logical size
+    pub fn update_file_metrics(&self) {
+        CACHE_FILE_BYTES
+            .with_label_values(&["logical"])
+            .set(self.map.file_size() as i64);
+
+        match self.map.allocated() {
+            Ok(allocated) => CACHE_FILE_BYTES
+                .with_label_values(&["allocated"])
+                .set(allocated as i64),
+            Err(err) => log::debug!("failed to get cache file allocation: {err}"),
+        }
+    }
+
+    /// compare displacements to hits over the last minute and warn if
+    /// the cache keeps dropping pages to load others. Supposed to be
+    /// called periodically. Returns true if the cache is thrashing.
+    pub fn check_thrashing(&mut self) -> bool {
+        let now = self.clock.now();
+        let rates = match self.thrash.sample(now, self.hits, self.displaced) {
+            Some(rates) => rates,
+            None => return false,
+        };
+
+        let thrashing = rates.is_thrashing();
+        CACHE_THRASHING.set(thrashing as i64);
+        if thrashing && self.thrash.should_warn(now) {
+            log::warn!(
+                "cache is thrashing: {:.0} pages/s displaced, {:.0}% of accesses, the cache ({}) is probably smaller than the working set, consider a bigger --cache-size",
+                rates.rate,
+                rates.ratio * 100.0,
+                ByteSize((self.map.page_count() * self.page_size()) as u64).to_string_as(true),
+            );
+        }
+
+        thrashing
+    }
+
+    /// check if page is already in the cache. This neither loads the page
+    /// nor updates the lru
+    pub fn is_resident(&self, page: u32) -> bool {
+        self.cache.contains(&page)
+    }
+
+    /// goes over all pages of the device that are not holes, see [`Pages`]
+    pub fn pages(&mut self) -> Pages<'_, S> {
+        Pages {
+            cache: self,
+            next: 0,
+        }
+    }
+
+    /// drop clean pages in eviction order until there are at least target free
+    /// slots in the map. Clean pages are already in the store so nothing is
+    /// written back, the slots are just marked free. Returns the number of
+    /// dropped pages.
+    pub fn drop_clean(&mut self, target: usize) -> usize {
+        let needed = target.saturating_sub(self.free.len());
+        if needed == 0 {
+            return 0;
+        }
+
+        let clean: Vec<(u32, usize)> = self
+            .eviction
+            .order(&self.cache)
+            .filter(|(_, address)| !self.map.header_at(*address).flag(Flags::Dirty))
+            .take(needed)
+            .collect();
+
+        self.release(&clean);
+        clean.len()
+    }
+
+    /// verifies the crc of all clean cached pages. A clean page with a bad crc
+    /// is dropped from the cache so next access loads it again from the store.
+    /// Dirty pages are skipped since their crc is only updated once they are
+    /// evicted. A slot that gets a bad crc for the second time is quarantined
+    /// and never used again. Returns the number of dropped pages.
+    pub fn scrub(&mut self) -> usize {
+        let corrupted = self.corrupted();
+
+        for (page, address) in corrupted.iter() {
+            log::warn!("page {page} at {address} has a bad crc, dropping it from cache");
+            self.strike(*address);
+        }
+
+        PAGES_QUARANTINED.set(self.quarantined as i64);
+        SCRUB_DROPPED.inc_by(corrupted.len() as u64);
+
+        self.release(&corrupted);
+        corrupted.len()
+    }
+
+    /// same as [`Cache::scrub`] but a corrupted page is fetched again from
+    /// the store and written over its slot instead of being dropped. This is
+    /// for stores that still have a good copy of the page (a mirror for
+    /// example). A repaired slot is not counted as corrupted anymore. A page
+    /// is still dropped if the store fails to return it or if its slot got
+    /// quarantined. Returns (repaired, dropped) pages.
+    pub async fn scrub_repair(&mut self) -> (usize, usize) {
+        let mut dropped = vec![];
+        let mut repaired = 0;
+
+        for (page, address) in self.corrupted() {
+            if self.strike(address) {
+                dropped.push((page, address));
+                continue;
+            }
+
+            match self.refetch(page, address).await {
+                Ok(_) => {
+                    self.map
+                        .at_mut(address)
+                        .header_mut()
+                        .set(Flags::Corrupted, false);
+                    log::info!("page {page} at {address} had a bad crc, repaired from store");
+                    repaired += 1;
+                }
+                Err(err) => {
+                    log::error!(
+                        "failed to repair page {page} at {address}: {err}, dropping it from cache"
+                    );
+                    dropped.push((page, address));
+                }
+            }
+        }
+
+        PAGES_QUARANTINED.set(self.quarantined as i64);
+        SCRUB_REPAIRED.inc_by(repaired as u64);
+        SCRUB_DROPPED.inc_by(dropped.len() as u64);
+
+        self.release(&dropped);
+        (repaired, dropped.len())
+    }
+
+    /// drops a clean page that was found with a bad crc outside of scrub
+    /// (a verified read for example). The bad crc counts against its slot
+    /// like in [`Cache::scrub`], and the page is loaded again from the
+    /// store on next access
+    pub fn drop_corrupted(&mut self, page: u32) {
+        let Some(address) = self.cache.peek(&page).map(|cached| cached.address) else {
+            return;
+        };
+
+        if self.map.at(address).header().flag(Flags::Dirty) {
+            return;
+        }
+
+        log::warn!("page {page} at {address} has a bad crc, dropping it from cache");
+        self.strike(address);
+        PAGES_QUARANTINED.set(self.quarantined as i64);
+        self.release(&[(page, address)]);
+    }
+
+    /// clean cached pages with a bad crc as (page, address)
+    fn corrupted(&self) -> Vec<(u32, usize)> {
+        self.cache
+            .iter()
+            .filter(|(_, cached)| {
+                let page = self.map.at(cached.address);
+                !page.header().flag(Flags::Dirty) && !page.is_crc_ok()
+            })
+            .map(|(page, cached)| (*page, cached.address))
+            .collect()
+    }
+
+    /// records a bad crc at the slot, returns true if that was the second
+    /// one and the slot is now quarantined
+    fn strike(&mut self, address: usize) -> bool {
+        let mut slot = self.map.at_mut(address);
+        let header = slot.header_mut();
+        if header.flag(Flags::Corrupted) {
+            log::error!("slot {address} had a bad crc before, quarantining it");
+            header.set(Flags::Quarantined, true);
+            self.quarantined += 1;
+            return true;
+        }
+
+        header.set(Flags::Corrupted, true);
+        false
+    }
+
+    /// loads page from the store again over its (clean) slot
+    async fn refetch(&mut self, page: u32, address: usize) -> Result<()> {
+        let data = self.store.get(page).await?;
+        let mut slot = self.map.at_mut(address);
+        let dest = slot.data_mut();
+        match data {
+            Some(data) => copy_page(dest, &data, self.page_len(page))?,
+            None => dest.fill(0),
+        }
+
+        slot.update_crc();
+        Ok(())
+    }
+
+    /// number of quarantined slots of the map, see [`Cache::scrub`]
+    pub fn quarantined(&self) -> usize {
+        self.quarantined
+    }
+
+    /// remove pages from the cache and mark their slots as free. Only clean
+    /// pages can be released otherwise data is lost. Quarantined slots are
+    /// not reused
+    fn release(&mut self, pages: &[(u32, usize)]) {
+        for (page, address) in pages.iter() {
+            self.cache.pop(page);
+            self.eviction.dropped(*page);
+            let mut slot = self.map.at_mut(*address);
+            slot.header_mut().set(Flags::Occupied, false);
+            if !slot.header().flag(Flags::Quarantined) {
+                self.free.push(*address);
+            }
+        }
+
+        self.free.sort_unstable_by(|a, b| b.cmp(a));
+        update_lru_metrics(&self.cache);
+    }
+
+    /// drops page from the cache (even if dirty) and discards it on the
+    /// store, so it reads as zeros afterwards. A store that can't discard
+    /// would return the old content again so the page is zeroed in the cache
+    /// instead, and written to the store as zeros like any other write
+    pub async fn discard(&mut self, page: u32) -> Result<()> {
+        if page as usize >= self.pages {
+            return Err(Error::PageIndexOutOfRange);
+        }
+
+        if !self.store.can_discard() {
+            let mut slot = if self.cache.contains(&page) {
+                self.get_mut(page).await?
+            } else {
+                // the old content is not needed so nothing is loaded
+                self.warm_with(page, Some(None)).await?
+            };
+
+            slot.data_mut().fill(0);
+            slot.header_mut().set(Flags::Dirty, true);
+            return Ok(());
+        }
+
+        if let Some(cached) = self.cache.peek(&page) {
+            let address = cached.address;
+            // the content is not needed anymore so it's fine to lose it
+            self.map
+                .at_mut(address)
+                .header_mut()
+                .set(Flags::Dirty, false);
+            self.release(&[(page, address)]);
+        }
+
+        self.store.discard(page).await
+    }
+
+    /// how long the oldest dirty page has been waiting to be written to
+    /// the store, zero if no page is dirty
+    pub fn writeback_lag(&self) -> Duration {
+        let now = self.clock.now();
+        self.cache
+            .iter()
+            .filter(|(_, cached)| self.map.header_at(cached.address).flag(Flags::Dirty))
+            .map(|(_, cached)| now.saturating_duration_since(cached.dirty_since))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// update the writeback lag metric, see [`Cache::writeback_lag`]
+    pub fn update_writeback_lag(&self) -> Duration {
+        let lag = self.writeback_lag();
+        WRITEBACK_LAG.set(lag.as_secs_f64());
+        lag
+    }
+
+    /// iterate over all cached pages that are currently marked dirty. it yields
+    /// the (page id, map address) of each dirty page. This does not update the
+    /// lru so it can be called while reads are happening
+    pub fn dirty_pages(&self) -> impl Iterator<Item = (u32, usize)> + '_ {
+        self.cache.iter().filter_map(|(page, cached)| {
+            if self.map.header_at(cached.address).flag(Flags::Dirty) {
+                Some((*page, cached.address))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// locks the pages in range until the guard is dropped, writers of
+    /// the same pages have to hold the lock. Multi page writes must lock
+    /// the full range at once so pages are always locked in order
+    pub async fn lock(&self, pages: RangeInclusive<u32>) -> PagesGuard {
+        self.locks.lock(pages).await
+    }
+
     /// gets the page with index <page> if already in cache, other wise return None
     /// TODO: enhance access to this method. the `mut` is only needed to allow
     /// the lru cache to update, but the block itself doesn't need it because it
@@ -138,10 +725,41 @@ where
         if page as usize >= self.pages {
             return Err(Error::PageIndexOutOfRange);
         }
-        let item = self.cache.get(&page);
-        match item {
-            Some(cached) => Ok(self.map.at(cached.address)),
-            None => self.warm(page).await.map(Page::from),
+        let sequential = self.last_read.and_then(|last| last.checked_add(1)) == Some(page);
+        self.last_read = Some(page);
+
+        let address = self
+            .eviction
+            .touch(&mut self.cache, page)
+            .map(|cached| cached.address);
+
+        // the page itself goes last (or is already cached) so the pages
+        // ahead never displace it. The next batch is only read once the
+        // pages read ahead before run out, so a sequential read that hits
+        // the cache never waits for the store
+        let next_cached = self.cache.contains(&page.saturating_add(1));
+        if sequential && self.read_ahead > 0 && !next_cached {
+            self.warm_ahead(
+                page,
+                self.read_ahead.max(self.warm_batch),
+                &PAGES_PREFETCHED,
+            )
+            .await;
+        } else if address.is_none() && self.warm_batch > 0 {
+            self.warm_ahead(page, self.warm_batch, &PAGES_WARMED_AHEAD)
+                .await;
+        }
+
+        match address {
+            Some(address) => {
+                self.hits += 1;
+                READ_FROM_RESIDENT.inc();
+                Ok(self.map.at(address))
+            }
+            None => {
+                READ_FROM_WARM.inc();
+                self.warm(page).await.map(Page::from)
+            }
         }
     }
 
@@ -151,149 +769,615 @@ where
             return Err(Error::PageIndexOutOfRange);
         }
 
-        let item = self.cache.get(&page);
+        let item = self.eviction.touch(&mut self.cache, page);
         match item {
-            Some(cached) => Ok(self.map.at_mut(cached.address)),
+            Some(cached) => {
+                self.hits += 1;
+                let page = self.map.at_mut(cached.address);
+                if !page.header().flag(Flags::Dirty) {
+                    cached.dirty_since = self.clock.now();
+                }
+
+                Ok(page)
+            }
             None => self.warm(page).await,
         }
     }
 
+    /// loads up to count pages after page that are not cached yet. The
+    /// pages are read from the store concurrently, a page that fails to
+    /// load is skipped since nobody asked for it yet. This stops once a
+    /// page ahead would displace a dirty page or page itself, pages that
+    /// may never be read are not worth a write to the store. Errors are
+    /// never returned, the read of page itself must not fail because of
+    /// the pages after it
+    async fn warm_ahead(&mut self, page: u32, count: usize, counter: &IntCounter) {
+        // a slot is always left for the page itself
+        let room = self.map.page_count().saturating_sub(1);
+        let end = (page as usize + 1 + count).min(self.pages) as u32;
+        let ahead: Vec<u32> = (page + 1..end)
+            .filter(|index| !self.cache.contains(index))
+            .take(room)
+            .collect();
+
+        if ahead.is_empty() {
+            return;
+        }
+
+        let store = &self.store;
+        let loaded = join_all(ahead.iter().map(|index| async move {
+            store
+                .get(*index)
+                .await
+                .map(|data| data.map(Vec::<u8>::from))
+        }))
+        .await;
+
+        // a page that is not cached yet needs a slot after the pages ahead
+        let reserved = usize::from(!self.cache.contains(&page));
+        for (index, data) in ahead.into_iter().zip(loaded) {
+            // the slot that is displaced now or once page is loaded
+            if self.free.len() <= reserved {
+                match self.eviction.victim(&self.cache) {
+                    Some((victim, address))
+                        if victim != page && !self.map.header_at(address).flag(Flags::Dirty) => {}
+                    _ => break,
+                }
+            }
+
+            let result = match data {
+                Ok(data) => self.warm_with(index, Some(data)).await.map(|_| ()),
+                Err(err) => {
+                    WARM_AHEAD_ERR.inc();
+                    log::debug!("failed to warm page {index} ahead of {page}: {err}");
+                    continue;
+                }
+            };
+
+            if let Err(err) = result {
+                // a slot could not be set up for the page, the pages after
+                // it would most likely fail the same way
+                WARM_AHEAD_ERR.inc();
+                log::warn!(
+                    "failed to warm page {index} ahead of {page}: {err}, stopping read ahead"
+                );
+                break;
+            }
+            counter.inc();
+        }
+    }
+
     async fn warm(&mut self, page: u32) -> Result<PageMut> {
+        self.warm_with(page, None).await
+    }
+
+    /// loads page into a slot. With loaded set the store is not asked
+    /// again and that data is used instead
+    async fn warm_with(&mut self, page: u32, loaded: Option<Option<Vec<u8>>>) -> Result<PageMut> {
         // first find which block to evict.
 
         let mut pge: PageMut;
-        if self.cache.len() < self.cache.cap().get() {
+        let mut victim = None;
+        if let Some(address) = self.free.pop() {
             // the map still has free slots then
-            pge = self.map.at_mut(self.cache.len());
+            pge = self.map.at_mut(address);
         } else {
             // other wise, we need to evict one of the blocks from the map file
-            // so wee peek into lru find out which one we can kick out first.
+            // so we ask the eviction policy which one we can kick out first.
 
-            // we know that the cache is full, so this will always return Some
-            let (page_index, item) = self.cache.peek_lru().unwrap();
+            // we know that the map is full, so this will always return Some
+            let (page_index, address) = self.eviction.victim(&self.cache).unwrap();
+            let len = self.page_len(page_index);
             // so block block_index stored at map location item.location
             // can be evicted
-            pge = self.map.at_mut(item.address);
+            pge = self.map.at_mut(address);
 
             // store this in permanent store
             // eviction should only happen if blk is dirty
             // note it's up to user of the cache to mark blocks as
             // dirty otherwise they won't evict to backend
             if pge.header().flag(Flags::Dirty) {
-                log::debug!("page {} eviction", *page_index);
+                log::debug!("page {} eviction", page_index);
                 PAGES_EVICTED.inc();
                 let timer = EVICT_HISTOGRAM.start_timer();
-                self.store.set(*page_index, pge.data()).await?;
+                // like any written page it is only durable once the store is
+                // flushed (on the next flush of the device)
+                store_set(&mut self.store, page_index, &pge.data()[..len])
+                    .await
+                    .map_err(store_set_err)?;
                 timer.observe_duration();
             } else {
-                log::trace!("block {} eviction skipped", *page_index);
+                log::trace!("block {} eviction skipped", page_index);
             }
 
             // now the block location is ready to be reuse
-            // note that the next call to push will actually remove that item from the lru
+            victim = Some(page_index);
         }
 
-        pge.header_mut()
-            .set_page(page)
-            .set(Flags::Dirty, false)
-            .set(Flags::Occupied, true);
-
-        assert_eq!(pge.header().page(), page, "page header update");
-        let timer = LOAD_HISTOGRAM.start_timer();
-        let data = self.store.get(page).await?;
-        timer.observe_duration();
+        let data = match loaded {
+            Some(data) => Ok(data.map(PageData::Owned)),
+            None => {
+                let timer = LOAD_HISTOGRAM.start_timer();
+                let data = match self.store.try_get_sync(page) {
+                    Some(data) => data,
+                    None => self.store.get(page).await,
+                };
+                timer.observe_duration();
+                data
+            }
+        };
+        // the slot is left untouched if the page can't be loaded. A free slot
+        // goes back to the free list, a victim keeps its slot and stays cached
+        let data = match data {
+            Ok(data) => data,
+            Err(err) => {
+                if victim.is_none() {
+                    self.free.push(pge.address());
+                }
+                return Err(err);
+            }
+        };
         if let Some(data) = data {
             // override block
             PAGES_LOADED.inc();
             log::trace!("warming cache for block {page}");
-            pge.data_mut().copy_from_slice(&data);
-            pge.update_crc();
+            let len = self.page_len(page);
+            if let Err(err) = copy_page(pge.data_mut(), &data, len) {
+                log::error!(
+                    "store returned {} bytes for page {page}, expected {len}",
+                    data.len()
+                );
+                if victim.is_none() {
+                    self.free.push(pge.address());
+                }
+                return Err(err);
+            }
         } else {
-            // should we zero it out ?
-            // or not
+            // the store doesn't have this page, so it reads as zeros. The slot
+            // could still hold the data of the evicted page
+            pge.data_mut().fill(0);
+        }
+
+        // the slot only holds the page once its data is in place
+        pge.header_mut()
+            .set_page(page)
+            .set(Flags::Dirty, false)
+            .set(Flags::Occupied, true);
+        pge.update_crc();
+        assert_eq!(pge.header().page(), page, "page header update");
+
+        // we can't rely on push to drop the evicted page since the lru
+        // capacity can be bigger than the map
+        if let Some(victim) = victim {
+            self.cache.pop(&victim);
+            self.eviction.dropped(victim);
+
+            PAGE_DISPLACEMENTS.inc();
+            self.displaced += 1;
+            log::debug!("page {page} displaced page {victim}");
+            if let Some(hook) = &self.on_displace {
+                hook(page, victim);
+            }
         }
 
         self.cache.push(
             page,
             CachedPage {
                 address: pge.address(),
+                dirty_since: self.clock.now(),
             },
         );
+        self.eviction.loaded(page);
 
-        PAGES_CACHED.set(self.cache.len() as i64);
+        update_lru_metrics(&self.cache);
         Ok(pge)
     }
 
+    /// resize grows the cache to size. All dirty pages are evicted to the
+    /// store first so if the process dies while the cache file is
+    /// being rewritten no data is lost (only the warm pages). Please check
+    /// [`PageMap::grow`] for more details. With multiple cache files the
+    /// size is split over all files, see [`MultiPageMap::grow`].
+    pub async fn resize(&mut self, size: ByteSize) -> Result<()> {
+        self.evict(Duration::MAX).await?;
+
+        // slots move when the shards before them grow
+        let located: Vec<(u32, (usize, usize))> = self
+            .cache
+            .iter()
+            .map(|(page, cached)| (*page, self.map.locate(cached.address)))
+            .collect();
+        let grown = self.map.grow(size);
+        for (page, (shard, local)) in located {
+            if let Some(cached) = self.cache.peek_mut(&page) {
+                cached.address = self.map.address(shard, local);
+            }
+        }
+
+        // free slots are collected again from the map, the new ones
+        // included. Lowest address goes last
+        self.free = self
+            .map
+            .iter()
+            .filter(|page| {
+                let header = page.header();
+                !header.flag(Flags::Occupied) && !header.flag(Flags::Quarantined)
+            })
+            .map(|page| page.address())
+            .collect();
+        self.free.reverse();
+        grown?;
+
+        let cap = NonZeroUsize::new(self.map.page_count()).ok_or(Error::ZeroSize)?;
+        self.cache.resize(cap);
+
+        Ok(())
+    }
+
+    /// evicts all dirty pages to the store and waits until the cache map is
+    /// flushed to disk. Once this returns, the store holds all written data.
+    /// Returns the number of pages that were written
+    pub async fn sync_all(&mut self) -> Result<usize> {
+        let written = self.evict_at_least(Duration::MAX, 1).await?;
+        self.sync()?;
+        Ok(written)
+    }
+
+    /// flush the cache map and wait until it's on disk
+    pub fn sync(&self) -> Result<()> {
+        self.map.flush()
+    }
+
     pub fn flush(&self) -> Result<()> {
         self.map.flush_async()?;
         Ok(())
     }
 
+    /// flush the backend store, see [`Store::flush`]
+    pub async fn flush_store(&self) -> Result<()> {
+        self.store.flush().await
+    }
+
     pub fn flush_range(&self, location: usize, count: usize) -> Result<()> {
         self.map.flush_range_async(location, count)
     }
 
-    // try evicting whatever it can in no_longer_than
+    // try evicting whatever it can in no_longer_than. Dirty pages with
+    // contiguous page ids are written to the store together. Nothing is
+    // written if less than evict batch pages are dirty
     pub async fn evict(&mut self, no_longer_than: Duration) -> Result<()> {
-        let start = Instant::now();
-        for (page_index, cached) in self.cache.iter().rev() {
-            log::trace!("check page {} for eviction", *page_index);
-            let mut page = self.map.at_mut(cached.address);
-            if page.header().flag(Flags::Dirty) {
-                PAGES_EVICTED.inc();
-                log::trace!("background eviction of {}", *page_index);
-                self.store.set(*page_index, page.data()).await?;
-                page.header_mut().set(Flags::Dirty, false);
-            }
+        self.evict_at_least(no_longer_than, self.evict_batch)
+            .await?;
+        Ok(())
+    }
 
-            if start.elapsed() > no_longer_than {
-                return Ok(());
-            }
+    async fn evict_at_least(&mut self, no_longer_than: Duration, min: usize) -> Result<usize> {
+        let dirty: Vec<(u32, usize)> = self.dirty_pages().collect();
+        if dirty.len() < min {
+            log::trace!("only {} dirty pages, waiting for {min}", dirty.len());
+            EVICT_QUEUE_DEPTH.set(dirty.len() as i64);
+            return Ok(0);
         }
 
-        Ok(())
+        self.write_back(dirty, no_longer_than).await
     }
-}
 
-pub struct NullStore;
+    /// evicts all pages that are dirty for at least age, however long it
+    /// takes and whatever the evict batch is. Returns the number of evicted
+    /// pages, they are durable in the store once this returns
+    pub async fn evict_older_than(&mut self, age: Duration) -> Result<usize> {
+        let now = self.clock.now();
+        let dirty: Vec<(u32, usize)> = self
+            .cache
+            .iter()
+            .filter(|(_, cached)| {
+                self.map.header_at(cached.address).flag(Flags::Dirty)
+                    && now.saturating_duration_since(cached.dirty_since) >= age
+            })
+            .map(|(page, cached)| (*page, cached.address))
+            .collect();
+
+        if dirty.is_empty() {
+            return Ok(0);
+        }
 
-#[async_trait::async_trait]
-impl Store for NullStore {
-    async fn set(&mut self, _index: u32, _block: &[u8]) -> Result<()> {
-        Ok(())
+        self.write_back(dirty, Duration::MAX).await
     }
 
-    async fn get(&self, _index: u32) -> Result<Option<PageData>> {
-        Ok(None)
-    }
+    /// writes the dirty pages to the store (until no_longer_than is over)
+    /// then flushes the store and marks the written pages clean. Returns
+    /// the number of written pages
+    async fn write_back(
+        &mut self,
+        mut dirty: Vec<(u32, usize)>,
+        no_longer_than: Duration,
+    ) -> Result<usize> {
+        let start = self.clock.now();
+        dirty.sort_unstable();
+
+        // runs are written oldest first, otherwise the pages at the end of
+        // the device never make it to the store if every tick runs out of
+        // time before reaching them
+        let mut queue: Vec<&[(u32, usize)]> = runs(&dirty).collect();
+        queue.sort_by_cached_key(|run| {
+            run.iter()
+                .filter_map(|(page, _)| self.cache.peek(page))
+                .map(|cached| cached.dirty_since)
+                .min()
+        });
+
+        // pages are only marked clean once the store flushed them, a store
+        // can buffer writes so a page is not durable just because set returned
+        let mut written = Vec::with_capacity(dirty.len());
+        let tail = (self.tail != 0).then(|| (self.pages as u32 - 1, self.tail));
+        for run in queue {
+            let (first, _) = run[0];
+            log::trace!("background eviction of [{first}: {}]", run.len());
+            if run.len() == 1 {
+                store_set(
+                    &mut self.store,
+                    first,
+                    stored(self.map.data_at(run[0].1), first, tail),
+                )
+                .await
+                .map_err(store_set_err)?;
+            } else {
+                let pages: Vec<&[u8]> = run
+                    .iter()
+                    .map(|(page, address)| stored(self.map.data_at(*address), *page, tail))
+                    .collect();
+
+                self.store
+                    .set_many(first, &pages)
+                    .await
+                    .map_err(store_set_err)?;
+            }
 
-    fn size(&self) -> ByteSize {
-        ByteSize::b(u64::MAX)
-    }
+            PAGES_EVICTED.inc_by(run.len() as u64);
+            written.extend_from_slice(run);
 
-    fn page_size(&self) -> usize {
-        0
+            if self.clock.now().saturating_duration_since(start) > no_longer_than {
+                break;
+            }
+        }
+
+        self.store.flush().await.map_err(store_set_err)?;
+
+        for (_, address) in &written {
+            let mut page = self.map.at_mut(*address);
+            // crc is only valid for clean pages
+            page.update_crc();
+            page.header_mut().set(Flags::Dirty, false);
+        }
+
+        EVICT_QUEUE_DEPTH.set(self.dirty_pages().count() as i64);
+        Ok(written.len())
     }
 }
 
-#[cfg(test)]
-mod test {
-    use crate::store;
+fn update_lru_metrics(lru: &LruCache<u32, CachedPage>) {
+    PAGES_CACHED.set(lru.len() as i64);
+    LRU_ENTRIES.set(lru.len() as i64);
+    LRU_BYTES.set(lru_memory(lru.len(), lru.cap().get()) as i64);
+}
 
-    use super::*;
+/// approximate memory used by an lru with len entries. The hash table
+/// is allocated for the full capacity up front while the list nodes are
+/// allocated per entry
+fn lru_memory(len: usize, cap: usize) -> usize {
+    use std::mem::size_of;
+    // key, value and the prev and next pointers
+    let node = size_of::<u32>() + size_of::<CachedPage>() + 2 * size_of::<usize>();
+    // key and node pointers plus a control byte
+    let slot = 2 * size_of::<usize>() + 1;
+
+    len * node + cap * slot
+}
 
-    #[tokio::test]
-    async fn test_cache_new() {
-        const PATH: &str = "/tmp/cache.test";
-        // start from clean slate
-        let _ = std::fs::remove_file(PATH);
+/// splits sorted (page, address) pairs into runs of contiguous page ids
+fn runs(pages: &[(u32, usize)]) -> impl Iterator<Item = &[(u32, usize)]> {
+    let mut rest = pages;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
 
-        let mut cache = Cache::new(NullStore, PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        let len = rest
+            .windows(2)
+            .position(|w| w[1].0 != w[0].0 + 1)
+            .map_or(rest.len(), |i| i + 1);
 
-        let page = cache.get_mut(20).await;
-        //this block does not exist in the cache file yet.
-        // the NullStore is HUGE in size, so while the cache size is only 10kib
-        // blocks can be retrieved behind the cache size but as long as they
+        let (run, tail) = rest.split_at(len);
+        rest = tail;
+        Some(run)
+    })
+}
+
+/// polls all futures concurrently, returns their outputs in order
+async fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let mut futures: Vec<Pin<Box<F>>> = futures.into_iter().map(Box::pin).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+
+    std::future::poll_fn(|cx| {
+        let mut pending = false;
+        for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+            if output.is_some() {
+                continue;
+            }
+
+            match future.as_mut().poll(cx) {
+                Poll::Ready(value) => *output = Some(value),
+                Poll::Pending => pending = true,
+            }
+        }
+
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })
+    .await;
+
+    outputs.into_iter().flatten().collect()
+}
+
+/// set page on store using the sync fast path if the store has one
+async fn store_set<S: Store>(store: &mut S, index: u32, page: &[u8]) -> Result<()> {
+    match store.try_set_sync(index, page) {
+        Some(result) => result,
+        None => store.set(index, page).await,
+    }
+}
+
+/// the part of the data of page the store holds. With tail set to the
+/// (page, len) of a partial last page, only its first len bytes are stored
+fn stored(data: &[u8], page: u32, tail: Option<(u32, usize)>) -> &[u8] {
+    match tail {
+        Some((last, len)) if page == last => &data[..len],
+        _ => data,
+    }
+}
+
+fn store_set_err(err: Error) -> Error {
+    STORE_SET_ERR.inc();
+    err
+}
+
+/// copies the data the store returned for a page into dest. The store can
+/// return less than a page only if it holds just len bytes of the page (the
+/// tail of a store that is not page aligned), the rest of dest is zeroed
+fn copy_page(dest: &mut [u8], data: &[u8], len: usize) -> Result<()> {
+    if data.len() == dest.len() {
+        dest.copy_from_slice(data);
+    } else if data.len() == len {
+        dest[..len].copy_from_slice(data);
+        dest[len..].fill(0);
+    } else {
+        return Err(Error::InvalidPageSize);
+    }
+
+    Ok(())
+}
+
+/// Pages is a cursor over all pages of the device in order, for example to
+/// back up a live device. Cached pages (including the dirty ones that are
+/// not in the store yet) are read from the cache, other pages are read from
+/// the store without loading them in the cache so a full scan doesn't push
+/// out the working set. Holes in the store are skipped, cached pages are
+/// always returned even if they only hold zeros.
+///
+/// The cache is borrowed for as long as the cursor lives, so nothing can
+/// write to the device in between and the pages are a consistent view
+pub struct Pages<'a, S>
+where
+    S: Store,
+{
+    cache: &'a mut Cache<S>,
+    next: u32,
+}
+
+impl<'a, S> Pages<'a, S>
+where
+    S: Store,
+{
+    /// returns the next page as (index, data), None once all pages are done
+    pub async fn next(&mut self) -> Option<Result<(u32, PageData<'_>)>> {
+        loop {
+            let index = self.next;
+            if index as usize >= self.cache.pages {
+                return None;
+            }
+            self.next += 1;
+
+            if let Some(address) = self.cache.cache.peek(&index).map(|cached| cached.address) {
+                let data = self.cache.map.data_at(address);
+                return Some(Ok((index, PageData::Borrowed(data))));
+            }
+
+            match self.cache.store.get(index).await {
+                Ok(Some(data)) => return Some(Ok((index, PageData::Owned(data.into())))),
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// NullStore holds nothing, reads always return no data and
+/// writes are dropped. By default it reports the max size that can be
+/// addressed with its page size
+pub struct NullStore {
+    page_size: usize,
+    size: ByteSize,
+}
+
+impl NullStore {
+    pub fn new(page_size: ByteSize) -> Self {
+        Self::with_size(ByteSize::b(MAX_PAGE_COUNT * page_size.as_u64()), page_size)
+    }
+
+    /// null store that reports the given size
+    pub fn with_size(size: ByteSize, page_size: ByteSize) -> Self {
+        Self {
+            page_size: page_size.as_u64() as usize,
+            size,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for NullStore {
+    async fn set(&mut self, _index: u32, _block: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    async fn get(&self, _index: u32) -> Result<Option<PageData>> {
+        Ok(None)
+    }
+
+    fn size(&self) -> ByteSize {
+        self.size
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    // all pages are holes
+    fn can_discard(&self) -> bool {
+        true
+    }
+
+    fn describe(&self) -> String {
+        format!("null ({})", self.size.to_string_as(true))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::store;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cache_new() {
+        const PATH: &str = "/tmp/cache.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mut cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+
+        let page = cache.get_mut(20).await;
+        //this block does not exist in the cache file yet.
+        // the NullStore is HUGE in size, so while the cache size is only 10kib
+        // blocks can be retrieved behind the cache size but as long as they
         // are
         assert!(page.is_ok());
 
@@ -323,7 +1407,13 @@ mod test {
         // start from clean slate
         let _ = std::fs::remove_file(PATH);
 
-        let mut cache = Cache::new(NullStore, PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        let mut cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
 
         let page = cache.get_mut(20).await;
         //this block does not exist in the cache file yet.
@@ -344,7 +1434,13 @@ mod test {
         // drop cache
         drop(cache);
 
-        let mut cache = Cache::new(NullStore, PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        let mut cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
 
         // block 0 was not here before just to make sure
         let page = cache.get(0).await;
@@ -368,6 +1464,886 @@ mod test {
         assert_eq!(page.data().len(), 1024);
     }
 
+    #[tokio::test]
+    async fn test_dirty_pages() {
+        const PATH: &str = "/tmp/cache.dirty.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mut cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+
+        assert_eq!(cache.dirty_pages().count(), 0);
+
+        for index in [1, 3, 5] {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.header_mut().set(Flags::Dirty, true);
+        }
+
+        // this page is loaded but never modified
+        assert!(cache.get(7).await.is_ok());
+
+        let mut dirty: Vec<(u32, usize)> = cache.dirty_pages().collect();
+        dirty.sort();
+
+        // pages are allocated in map order 1 -> 0, 3 -> 1, 5 -> 2, 7 -> 3
+        assert_eq!(dirty, vec![(1, 0), (3, 1), (5, 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_is_resident() {
+        const PATH: &str = "/tmp/cache.resident.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(2), ByteSize::kib(1)).unwrap();
+
+        assert!(!cache.is_resident(0));
+        cache.get(0).await.unwrap();
+        cache.get(1).await.unwrap();
+        assert!(cache.is_resident(0));
+        assert!(cache.is_resident(1));
+
+        // checking 0 doesn't make it recent, so it's still the one evicted
+        assert!(cache.is_resident(0));
+        cache.get(2).await.unwrap();
+        assert!(!cache.is_resident(0));
+        assert!(cache.is_resident(1));
+        assert!(cache.is_resident(2));
+    }
+
+    #[tokio::test]
+    async fn test_drop_clean() {
+        const PATH: &str = "/tmp/cache.drop_clean.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(5), ByteSize::kib(1)).unwrap();
+
+        // 0 is the lru tail but it's dirty so it's kept
+        cache
+            .get_mut(0)
+            .await
+            .unwrap()
+            .header_mut()
+            .set(Flags::Dirty, true);
+
+        for index in 1..5 {
+            cache.get(index).await.unwrap();
+        }
+
+        assert_eq!(cache.drop_clean(0), 0);
+        assert_eq!(cache.drop_clean(2), 2);
+        assert_eq!(cache.occupied(), 3);
+        assert!(cache.is_resident(0));
+        assert!(!cache.is_resident(1));
+        assert!(!cache.is_resident(2));
+
+        // already enough free slots
+        assert_eq!(cache.drop_clean(2), 0);
+
+        // nothing was written to the store
+        assert!(cache.store.mem.is_empty());
+
+        // free slots are reused lowest first without evicting
+        assert_eq!(cache.get(7).await.unwrap().address(), 1);
+        assert_eq!(cache.get(8).await.unwrap().address(), 2);
+        assert!(cache.is_resident(0));
+        assert!(cache.store.mem.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drop_clean_eviction() {
+        const PATH: &str = "/tmp/cache.drop_clean_eviction.test";
+
+        // 1 is the lru tail, 2 is the least used page
+        for (eviction, dropped) in [(Eviction::Lru, 1), (Eviction::Lfu, 2)] {
+            let _ = std::fs::remove_file(PATH);
+            let mem = store::InMemory::new(10);
+            let mut cache = Cache::new(mem, PATH, ByteSize::kib(3), ByteSize::kib(1))
+                .unwrap()
+                .with_eviction(eviction);
+
+            for index in [0, 1, 2, 1, 1, 2, 0] {
+                cache.get(index).await.unwrap();
+            }
+
+            assert_eq!(cache.drop_clean(1), 1);
+            assert!(!cache.is_resident(dropped), "{eviction}");
+            assert_eq!(cache.occupied(), 2, "{eviction}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scrub() {
+        const PATH: &str = "/tmp/cache.scrub.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(5), ByteSize::kib(1)).unwrap();
+
+        for index in 0..4 {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.data_mut().fill(index as u8 + 1);
+            page.header_mut().set(Flags::Dirty, true);
+        }
+
+        // eviction makes pages clean with a valid crc
+        cache.evict(Duration::MAX).await.unwrap();
+        assert_eq!(cache.scrub(), 0);
+
+        // corrupt page 1 behind the cache back, and make 2 dirty
+        cache.map.at_mut(1).data_mut().fill(0xff);
+        let mut page = cache.get_mut(2).await.unwrap();
+        page.data_mut().fill(0xff);
+        page.header_mut().set(Flags::Dirty, true);
+
+        assert_eq!(cache.scrub(), 1);
+        assert!(!cache.is_resident(1));
+        assert!(cache.is_resident(2));
+        assert_eq!(cache.scrub(), 0);
+
+        // page is loaded again from the store
+        let page = cache.get(1).await.unwrap();
+        assert!(page.data().iter().all(|v| *v == 2));
+    }
+
+    #[tokio::test]
+    async fn scrub_repair() {
+        const PATH: &str = "/tmp/cache.scrub_repair.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(5), ByteSize::kib(1)).unwrap();
+
+        for index in 0..2 {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.data_mut().fill(index as u8 + 1);
+            page.header_mut().set(Flags::Dirty, true);
+        }
+        cache.evict(Duration::MAX).await.unwrap();
+
+        // the page is fetched again and stays cached
+        cache.map.at_mut(1).data_mut().fill(0xff);
+        assert_eq!(cache.scrub_repair().await, (1, 0));
+        assert!(cache.is_resident(1));
+        assert!(cache.map.at(1).is_crc_ok());
+        assert!(cache.map.at(1).data().iter().all(|v| *v == 2));
+        assert!(!cache.map.at(1).header().flag(Flags::Corrupted));
+
+        // the error was cleared so the slot is repaired again
+        cache.map.at_mut(1).data_mut().fill(0xff);
+        assert_eq!(cache.scrub_repair().await, (1, 0));
+        assert_eq!(cache.quarantined(), 0);
+
+        // a slot that failed before without a repair is quarantined
+        cache.map.at_mut(1).header_mut().set(Flags::Corrupted, true);
+        cache.map.at_mut(1).data_mut().fill(0xff);
+        assert_eq!(cache.scrub_repair().await, (0, 1));
+        assert!(!cache.is_resident(1));
+        assert_eq!(cache.quarantined(), 1);
+    }
+
+    #[tokio::test]
+    async fn drop_corrupted() {
+        const PATH: &str = "/tmp/cache.drop_corrupted.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(5), ByteSize::kib(1)).unwrap();
+
+        // page 0 is loaded again in the same slot so the second bad crc
+        // quarantines it
+        for strike in 0..2 {
+            let address = cache.get(0).await.unwrap().address();
+            cache.map.at_mut(address).data_mut().fill(0xff);
+            cache.drop_corrupted(0);
+            assert!(!cache.is_resident(0));
+            assert_eq!(cache.quarantined(), strike);
+        }
+
+        // dirty pages are never dropped
+        let mut page = cache.get_mut(1).await.unwrap();
+        page.header_mut().set(Flags::Dirty, true);
+        cache.drop_corrupted(1);
+        assert!(cache.is_resident(1));
+    }
+
+    #[tokio::test]
+    async fn quarantine() {
+        const PATH: &str = "/tmp/cache.quarantine.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(5), ByteSize::kib(1)).unwrap();
+
+        // page 0 always goes to the first free slot, so it's corrupted
+        // twice at the same slot
+        for strike in 0..2 {
+            let mut page = cache.get_mut(0).await.unwrap();
+            page.data_mut().fill(1);
+            page.header_mut().set(Flags::Dirty, true);
+            cache.evict(Duration::MAX).await.unwrap();
+
+            let address = cache.cache.peek(&0).unwrap().address;
+            cache.map.at_mut(address).data_mut().fill(0xff);
+            assert_eq!(cache.scrub(), 1);
+            assert_eq!(cache.quarantined(), strike);
+        }
+
+        assert_eq!(cache.quarantined(), 1);
+        // the quarantined slot is not used anymore
+        let page = cache.get(0).await.unwrap();
+        assert_ne!(page.address(), 0);
+        drop(page);
+        let free = cache.free.len();
+
+        // quarantine survives reopening the cache
+        drop(cache);
+        let cache = Cache::new(
+            store::InMemory::new(10),
+            PATH,
+            ByteSize::kib(5),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+        assert_eq!(cache.quarantined(), 1);
+        assert_eq!(cache.free.len(), free);
+    }
+
+    #[tokio::test]
+    async fn pages() {
+        const PATH: &str = "/tmp/cache.pages.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mut mem = store::InMemory::new(10);
+        mem.mem.insert(5, vec![5; 1024]);
+        mem.mem.insert(7, vec![7; 1024]);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(5), ByteSize::kib(1)).unwrap();
+
+        // dirty page that is not in the store yet, and a newer page 7
+        for index in [1, 7] {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.data_mut().fill(index as u8 + 1);
+            page.header_mut().set(Flags::Dirty, true);
+        }
+
+        let mut pages = cache.pages();
+        let mut found = vec![];
+        while let Some(page) = pages.next().await {
+            let (index, data) = page.unwrap();
+            found.push((index, data[0]));
+        }
+        assert_eq!(found, vec![(1, 2), (5, 5), (7, 8)]);
+
+        // page 5 was not loaded in the cache
+        assert!(!cache.is_resident(5));
+        assert!(!cache.store.mem.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn test_missing_page_zeroed() {
+        const PATH: &str = "/tmp/cache.zeroed.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(1), ByteSize::kib(1)).unwrap();
+
+        let mut page = cache.get_mut(0).await.unwrap();
+        page.data_mut().fill(1);
+        page.header_mut().set(Flags::Dirty, true);
+
+        // page 1 reuses the slot of page 0 but it is not in the store
+        let page = cache.get(1).await.unwrap();
+        assert_eq!(page.address(), 0);
+        assert!(page.data().iter().all(|v| *v == 0));
+        assert!(page.is_crc_ok());
+    }
+
+    #[tokio::test]
+    async fn test_resize() {
+        const PATH: &str = "/tmp/cache.resize.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(20);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(5), ByteSize::kib(1)).unwrap();
+
+        for index in 0..5 {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.data_mut().fill(index as u8 + 1);
+            page.header_mut().set(Flags::Dirty, true);
+        }
+
+        cache.resize(ByteSize::kib(10)).await.unwrap();
+        assert_eq!(cache.page_count(), 10);
+
+        // all pages are still in the same place
+        for index in 0..5 {
+            let page = cache.get(index).await.unwrap();
+            assert_eq!(page.address(), index as usize);
+            assert!(page.data().iter().all(|v| *v == index as u8 + 1));
+        }
+
+        // new pages goes to the new free slots without evicting any
+        for index in 5..10 {
+            let page = cache.get(index).await.unwrap();
+            assert_eq!(page.address(), index as usize);
+        }
+
+        assert_eq!(cache.occupied(), 10);
+
+        let mem = cache.inner();
+        // dirty pages were pushed to the store before resize
+        assert_eq!(mem.mem.len(), 5);
+
+        // and the cache can be opened with the new size
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        let page = cache.get(3).await.unwrap();
+        assert_eq!(page.address(), 3);
+        assert!(page.data().iter().all(|v| *v == 4));
+    }
+
+    #[tokio::test]
+    async fn test_resize_shards() {
+        const PATHS: [&str; 2] = [
+            "/tmp/cache.resize_shards.0.test",
+            "/tmp/cache.resize_shards.1.test",
+        ];
+        for path in PATHS {
+            let _ = std::fs::remove_file(path);
+        }
+
+        let open = |size| {
+            let shards = PATHS
+                .iter()
+                .map(|path| PageMap::new(path, size, ByteSize::kib(1)).unwrap())
+                .collect();
+            MultiPageMap::new(shards).unwrap()
+        };
+
+        let mut cache = Cache::with_map(store::InMemory::new(20), open(ByteSize::kib(2))).unwrap();
+        for index in 0..4 {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.data_mut().fill(index as u8 + 1);
+            page.header_mut().set(Flags::Dirty, true);
+        }
+
+        // the slots of the second shard move but the pages follow them
+        cache.resize(ByteSize::kib(8)).await.unwrap();
+        assert_eq!(cache.page_count(), 8);
+        for index in 0..4 {
+            let page = cache.get(index).await.unwrap();
+            assert_eq!(page.header().page(), index);
+            assert!(page.data().iter().all(|v| *v == index as u8 + 1));
+        }
+
+        for index in 4..8 {
+            cache.get(index).await.unwrap();
+        }
+        assert_eq!(cache.occupied(), 8);
+        drop(cache);
+
+        // every shard opens again with the same size
+        let cache = Cache::with_map(store::InMemory::new(20), open(ByteSize::kib(4))).unwrap();
+        assert_eq!(cache.occupied(), 8);
+    }
+
+    #[tokio::test]
+    async fn evict_runs() {
+        const PATH: &str = "/tmp/cache.evict_runs.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+
+        // touch pages out of order so lru order differs from page order
+        for index in [7, 2, 0, 8, 5, 1, 3] {
+            let mut page = cache.get_mut(index).await.unwrap();
+            if index != 3 {
+                page.data_mut().fill(index as u8);
+                page.header_mut().set(Flags::Dirty, true);
+            }
+        }
+
+        cache.evict(Duration::MAX).await.unwrap();
+        assert_eq!(cache.dirty_pages().count(), 0);
+
+        let mem = cache.inner();
+        // 7 was dirty first so its run goes first
+        assert_eq!(mem.runs, vec![(7, 2), (0, 3)]);
+        // single pages go through set
+        assert_eq!(mem.mem.len(), 6);
+        for (index, data) in mem.mem.iter() {
+            assert!(data.iter().all(|v| *v == *index as u8));
+        }
+    }
+
+    /// store where every set takes 10ms of the clock
+    struct Slow {
+        inner: store::InMemory,
+        clock: crate::clock::MockClock,
+    }
+
+    #[async_trait::async_trait]
+    impl Store for Slow {
+        async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+            self.clock.advance(Duration::from_millis(10));
+            self.inner.set(index, page).await
+        }
+
+        async fn get(&self, index: u32) -> Result<Option<store::Page>> {
+            self.inner.get(index).await
+        }
+
+        fn size(&self) -> ByteSize {
+            self.inner.size()
+        }
+
+        fn page_size(&self) -> usize {
+            self.inner.page_size()
+        }
+    }
+
+    #[tokio::test]
+    async fn discard() {
+        const PATH: &str = "/tmp/cache.discard.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut mem = store::InMemory::new(10);
+        for index in 0..3 {
+            mem.mem.insert(index, vec![1; 1024]);
+        }
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(2), ByteSize::kib(1)).unwrap();
+        cache.get(0).await.unwrap();
+        cache.discard(0).await.unwrap();
+        cache.discard(1).await.unwrap();
+        assert_eq!(cache.occupied(), 0);
+        assert!(!cache.store.mem.contains_key(&0));
+        assert!(!cache.store.mem.contains_key(&1));
+
+        // slow can't discard, the pages are zeroed in the cache instead
+        let _ = std::fs::remove_file(PATH);
+        let clock = crate::clock::MockClock::default();
+        let store = Slow {
+            inner: cache.inner(),
+            clock: clock.clone(),
+        };
+        let mut cache = Cache::new(store, PATH, ByteSize::kib(2), ByteSize::kib(1)).unwrap();
+        cache.get(2).await.unwrap();
+        cache.discard(2).await.unwrap();
+        cache.discard(3).await.unwrap();
+        for index in [2, 3] {
+            let page = cache.get(index).await.unwrap();
+            assert!(page.header().flag(Flags::Dirty));
+            assert!(page.data().iter().all(|v| *v == 0));
+        }
+
+        cache.sync_all().await.unwrap();
+        assert!(cache.store.inner.mem[&2].iter().all(|v| *v == 0));
+    }
+
+    #[tokio::test]
+    async fn evict_budget() {
+        const PATH: &str = "/tmp/cache.evict_budget.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let clock = crate::clock::MockClock::default();
+        let store = Slow {
+            inner: store::InMemory::new(10),
+            clock: clock.clone(),
+        };
+        let mut cache = Cache::new(store, PATH, ByteSize::kib(10), ByteSize::kib(1))
+            .unwrap()
+            .with_clock(Arc::new(clock));
+
+        for index in [0, 2, 4, 6] {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.header_mut().set(Flags::Dirty, true);
+        }
+
+        // the budget is over after the second run
+        cache.evict(Duration::from_millis(15)).await.unwrap();
+        assert_eq!(cache.dirty_pages().count(), 2);
+
+        cache.evict(Duration::MAX).await.unwrap();
+        assert_eq!(cache.dirty_pages().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn evict_fair() {
+        const PATH: &str = "/tmp/cache.evict_fair.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+
+        for index in [8, 6, 4, 2, 0] {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.header_mut().set(Flags::Dirty, true);
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // a zero budget only writes one run per tick
+        for tick in 0..5 {
+            // page 0 keeps getting written, it must not hold back the others
+            let mut page = cache.get_mut(0).await.unwrap();
+            page.header_mut().set(Flags::Dirty, true);
+
+            cache.evict(Duration::ZERO).await.unwrap();
+            assert_eq!(cache.dirty_pages().count(), 4 - tick);
+        }
+
+        assert_eq!(cache.inner().mem.len(), 5);
+    }
+
+    /// a store that only keeps pages once they are flushed
+    #[derive(Default)]
+    struct Buffered {
+        pending: std::sync::Mutex<Vec<(u32, Vec<u8>)>>,
+        durable: std::sync::Mutex<std::collections::HashMap<u32, Vec<u8>>>,
+        fail_flush: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl Store for Buffered {
+        async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+            self.pending.lock().unwrap().push((index, page.into()));
+            Ok(())
+        }
+
+        async fn get(&self, index: u32) -> Result<Option<store::Page>> {
+            let durable = self.durable.lock().unwrap();
+            Ok(durable.get(&index).cloned().map(store::Page::Owned))
+        }
+
+        async fn flush(&self) -> Result<()> {
+            if self.fail_flush {
+                return Err(anyhow::anyhow!("flush failed").into());
+            }
+
+            let mut durable = self.durable.lock().unwrap();
+            durable.extend(self.pending.lock().unwrap().drain(..));
+            Ok(())
+        }
+
+        fn size(&self) -> ByteSize {
+            ByteSize::kib(10)
+        }
+
+        fn page_size(&self) -> usize {
+            1024
+        }
+    }
+
+    #[tokio::test]
+    async fn evict_durable() {
+        const PATH: &str = "/tmp/cache.evict_durable.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let store = Buffered {
+            fail_flush: true,
+            ..Default::default()
+        };
+        let mut cache = Cache::new(store, PATH, ByteSize::kib(2), ByteSize::kib(1)).unwrap();
+
+        for index in [0, 3] {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.data_mut().fill(index as u8 + 1);
+            page.header_mut().set(Flags::Dirty, true);
+        }
+
+        // the pages were set but never flushed so they must stay dirty
+        assert!(cache.evict(Duration::MAX).await.is_err());
+        assert_eq!(cache.dirty_pages().count(), 2);
+        assert!(cache.store.durable.lock().unwrap().is_empty());
+
+        cache.store.fail_flush = false;
+        cache.evict(Duration::MAX).await.unwrap();
+        assert_eq!(cache.dirty_pages().count(), 0);
+        assert_eq!(cache.store.durable.lock().unwrap().len(), 2);
+
+        // a displaced dirty page is written to the store but only durable
+        // once the store is flushed, same as any write to the device
+        let mut page = cache.get_mut(0).await.unwrap();
+        page.data_mut().fill(9);
+        page.header_mut().set(Flags::Dirty, true);
+        cache.get(3).await.unwrap();
+        cache.get(5).await.unwrap();
+        assert_eq!(cache.store.pending.lock().unwrap().len(), 1);
+
+        cache.flush_store().await.unwrap();
+        let durable = cache.store.durable.lock().unwrap();
+        assert!(durable[&0].iter().all(|v| *v == 9));
+    }
+
+    #[tokio::test]
+    async fn writeback_lag() {
+        const PATH: &str = "/tmp/cache.writeback_lag.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert_eq!(cache.writeback_lag(), Duration::ZERO);
+
+        let mut page = cache.get_mut(1).await.unwrap();
+        page.header_mut().set(Flags::Dirty, true);
+        // a clean page that is never written does not count
+        cache.get_mut(2).await.unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        // writing again to a dirty page keeps its age
+        cache.get_mut(1).await.unwrap();
+        let mut page = cache.get_mut(3).await.unwrap();
+        page.header_mut().set(Flags::Dirty, true);
+
+        assert!(cache.update_writeback_lag() >= Duration::from_millis(20));
+
+        cache.evict(Duration::MAX).await.unwrap();
+        assert_eq!(cache.writeback_lag(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn replace_store() {
+        const PATH: &str = "/tmp/cache.replace_store.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(4), ByteSize::kib(1)).unwrap();
+
+        let mut page = cache.get_mut(2).await.unwrap();
+        page.data_mut().fill(4);
+        page.header_mut().set(Flags::Dirty, true);
+
+        assert!(cache.replace_store(store::InMemory::new(5)).await.is_err());
+
+        let old = cache.replace_store(store::InMemory::new(10)).await.unwrap();
+        // dirty pages went to the old store
+        assert!(old.mem[&2].iter().all(|v| *v == 4));
+        assert_eq!(cache.dirty_pages().count(), 0);
+
+        let mut page = cache.get_mut(3).await.unwrap();
+        page.header_mut().set(Flags::Dirty, true);
+        cache.sync_all().await.unwrap();
+
+        let new = cache.inner();
+        assert_eq!(new.mem.len(), 1);
+        assert!(new.mem.contains_key(&3));
+    }
+
+    #[tokio::test]
+    async fn lru_memory() {
+        const PATH: &str = "/tmp/cache.lru_memory.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(4), ByteSize::kib(1)).unwrap();
+
+        // the table is there even if nothing is cached
+        let empty = cache.lru_memory();
+        assert!(empty > 0);
+
+        // every cached page costs the same
+        cache.get(0).await.unwrap();
+        let one = cache.lru_memory();
+        cache.get(1).await.unwrap();
+        let two = cache.lru_memory();
+        assert!(one > empty);
+        assert_eq!(two - one, one - empty);
+    }
+
+    #[tokio::test]
+    async fn evict_batch() {
+        const PATH: &str = "/tmp/cache.evict_batch.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(10);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(10), ByteSize::kib(1))
+            .unwrap()
+            .with_evict_batch(3);
+
+        for index in [0, 4] {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.header_mut().set(Flags::Dirty, true);
+        }
+
+        // not enough dirty pages yet
+        cache.evict(Duration::MAX).await.unwrap();
+        assert_eq!(cache.dirty_pages().count(), 2);
+
+        let mut page = cache.get_mut(8).await.unwrap();
+        page.header_mut().set(Flags::Dirty, true);
+
+        cache.evict(Duration::MAX).await.unwrap();
+        assert_eq!(cache.dirty_pages().count(), 0);
+
+        // sync_all ignores the batch
+        let mut page = cache.get_mut(1).await.unwrap();
+        page.header_mut().set(Flags::Dirty, true);
+        cache.evict(Duration::MAX).await.unwrap();
+        assert_eq!(cache.dirty_pages().count(), 1);
+
+        assert_eq!(cache.sync_all().await.unwrap(), 1);
+        assert_eq!(cache.dirty_pages().count(), 0);
+        assert_eq!(cache.sync_all().await.unwrap(), 0);
+
+        let mem = cache.inner();
+        assert_eq!(mem.mem.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn eviction() {
+        const PATH: &str = "/tmp/cache.eviction.test";
+
+        // 0 is loaded first, 1 is used a lot but not lately and 2 is used
+        // lately but the least
+        for (eviction, victim) in [(Eviction::Lru, 1), (Eviction::Fifo, 0), (Eviction::Lfu, 2)] {
+            let _ = std::fs::remove_file(PATH);
+            let mut cache = Cache::new(
+                store::InMemory::new(10),
+                PATH,
+                ByteSize::kib(3),
+                ByteSize::kib(1),
+            )
+            .unwrap()
+            .with_eviction(eviction);
+
+            for page in [0, 1, 2, 1, 1, 0, 0, 2] {
+                cache.get(page).await.unwrap();
+            }
+
+            cache.get(3).await.unwrap();
+            assert!(!cache.is_resident(victim), "{eviction}");
+            assert_eq!(cache.cache.len(), 3, "{eviction}");
+        }
+
+        assert_eq!("lfu".parse::<Eviction>().unwrap(), Eviction::Lfu);
+        assert!("mru".parse::<Eviction>().is_err());
+    }
+
+    #[tokio::test]
+    async fn read_ahead() {
+        const PATH: &str = "/tmp/cache.read_ahead.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut mem = store::InMemory::new(10);
+        for index in 0..10 {
+            mem.mem.insert(index, vec![index as u8; 1024]);
+        }
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(10), ByteSize::kib(1))
+            .unwrap()
+            .with_read_ahead(2);
+
+        // a random read loads nothing more
+        cache.get(7).await.unwrap();
+        assert!(!cache.is_resident(8));
+
+        // reading 1 loads 2 and 3, reading 2 hits and loads nothing
+        for page in 0..3 {
+            cache.get(page).await.unwrap();
+        }
+        assert!(cache.is_resident(3));
+        assert!(!cache.is_resident(4));
+
+        // 3 is the last page read ahead so the next batch is loaded
+        cache.get(3).await.unwrap();
+        for page in 4..6 {
+            assert!(cache.is_resident(page));
+            let address = cache.cache.peek(&page).unwrap().address;
+            assert!(!cache.map.header_at(address).flag(Flags::Dirty));
+        }
+        assert!(!cache.is_resident(6));
+
+        // a dirty page is never displaced by a page ahead
+        let mem = cache.inner();
+        let _ = std::fs::remove_file(PATH);
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(3), ByteSize::kib(1))
+            .unwrap()
+            .with_read_ahead(2);
+        let mut page = cache.get_mut(9).await.unwrap();
+        page.header_mut().set(Flags::Dirty, true);
+        cache.get(0).await.unwrap();
+        cache.get(1).await.unwrap();
+        assert!(cache.is_resident(9));
+        assert!(!cache.is_resident(2));
+        assert_eq!(cache.dirty_pages().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn warm_batch() {
+        const PATH: &str = "/tmp/cache.warm_batch.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut mem = store::InMemory::new(10);
+        for index in 0..10 {
+            mem.mem.insert(index, vec![index as u8; 1024]);
+        }
+        let mut cache = Cache::new(mem, PATH, ByteSize::kib(5), ByteSize::kib(1))
+            .unwrap()
+            .with_warm_batch(2);
+
+        let page = cache.get(3).await.unwrap();
+        assert!(page.data().iter().all(|v| *v == 3));
+        drop(page);
+        for index in [3, 4, 5] {
+            assert!(cache.is_resident(index));
+        }
+
+        // a hit loads nothing ahead and nothing past the end is loaded
+        let data = cache.get(4).await.unwrap().data()[0];
+        assert_eq!(data, 4);
+        cache.get(9).await.unwrap();
+        assert!(cache.is_resident(9));
+        assert_eq!(cache.cache.len(), 4);
+
+        // the loaded pages are what the store has
+        let data = cache.get(5).await.unwrap().data()[0];
+        assert_eq!(data, 5);
+
+        // a batch bigger than the cache never displaces the page itself
+        let mut cache = cache.with_warm_batch(20);
+        let data = cache.get(0).await.unwrap().data()[0];
+        assert_eq!(data, 0);
+        assert!(cache.is_resident(0));
+        assert_eq!(cache.cache.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn displace_hook() {
+        const PATH: &str = "/tmp/cache.displace_hook.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let displaced = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hook = displaced.clone();
+        let mut cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(2),
+            ByteSize::kib(1),
+        )
+        .unwrap()
+        .with_displace_hook(move |loaded, victim| hook.lock().unwrap().push((loaded, victim)));
+
+        for page in [0, 1, 0, 2, 3] {
+            cache.get(page).await.unwrap();
+        }
+
+        assert_eq!(*displaced.lock().unwrap(), vec![(2, 1), (3, 0)]);
+    }
+
     #[tokio::test]
     async fn test_eviction() {
         const PATH: &str = "/tmp/cache.eviction.test";
@@ -429,4 +2405,167 @@ mod test {
 
         assert_eq!(cache.occupied(), 5);
     }
+
+    /// a store of the given size that only returns 100 bytes for any page
+    /// but the first one
+    struct Short(ByteSize);
+
+    #[async_trait::async_trait]
+    impl Store for Short {
+        async fn set(&mut self, _index: u32, _page: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get(&self, index: u32) -> Result<Option<PageData>> {
+            let len = if index == 0 { 1024 } else { 100 };
+            Ok(Some(PageData::Owned(vec![9; len])))
+        }
+
+        fn size(&self) -> ByteSize {
+            self.0
+        }
+
+        fn page_size(&self) -> usize {
+            1024
+        }
+    }
+
+    #[tokio::test]
+    async fn test_short_page() {
+        const PATH: &str = "/tmp/cache.short.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        // the store holds full pages so none of them can be short, not even
+        // the last one
+        let store = Short(ByteSize::kib(4));
+        let mut cache = Cache::new(store, PATH, ByteSize::kib(2), ByteSize::kib(1)).unwrap();
+        assert!(matches!(cache.get(3).await, Err(Error::InvalidPageSize)));
+        assert!(matches!(cache.get(1).await, Err(Error::InvalidPageSize)));
+        // the slots don't claim to hold the pages and are still free
+        assert_eq!(cache.occupied(), 0);
+        assert_eq!(cache.free.len(), 2);
+        drop(cache);
+
+        let _ = std::fs::remove_file(PATH);
+        // the last page of this store only has 100 bytes
+        let store = Short(ByteSize::b(3 * 1024 + 100));
+        let mut cache = Cache::new(store, PATH, ByteSize::kib(2), ByteSize::kib(1)).unwrap();
+        assert_eq!(cache.size(), ByteSize::b(3 * 1024 + 100));
+
+        let page = cache.get(3).await.unwrap();
+        assert!(page.data()[..100].iter().all(|v| *v == 9));
+        assert!(page.data()[100..].iter().all(|v| *v == 0));
+        assert!(page.is_crc_ok());
+
+        assert!(matches!(cache.get(1).await, Err(Error::InvalidPageSize)));
+    }
+
+    #[tokio::test]
+    async fn test_warm_ahead_err() {
+        const PATH: &str = "/tmp/cache.warm_ahead_err.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let store = Short(ByteSize::kib(4));
+        let mut cache = Cache::new(store, PATH, ByteSize::kib(4), ByteSize::kib(1))
+            .unwrap()
+            .with_warm_batch(2);
+
+        // the pages ahead can't be loaded but the read itself works
+        let page = cache.get(0).await.unwrap();
+        assert!(page.data().iter().all(|v| *v == 9));
+        assert_eq!(cache.occupied(), 1);
+        assert_eq!(cache.free.len(), 3);
+    }
+
+    /// a store of 4 pages that fails every get
+    struct Failing;
+
+    #[async_trait::async_trait]
+    impl Store for Failing {
+        async fn set(&mut self, _index: u32, _page: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get(&self, _index: u32) -> Result<Option<PageData>> {
+            Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into())
+        }
+
+        fn size(&self) -> ByteSize {
+            ByteSize::kib(4)
+        }
+
+        fn page_size(&self) -> usize {
+            1024
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_load() {
+        const PATH: &str = "/tmp/cache.failed_load.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mut cache = Cache::new(Failing, PATH, ByteSize::kib(2), ByteSize::kib(1)).unwrap();
+        // more failed loads than slots, none of them is lost
+        for index in 0..4 {
+            assert!(matches!(cache.get(index).await, Err(Error::IO(_))));
+        }
+        assert_eq!(cache.free.len(), 2);
+        assert_eq!(cache.occupied(), 0);
+    }
+
+    #[test]
+    fn test_page_size_mismatch() {
+        const PATH: &str = "/tmp/cache.page_size.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        // in memory store uses 1k pages
+        let mem = store::InMemory::new(10);
+        let cache = Cache::new(mem, PATH, ByteSize::kib(10), ByteSize::kib(2));
+        assert!(matches!(cache, Err(Error::InvalidPageSize)));
+
+        let cache = Cache::new(
+            NullStore::new(ByteSize::kib(2)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(2),
+        );
+        assert!(cache.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_lru_capacity() {
+        const PATH: &str = "/tmp/cache.capacity.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let mem = store::InMemory::new(20);
+        let map = PageMap::new(PATH, ByteSize::kib(5), ByteSize::kib(1)).unwrap();
+        let mut cache = Cache::with_map(mem, map).unwrap();
+        // the lru tracks the map slots
+        assert_eq!(cache.cache.cap().get(), 5);
+
+        for index in 0..10 {
+            let mut page = cache.get_mut(index).await.unwrap();
+            page.data_mut().fill(index as u8 + 1);
+            page.header_mut().set(Flags::Dirty, true);
+        }
+
+        assert_eq!(cache.cache.len(), 5);
+        assert_eq!(cache.occupied(), 5);
+        for index in 5..10 {
+            let page = cache.get(index).await.unwrap();
+            assert_eq!(page.address(), index as usize - 5);
+        }
+
+        // and follows them when the map grows
+        cache.resize(ByteSize::kib(8)).await.unwrap();
+        assert_eq!(cache.cache.cap().get(), 8);
+
+        let mem = cache.inner();
+        assert!(mem.mem.get(&4).unwrap().iter().all(|v| *v == 5));
+    }
 }
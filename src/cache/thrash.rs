@@ -0,0 +1,139 @@
+//! detects when the cache is thrashing, which means most accesses need
+//! a page to be displaced from the cache to make room for another. This
+//! happens when the cache is smaller than the working set and shows as
+//! a device that is slow for no clear reason.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// counters are compared over that window
+const WINDOW: Duration = Duration::from_secs(60);
+/// window must cover at least that much before any decision is made
+const MIN_SPAN: Duration = Duration::from_secs(10);
+/// thrashing only counts if at least that many pages per second are displaced
+const MIN_RATE: f64 = 10.0;
+/// and at least that ratio of the accesses displaced a page
+const MIN_RATIO: f64 = 0.5;
+
+struct Sample {
+    at: Instant,
+    hits: u64,
+    displaced: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rates {
+    /// displaced pages per second
+    pub rate: f64,
+    /// ratio of accesses that displaced a page
+    pub ratio: f64,
+}
+
+impl Rates {
+    pub fn is_thrashing(&self) -> bool {
+        self.rate >= MIN_RATE && self.ratio >= MIN_RATIO
+    }
+}
+
+#[derive(Default)]
+pub struct Thrash {
+    samples: VecDeque<Sample>,
+    last_warn: Option<Instant>,
+}
+
+impl Thrash {
+    /// record the counters at now and return the rates over the window,
+    /// None if the samples don't cover enough time yet
+    pub fn sample(&mut self, now: Instant, hits: u64, displaced: u64) -> Option<Rates> {
+        self.samples.push_back(Sample {
+            at: now,
+            hits,
+            displaced,
+        });
+
+        // the newest sample out of the window is kept as the baseline,
+        // otherwise nothing is left to compare with if samples are taken
+        // less often than the window
+        while matches!(self.samples.get(1), Some(s) if now.saturating_duration_since(s.at) > WINDOW)
+        {
+            self.samples.pop_front();
+        }
+
+        let first = self.samples.front()?;
+        let span = now.saturating_duration_since(first.at);
+        if span < MIN_SPAN {
+            return None;
+        }
+
+        let hits = hits - first.hits;
+        let displaced = displaced - first.displaced;
+        let accesses = hits + displaced;
+
+        Some(Rates {
+            rate: displaced as f64 / span.as_secs_f64(),
+            ratio: if accesses == 0 {
+                0.0
+            } else {
+                displaced as f64 / accesses as f64
+            },
+        })
+    }
+
+    /// true if no warning was emitted for a full window, so the log
+    /// is not flooded while thrashing
+    pub fn should_warn(&mut self, now: Instant) -> bool {
+        match self.last_warn {
+            Some(at) if now.saturating_duration_since(at) < WINDOW => false,
+            _ => {
+                self.last_warn = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn thrash() {
+        let mut thrash = Thrash::default();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        assert!(thrash.sample(at(0), 0, 0).is_none());
+        // window is too short
+        assert!(thrash.sample(at(5), 10, 1000).is_none());
+
+        // 2000 pages displaced in 10 seconds against 10 hits
+        let rates = thrash.sample(at(10), 10, 2000).unwrap();
+        assert_eq!(rates.rate, 200.0);
+        assert!(rates.is_thrashing());
+
+        // mostly hits afterwards, the old samples go out of the window
+        let rates = thrash.sample(at(80), 100_000, 2100).unwrap();
+        assert!(!rates.is_thrashing());
+
+        // samples further apart than the window still compare with the
+        // previous one
+        let rates = thrash.sample(at(170), 100_000, 11_100).unwrap();
+        assert_eq!(rates.rate, 100.0);
+        assert!(rates.is_thrashing());
+
+        assert!(thrash.should_warn(at(10)));
+        assert!(!thrash.should_warn(at(30)));
+        assert!(thrash.should_warn(at(71)));
+    }
+
+    #[test]
+    fn idle() {
+        let mut thrash = Thrash::default();
+        let start = Instant::now();
+        thrash.sample(start, 0, 0);
+        let rates = thrash
+            .sample(start + Duration::from_secs(30), 0, 0)
+            .unwrap();
+        assert_eq!(rates.ratio, 0.0);
+        assert!(!rates.is_thrashing());
+    }
+}
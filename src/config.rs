@@ -0,0 +1,658 @@
+//! builds the full device stack (stores, policy, cache and device) from
+//! a declarative config. This is what the qbd binary does on startup, so
+//! embedders get the exact same device with one call to [`open`].
+use std::{
+    collections::HashMap,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::Context;
+use bytesize::ByteSize;
+use url::Url;
+
+use crate::{
+    cache::{Cache, Eviction, NullStore},
+    device::{Device, FlushMode},
+    map::{MapOptions, MultiPageMap, PageMap, MAX_PAGE_COUNT},
+    store::{
+        policy::{DelayPolicy, Policy},
+        DirStore, FileStore, MemoryStore, MeteredStore, NbdStore, Store, TieredStore, VerifyReport,
+    },
+};
+
+#[cfg(feature = "s3")]
+use crate::store::{Credentials, Region, S3Store};
+
+/// default port of nbd servers
+pub const NBD_PORT: u16 = 10809;
+
+/// store types [`store_from_url`] knows, for error messages
+#[cfg(not(feature = "s3"))]
+const STORE_TYPES: &str = "`file`, `dir`, `mem`, `nbd`, `tiered` and `delay`";
+#[cfg(feature = "s3")]
+const STORE_TYPES: &str = "`file`, `dir`, `mem`, `nbd`, `s3`, `tiered` and `delay`";
+
+/// the store of a device built by [`open`]
+pub type DeviceStore = Policy<Box<dyn Store>>;
+
+/// page and disk size of a device, or of a part of it (a store file or a
+/// cache file). The device geometry is computed once from the page size of
+/// the config and the size of the stores, the parts are built from their
+/// own geometry with the same page size so they can't drift apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub page_size: ByteSize,
+    pub disk_size: ByteSize,
+}
+
+impl Geometry {
+    /// fails if a disk of disk_size can't be served with page_size
+    pub fn new(page_size: ByteSize, disk_size: ByteSize) -> anyhow::Result<Self> {
+        if page_size.as_u64() == 0 {
+            anyhow::bail!("page-size can't be zero");
+        }
+
+        // otherwise the last page is partial and can't be served
+        if disk_size.as_u64() % page_size.as_u64() != 0 {
+            anyhow::bail!(
+                "size {} must be multiple of page-size {}",
+                disk_size.to_string_as(true),
+                page_size.to_string_as(true)
+            );
+        }
+
+        // stores take u32 page ids so we can't address more than
+        // MAX_PAGE_COUNT pages, even with a map that has wide ids
+        let max_size = ByteSize::b(MAX_PAGE_COUNT * page_size.as_u64());
+        if disk_size > max_size {
+            anyhow::bail!(
+                "size {} is bigger than max device size {} for page-size {}, use a bigger page-size",
+                disk_size.to_string_as(true),
+                max_size.to_string_as(true),
+                page_size.to_string_as(true)
+            );
+        }
+
+        Ok(Self {
+            page_size,
+            disk_size,
+        })
+    }
+
+    /// geometry of a part of size with the same page size
+    pub fn part(&self, size: ByteSize) -> anyhow::Result<Self> {
+        Self::new(self.page_size, size)
+    }
+}
+
+/// configuration of a device
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// url of each store (see [`store_from_url`]). The device is a strip
+    /// over all stores
+    pub stores: Vec<Url>,
+    /// cache files, the cache size is split evenly over all of them
+    pub cache: Vec<PathBuf>,
+    /// has to be multiple of page size times the number of cache files
+    pub cache_size: ByteSize,
+    /// page size of both cache and stores
+    pub page_size: ByteSize,
+    /// options used to create cache and store files
+    pub map_options: MapOptions,
+    pub flush_mode: FlushMode,
+    /// max size of a single read or write request
+    pub max_request: Option<usize>,
+    /// see [`Cache::with_evict_batch`]
+    pub evict_batch: usize,
+    /// see [`Cache::with_warm_batch`]
+    pub warm_batch: usize,
+    /// see [`Cache::with_read_ahead`]
+    pub read_ahead: usize,
+    /// see [`Cache::with_eviction`]
+    pub eviction: Eviction,
+    /// see [`Device::with_write_combine`]
+    pub write_combine: Option<Duration>,
+    /// see [`Device::with_verify_reads`]
+    pub verify_reads: bool,
+    /// see [`Device::with_scrub_repair`]
+    pub scrub_repair: bool,
+    /// see [`Device::with_idle_flush`]
+    pub idle_flush: Option<Duration>,
+    /// see [`Device::with_max_dirty_age`]
+    pub max_dirty_age: Option<Duration>,
+    /// see [`Device::with_shutdown_timeout`]
+    pub shutdown_timeout: Option<Duration>,
+    /// see [`Device::with_read_only`]
+    pub read_only: bool,
+}
+
+impl Config {
+    /// config with the default sizes (10GiB cache, 256KiB pages) and
+    /// options which can then be changed as needed
+    pub fn new(stores: Vec<Url>, cache: Vec<PathBuf>) -> Self {
+        Self {
+            stores,
+            cache,
+            cache_size: ByteSize::gib(10),
+            page_size: ByteSize::kib(256),
+            map_options: MapOptions::default(),
+            flush_mode: FlushMode::default(),
+            max_request: None,
+            evict_batch: 1,
+            warm_batch: 0,
+            read_ahead: 0,
+            eviction: Eviction::default(),
+            write_combine: None,
+            verify_reads: false,
+            scrub_repair: false,
+            idle_flush: None,
+            max_dirty_age: None,
+            shutdown_timeout: None,
+            read_only: false,
+        }
+    }
+}
+
+/// open all stores and cache files of config and build the device over them
+pub async fn open(config: Config) -> anyhow::Result<Device<DeviceStore>> {
+    let cache_size = config.cache_size;
+    let page_size = config.page_size;
+
+    let shard_size = shard_size(cache_size, page_size, config.cache.len())?;
+
+    // tracks (dev, inode) of all used files so we can detect if
+    // the same file is used twice (even through a symlink or hardlink)
+    let mut files: HashMap<(u64, u64), String> = HashMap::new();
+    let (store, geometry) =
+        open_stores(&config.stores, page_size, config.map_options, &mut files).await?;
+    let disk_size = geometry.disk_size;
+
+    log::info!("store: {}", store.describe());
+    log::info!(
+        "size: {} cache-size: {}, page-size: {}",
+        disk_size.to_string_as(true),
+        cache_size.to_string_as(true),
+        page_size.to_string_as(true)
+    );
+
+    let shard = geometry.part(shard_size)?;
+    let mut maps = Vec::with_capacity(config.cache.len());
+    for path in &config.cache {
+        let owner = format!("cache {}", path.display());
+        let used = use_file(&mut files, path, &owner)?;
+        maps.push(
+            PageMap::with_geometry(path, &shard, config.map_options)
+                .with_context(|| format!("failed to create cache {}", path.display()))?,
+        );
+        if !used {
+            use_file(&mut files, path, &owner)?;
+        }
+    }
+
+    let map = MultiPageMap::new(maps).context("failed to create cache")?;
+    let cache = Cache::with_map(store, map)
+        .context("failed to create cache")?
+        .with_evict_batch(config.evict_batch)
+        .with_warm_batch(config.warm_batch)
+        .with_read_ahead(config.read_ahead)
+        .with_eviction(config.eviction);
+
+    let mut device = Device::new(cache)
+        .with_flush_mode(config.flush_mode)
+        .with_verify_reads(config.verify_reads)
+        .with_scrub_repair(config.scrub_repair)
+        .with_read_only(config.read_only);
+
+    if let Some(max) = config.max_request {
+        device = device.with_max_request(max);
+    }
+
+    if let Some(window) = config.write_combine {
+        device = device.with_write_combine(window);
+    }
+
+    if let Some(idle) = config.idle_flush {
+        device = device.with_idle_flush(idle);
+    }
+
+    if let Some(age) = config.max_dirty_age {
+        device = device.with_max_dirty_age(age);
+    }
+
+    if let Some(timeout) = config.shutdown_timeout {
+        device = device.with_shutdown_timeout(timeout);
+    }
+
+    Ok(device)
+}
+
+/// opens (or creates) all stores and makes sure they can be used together
+/// as the store of a device with config page size. Nothing else (cache or
+/// device) is built, this is meant to provision the stores before serving
+/// them. Store files are always fully allocated.
+pub async fn provision(
+    stores: &[Url],
+    page_size: ByteSize,
+    options: MapOptions,
+) -> anyhow::Result<DeviceStore> {
+    let (store, _) = open_stores(
+        stores,
+        page_size,
+        options.sparse(false),
+        &mut HashMap::new(),
+    )
+    .await?;
+    store.flush().await.context("failed to flush stores")?;
+    Ok(store)
+}
+
+/// opens the existing stores and checks the integrity of all their pages
+/// (see [`Store::verify`]). Local stores must already exist, they are
+/// never created
+pub async fn verify(
+    stores: &[Url],
+    page_size: ByteSize,
+    options: MapOptions,
+) -> anyhow::Result<(DeviceStore, VerifyReport)> {
+    for u in stores {
+        if matches!(u.scheme(), "file" | "dir") && !Path::new(u.path()).exists() {
+            anyhow::bail!("store {u} does not exist");
+        }
+    }
+
+    let (store, _) = open_stores(stores, page_size, options, &mut HashMap::new()).await?;
+    let report = store
+        .verify()
+        .await
+        .with_context(|| format!("failed to verify {}", store.describe()))?;
+    Ok((store, report))
+}
+
+/// opens the existing cache files read-only and returns the addresses of the
+/// clean pages with a bad crc of each of them, see [`PageMap::scrub`]. The
+/// cache must not be in use
+pub fn scrub(
+    cache: &[PathBuf],
+    cache_size: ByteSize,
+    page_size: ByteSize,
+) -> anyhow::Result<Vec<(PathBuf, Vec<usize>)>> {
+    let shard_size = shard_size(cache_size, page_size, cache.len())?;
+    cache
+        .iter()
+        .map(|path| {
+            let map = PageMap::open_read_only(path, shard_size, page_size)
+                .with_context(|| format!("failed to open cache {}", path.display()))?;
+            Ok((path.clone(), map.scrub()))
+        })
+        .collect()
+}
+
+/// size of each cache file when cache size is split over shards files
+fn shard_size(
+    cache_size: ByteSize,
+    page_size: ByteSize,
+    shards: usize,
+) -> anyhow::Result<ByteSize> {
+    if page_size.as_u64() == 0 || cache_size.as_u64() % page_size.as_u64() != 0 {
+        anyhow::bail!("cache-size must be multiple of page-size");
+    }
+
+    let shards = shards as u64;
+    if shards == 0 {
+        anyhow::bail!("at least one cache file is required");
+    }
+
+    if cache_size.as_u64() % (page_size.as_u64() * shards) != 0 {
+        anyhow::bail!("cache-size must be multiple of page-size * {shards} cache files");
+    }
+
+    Ok(ByteSize::b(cache_size.as_u64() / shards))
+}
+
+/// opens the stores and returns them as a single store with the geometry
+/// of the device they can be served as
+async fn open_stores(
+    urls: &[Url],
+    page_size: ByteSize,
+    options: MapOptions,
+    files: &mut HashMap<(u64, u64), String>,
+) -> anyhow::Result<(DeviceStore, Geometry)> {
+    let mut stores: Vec<Box<dyn Store>> = vec![];
+    for (i, u) in urls.iter().enumerate() {
+        let local = matches!(u.scheme(), "file" | "dir");
+        let used = local && use_file(files, u.path(), u.as_str())?;
+
+        let store = store_from_url(u, page_size, options, &format!("strip/{i}")).await?;
+        if store.page_size() as u64 != page_size.as_u64() {
+            anyhow::bail!(
+                "store {u} uses page-size {} but the device page-size is {}",
+                ByteSize::b(store.page_size() as u64).to_string_as(true),
+                page_size.to_string_as(true)
+            );
+        }
+        stores.push(store);
+
+        if local && !used {
+            use_file(files, u.path(), u.as_str())?;
+        }
+    }
+
+    let store = Policy::strip(stores)?;
+    let geometry =
+        Geometry::new(page_size, store.size()).context("invalid total size of the stores")?;
+
+    Ok((store, geometry))
+}
+
+/// builds a store from its url
+/// - `file:///path/to/file?size=SIZE` a local file, created with options
+///   if it does not exist
+/// - `dir:///path/to/dir?size=SIZE` a page per file in a local directory,
+///   created if it does not exist
+/// - `mem://?size=SIZE` keeps all pages in memory, nothing survives a
+///   restart
+/// - `nbd://host[:port]/export` a remote nbd export, the size of the
+///   store is the size of the export
+/// - `delay://?size=SIZE&ms=MS&jitter=MS` keeps nothing but delays each
+///   operation by ms plus a random jitter, for testing
+/// - `s3://bucket/prefix?size=SIZE&endpoint=URL&region=REGION` an object
+///   per page in an s3 bucket, credentials are taken from the AWS_* env
+///   vars. Only if built with the s3 feature
+/// - `tiered:///path/to/index?hot=URL&cold=URL` a fast hot store in front
+///   of a slow cold one, both given as urls. The hot index is kept at path
+///
+/// the store is metered under `{path}/{scheme}` and stores nested in it use
+/// that as the prefix of their own path, see [`MeteredStore`]
+pub async fn store_from_url(
+    u: &Url,
+    page_size: ByteSize,
+    options: MapOptions,
+    path: &str,
+) -> anyhow::Result<Box<dyn Store>> {
+    let store: Box<dyn Store> = match u.scheme() {
+        "file" => {
+            let geometry = Geometry::new(page_size, url_size(u)?)
+                .with_context(|| format!("invalid size of store {u}"))?;
+            Box::new(
+                FileStore::with_geometry(u.path(), &geometry, options)
+                    .with_context(|| format!("failed to create store {u}"))?,
+            )
+        }
+        "dir" => Box::new(
+            DirStore::new(u.path(), url_size(u)?, page_size)
+                .with_context(|| format!("failed to create store {u}"))?,
+        ),
+        "mem" => Box::new(
+            MemoryStore::new(url_size(u)?, page_size)
+                .with_context(|| format!("failed to create store {u}"))?,
+        ),
+        "delay" => Box::new(delay_store(u, url_size(u)?, page_size)?),
+        "tiered" => Box::new(tiered_store(u, page_size, options, path).await?),
+        #[cfg(feature = "s3")]
+        "s3" => Box::new(s3_store(u, url_size(u)?, page_size)?),
+        "nbd" => {
+            let host = u.host_str().context("nbd store url requires a host")?;
+            let address = format!("{host}:{}", u.port().unwrap_or(NBD_PORT));
+            let export = u.path().trim_start_matches('/');
+            Box::new(
+                NbdStore::connect(address, export, page_size)
+                    .await
+                    .with_context(|| format!("failed to connect to store {u}"))?,
+            )
+        }
+        scheme => anyhow::bail!(
+            "unsupported store type `{scheme}` in {u}, supported types are {STORE_TYPES}"
+        ),
+    };
+
+    Ok(Box::new(MeteredStore::new(
+        store,
+        format!("{path}/{}", u.scheme()),
+    )))
+}
+
+/// size query param of a store url
+fn url_size(u: &Url) -> anyhow::Result<ByteSize> {
+    match u.query_pairs().find(|(key, _)| key == "size") {
+        Some((_, size)) => ByteSize::from_str(&size)
+            .map_err(|e| anyhow::anyhow!("failed to parse store size: {e}")),
+        None => anyhow::bail!("size param is required in store url"),
+    }
+}
+
+/// builds an s3 store, the endpoint defaults to aws s3 in the region
+#[cfg(feature = "s3")]
+fn s3_store(u: &Url, size: ByteSize, page_size: ByteSize) -> anyhow::Result<S3Store> {
+    let bucket = u.host_str().context("s3 store url requires a bucket")?;
+    let param = |name: &str| {
+        u.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+
+    let region = param("region").unwrap_or_else(|| "us-east-1".into());
+    let endpoint =
+        param("endpoint").unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com"));
+    let region = Region::Custom { region, endpoint };
+
+    let credentials =
+        Credentials::from_env().with_context(|| format!("missing s3 credentials for {u}"))?;
+
+    S3Store::new(bucket, u.path(), region, credentials, size, page_size)
+        .with_context(|| format!("failed to create store {u}"))
+}
+
+/// builds a tiered store over the stores in the hot and cold params. They are
+/// metered as the first and second part of the tiered store
+async fn tiered_store(
+    u: &Url,
+    page_size: ByteSize,
+    options: MapOptions,
+    path: &str,
+) -> anyhow::Result<TieredStore<Box<dyn Store>, Box<dyn Store>>> {
+    let mut tiers = vec![];
+    for (i, name) in ["hot", "cold"].into_iter().enumerate() {
+        let tier = match u.query_pairs().find(|(key, _)| key == name) {
+            Some((_, tier)) => Url::parse(&tier)
+                .with_context(|| format!("failed to parse {name} store url of {u}"))?,
+            None => anyhow::bail!("{name} param is required in store url {u}"),
+        };
+
+        // the tiers can be tiered stores themselves
+        let store = Box::pin(store_from_url(
+            &tier,
+            page_size,
+            options,
+            &format!("{path}/tiered/{i}"),
+        ))
+        .await?;
+        tiers.push(store);
+    }
+
+    let cold = tiers.pop().unwrap();
+    let hot = tiers.pop().unwrap();
+    TieredStore::new(hot, cold, u.path()).with_context(|| format!("failed to create store {u}"))
+}
+
+/// builds a store that holds nothing but delays every operation
+fn delay_store(
+    u: &Url,
+    size: ByteSize,
+    page_size: ByteSize,
+) -> anyhow::Result<DelayPolicy<NullStore>> {
+    let param = |name: &str| -> anyhow::Result<u64> {
+        match u.query_pairs().find(|(key, _)| key == name) {
+            Some((_, value)) => value
+                .parse()
+                .with_context(|| format!("failed to parse store {name}")),
+            None => Ok(0),
+        }
+    };
+
+    let store = DelayPolicy::new(
+        NullStore::with_size(size, page_size),
+        Duration::from_millis(param("ms")?),
+    )
+    .with_jitter(Duration::from_millis(param("jitter")?));
+
+    Ok(store)
+}
+
+/// records that file at path is used by owner, fails if the same
+/// file is already used by someone else. Using the same file twice
+/// will corrupt the data silently, so this must be called before the
+/// file is opened. A file that does not exist yet can't be used by
+/// anyone, false is returned and it has to be recorded again once it
+/// is created.
+fn use_file<P: AsRef<Path>>(
+    files: &mut HashMap<(u64, u64), String>,
+    path: P,
+    owner: &str,
+) -> anyhow::Result<bool> {
+    let meta = match std::fs::metadata(&path) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("failed to get metadata of {}", path.as_ref().display()))
+        }
+    };
+
+    if let Some(other) = files.insert((meta.dev(), meta.ino()), owner.into()) {
+        anyhow::bail!(
+            "{owner} and {other} are using the same file {}",
+            path.as_ref().display()
+        );
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn open() {
+        const STORE: &str = "/tmp/config.store.test";
+        const CACHE: &str = "/tmp/config.cache.test";
+        let _ = std::fs::remove_file(STORE);
+        let _ = std::fs::remove_file(CACHE);
+
+        let store = Url::parse(&format!("file://{STORE}?size=1MiB")).unwrap();
+        let mut config = Config::new(vec![store], vec![CACHE.into()]);
+        config.cache_size = ByteSize::kib(256);
+        config.page_size = ByteSize::kib(64);
+
+        let device = super::open(config.clone()).await.unwrap();
+        assert_eq!(device.size(), ByteSize::mib(1));
+        drop(device);
+
+        // the cache can't be used as a store too, and it's not touched
+        let cache = std::fs::read(CACHE).unwrap();
+        let mut bad = config.clone();
+        bad.stores
+            .push(Url::parse(&format!("file://{CACHE}?size=1MiB")).unwrap());
+        assert!(super::open(bad).await.is_err());
+        assert!(std::fs::read(CACHE).unwrap() == cache);
+
+        let mut bad = config;
+        bad.stores = vec![Url::parse("memory://?size=1MiB").unwrap()];
+        assert!(super::open(bad).await.is_err());
+    }
+
+    #[test]
+    fn geometry() {
+        let geometry = Geometry::new(ByteSize::kib(64), ByteSize::mib(1)).unwrap();
+        let part = geometry.part(ByteSize::kib(256)).unwrap();
+        assert_eq!(part.page_size, ByteSize::kib(64));
+        assert_eq!(part.disk_size, ByteSize::kib(256));
+        assert!(geometry.part(ByteSize::kib(100)).is_err());
+
+        assert!(Geometry::new(ByteSize::b(0), ByteSize::mib(1)).is_err());
+        // partial last page
+        assert!(Geometry::new(ByteSize::kib(64), ByteSize::kib(100)).is_err());
+        // more pages than can be addressed
+        let size = ByteSize::b((MAX_PAGE_COUNT + 1) * 1024);
+        assert!(Geometry::new(ByteSize::kib(1), size).is_err());
+    }
+
+    #[tokio::test]
+    async fn provision() {
+        const STORE: &str = "/tmp/config.provision.test";
+        let _ = std::fs::remove_file(STORE);
+
+        let stores = vec![Url::parse(&format!("file://{STORE}?size=1MiB")).unwrap()];
+        let options = MapOptions::default().sparse(true);
+        let store = super::provision(&stores, ByteSize::kib(64), options)
+            .await
+            .unwrap();
+        assert_eq!(store.size(), ByteSize::mib(1));
+        drop(store);
+
+        // space is reserved even if sparse files were asked for
+        let meta = std::fs::metadata(STORE).unwrap();
+        assert!(meta.blocks() * 512 >= ByteSize::mib(1).as_u64());
+
+        // an existing store is validated against the page size
+        assert!(super::provision(&stores, ByteSize::kib(128), options)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn tiered() {
+        const INDEX: &str = "/tmp/config.tiered.test";
+        let _ = std::fs::remove_file(INDEX);
+
+        let u = format!("tiered://{INDEX}?hot=mem://?size=128KiB&cold=mem://?size=1MiB");
+        let stores = vec![Url::parse(&u).unwrap()];
+        let store = super::provision(&stores, ByteSize::kib(64), MapOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(store.size(), ByteSize::mib(1));
+        assert!(store.describe().contains("tiered"));
+        drop(store);
+
+        let stores = vec![Url::parse(&format!("tiered://{INDEX}?hot=mem://?size=128KiB")).unwrap()];
+        assert!(
+            super::provision(&stores, ByteSize::kib(64), MapOptions::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn verify() {
+        const STORE: &str = "/tmp/config.verify.test";
+        let _ = std::fs::remove_file(STORE);
+
+        let stores = vec![Url::parse(&format!("file://{STORE}?size=1MiB")).unwrap()];
+        let options = MapOptions::default();
+        // the store is never created
+        assert!(super::verify(&stores, ByteSize::kib(64), options)
+            .await
+            .is_err());
+
+        let mut store = super::provision(&stores, ByteSize::kib(64), options)
+            .await
+            .unwrap();
+        store.set(3, &[3; 64 * 1024]).await.unwrap();
+        store.flush().await.unwrap();
+        drop(store);
+
+        let (_, report) = super::verify(&stores, ByteSize::kib(64), options)
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            VerifyReport {
+                good: 1,
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -1,35 +1,68 @@
-use crate::{cache::Cache, map::Flags, store::Store};
+use crate::{cache::Cache, clock::Clock, map::Flags, metrics, store::Store, Error, Result};
+use bytesize::ByteSize;
 use lazy_static::lazy_static;
 use nbd_async::{BlockDevice, Control};
-use prometheus::{register_histogram, register_int_counter, Histogram, IntCounter};
+use nix::errno::Errno;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_gauge, Histogram, IntCounter, IntGauge,
+};
 use std::{
+    fmt::Display,
     io,
-    time::{Duration, Instant},
+    ops::Range,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
 lazy_static! {
     static ref IO_READ_BYTES: IntCounter =
-        register_int_counter!("nbd_io_read_bytes", "number of bytes read").unwrap();
+        register_int_counter!(metrics::name("io_read_bytes"), "number of bytes read").unwrap();
     static ref IO_WRITE_BYTES: IntCounter =
-        register_int_counter!("nbd_io_write_bytes", "number of bytes written").unwrap();
+        register_int_counter!(metrics::name("io_write_bytes"), "number of bytes written").unwrap();
     static ref IO_READ_OP: IntCounter =
-        register_int_counter!("nbd_io_read_op", "number of read io operations").unwrap();
+        register_int_counter!(metrics::name("io_read_op"), "number of read io operations").unwrap();
     static ref IO_READ_ERR: IntCounter =
-        register_int_counter!("nbd_io_read_err", "number of read errors").unwrap();
-    static ref IO_WRITE_OP: IntCounter =
-        register_int_counter!("nbd_io_write_op", "number of write io operations").unwrap();
+        register_int_counter!(metrics::name("io_read_err"), "number of read errors").unwrap();
+    static ref IO_WRITE_OP: IntCounter = register_int_counter!(
+        metrics::name("io_write_op"),
+        "number of write io operations"
+    )
+    .unwrap();
     static ref IO_WRITE_ERR: IntCounter =
-        register_int_counter!("nbd_io_write_err", "number of write errors").unwrap();
+        register_int_counter!(metrics::name("io_write_err"), "number of write errors").unwrap();
+    static ref IO_TRIM_OP: IntCounter =
+        register_int_counter!(metrics::name("io_trim_op"), "number of trim io operations").unwrap();
+    static ref IO_ZERO_BYTES: IntCounter = register_int_counter!(
+        metrics::name("io_zero_bytes"),
+        "number of bytes zeroed with write zeroes"
+    )
+    .unwrap();
+    static ref IO_READ_CRC_ERR: IntCounter = register_int_counter!(
+        metrics::name("io_read_crc_err"),
+        "number of served pages with a bad crc"
+    )
+    .unwrap();
+    static ref CACHE_FLUSH_ERR: IntCounter = register_int_counter!(
+        metrics::name("cache_flush_err"),
+        "number of failed cache flushes"
+    )
+    .unwrap();
+    static ref LAST_CLEAN: IntGauge = register_int_gauge!(
+        metrics::name("last_clean_timestamp"),
+        "unix time of the last time all written data was flushed to the store"
+    )
+    .unwrap();
     static ref DEVICE_FLUSH: IntCounter =
-        register_int_counter!("nbd_device_flush", "number of flush requests").unwrap();
+        register_int_counter!(metrics::name("device_flush"), "number of flush requests").unwrap();
     static ref IO_READ_HISTOGRAM: Histogram = register_histogram!(
-        "nbd_io_read_histogram",
+        metrics::name("io_read_histogram"),
         "read io histogram",
         vec![0.001, 0.010, 0.050, 0.100, 0.250, 0.500]
     )
     .unwrap();
     static ref IO_WRITE_HISTOGRAM: Histogram = register_histogram!(
-        "nbd_io_write_histogram",
+        metrics::name("io_write_histogram"),
         "write io histogram",
         vec![0.001, 0.010, 0.050, 0.100, 0.250, 0.500]
     )
@@ -86,10 +119,87 @@ impl FlushRange {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+const COMBINE_PAGES: usize = 16;
+/// WriteCombine keeps track of recently written pages so a page
+/// that receives many small writes is only flushed once it stops
+/// receiving writes for window.
+struct WriteCombine {
+    window: Duration,
+    // (address, last write)
+    pending: Vec<(usize, Instant)>,
+}
 
+impl WriteCombine {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: Vec::with_capacity(COMBINE_PAGES),
+        }
+    }
+
+    /// record a write to the page at address. If the buffer is full
+    /// the page that was written the longest time ago is returned and
+    /// must be flushed now
+    fn touch(&mut self, address: usize, now: Instant) -> Option<usize> {
+        if let Some(entry) = self.pending.iter_mut().find(|(a, _)| *a == address) {
+            entry.1 = now;
+            return None;
+        }
+
+        let mut oldest = None;
+        if self.pending.len() >= COMBINE_PAGES {
+            let index = self
+                .pending
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (_, at))| *at)
+                .map(|(index, _)| index)
+                .unwrap();
+
+            oldest = Some(self.pending.swap_remove(index).0);
+        }
+
+        self.pending.push((address, now));
+        oldest
+    }
+
+    /// remove and return the pages that were not written for window
+    fn quiesced(&mut self, now: Instant) -> Vec<usize> {
+        let window = self.window;
+        let mut pages = Vec::new();
+        self.pending.retain(|(address, at)| {
+            if now.saturating_duration_since(*at) >= window {
+                pages.push(*address);
+                false
+            } else {
+                true
+            }
+        });
+
+        pages.sort_unstable();
+        pages
+    }
+
+    fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// DeviceControl are maintenance commands sent to the device over the
+/// control stream. All of them are idempotent, sending the same command
+/// twice does no harm.
+#[derive(Debug, Clone, Copy)]
 pub enum DeviceControl {
+    /// evict dirty pages to the store, only if the device
+    /// was idle for the given duration
     Evict(Duration),
+    /// evict all dirty pages to the store and sync the cache to disk
+    Flush,
+    /// drop clean pages with a bad crc from the cache
+    Scrub,
+    /// from now on evict once the device was idle for the given duration,
+    /// whatever the duration of the evict notifies is
+    SetEvictThreshold(Duration),
 }
 
 impl DeviceControl {
@@ -97,6 +207,41 @@ impl DeviceControl {
         DeviceControl::Evict(after)
     }
 }
+/// FlushMode controls when written pages are flushed to the cache file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FlushMode {
+    /// pages are flushed as soon as a flush range is complete
+    #[default]
+    Eager,
+    /// pages are only flushed on the periodic eviction tick and when
+    /// the device is explicitly flushed. Much less syncs on small random
+    /// writes but whatever was written since the last flush can be lost
+    /// on a crash.
+    Deferred,
+}
+
+impl FromStr for FlushMode {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "eager" => Ok(Self::Eager),
+            "deferred" => Ok(Self::Deferred),
+            _ => Err(format!(
+                "invalid flush mode '{s}', expected eager or deferred"
+            )),
+        }
+    }
+}
+
+impl Display for FlushMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Eager => f.write_str("eager"),
+            Self::Deferred => f.write_str("deferred"),
+        }
+    }
+}
+
 /// implementation of the nbd device
 ///
 /// The device mainly works against a cache object
@@ -107,41 +252,215 @@ where
 {
     cache: Cache<S>,
     flush: FlushRange,
+    flush_mode: FlushMode,
+    max_request: Option<usize>,
+    combine: Option<WriteCombine>,
+    verify_reads: bool,
+    scrub_repair: bool,
+    idle_flush: Option<Duration>,
+    max_dirty_age: Option<Duration>,
+    shutdown_timeout: Option<Duration>,
+    read_only: bool,
+    // overrides the idle duration of evict notifies if set
+    evict_threshold: Option<Duration>,
+    // nothing was written since the last full flush
+    clean: bool,
     atime: Instant,
+    // same clock as the cache
+    clock: Arc<dyn Clock>,
 }
 
 impl<S> Device<S>
 where
     S: Store,
 {
+    /// the device uses the clock of the cache, see [`Cache::with_clock`]
     pub fn new(cache: Cache<S>) -> Self {
+        let clock = cache.clock();
         Self {
+            atime: clock.now(),
+            clock,
             cache,
             flush: FlushRange::default(),
-            atime: Instant::now(),
+            flush_mode: FlushMode::default(),
+            max_request: None,
+            combine: None,
+            verify_reads: false,
+            scrub_repair: false,
+            idle_flush: None,
+            max_dirty_age: None,
+            shutdown_timeout: None,
+            read_only: false,
+            evict_threshold: None,
+            clean: false,
+        }
+    }
+
+    /// set how written pages are flushed (default is eager)
+    pub fn with_flush_mode(mut self, mode: FlushMode) -> Self {
+        self.flush_mode = mode;
+        self
+    }
+
+    /// reject read and write requests bigger than max bytes. A huge request
+    /// holds the device for as long as it takes to go over all its pages
+    pub fn with_max_request(mut self, max: usize) -> Self {
+        self.max_request = Some(max);
+        self
+    }
+
+    /// in eager mode, only flush a written page once it received no
+    /// writes for window instead of on every flush range. Useful when
+    /// the guest does many small writes to the same pages.
+    pub fn with_write_combine(mut self, window: Duration) -> Self {
+        self.combine = Some(WriteCombine::new(window));
+        self
+    }
+
+    /// verify the crc of every clean page before it's served to a read.
+    /// This catches corruption of the cache file after the page was loaded
+    /// at the cost of a crc over the full page on each read
+    pub fn with_verify_reads(mut self, verify: bool) -> Self {
+        self.verify_reads = verify;
+        self
+    }
+
+    /// give up on syncing the cache to the store on shutdown after timeout,
+    /// so a hung store can't block the exit. Pages that are not written
+    /// yet stay dirty in the cache file and are evicted on next start
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// reject all writes and trims (EPERM) and never write back to the
+    /// store, for serving a snapshot of the store safely. Dirty pages left
+    /// in the cache file by an earlier run are kept there and not synced
+    /// on shutdown, but they are still written back once their slot is
+    /// needed for another page, so read only is meant for a clean cache
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// on scrub, fetch corrupted pages again from the store instead of
+    /// dropping them, see [`Cache::scrub_repair`]
+    pub fn with_scrub_repair(mut self, repair: bool) -> Self {
+        self.scrub_repair = repair;
+        self
+    }
+
+    /// once the device had no reads or writes for idle, all dirty pages
+    /// are evicted and both the cache and the store are flushed. A device
+    /// that is idle for long is then fully durable.
+    pub fn with_idle_flush(mut self, idle: Duration) -> Self {
+        self.idle_flush = Some(idle);
+        self
+    }
+
+    /// on every eviction tick, pages that are dirty for at least age are
+    /// evicted and the store is flushed, even if the device is busy. This
+    /// caps how long an acknowledged write can stay only in the cache
+    pub fn with_max_dirty_age(mut self, age: Duration) -> Self {
+        self.max_dirty_age = Some(age);
+        self
+    }
+
+    /// size of the device, this is the only size that should be used
+    /// to configure the nbd device since it's exactly what the cache
+    /// can serve
+    pub fn size(&self) -> ByteSize {
+        self.cache.size()
+    }
+
+    /// number of nbd blocks of block_size in the device. Fails if the
+    /// device size is not a multiple of block_size since the tail of the
+    /// device would then either be lost or fail on access
+    pub fn blocks(&self, block_size: u64) -> io::Result<u64> {
+        let size = self.size().as_u64();
+        if block_size == 0 || size % block_size != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("device size {size} is not multiple of block size {block_size}"),
+            ));
         }
+
+        Ok(size / block_size)
+    }
+
+    pub fn inner(self) -> Cache<S> {
+        self.cache
     }
 
     /// we can only map blocks index that fits in a u32.
-    /// this is because
+    /// this is because stores address pages with a u32 (even if the map
+    /// uses wide ids, see [`crate::map::Header`]) so the device can't be
+    /// bigger than `MAX_PAGE_COUNT * page_size`. An offset beyond that
+    /// returns an `InvalidInput` error
     pub fn page_of(&self, offset: u64) -> io::Result<u32> {
         let block = offset as usize / self.cache.page_size();
 
         u32::try_from(block).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
     }
 
-    async fn inner_read(&mut self, offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
-        // find the block
+    /// make sure a request with len bytes of data is not bigger than the
+    /// max request size. Requests with no data (a trim) are not limited
+    fn check_request_size(&self, len: usize) -> io::Result<()> {
+        if matches!(self.max_request, Some(max) if len > max) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "request is bigger than max request size",
+            ));
+        }
+
+        Ok(())
+    }
 
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "device is read only",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// make sure [offset, offset + len[ is inside the device
+    fn check_bounds(&self, offset: u64, len: usize) -> io::Result<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.cache.size().as_u64() => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "io beyond device size",
+            )),
+        }
+    }
+
+    async fn inner_read(&mut self, offset: u64, mut buf: &mut [u8]) -> Result<()> {
+        self.check_request_size(buf.len())?;
+        self.check_bounds(offset, buf.len())?;
+
+        // find the block
         let mut index = self.page_of(offset)?;
-        // TODO: make sure that index is not beyond the max index size by
-        // the cold store.
 
         let mut inner_offset = offset as usize % self.cache.page_size();
 
         loop {
             let page = self.cache.get(index).await?;
 
+            // crc of dirty pages is only updated on eviction
+            if self.verify_reads && !page.header().flag(Flags::Dirty) && !page.is_crc_ok() {
+                IO_READ_CRC_ERR.inc();
+                self.cache.drop_corrupted(index);
+                return Err(Error::BadCrc(index));
+            }
+
             let source = &page.data()[inner_offset..];
             let to_copy = std::cmp::min(source.len(), buf.len());
             buf[..to_copy].copy_from_slice(&source[..to_copy]);
@@ -156,10 +475,20 @@ where
     }
 
     /// Write a block of data at offset.
-    async fn inner_write(&mut self, offset: u64, mut buf: &[u8]) -> io::Result<()> {
+    async fn inner_write(&mut self, offset: u64, mut buf: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        self.check_request_size(buf.len())?;
+        self.check_bounds(offset, buf.len())?;
+        self.clean = false;
+
         let mut index = self.page_of(offset)?;
         let mut inner_offset = offset as usize % self.cache.page_size();
 
+        // all pages of the request are locked at once, so they are
+        // locked in order
+        let last = self.page_of(offset + buf.len().max(1) as u64 - 1)?;
+        let _guard = self.cache.lock(index..=last).await;
+
         loop {
             let mut page = self.cache.get_mut(index).await?;
             let dest = &mut page.data_mut()[inner_offset..];
@@ -168,10 +497,8 @@ where
 
             // mark it dirty because it was modified
             page.header_mut().set(Flags::Dirty, true);
-
-            if let Some(flush) = self.flush.append(page.address()) {
-                self.cache.flush_range(flush.start(), flush.len())?;
-            }
+            let address = page.address();
+            self.written(address)?;
 
             buf = &buf[to_copy..];
             if buf.is_empty() {
@@ -181,25 +508,294 @@ where
             inner_offset = 0;
         }
 
+        self.flush_quiesced()
+    }
+
+    /// discards [offset, offset + len[. Pages that are fully covered are
+    /// dropped from the cache and discarded on the store, the partially
+    /// covered pages at the edges are zeroed instead
+    async fn inner_trim(&mut self, offset: u64, len: usize) -> Result<()> {
+        self.check_writable()?;
+        let pages = self.covered(offset, len)?;
+        let Some(last) = pages.last().map(|(index, _)| *index) else {
+            return Ok(());
+        };
+        self.clean = false;
+        let _guard = self.cache.lock(pages[0].0..=last).await;
+
+        for (index, range) in pages {
+            if range.len() == self.cache.page_size() {
+                self.cache.discard(index).await?;
+            } else {
+                self.zero(index, range).await?;
+            }
+        }
+
+        self.flush_quiesced()
+    }
+
+    /// zeroes [offset, offset + len[ without a buffer of zeros, pages
+    /// are zeroed in place in the cache. The pages are still written to
+    /// the store in full, unlike trim the zeros are really stored
+    pub async fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.atime = self.clock.now();
+        let _timer = IO_WRITE_HISTOGRAM.start_timer();
+        match self.inner_write_zeroes(offset, len as usize).await {
+            Ok(_) => {
+                IO_ZERO_BYTES.inc_by(len);
+                Ok(())
+            }
+            Err(err) => {
+                log::error!("write zeroes error {err:#}");
+                IO_WRITE_ERR.inc();
+                Err(nbd_error(err))
+            }
+        }
+    }
+
+    async fn inner_write_zeroes(&mut self, offset: u64, len: usize) -> Result<()> {
+        self.check_writable()?;
+        let pages = self.covered(offset, len)?;
+        let Some(last) = pages.last().map(|(index, _)| *index) else {
+            return Ok(());
+        };
+        self.clean = false;
+        let _guard = self.cache.lock(pages[0].0..=last).await;
+
+        for (index, range) in pages {
+            self.zero(index, range).await?;
+        }
+
+        self.flush_quiesced()
+    }
+
+    /// the pages [offset, offset + len[ covers, each with the range of the
+    /// page it covers. For requests with no data (trim, write zeroes)
+    fn covered(&self, offset: u64, len: usize) -> Result<Vec<(u32, Range<usize>)>> {
+        self.check_bounds(offset, len)?;
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        let page_size = self.cache.page_size() as u64;
+        let end = offset + len as u64;
+        let mut pages = vec![];
+        for index in self.page_of(offset)?..=self.page_of(end - 1)? {
+            let start = index as u64 * page_size;
+            let range =
+                offset.saturating_sub(start) as usize..(end - start).min(page_size) as usize;
+            pages.push((index, range));
+        }
+
+        Ok(pages)
+    }
+
+    /// zeroes range of the page at index
+    async fn zero(&mut self, index: u32, range: Range<usize>) -> Result<()> {
+        let mut page = self.cache.get_mut(index).await?;
+        page.data_mut()[range].fill(0);
+        page.update_crc();
+        page.header_mut().set(Flags::Dirty, true);
+        let address = page.address();
+        self.written(address)
+    }
+
+    /// the page at address was just modified. In deferred mode pages are
+    /// flushed on next tick or device flush, in eager mode it's flushed
+    /// now (or once it's no longer written to with write combine)
+    fn written(&mut self, address: usize) -> Result<()> {
+        if self.flush_mode != FlushMode::Eager {
+            return Ok(());
+        }
+
+        let flush = match self.combine.as_mut() {
+            Some(combine) => combine
+                .touch(address, self.clock.now())
+                .map(|address| FlushRange(address, address + 1)),
+            None => self.flush.append(address),
+        };
+
+        if let Some(flush) = flush {
+            self.cache
+                .flush_range(flush.start(), flush.len())
+                .map_err(cache_flush_err)?;
+        }
+
         Ok(())
     }
 
+    /// flush the combined pages that are no longer written to
+    fn flush_quiesced(&mut self) -> Result<()> {
+        if let Some(combine) = self.combine.as_mut() {
+            for address in combine.quiesced(self.clock.now()) {
+                self.cache
+                    .flush_range(address, 1)
+                    .map_err(cache_flush_err)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// evict all dirty pages, then flush the cache and the store. Returns
+    /// the number of pages that were written
+    async fn flush_all(&mut self) -> Result<usize> {
+        let written = self.cache.sync_all().await.map_err(cache_flush_err)?;
+        self.cache.flush_store().await?;
+
+        self.clean = true;
+        if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            LAST_CLEAN.set(now.as_secs() as i64);
+        }
+
+        Ok(written)
+    }
+
+    /// time since the last read or write
+    fn idle(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.atime)
+    }
+
     // evict whatever you can in 50 milliseconds
-    async fn evict(&mut self) -> io::Result<()> {
-        self.cache
-            .evict(Duration::from_millis(50))
-            .await
-            .map_err(io::Error::from)
+    async fn evict(&mut self) -> Result<()> {
+        self.cache.evict(Duration::from_millis(50)).await
+    }
+
+    async fn inner_control(&mut self, control: &Control<DeviceControl>) -> Result<()> {
+        match control {
+            Control::Shutdown if self.read_only => {
+                log::info!("read only device, nothing to sync before shutdown");
+            }
+            Control::Shutdown => {
+                // controls are handled in order, so any eviction started by
+                // an earlier notify is already done here
+                log::info!("syncing cache before shutdown");
+                let Some(timeout) = self.shutdown_timeout else {
+                    let written = self.flush_all().await?;
+                    log::info!("synced {written} dirty pages before shutdown");
+                    return Ok(());
+                };
+
+                // pages are only marked clean after the store has them, so
+                // giving up in the middle of the eviction loses nothing
+                if let Ok(written) = tokio::time::timeout(timeout, self.flush_all()).await {
+                    log::info!("synced {} dirty pages before shutdown", written?);
+                } else {
+                    self.cache.sync()?;
+                    log::error!(
+                        "sync timed out after {timeout:?}, {} dirty pages are left in the cache",
+                        self.cache.dirty_pages().count()
+                    );
+                }
+            }
+            Control::Notify(DeviceControl::Evict(_)) if self.read_only => {
+                self.cache.update_file_metrics();
+                self.cache.check_thrashing();
+            }
+            Control::Notify(DeviceControl::Flush) if self.read_only => {
+                self.check_writable()?;
+            }
+            Control::Notify(DeviceControl::Evict(duration)) => {
+                self.cache.update_file_metrics();
+                self.cache.check_thrashing();
+
+                if self.flush_mode == FlushMode::Deferred {
+                    // start writing back whatever was written since last tick
+                    self.cache.flush().map_err(cache_flush_err)?;
+                }
+
+                self.flush_quiesced()?;
+
+                // only if no read/write operations happening in
+                // duration time we can call cleanup
+                let threshold = self.evict_threshold.unwrap_or(*duration);
+                if self.idle() > threshold {
+                    log::trace!("background eviction");
+                    self.evict().await?;
+                }
+
+                if matches!(self.idle_flush, Some(idle) if !self.clean && self.idle() > idle) {
+                    log::debug!("device is idle, flushing everything");
+                    self.flush_all().await?;
+                }
+
+                if let Some(age) = self.max_dirty_age {
+                    let evicted = self.cache.evict_older_than(age).await?;
+                    if evicted > 0 {
+                        log::debug!("evicted {evicted} pages dirty for more than {age:?}");
+                    }
+                }
+
+                self.cache.update_writeback_lag();
+            }
+            Control::Notify(DeviceControl::Flush) => {
+                log::debug!("flushing device");
+                self.flush_all().await?;
+            }
+            Control::Notify(DeviceControl::Scrub) if self.scrub_repair => {
+                let (repaired, dropped) = self.cache.scrub_repair().await;
+                log::info!("scrub repaired {repaired} and dropped {dropped} corrupted pages");
+            }
+            Control::Notify(DeviceControl::Scrub) => {
+                let dropped = self.cache.scrub();
+                log::info!("scrub dropped {dropped} corrupted pages");
+            }
+            Control::Notify(DeviceControl::SetEvictThreshold(threshold)) => {
+                log::info!("evicting after {threshold:?} of idle time");
+                self.evict_threshold = Some(*threshold);
+            }
+        };
+
+        Ok(())
     }
 }
 
+/// a flush error is always a failure of the cache file so it's
+/// counted separately from store errors
+fn cache_flush_err(err: Error) -> Error {
+    CACHE_FLUSH_ERR.inc();
+    err
+}
+
+/// maps an error to the errno that is sent back to the nbd client, so the
+/// guest can tell bad requests (EINVAL) from a full disk (ENOSPC) and
+/// real io failures (EIO)
+fn errno(err: &Error) -> Errno {
+    match err {
+        Error::PageIndexOutOfRange | Error::InvalidPageSize | Error::SizeNotMultipleOfPageSize => {
+            Errno::EINVAL
+        }
+        Error::IO(err) => io_errno(err),
+        _ => Errno::EIO,
+    }
+}
+
+fn io_errno(err: &io::Error) -> Errno {
+    if let Some(code) = err.raw_os_error() {
+        return Errno::from_i32(code);
+    }
+
+    match err.kind() {
+        io::ErrorKind::InvalidInput => Errno::EINVAL,
+        io::ErrorKind::PermissionDenied => Errno::EPERM,
+        io::ErrorKind::StorageFull => Errno::ENOSPC,
+        _ => Errno::EIO,
+    }
+}
+
+/// the error returned to nbd only carries the errno, so make sure
+/// the error is logged before it's converted.
+fn nbd_error(err: Error) -> io::Error {
+    io::Error::from_raw_os_error(errno(&err) as i32)
+}
+
 #[async_trait::async_trait(?Send)]
 impl<S> BlockDevice<DeviceControl> for Device<S>
 where
     S: Store,
 {
     async fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
-        self.atime = Instant::now();
+        self.atime = self.clock.now();
         let _timer = IO_READ_HISTOGRAM.start_timer();
         match self.inner_read(offset, buf).await {
             Ok(_) => {
@@ -211,14 +807,14 @@ where
             Err(err) => {
                 log::error!("read error {err:#}");
                 IO_READ_ERR.inc();
-                Err(err)
+                Err(nbd_error(err))
             }
         }
     }
 
     /// Write a block of data at offset.
     async fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
-        self.atime = Instant::now();
+        self.atime = self.clock.now();
         let _timer = IO_WRITE_HISTOGRAM.start_timer();
         match self.inner_write(offset, buf).await {
             Ok(_) => {
@@ -229,7 +825,22 @@ where
             Err(err) => {
                 log::error!("write error {err:#}");
                 IO_WRITE_ERR.inc();
-                Err(err)
+                Err(nbd_error(err))
+            }
+        }
+    }
+
+    /// discards a range, the data reads as zeros afterwards
+    async fn trim(&mut self, offset: u64, len: usize) -> io::Result<()> {
+        self.atime = self.clock.now();
+        match self.inner_trim(offset, len).await {
+            Ok(_) => {
+                IO_TRIM_OP.inc();
+                Ok(())
+            }
+            Err(err) => {
+                log::error!("trim error {err:#}");
+                Err(nbd_error(err))
             }
         }
     }
@@ -237,25 +848,31 @@ where
     /// Flushes write buffers to the underlying storage medium
     async fn flush(&mut self) -> io::Result<()> {
         DEVICE_FLUSH.inc();
-        self.cache.flush()?;
-        Ok(())
-    }
-
-    /// called if a new control message is available on control stream
-    async fn control(&mut self, control: &Control<DeviceControl>) -> io::Result<()> {
-        match control {
-            Control::Shutdown => {}
-            Control::Notify(DeviceControl::Evict(duration)) => {
-                // only if no read/write operations happening in
-                // duration time we can call cleanup
-                if self.atime.elapsed() > *duration {
-                    log::trace!("background eviction");
-                    self.evict().await?;
+        let result = match self.flush_mode {
+            FlushMode::Eager => {
+                // the whole map is flushed so nothing is pending anymore
+                if let Some(combine) = self.combine.as_mut() {
+                    combine.clear();
                 }
+                self.cache.flush()
             }
+            // nothing was flushed on write so we have to wait
+            // until everything is on disk
+            FlushMode::Deferred => self.cache.sync(),
         };
 
-        Ok(())
+        result.map_err(|err| {
+            log::error!("flush error {err:#}");
+            nbd_error(cache_flush_err(err))
+        })
+    }
+
+    /// called if a new control message is available on control stream
+    async fn control(&mut self, control: &Control<DeviceControl>) -> io::Result<()> {
+        self.inner_control(control).await.map_err(|err| {
+            log::error!("control error {err:#}");
+            nbd_error(err)
+        })
     }
 }
 
@@ -263,7 +880,6 @@ where
 mod test {
     use super::*;
     use crate::cache::{Cache, NullStore};
-    use bytesize::ByteSize;
     use nbd_async::BlockDevice;
 
     #[tokio::test]
@@ -272,7 +888,13 @@ mod test {
         // start from clean slate
         let _ = std::fs::remove_file(PATH);
 
-        let cache = Cache::new(NullStore, PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        let cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
 
         let mut dev = Device::new(cache);
 
@@ -304,7 +926,13 @@ mod test {
         // start from clean slate
         let _ = std::fs::remove_file(PATH);
 
-        let cache = Cache::new(NullStore, PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        let cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
 
         let mut dev = Device::new(cache);
 
@@ -340,6 +968,539 @@ mod test {
         assert!(buf[512..1024].iter().all(|v| *v == 3));
     }
 
+    #[tokio::test]
+    async fn trim() {
+        const PATH: &str = "/tmp/device.trim.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(
+            crate::store::InMemory::new(10),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+        let mut dev = Device::new(cache);
+
+        dev.write(0, &[1; 3072]).await.unwrap();
+        assert_eq!(dev.flush_all().await.unwrap(), 3);
+
+        // only page 1 is fully covered
+        dev.trim(512, 2048).await.unwrap();
+        assert!(!dev.cache.is_resident(1));
+
+        let mut buf = [0xff; 3072];
+        dev.read(0, &mut buf).await.unwrap();
+        assert!(buf[..512].iter().all(|v| *v == 1));
+        assert!(buf[512..2560].iter().all(|v| *v == 0));
+        assert!(buf[2560..].iter().all(|v| *v == 1));
+
+        assert!(dev.trim(9 * 1024, 2048).await.is_err());
+
+        dev.flush_all().await.unwrap();
+        let store = dev.inner().inner();
+        assert!(!store.mem.contains_key(&1));
+        assert!(store.mem[&0][512..].iter().all(|v| *v == 0));
+    }
+
+    #[tokio::test]
+    async fn write_zeroes() {
+        const PATH: &str = "/tmp/device.write_zeroes.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+        let mut dev = Device::new(cache);
+
+        let pattern: Vec<u8> = (0..4096).map(|i| (i % 250) as u8 + 1).collect();
+        dev.write(0, &pattern).await.unwrap();
+
+        // from the middle of page 0 to the middle of page 3
+        dev.write_zeroes(700, 2500).await.unwrap();
+
+        let mut buf = vec![0; 4096];
+        dev.read(0, &mut buf).await.unwrap();
+        assert_eq!(&buf[..700], &pattern[..700]);
+        assert!(buf[700..3200].iter().all(|v| *v == 0));
+        assert_eq!(&buf[3200..], &pattern[3200..]);
+
+        assert!(dev.write_zeroes(9 * 1024, 2048).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_only() {
+        const PATH: &str = "/tmp/device.read_only.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut mem = crate::store::InMemory::new(10);
+        mem.mem.insert(0, vec![1; 1024]);
+        let cache = Cache::new(mem, PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        let mut dev = Device::new(cache).with_read_only(true);
+
+        let err = dev.write(0, &[2; 512]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(dev.trim(0, 1024).await.is_err());
+        assert!(dev.write_zeroes(0, 1024).await.is_err());
+
+        let mut buf = [0; 1024];
+        dev.read(0, &mut buf).await.unwrap();
+        assert!(buf.iter().all(|v| *v == 1));
+        assert_eq!(dev.cache.dirty_pages().count(), 0);
+
+        dev.control(&Control::Shutdown).await.unwrap();
+        let store = dev.inner().inner();
+        assert!(store.mem[&0].iter().all(|v| *v == 1));
+        assert_eq!(store.mem.len(), 1);
+    }
+
+    /// a store where the last page is only half a page
+    struct Unaligned;
+
+    #[async_trait::async_trait]
+    impl Store for Unaligned {
+        async fn set(&mut self, index: u32, page: &[u8]) -> crate::Result<()> {
+            let len = if index == 10 { 512 } else { 1024 };
+            if index > 10 || page.len() != len {
+                return Err(crate::Error::InvalidPageSize);
+            }
+
+            Ok(())
+        }
+
+        async fn get(&self, _index: u32) -> crate::Result<Option<crate::store::Page>> {
+            Ok(None)
+        }
+
+        fn size(&self) -> ByteSize {
+            ByteSize::b(10 * 1024 + 512)
+        }
+
+        fn page_size(&self) -> usize {
+            1024
+        }
+    }
+
+    #[tokio::test]
+    async fn unaligned() {
+        const PATH: &str = "/tmp/device.unaligned.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(Unaligned, PATH, ByteSize::kib(4), ByteSize::kib(1)).unwrap();
+        // the partial page is usable up to the end of the store
+        assert_eq!(cache.size(), ByteSize::b(10 * 1024 + 512));
+
+        let mut dev = Device::new(cache);
+        // nbd size is computed from the same size
+        assert_eq!(dev.size(), ByteSize::b(10 * 1024 + 512));
+        assert_eq!(dev.blocks(512).unwrap(), 21);
+        // 10.5k can't be split in 1k blocks
+        assert!(dev.blocks(1024).is_err());
+
+        let mut buf: [u8; 1024] = [1; 1024];
+
+        assert!(dev.read(9 * 1024, &mut buf).await.is_ok());
+        assert!(dev.write(9 * 1024, &buf).await.is_ok());
+
+        // the tail
+        assert!(dev.read(10 * 1024, &mut buf[..512]).await.is_ok());
+        assert!(dev.write(9 * 1024 + 512, &buf).await.is_ok());
+
+        // crossing the end of the device
+        assert!(dev.read(10 * 1024, &mut buf).await.is_err());
+        assert!(dev.write(10 * 1024, &buf).await.is_err());
+
+        // only the part of the tail the store holds is written back
+        dev.cache.evict(Duration::MAX).await.unwrap();
+        assert_eq!(dev.cache.dirty_pages().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        const PATH: &str = "/tmp/device.shutdown.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(
+            crate::store::InMemory::new(10),
+            PATH,
+            ByteSize::kib(4),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+
+        let mut dev = Device::new(cache);
+        let buf: [u8; 1024] = [5; 1024];
+        for page in 0..3 {
+            dev.write(page * 1024, &buf).await.unwrap();
+        }
+
+        // an evict notify right before shutdown is ignored since the device
+        // was just written to, shutdown still has to sync everything
+        let evict = Control::Notify(DeviceControl::evict(Duration::from_secs(60)));
+        dev.control(&evict).await.unwrap();
+        assert_eq!(dev.cache.dirty_pages().count(), 3);
+
+        dev.control(&Control::Shutdown).await.unwrap();
+
+        let cache = dev.inner();
+        assert_eq!(cache.dirty_pages().count(), 0);
+
+        let mem = cache.inner();
+        assert_eq!(mem.mem.len(), 3);
+        assert!(mem.mem.values().all(|page| page.iter().all(|v| *v == 5)));
+    }
+
+    /// a store where writes never finish
+    struct Hung;
+
+    #[async_trait::async_trait]
+    impl Store for Hung {
+        async fn set(&mut self, _index: u32, _page: &[u8]) -> crate::Result<()> {
+            std::future::pending().await
+        }
+
+        async fn get(&self, _index: u32) -> crate::Result<Option<crate::store::Page>> {
+            Ok(None)
+        }
+
+        fn size(&self) -> ByteSize {
+            ByteSize::kib(10)
+        }
+
+        fn page_size(&self) -> usize {
+            1024
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_timeout() {
+        const PATH: &str = "/tmp/device.shutdown_timeout.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(Hung, PATH, ByteSize::kib(4), ByteSize::kib(1)).unwrap();
+        let mut dev = Device::new(cache).with_shutdown_timeout(Duration::from_millis(50));
+        let buf: [u8; 1024] = [5; 1024];
+        for page in 0..3 {
+            dev.write(page * 1024, &buf).await.unwrap();
+        }
+
+        dev.control(&Control::Shutdown).await.unwrap();
+        drop(dev);
+
+        // the pages are still dirty once the cache is opened again
+        let cache = Cache::new(Hung, PATH, ByteSize::kib(4), ByteSize::kib(1)).unwrap();
+        assert_eq!(cache.dirty_pages().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn evict_threshold() {
+        const PATH: &str = "/tmp/device.evict_threshold.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(
+            crate::store::InMemory::new(10),
+            PATH,
+            ByteSize::kib(4),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+
+        let mut dev = Device::new(cache);
+        dev.write(0, &[5; 1024]).await.unwrap();
+
+        let evict = Control::Notify(DeviceControl::evict(Duration::from_secs(60)));
+        dev.control(&evict).await.unwrap();
+        assert_eq!(dev.cache.dirty_pages().count(), 1);
+
+        // the threshold of the notify is ignored from now on
+        let set = Control::Notify(DeviceControl::SetEvictThreshold(Duration::ZERO));
+        dev.control(&set).await.unwrap();
+        dev.control(&evict).await.unwrap();
+        assert_eq!(dev.cache.dirty_pages().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn idle_clock() {
+        const PATH: &str = "/tmp/device.idle_clock.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let clock = crate::clock::MockClock::default();
+        let cache = Cache::new(
+            crate::store::InMemory::new(10),
+            PATH,
+            ByteSize::kib(4),
+            ByteSize::kib(1),
+        )
+        .unwrap()
+        .with_clock(Arc::new(clock.clone()));
+
+        let mut dev = Device::new(cache);
+        dev.write(0, &[5; 1024]).await.unwrap();
+
+        let evict = Control::Notify(DeviceControl::evict(Duration::from_secs(60)));
+        clock.advance(Duration::from_secs(60));
+        dev.control(&evict).await.unwrap();
+        assert_eq!(dev.cache.dirty_pages().count(), 1);
+
+        // only once the device is idle for longer than the threshold
+        clock.advance(Duration::from_secs(1));
+        dev.control(&evict).await.unwrap();
+        assert_eq!(dev.cache.dirty_pages().count(), 0);
+    }
+
+    #[test]
+    fn errno() {
+        assert_eq!(super::errno(&Error::PageIndexOutOfRange), Errno::EINVAL);
+        assert_eq!(super::errno(&Error::ZeroSize), Errno::EIO);
+
+        let err = Error::IO(io::Error::from_raw_os_error(Errno::ENOSPC as i32));
+        assert_eq!(super::errno(&err), Errno::ENOSPC);
+
+        let err = Error::IO(io::Error::from(io::ErrorKind::StorageFull));
+        assert_eq!(super::errno(&err), Errno::ENOSPC);
+
+        let err = Error::IO(io::Error::from(io::ErrorKind::InvalidInput));
+        assert_eq!(super::errno(&err), Errno::EINVAL);
+
+        let err = Error::Other(anyhow::anyhow!("store failed"));
+        assert_eq!(super::errno(&err), Errno::EIO);
+    }
+
+    #[tokio::test]
+    async fn max_request() {
+        const PATH: &str = "/tmp/device.max_request.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+
+        let mut dev = Device::new(cache).with_max_request(2048);
+
+        let mut buf = vec![0; 4096];
+        assert!(dev.read(0, &mut buf[..2048]).await.is_ok());
+        assert!(dev.write(0, &buf[..2048]).await.is_ok());
+
+        let err = dev.read(0, &mut buf).await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(Errno::EINVAL as i32));
+        let err = dev.write(0, &buf).await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(Errno::EINVAL as i32));
+    }
+
+    #[tokio::test]
+    async fn deferred() {
+        const PATH: &str = "/tmp/device.deferred.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+
+        let mut dev = Device::new(cache).with_flush_mode(FlushMode::Deferred);
+
+        let mut buf: [u8; 512] = [4; 512];
+        for offset in (0..10 * 1024).step_by(512) {
+            dev.write(offset, &buf).await.unwrap();
+        }
+
+        dev.flush().await.unwrap();
+
+        buf.fill(0);
+        dev.read(5 * 1024, &mut buf).await.unwrap();
+        assert!(buf.iter().all(|v| *v == 4));
+    }
+
+    #[tokio::test]
+    async fn write_combine() {
+        const PATH: &str = "/tmp/device.write_combine.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+
+        let mut dev = Device::new(cache).with_write_combine(Duration::from_secs(60));
+
+        let mut buf: [u8; 4] = [5; 4];
+        for offset in (0..1024).step_by(4) {
+            dev.write(offset, &buf).await.unwrap();
+        }
+
+        // many writes to the same page are pending once
+        let combine = dev.combine.as_mut().unwrap();
+        assert_eq!(combine.pending.len(), 1);
+        assert!(combine.quiesced(Instant::now()).is_empty());
+        assert_eq!(
+            combine.quiesced(Instant::now() + Duration::from_secs(61)),
+            vec![0]
+        );
+
+        dev.read(512, &mut buf).await.unwrap();
+        assert!(buf.iter().all(|v| *v == 5));
+    }
+
+    #[test]
+    fn write_combine_full() {
+        let mut combine = WriteCombine::new(Duration::from_secs(1));
+        let now = Instant::now();
+        for address in 0..COMBINE_PAGES {
+            let at = now + Duration::from_millis(address as u64);
+            assert!(combine.touch(address, at).is_none());
+        }
+
+        // writing again to 0 makes 1 the oldest
+        assert!(combine.touch(0, now + Duration::from_secs(1)).is_none());
+        assert_eq!(combine.touch(100, now + Duration::from_secs(1)), Some(1));
+        assert_eq!(combine.pending.len(), COMBINE_PAGES);
+    }
+
+    #[tokio::test]
+    async fn verify_reads() {
+        const PATH: &str = "/tmp/device.verify_reads.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(
+            NullStore::new(ByteSize::kib(1)),
+            PATH,
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+
+        let mut dev = Device::new(cache).with_verify_reads(true);
+
+        let mut buf: [u8; 512] = [6; 512];
+        dev.write(0, &buf).await.unwrap();
+        // dirty pages are not verified
+        dev.read(0, &mut buf).await.unwrap();
+
+        dev.cache.evict(Duration::MAX).await.unwrap();
+        dev.read(0, &mut buf).await.unwrap();
+        assert!(buf.iter().all(|v| *v == 6));
+
+        // corrupt the page behind the cache back
+        let mut page = dev.cache.get_mut(0).await.unwrap();
+        page.data_mut()[100] = 0;
+
+        let err = dev.read(0, &mut buf).await.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(Errno::EIO as i32));
+        // the bad page is not kept, it's loaded again on next read
+        assert!(!dev.cache.is_resident(0));
+
+        // same read passes without verification
+        dev.verify_reads = false;
+        assert!(dev.read(0, &mut buf).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn idle_flush() {
+        const PATH: &str = "/tmp/device.idle_flush.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(
+            crate::store::InMemory::new(10),
+            PATH,
+            ByteSize::kib(5),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+
+        let mut dev = Device::new(cache).with_idle_flush(Duration::from_millis(10));
+
+        let buf: [u8; 512] = [7; 512];
+        for offset in (0..4 * 1024).step_by(512) {
+            dev.write(offset, &buf).await.unwrap();
+        }
+
+        // a tick right after the write does nothing
+        let tick = Control::Notify(DeviceControl::evict(Duration::from_secs(60)));
+        dev.control(&tick).await.unwrap();
+        assert!(!dev.clean);
+        assert_eq!(dev.cache.dirty_pages().count(), 4);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        dev.control(&tick).await.unwrap();
+        assert!(dev.clean);
+        assert_eq!(dev.cache.dirty_pages().count(), 0);
+
+        dev.write(0, &buf).await.unwrap();
+        assert!(!dev.clean);
+
+        let mem = dev.inner().inner();
+        assert_eq!(mem.mem.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn max_dirty_age() {
+        const PATH: &str = "/tmp/device.max_dirty_age.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let cache = Cache::new(
+            crate::store::InMemory::new(10),
+            PATH,
+            ByteSize::kib(5),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+
+        let mut dev = Device::new(cache).with_max_dirty_age(Duration::from_millis(20));
+
+        let buf: [u8; 512] = [7; 512];
+        dev.write(0, &buf).await.unwrap();
+
+        // the device is never idle for 60 seconds so only the age
+        // of the page can evict it
+        let tick = Control::Notify(DeviceControl::evict(Duration::from_secs(60)));
+        dev.control(&tick).await.unwrap();
+        assert_eq!(dev.cache.dirty_pages().count(), 1);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        dev.write(2048, &buf).await.unwrap();
+        dev.control(&tick).await.unwrap();
+
+        // only the old page is evicted
+        let dirty: Vec<u32> = dev.cache.dirty_pages().map(|(page, _)| page).collect();
+        assert_eq!(dirty, vec![2]);
+
+        let mem = dev.inner().inner();
+        assert_eq!(mem.mem.len(), 1);
+    }
+
+    #[test]
+    fn flush_mode() {
+        assert_eq!("eager".parse(), Ok(FlushMode::Eager));
+        assert_eq!("deferred".parse(), Ok(FlushMode::Deferred));
+        assert!("lazy".parse::<FlushMode>().is_err());
+        assert_eq!(FlushMode::Deferred.to_string(), "deferred");
+    }
+
     #[test]
     fn flush_range() {
         let mut range = FlushRange::default();
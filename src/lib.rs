@@ -4,14 +4,27 @@ use std::{
 };
 
 pub mod cache;
+pub mod clock;
+pub mod config;
 pub mod device;
 pub mod map;
+pub mod metrics;
+mod proto;
+pub mod server;
 pub mod store;
 
+pub use config::{open, Config};
+
 #[derive(thiserror::Error, Debug)]
 pub enum PolicyError {
     #[error("stores not same size")]
     StoresNotSameSize,
+
+    #[error("stores geometry changed from the one recorded in {0}")]
+    GeometryChanged(PathBuf),
+
+    #[error("write quorum {0} must be between 1 and the number of mirrors ({1})")]
+    InvalidWriteQuorum(usize, usize),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -31,22 +44,46 @@ pub enum Error {
     #[error("page index out of range")]
     PageIndexOutOfRange,
 
+    #[error("page {0} has a bad crc")]
+    BadCrc(u32),
+
+    #[error("page {0} is not a valid compressed page")]
+    BadCompressedPage(u32),
+
+    #[error("page {0} failed authentication, wrong key or corrupted data")]
+    BadAuthTag(u32),
+
     // #[error("block count is too big")]
     #[error("page size must be multiple of block size")]
     SizeNotMultipleOfPageSize,
 
+    #[error("map can not be shrunk")]
+    CannotShrink,
+
     #[error("size change to file {0}")]
     SizeChanged(PathBuf),
 
+    #[error("file {0} is locked by another process")]
+    Locked(PathBuf),
+
+    #[error("file {0} was created with checksum {1}, not {2}")]
+    ChecksumMismatch(PathBuf, map::Checksum, map::Checksum),
+
     #[error("invalid meta size")]
     InvalidMetaSize,
 
     #[error("invalid meta magic")]
     InvalidMetaMagic,
 
+    #[error("invalid meta crc, the meta is corrupted")]
+    InvalidMetaCrc,
+
     #[error("invalid meta version")]
     InvalidMetaVersion,
 
+    #[error("meta version {0} is newer than this version of qbd supports")]
+    UnsupportedMetaVersion(u32),
+
     #[error("invalid meta page size")]
     InvalidMetaPageSize,
 
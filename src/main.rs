@@ -1,22 +1,56 @@
 use anyhow::Context;
 use bytesize::ByteSize;
-use clap::{ArgAction, Parser};
-use nbd_async::Control;
+use clap::{ArgAction, Parser, Subcommand};
+use nbd_async::{BlockDevice, Control};
 use qbd::{
-    device::DeviceControl,
-    store::{policy::Policy, FileStore, Store},
+    cache::Eviction,
+    config::DeviceStore,
+    device::{Device, DeviceControl, FlushMode},
+    map::{Checksum, MapOptions},
+    store::Store,
     *,
 };
 use std::{
-    fmt::Display, future, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc, time::Duration,
+    fmt::Display,
+    future, io,
+    net::SocketAddr,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::fs::FileTypeExt,
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc::{channel, Sender};
-use tokio_stream::wrappers::ReceiverStream;
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    watch,
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
 
 /// Send an evict control signal to the device every 500 milliseconds
 /// the device can choose to ignore that
 const EVICT_DURATION: Duration = Duration::from_millis(500);
 
+/// first fd passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// max time to wait before attaching the device again (see --reconnect)
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// an attempt that served the device for that long resets the attempts count
+const RECONNECT_RESET: Duration = Duration::from_secs(60);
+
+/// max time to wait for the device to get attached before its max request
+/// size is set
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// nbd ioctls as defined by linux/nbd.h
+nix::ioctl_none!(nbd_clear_sock, 0xab, 4);
+nix::ioctl_none!(nbd_disconnect, 0xab, 8);
+
 /// This wrapper is only to overcome the default
 /// stupid format of ByteSize which uses MB/GB units instead
 /// of MiB/GiB units
@@ -40,16 +74,38 @@ impl Display for BSWrapper {
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[command(name="qbd", author, version = env!("GIT_VERSION"), about, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    /// path to nbd device to attach to
-    #[arg(short, long)]
-    nbd: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// path to the cache file, usually should reside on SSD storage
+    /// path to nbd device to attach to. If not set, an already open
+    /// nbd device fd is used (see --nbd-fd)
     #[arg(short, long)]
-    cache: PathBuf,
-
-    /// cache size has to be multiple of page-size
+    nbd: Option<PathBuf>,
+
+    /// use an inherited fd of an already open nbd device instead of --nbd.
+    /// If neither is set, the fd passed by systemd (LISTEN_FDS) is used
+    #[arg(long, conflicts_with = "nbd")]
+    nbd_fd: Option<RawFd>,
+
+    /// serve the device over tcp to nbd clients on that address instead
+    /// of attaching it to a local nbd device
+    #[arg(long, conflicts_with_all = ["nbd", "nbd_fd"])]
+    listen: Option<SocketAddr>,
+
+    /// name of the export served with --listen
+    #[arg(long, default_value = "qbd")]
+    export_name: String,
+
+    /// path to the cache file, usually should reside on SSD storage.
+    /// accepts multiple files (for example on different disks), the
+    /// cache size is then split evenly over all of them
+    #[arg(short, long, required = true)]
+    cache: Vec<PathBuf>,
+
+    /// cache size has to be multiple of page-size (times the number of
+    /// cache files)
     #[arg(long, default_value_t=BSWrapper(bytesize::ByteSize::gib(10)))]
     cache_size: BSWrapper,
 
@@ -60,10 +116,138 @@ struct Args {
 
     /// url to backend store as `file:///path/to/file?size=SIZE`
     /// accepts multiple stores, the total size of the disk
-    /// is the total size of all stores provided
+    /// is the total size of all stores provided.
+    /// `dir:///path/to/dir?size=SIZE` keeps each page in its own file in dir.
+    /// `nbd://host[:port]/export` uses a remote nbd export, the size of the
+    /// store is the size of the export.
+    /// `mem://?size=SIZE` keeps all pages in memory, they are lost on exit.
+    /// `s3://bucket/prefix?size=SIZE&endpoint=URL&region=REGION` keeps each
+    /// page in its own object (needs the s3 feature, credentials are taken
+    /// from the AWS_* env vars).
+    /// `tiered:///path/to/index?hot=URL&cold=URL` puts the hot store in front
+    /// of the cold one, the index of the hot pages is kept at path.
+    /// For testing, `delay://?size=SIZE&ms=MS&jitter=MS` is a store that keeps
+    /// nothing but delays each operation by ms plus a random jitter
     #[arg(long, required = true)]
     store: Vec<url::Url>,
 
+    /// create cache and store files sparse instead of pre-allocating
+    /// their full size. This saves disk space on thin-provisioned setups
+    /// but writes can fail later if the disk runs out of space
+    #[arg(long)]
+    sparse: bool,
+
+    /// disable copy on write on cache and store files. Only useful
+    /// if the files are on a COW filesystem like btrfs
+    #[arg(long)]
+    nocow: bool,
+
+    /// request transparent huge pages for cache and store maps, this reduces tlb
+    /// pressure on very big caches. Ignored if not supported
+    #[arg(long)]
+    huge_pages: bool,
+
+    /// fault in all pages of cache and store maps on startup. Startup takes
+    /// longer (the files are read fully) but first accesses are not slower
+    #[arg(long)]
+    prefault: bool,
+
+    /// checksum kept for each page of newly created cache and store files
+    /// (crc64, crc32c or none), `none` skips the checksum completely. Defaults
+    /// to crc64. Existing files keep the checksum they were created with, if
+    /// this is set they fail to open unless it matches
+    #[arg(long)]
+    checksum: Option<Checksum>,
+
+    /// upgrade cache and store files created by an older version of qbd to
+    /// the current format. A backup of the old meta is kept next to each file
+    #[arg(long)]
+    migrate: bool,
+
+    /// when written pages are flushed to the cache file. `eager` flushes
+    /// as soon as possible, `deferred` only flushes on the periodic eviction
+    /// tick and on explicit device flush. deferred causes far less syncs on
+    /// small random writes but data written since the last flush can be lost
+    /// on a crash or power loss.
+    #[arg(long, default_value_t = FlushMode::Eager)]
+    flush_mode: FlushMode,
+
+    /// max size of a single read or write request, bigger requests are
+    /// rejected. The kernel is also told to never send requests bigger
+    /// than that (rounded down to KiB)
+    #[arg(long, default_value_t=BSWrapper(bytesize::ByteSize::mib(32)))]
+    max_request: BSWrapper,
+
+    /// background eviction only writes to the store once at least that
+    /// many pages are dirty. Bigger values mean less but bigger writes to
+    /// slow stores. Everything is still written on flush and shutdown
+    #[arg(long, default_value_t = 1)]
+    evict_batch: usize,
+
+    /// a read that misses the cache also loads that many of the following
+    /// pages, helps sequential reads from slow stores. 0 disables it
+    #[arg(long, default_value_t = 0)]
+    warm_batch: usize,
+
+    /// a read right after the previous one also loads that many of the
+    /// following pages, helps sequential reads from slow stores. Unlike
+    /// warm-batch random reads are not affected. 0 disables it
+    #[arg(long, default_value_t = 0)]
+    read_ahead: usize,
+
+    /// which cached page makes room for a page that is loaded once the
+    /// cache is full: the least recently used (lru), the oldest (fifo) or
+    /// the least used (lfu). lfu keeps hot pages through big scans
+    #[arg(long, default_value_t = Eviction::Lru)]
+    eviction: Eviction,
+
+    /// in eager flush mode, only flush a written page once it had no writes
+    /// for that many milliseconds. Reduces flushes when the guest does many
+    /// small writes to the same page. 0 disables it
+    #[arg(long, default_value_t = 0)]
+    write_combine_ms: u64,
+
+    /// verify the crc of each page before it's served to a read, a page
+    /// with a bad crc fails the read with EIO
+    #[arg(long)]
+    verify_reads: bool,
+
+    /// on scrub, load pages with a bad crc again from the store instead of
+    /// dropping them from the cache. Only useful if the store is redundant
+    #[arg(long)]
+    scrub_repair: bool,
+
+    /// reject all writes and never write to the stores, to serve a
+    /// snapshot of the stores safely. The cache should be clean
+    #[arg(long)]
+    read_only: bool,
+
+    /// once the device had no io for that many milliseconds, everything
+    /// is flushed to the store so an idle device is fully durable.
+    /// 0 disables it
+    #[arg(long, default_value_t = 0)]
+    idle_flush_ms: u64,
+
+    /// a written page is evicted to the store (and the store flushed) at
+    /// most that many seconds after it was first written, even if the
+    /// device is never idle. This caps the data lost on a crash of the
+    /// cache disk. 0 disables it
+    #[arg(long, default_value_t = 0)]
+    max_dirty_age: u64,
+
+    /// give up on writing the cache back to the store on shutdown after that
+    /// many seconds. Pages that are not written yet are kept in the cache
+    /// and written on next start. 0 waits forever
+    #[arg(long, default_value_t = 0)]
+    shutdown_timeout: u64,
+
+    /// on a transient error while serving the local nbd device (for example
+    /// a broken socket) attach the device again, up to that many times in a
+    /// row with an increasing backoff. The cache is kept between attempts.
+    /// 0 exits on the first error
+    #[arg(long, default_value_t = 0)]
+    reconnect: u32,
+
     /// listen address for metrics. metrics will be available at /metrics
     #[arg(short, long, default_value_t = SocketAddr::from(([127, 0, 0, 1], 9000)))]
     metrics: SocketAddr,
@@ -72,54 +256,214 @@ struct Args {
     #[arg(long)]
     disable_metrics: bool,
 
+    /// prefix of all metric names, useful to tell apart the metrics of
+    /// different daemons on the same host
+    #[arg(long, default_value = qbd::metrics::DEFAULT_PREFIX)]
+    metrics_prefix: String,
+
+    /// build the full device (open stores, validate sizes and create
+    /// the cache) then exit without serving it
+    #[arg(long)]
+    dry_run: bool,
+
     /// enable debugging logs
     #[clap(short, long, action=ArgAction::Count)]
     debug: u8,
 }
 
-async fn app(args: Args) -> anyhow::Result<()> {
-    let cache_size = args.cache_size.0;
-    let page_size = args.page_size.0;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// create (or validate) all stores then exit without serving them.
+    /// Store files are fully allocated so the space is reserved up front
+    Provision(ProvisionArgs),
+    /// check the integrity of all pages of existing stores then exit,
+    /// fails if any page is corrupted
+    Verify(VerifyArgs),
+    /// check the crc of all clean pages of existing cache files then exit,
+    /// prints the addresses of bad pages and fails if there are any. The
+    /// cache must not be in use
+    Scrub(ScrubArgs),
+}
 
-    if cache_size.as_u64() % page_size.as_u64() != 0 {
-        anyhow::bail!("cache-size must be multiple of page-size");
+#[derive(clap::Args, Debug)]
+struct ProvisionArgs {
+    /// url to backend store, same as the --store of the daemon
+    #[arg(long, required = true)]
+    store: Vec<url::Url>,
+
+    /// page size the stores will be served with
+    #[arg(long, default_value_t=BSWrapper(bytesize::ByteSize::kib(256)))]
+    page_size: BSWrapper,
+
+    /// disable copy on write on store files
+    #[arg(long)]
+    nocow: bool,
+
+    /// checksum kept for each page of newly created store files, defaults
+    /// to crc64. Existing store files must match it if it is set
+    #[arg(long)]
+    checksum: Option<Checksum>,
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    /// url to backend store, same as the --store of the daemon
+    #[arg(long, required = true)]
+    store: Vec<url::Url>,
+
+    /// page size the stores are served with
+    #[arg(long, default_value_t=BSWrapper(bytesize::ByteSize::kib(256)))]
+    page_size: BSWrapper,
+}
+
+#[derive(clap::Args, Debug)]
+struct ScrubArgs {
+    /// path to the cache file, same as the --cache of the daemon
+    #[arg(short, long, required = true)]
+    cache: Vec<PathBuf>,
+
+    /// cache size the cache files were created with
+    #[arg(long, default_value_t=BSWrapper(bytesize::ByteSize::gib(10)))]
+    cache_size: BSWrapper,
+
+    /// page size the cache files were created with
+    #[arg(long, default_value_t=BSWrapper(bytesize::ByteSize::kib(256)))]
+    page_size: BSWrapper,
+}
+
+async fn provision(args: ProvisionArgs) -> anyhow::Result<()> {
+    let mut options = MapOptions::default().nocow(args.nocow);
+    if let Some(checksum) = args.checksum {
+        options = options.checksum(checksum);
     }
 
-    // todo: probably move building of a store from url
-    // somewhere else
-    let mut stores = vec![];
-    for u in &args.store {
-        if u.scheme() != "file" {
-            anyhow::bail!("only store type `file` is supported");
+    let store = qbd::config::provision(&args.store, args.page_size.0, options).await?;
+    println!(
+        "provisioned {}: size: {}, page-size: {}",
+        store.describe(),
+        store.size().to_string_as(true),
+        args.page_size.0.to_string_as(true)
+    );
+
+    Ok(())
+}
+
+async fn verify(args: VerifyArgs) -> anyhow::Result<()> {
+    let (store, report) =
+        qbd::config::verify(&args.store, args.page_size.0, MapOptions::default()).await?;
+    println!(
+        "verified {}: good: {}, corrupt: {}, unrecoverable: {}",
+        store.describe(),
+        report.good,
+        report.corrupt,
+        report.unrecoverable
+    );
+
+    if report.corrupt + report.unrecoverable > 0 {
+        anyhow::bail!("store has corrupted pages");
+    }
+
+    Ok(())
+}
+
+fn scrub(args: ScrubArgs) -> anyhow::Result<()> {
+    let caches = qbd::config::scrub(&args.cache, args.cache_size.0, args.page_size.0)?;
+    let mut bad = 0;
+    for (path, addresses) in caches {
+        for address in &addresses {
+            println!("{}: bad page at {address}", path.display());
         }
+        bad += addresses.len();
+    }
 
-        let size = u.query_pairs().find(|(key, _)| key == "size");
-        let size = match size {
-            Some((_, size)) => ByteSize::from_str(&size)
-                .map_err(|e| anyhow::anyhow!("failed to parse store size: {e}"))?,
-            None => anyhow::bail!("size param is required in store url"),
-        };
+    if bad > 0 {
+        anyhow::bail!("cache has {bad} corrupted pages");
+    }
 
-        stores.push(
-            FileStore::new(u.path(), size, page_size)
-                .with_context(|| format!("failed to create store {u}"))?,
-        );
+    Ok(())
+}
+
+async fn app(args: Args) -> anyhow::Result<()> {
+    match args.command {
+        Some(Command::Provision(args)) => return provision(args).await,
+        Some(Command::Verify(args)) => return verify(args).await,
+        Some(Command::Scrub(args)) => return scrub(args),
+        None => {}
     }
 
-    let store = Policy::strip(stores)?;
+    // must be set before any metric is registered
+    qbd::metrics::set_prefix(&args.metrics_prefix)?;
 
-    let disk_size = store.size();
-    log::info!(
-        "size: {} cache-size: {}, page-size: {}",
-        disk_size.to_string_as(true),
-        cache_size.to_string_as(true),
-        page_size.to_string_as(true)
-    );
+    // a device served over tcp is not attached to a local nbd device
+    let nbd = match args.listen {
+        Some(_) => None,
+        None => Some(nbd_device(&args)?),
+    };
+    let page_size = args.page_size.0;
+    let nbd_bs = ByteSize::kib(4);
+    let max_request = args.max_request.0;
+    if max_request.as_u64() < nbd_bs.as_u64() {
+        anyhow::bail!(
+            "max-request can't be smaller than device block size {}",
+            nbd_bs.to_string_as(true)
+        );
+    }
 
-    let cache = cache::Cache::new(store, args.cache, cache_size, page_size)
-        .context("failed to create cache")?;
+    let mut map_options = MapOptions::default()
+        .sparse(args.sparse)
+        .nocow(args.nocow)
+        .huge_pages(args.huge_pages)
+        .prefault(args.prefault)
+        .migrate(args.migrate);
+    if let Some(checksum) = args.checksum {
+        map_options = map_options.checksum(checksum);
+    }
 
-    let device = device::Device::new(cache);
+    let mut config = Config::new(args.store.clone(), args.cache.clone());
+    config.cache_size = args.cache_size.0;
+    config.page_size = page_size;
+    config.map_options = map_options;
+    config.flush_mode = args.flush_mode;
+    config.max_request = Some(max_request.as_u64() as usize);
+    config.evict_batch = args.evict_batch;
+    config.warm_batch = args.warm_batch;
+    config.read_ahead = args.read_ahead;
+    config.eviction = args.eviction;
+    config.verify_reads = args.verify_reads;
+    config.scrub_repair = args.scrub_repair;
+    config.read_only = args.read_only;
+    if args.write_combine_ms > 0 {
+        config.write_combine = Some(Duration::from_millis(args.write_combine_ms));
+    }
+    if args.idle_flush_ms > 0 {
+        config.idle_flush = Some(Duration::from_millis(args.idle_flush_ms));
+    }
+    if args.max_dirty_age > 0 {
+        config.max_dirty_age = Some(Duration::from_secs(args.max_dirty_age));
+    }
+    if args.shutdown_timeout > 0 {
+        config.shutdown_timeout = Some(Duration::from_secs(args.shutdown_timeout));
+    }
+
+    let device = qbd::open(config).await?;
+
+    // the nbd size must come from the device itself so both always agree
+    // on where the device ends
+    let blocks = device
+        .blocks(nbd_bs.as_u64())
+        .context("invalid device size")?;
+
+    if args.dry_run {
+        println!(
+            "configuration is valid: size: {} ({blocks} blocks of {}), cache-size: {} in {} file(s), page-size: {}",
+            device.size().to_string_as(true),
+            nbd_bs.to_string_as(true),
+            args.cache_size.0.to_string_as(true),
+            args.cache.len(),
+            page_size.to_string_as(true),
+        );
+        return Ok(());
+    }
 
     let registry = Arc::new(prometheus::default_registry().clone());
 
@@ -133,7 +477,12 @@ async fn app(args: Args) -> anyhow::Result<()> {
 
     let (ctl, recv) = channel(1);
 
-    handle_signals(ctl.clone()).context("handling hangup signals")?;
+    // once set, no more evict notifies are sent so the shutdown (and the
+    // final sync of the cache that comes with it) is the last control
+    // message the device handles
+    let (stop, mut stopped) = watch::channel(false);
+    let mut stopping = stopped.clone();
+    handle_signals(ctl.clone(), stop).context("handling hangup signals")?;
 
     tokio::spawn(async move {
         // this keep sending control jobs to the device.
@@ -142,29 +491,276 @@ async fn app(args: Args) -> anyhow::Result<()> {
         // ideal for that evict_duration
         let msg = DeviceControl::evict(EVICT_DURATION);
         loop {
-            if ctl.send(Control::Notify(msg)).await.is_err() {
+            // don't hold the borrow across the send
+            let stop = *stopped.borrow();
+            if stop || ctl.send(Control::Notify(msg)).await.is_err() {
                 break;
             }
-            tokio::time::sleep(EVICT_DURATION).await;
+
+            tokio::select! {
+                _ = stopped.changed() => {},
+                _ = tokio::time::sleep(EVICT_DURATION) => {},
+            }
         }
     });
 
-    let nbd_bs = ByteSize::kib(4);
-    nbd_async::serve_local_nbd(
-        args.nbd,
-        nbd_bs.0 as u32,
-        disk_size.0 / nbd_bs.0,
-        false,
-        device,
-        ReceiverStream::new(recv),
-    )
-    .await?;
+    if let Some(listen) = args.listen {
+        let listener = tokio::net::TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("failed to listen on {listen}"))?;
+
+        // pages are the natural unit of the device, anything smaller is
+        // a read-modify-write of the page
+        let export = server::Export::new(&args.export_name, device.size())
+            .with_block_size(
+                nbd_bs.as_u64() as u32,
+                page_size.as_u64().min(u32::MAX as u64) as u32,
+                max_request.as_u64().min(u32::MAX as u64) as u32,
+            )
+            .with_read_only(args.read_only);
+
+        log::info!("serving export '{}' on {listen}", args.export_name);
+        let result = server::serve(listener, export, device, ReceiverStream::new(recv)).await;
+        log::info!("shutting down");
+
+        result?;
+        return Ok(());
+    }
+
+    let nbd = nbd.context("nbd device is required")?;
+
+    // both are handed again to every attempt of serving the device
+    let device = Shared(Arc::new(tokio::sync::Mutex::new(device)));
+    let recv = Arc::new(std::sync::Mutex::new(recv));
+
+    let mut attempt = 0;
+    let result = loop {
+        // the kernel only learns about the device after it's attached
+        // so the queue limit is set in the background
+        tokio::spawn(limit_request_size(nbd.clone(), max_request));
+
+        let started = Instant::now();
+        let result = nbd_async::serve_local_nbd(
+            nbd.clone(),
+            nbd_bs.0 as u32,
+            blocks,
+            args.read_only,
+            device.clone(),
+            Controls(Arc::clone(&recv)),
+        )
+        .await
+        .map_err(anyhow::Error::from);
+
+        let err = match result {
+            Ok(_) => break Ok(()),
+            Err(err) => err,
+        };
+
+        // a device that was served fine for a while starts over
+        if started.elapsed() >= RECONNECT_RESET {
+            attempt = 0;
+        }
+
+        if *stopping.borrow() || attempt >= args.reconnect || is_fatal(&err) {
+            break Err(err);
+        }
+
+        attempt += 1;
+        let backoff = reconnect_backoff(attempt);
+        log::warn!(
+            "serving device failed: {err:#}, attaching again in {backoff:?} (attempt {attempt}/{})",
+            args.reconnect
+        );
+
+        // the broken socket must be cleared before the device is attached again
+        detach(&nbd);
+        tokio::select! {
+            _ = stopping.changed() => break Err(err),
+            _ = tokio::time::sleep(backoff) => {},
+        }
+    };
 
     log::info!("shutting down");
+    // this runs after both a clean shutdown (signal) or a serve error
+    // so the device is always released
+    detach(&nbd);
+
+    result?;
     Ok(())
 }
 
-fn handle_signals(ctr: Sender<Control<DeviceControl>>) -> Result<()> {
+/// device shared by all attempts of serving it (see --reconnect) so the
+/// cache survives a failed attempt
+#[derive(Clone)]
+struct Shared(Arc<tokio::sync::Mutex<Device<DeviceStore>>>);
+
+#[async_trait::async_trait(?Send)]
+impl BlockDevice<DeviceControl> for Shared {
+    async fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.0.lock().await.read(offset, buf).await
+    }
+
+    async fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.0.lock().await.write(offset, buf).await
+    }
+
+    async fn trim(&mut self, offset: u64, len: usize) -> io::Result<()> {
+        self.0.lock().await.trim(offset, len).await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().await.flush().await
+    }
+
+    async fn control(&mut self, control: &Control<DeviceControl>) -> io::Result<()> {
+        self.0.lock().await.control(control).await
+    }
+}
+
+/// control stream of a single attempt, all attempts receive from the same
+/// channel so evict ticks and signals keep working after attaching again
+struct Controls(Arc<std::sync::Mutex<Receiver<Control<DeviceControl>>>>);
+
+impl Stream for Controls {
+    type Item = Control<DeviceControl>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.0.lock().unwrap().poll_recv(cx)
+    }
+}
+
+/// errors that will happen again on every attempt, like a missing device
+/// or missing permissions. Everything else is worth another attempt
+fn is_fatal(err: &anyhow::Error) -> bool {
+    let Some(err) = err.chain().find_map(|e| e.downcast_ref::<io::Error>()) else {
+        return true;
+    };
+
+    matches!(
+        err.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::InvalidInput
+            | io::ErrorKind::Unsupported
+    )
+}
+
+/// backoff before the given attempt (starts at 1), doubles up to 30 seconds
+fn reconnect_backoff(attempt: u32) -> Duration {
+    let backoff = Duration::from_secs(1) * 2u32.saturating_pow(attempt.saturating_sub(1));
+    backoff.min(RECONNECT_MAX_BACKOFF)
+}
+
+/// returns path to the nbd device to attach to.
+fn nbd_device(args: &Args) -> anyhow::Result<PathBuf> {
+    if let Some(nbd) = &args.nbd {
+        return Ok(nbd.clone());
+    }
+
+    let fd = match args.nbd_fd.or_else(listen_fd) {
+        Some(fd) => fd,
+        None => anyhow::bail!("nbd device is required, use --nbd or --nbd-fd"),
+    };
+
+    // nbd-async can only attach to a device by path, so we use the /proc
+    // entry of the fd which opens the same device
+    let path = PathBuf::from(format!("/proc/self/fd/{fd}"));
+    let meta = std::fs::metadata(&path).with_context(|| format!("invalid nbd fd {fd}"))?;
+    if !meta.file_type().is_block_device() {
+        anyhow::bail!("fd {fd} is not an nbd device, only local nbd devices are supported");
+    }
+
+    Ok(path)
+}
+
+/// nbd in local mode doesn't negotiate NBD_INFO_BLOCK_SIZE with the kernel,
+/// instead we set the max request size directly on the device queue so the
+/// kernel splits bigger requests. This is best effort, the device still
+/// rejects requests that are too big if this fails. There is no way to
+/// set the preferred size of a local device, the kernel only knows about
+/// the block size (nbd_bs).
+async fn limit_request_size(nbd: PathBuf, max: ByteSize) {
+    let name = match std::fs::canonicalize(&nbd) {
+        Ok(path) => path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()),
+        Err(_) => None,
+    };
+
+    let Some(name) = name else {
+        log::warn!("failed to find device name of {}", nbd.display());
+        return;
+    };
+
+    // the driver sets up the queue of the device once it's connected (the
+    // pid node shows up then), a limit written before that can be reset
+    let pid = format!("/sys/block/{name}/pid");
+    let attached = tokio::time::timeout(ATTACH_TIMEOUT, async {
+        while tokio::fs::metadata(&pid).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+
+    if attached.is_err() {
+        log::warn!(
+            "{} is not attached after {ATTACH_TIMEOUT:?}, max request size is not set",
+            nbd.display()
+        );
+        return;
+    }
+
+    let path = format!("/sys/block/{name}/queue/max_sectors_kb");
+    let kb = (max.as_u64() / 1024).to_string();
+    match tokio::fs::write(&path, kb).await {
+        Ok(_) => log::debug!("max request size set to {}", max.to_string_as(true)),
+        Err(err) => log::warn!("failed to set max request size on {path}: {err}"),
+    }
+}
+
+/// returns the fd passed by systemd socket activation if any
+fn listen_fd() -> Option<RawFd> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+
+    let fds: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if fds == 0 {
+        return None;
+    }
+
+    if fds > 1 {
+        log::warn!("got {fds} fds from systemd, using the first one");
+    }
+
+    Some(SD_LISTEN_FDS_START)
+}
+
+/// explicitly disconnect the nbd device and clear its socket so the device
+/// is released and not left busy for the next start
+fn detach(nbd: &Path) {
+    let file = match std::fs::OpenOptions::new().read(true).write(true).open(nbd) {
+        Ok(file) => file,
+        Err(err) => {
+            log::error!("failed to open device {} to detach: {err}", nbd.display());
+            return;
+        }
+    };
+
+    // disconnect fails if the kernel already disconnected the device
+    // which is fine, we still clear the socket after.
+    if let Err(err) = unsafe { nbd_disconnect(file.as_raw_fd()) } {
+        log::debug!("disconnect device {}: {err}", nbd.display());
+    }
+
+    match unsafe { nbd_clear_sock(file.as_raw_fd()) } {
+        Ok(_) => log::info!("device {} detached", nbd.display()),
+        Err(err) => log::error!("failed to detach device {}: {err}", nbd.display()),
+    }
+}
+
+fn handle_signals(ctr: Sender<Control<DeviceControl>>, stop: watch::Sender<bool>) -> Result<()> {
     use tokio::signal::unix::{signal, SignalKind};
 
     // it's stupid we can't have one channel for all signals
@@ -172,6 +768,25 @@ fn handle_signals(ctr: Sender<Control<DeviceControl>>) -> Result<()> {
     let mut iu = signal(SignalKind::interrupt())?;
     let mut te = signal(SignalKind::terminate())?;
 
+    // usr1 makes eviction aggressive (on every tick) for bulk writes,
+    // usr2 goes back to evicting only when the device is idle
+    let mut u1 = signal(SignalKind::user_defined1())?;
+    let mut u2 = signal(SignalKind::user_defined2())?;
+    let tuner = ctr.clone();
+    tokio::spawn(async move {
+        loop {
+            let threshold = tokio::select! {
+                _ = u1.recv() => Duration::ZERO,
+                _ = u2.recv() => EVICT_DURATION,
+            };
+
+            let msg = DeviceControl::SetEvictThreshold(threshold);
+            if tuner.send(Control::Notify(msg)).await.is_err() {
+                break;
+            }
+        }
+    });
+
     tokio::spawn(async move {
         tokio::select! {
             _ = hu.recv() => {},
@@ -179,6 +794,7 @@ fn handle_signals(ctr: Sender<Control<DeviceControl>>) -> Result<()> {
             _ = te.recv() => {},
         }
 
+        let _ = stop.send(true);
         let _ = ctr.send(Control::Shutdown).await;
     });
 
@@ -1,7 +1,27 @@
+/// Header is a u64 where the lower 32 bits holds the page id and bits
+/// 32 to 39 are the flags. Since the page id is a u32 the device can only
+/// address `MAX_PAGE_COUNT` pages, which means the max size of the device
+/// is `MAX_PAGE_COUNT * page_size` (16TiB at 4KiB pages, 1024TiB at 256KiB
+/// pages, and 4096TiB at 1MiB pages).
+///
+/// Maps with wide ids (see [`super::MapOptions::wide_ids`]) also keep the
+/// high 16 bits of a 48 bits page id in bits 40 to 55, so a header can
+/// address `MAX_WIDE_PAGE_COUNT` pages. These bits are always zero in
+/// other maps, so both layouts read the same ids from them.
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Header(u64);
 
 const ID_MASK: u64 = 0x00000000ffffffff;
+const HIGH_ID_MASK: u64 = 0x00ffff0000000000;
+// the high id bits are stored 8 bits above their place in the id,
+// right after the flags
+const HIGH_ID_SHIFT: u32 = 8;
+
+/// max number of pages that can be addressed by a header
+pub const MAX_PAGE_COUNT: u64 = ID_MASK + 1;
+
+/// max number of pages that can be addressed by a header with a wide id
+pub const MAX_WIDE_PAGE_COUNT: u64 = 1 << 48;
 
 /// Possible header flags
 #[repr(u64)]
@@ -16,6 +36,13 @@ pub enum Flags {
     // from original form. And usually used later by the evict mechanism to see
     // if the evicted block should be committed to remote storage or not
     Dirty = 0b0000_0010 << 32,
+    // Set on a slot once a page in it had a bad crc. The flag stays when
+    // the slot is reused so a second bad crc at the same slot is detected,
+    // it's only cleared when the page is repaired from the store
+    Corrupted = 0b0000_0100 << 32,
+    // A slot that had a bad crc more than once (most likely a bad sector)
+    // and is never used again
+    Quarantined = 0b0000_1000 << 32,
 }
 
 impl Header {
@@ -30,7 +57,20 @@ impl Header {
 
     /// set page id stored in that header
     pub fn set_page(&mut self, id: u32) -> &mut Self {
-        self.0 = (self.0 & !ID_MASK) | (id as u64 & ID_MASK);
+        self.set_page_id(id as u64)
+    }
+
+    /// gets the full (wide) page id, this is the same as [`Header::page`]
+    /// unless the id is bigger than a u32
+    pub fn page_id(&self) -> u64 {
+        (self.0 & ID_MASK) | ((self.0 & HIGH_ID_MASK) >> HIGH_ID_SHIFT)
+    }
+
+    /// set a wide page id, only the lower 48 bits of id are kept. This
+    /// must only be used on maps with wide ids
+    pub fn set_page_id(&mut self, id: u64) -> &mut Self {
+        let id = (id & ID_MASK) | ((id << HIGH_ID_SHIFT) & HIGH_ID_MASK);
+        self.0 = (self.0 & !(ID_MASK | HIGH_ID_MASK)) | id;
         self
     }
 
@@ -89,4 +129,23 @@ mod test {
         assert_eq!(true, header.flag(Flags::Occupied));
         assert_eq!(8, header.page());
     }
+
+    #[test]
+    fn wide_id() {
+        let mut header = Header::new(20);
+        header.set(Flags::Dirty, true);
+        assert_eq!(20, header.page_id());
+
+        let id = MAX_WIDE_PAGE_COUNT - 1;
+        header.set_page_id(id).set(Flags::Occupied, true);
+        assert_eq!(id, header.page_id());
+        assert_eq!(true, header.flag(Flags::Dirty));
+        assert_eq!(true, header.flag(Flags::Occupied));
+        assert_eq!(false, header.flag(Flags::Quarantined));
+
+        // a short id clears the high bits
+        header.set_page(8);
+        assert_eq!(8, header.page_id());
+        assert_eq!(true, header.flag(Flags::Occupied));
+    }
 }
@@ -0,0 +1,65 @@
+use memmap2::{Mmap, MmapMut};
+use std::io::Result;
+use std::ops::{Deref, DerefMut};
+
+/// Mapping is either a writable or a read-only memory map of
+/// a file. Read-only mappings are used for files we are not allowed
+/// to modify, any attempt to write to them panics so it's up to the
+/// user of the map to never hand out mutable pages of a read-only map.
+pub enum Mapping {
+    ReadWrite(MmapMut),
+    ReadOnly(Mmap),
+}
+
+impl Mapping {
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, Self::ReadOnly(_))
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        match self {
+            Self::ReadWrite(map) => map.flush(),
+            Self::ReadOnly(_) => Ok(()),
+        }
+    }
+
+    pub fn flush_async(&self) -> Result<()> {
+        match self {
+            Self::ReadWrite(map) => map.flush_async(),
+            Self::ReadOnly(_) => Ok(()),
+        }
+    }
+
+    pub fn flush_range(&self, offset: usize, len: usize) -> Result<()> {
+        match self {
+            Self::ReadWrite(map) => map.flush_range(offset, len),
+            Self::ReadOnly(_) => Ok(()),
+        }
+    }
+
+    pub fn flush_async_range(&self, offset: usize, len: usize) -> Result<()> {
+        match self {
+            Self::ReadWrite(map) => map.flush_async_range(offset, len),
+            Self::ReadOnly(_) => Ok(()),
+        }
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::ReadWrite(map) => map,
+            Self::ReadOnly(map) => map,
+        }
+    }
+}
+
+impl DerefMut for Mapping {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Self::ReadWrite(map) => map,
+            Self::ReadOnly(_) => panic!("write to a read-only map"),
+        }
+    }
+}
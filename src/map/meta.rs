@@ -1,57 +1,212 @@
 use binary_layout::prelude::*;
 
+use super::{Checksum, CRC, MAX_PAGE_COUNT, MAX_WIDE_PAGE_COUNT};
+
 const MAGIC: u32 = 0x617a6d79;
-pub const VERSION: u32 = 1;
+/// version of newly created maps.
+/// - v1 has no checksum field, all pages have a crc
+/// - v2 adds the checksum kind, the crc section is omitted if the map
+///   has no checksum
+/// - v3 adds the page count and a crc of the meta so a torn or truncated
+///   meta is detected
+pub const VERSION: u32 = 3;
+
+/// version of maps with wide page ids. It has the same meta as v3, the
+/// version only keeps older binaries (that would drop the high bits of
+/// the ids) from opening the map. See [`super::Header`]
+pub const WIDE_VERSION: u32 = 4;
+
+const CHECKSUM_NONE: u32 = 0;
+const CHECKSUM_CRC: u32 = 1;
+const CHECKSUM_CRC32C: u32 = 2;
 
 use crate::{Error, Result};
 
+define_layout!(meta_v1, BigEndian, {
+    magic: u32,
+    version: u32,
+    page_size: u64,
+    data_size: u64,
+});
+
+define_layout!(meta_v2, BigEndian, {
+    magic: u32,
+    version: u32,
+    page_size: u64,
+    data_size: u64,
+    checksum: u32,
+    reserved: u32,
+});
+
 define_layout!(meta, BigEndian, {
     magic: u32,
     version: u32,
     page_size: u64,
     data_size: u64,
+    checksum: u32,
+    reserved: u32,
+    page_count: u64,
+    // crc of all the fields above
+    crc: u64,
 });
 
+/// size of the v1 meta object
+pub const SIZE_V1: usize = 24;
+/// size of the v2 meta object
+pub const SIZE_V2: usize = 32;
 /// full size of the meta object
-pub const SIZE: usize = 24;
+pub const SIZE: usize = 48;
 
 /// Meta object
 pub struct Meta {
     pub version: u32,
     pub page_size: u64,
     pub data_size: u64,
+    pub checksum: Checksum,
 }
 
 impl Meta {
+    /// size of the meta on disk, this is where the header section starts
+    pub fn size(&self) -> usize {
+        match self.version {
+            1 => SIZE_V1,
+            2 => SIZE_V2,
+            _ => SIZE,
+        }
+    }
+
     pub fn write(&self, buf: &mut [u8]) -> Result<()> {
-        if buf.len() != meta::SIZE.unwrap() {
+        if buf.len() != self.size() {
             return Err(Error::InvalidMetaSize);
         }
 
-        let mut view = meta::View::new(buf);
+        if self.version == 1 {
+            // v1 has no place for the checksum
+            if self.checksum != Checksum::Crc64 {
+                return Err(Error::InvalidMetaVersion);
+            }
+
+            let mut view = meta_v1::View::new(buf);
+            view.magic_mut().write(MAGIC);
+            view.version_mut().write(self.version);
+            view.page_size_mut().write(self.page_size);
+            view.data_size_mut().write(self.data_size);
+            return Ok(());
+        }
+
+        let checksum = match self.checksum {
+            Checksum::None => CHECKSUM_NONE,
+            Checksum::Crc64 => CHECKSUM_CRC,
+            Checksum::Crc32c => CHECKSUM_CRC32C,
+        };
+
+        if self.version == 2 {
+            let mut view = meta_v2::View::new(buf);
+            view.magic_mut().write(MAGIC);
+            view.version_mut().write(self.version);
+            view.page_size_mut().write(self.page_size);
+            view.data_size_mut().write(self.data_size);
+            view.checksum_mut().write(checksum);
+            view.reserved_mut().write(0);
+            return Ok(());
+        }
+
+        if self.page_size == 0 {
+            return Err(Error::InvalidMetaPageSize);
+        }
+
+        let mut view = meta::View::new(&mut *buf);
         view.magic_mut().write(MAGIC);
         view.version_mut().write(self.version);
         view.page_size_mut().write(self.page_size);
         view.data_size_mut().write(self.data_size);
+        view.checksum_mut().write(checksum);
+        view.reserved_mut().write(0);
+        view.page_count_mut().write(self.data_size / self.page_size);
+        let crc = CRC.checksum(&buf[..SIZE - 8]);
+        meta::View::new(buf).crc_mut().write(crc);
 
         Ok(())
     }
 
+    /// true if the meta was written by an older version and can be
+    /// migrated to the current one
+    pub fn outdated(&self) -> bool {
+        self.version < VERSION
+    }
+
+    /// true if the headers of the map can hold wide page ids
+    pub fn wide_ids(&self) -> bool {
+        self.version >= WIDE_VERSION
+    }
+
+    /// max number of pages the headers of the map can address
+    pub fn max_page_count(&self) -> u64 {
+        match self.wide_ids() {
+            true => MAX_WIDE_PAGE_COUNT,
+            false => MAX_PAGE_COUNT,
+        }
+    }
+
+    /// load meta from buf, buf must hold at least the full meta of the
+    /// version it was written with. Older versions are loaded as is (see
+    /// [`Meta::outdated`]), a version newer than [`WIDE_VERSION`] fails with
+    /// [`Error::UnsupportedMetaVersion`] since its layout is unknown
     pub fn load(buf: &[u8]) -> Result<Self> {
-        if buf.len() != meta::SIZE.unwrap() {
+        if buf.len() < SIZE_V1 {
             return Err(Error::InvalidMetaSize);
         }
 
-        let view = meta::View::new(buf);
+        let view = meta_v1::View::new(&buf[..SIZE_V1]);
 
         if view.magic().read() != MAGIC {
             return Err(Error::InvalidMetaMagic);
         }
 
+        let version = view.version().read();
+        let page_size = view.page_size().read();
+        let data_size = view.data_size().read();
+        let checksum = match version {
+            1 => CHECKSUM_CRC,
+            2 => {
+                if buf.len() < SIZE_V2 {
+                    return Err(Error::InvalidMetaSize);
+                }
+
+                meta_v2::View::new(&buf[..SIZE_V2]).checksum().read()
+            }
+            3 | WIDE_VERSION => {
+                if buf.len() < SIZE {
+                    return Err(Error::InvalidMetaSize);
+                }
+
+                let view = meta::View::new(&buf[..SIZE]);
+                if view.crc().read() != CRC.checksum(&buf[..SIZE - 8]) {
+                    return Err(Error::InvalidMetaCrc);
+                }
+
+                if page_size == 0 || view.page_count().read() != data_size / page_size {
+                    return Err(Error::InvalidMetaDataSize);
+                }
+
+                view.checksum().read()
+            }
+            v if v > WIDE_VERSION => return Err(Error::UnsupportedMetaVersion(v)),
+            _ => return Err(Error::InvalidMetaVersion),
+        };
+
+        let checksum = match checksum {
+            CHECKSUM_NONE => Checksum::None,
+            CHECKSUM_CRC => Checksum::Crc64,
+            CHECKSUM_CRC32C => Checksum::Crc32c,
+            _ => return Err(Error::InvalidMetaVersion),
+        };
+
         Ok(Meta {
-            version: VERSION,
-            page_size: view.page_size().read(),
-            data_size: view.data_size().read(),
+            version,
+            page_size,
+            data_size,
+            checksum,
         })
     }
 }
@@ -63,5 +218,113 @@ mod test {
     #[test]
     fn size() {
         assert!(matches!(Some(SIZE), meta::SIZE));
+        assert!(matches!(Some(SIZE_V2), meta_v2::SIZE));
+        assert!(matches!(Some(SIZE_V1), meta_v1::SIZE));
+    }
+
+    #[test]
+    fn versions() {
+        let mut buf = [0; SIZE];
+        let m = Meta {
+            version: VERSION,
+            page_size: 1024,
+            data_size: 4096,
+            checksum: Checksum::None,
+        };
+        m.write(&mut buf).unwrap();
+
+        let loaded = Meta::load(&buf).unwrap();
+        assert_eq!(loaded.version, VERSION);
+        assert_eq!(loaded.checksum, Checksum::None);
+        assert_eq!(loaded.data_size, 4096);
+        // the checksum field is missing
+        assert!(Meta::load(&buf[..SIZE_V1]).is_err());
+        assert!(!loaded.wide_ids());
+        assert_eq!(loaded.max_page_count(), MAX_PAGE_COUNT);
+
+        // wide ids only change the version
+        let m = Meta {
+            version: WIDE_VERSION,
+            page_size: 1024,
+            data_size: 4096,
+            checksum: Checksum::Crc32c,
+        };
+        assert_eq!(m.size(), SIZE);
+        m.write(&mut buf).unwrap();
+
+        let loaded = Meta::load(&buf).unwrap();
+        assert_eq!(loaded.version, WIDE_VERSION);
+        assert_eq!(loaded.checksum, Checksum::Crc32c);
+        assert!(!loaded.outdated());
+        assert!(loaded.wide_ids());
+        assert_eq!(loaded.max_page_count(), MAX_WIDE_PAGE_COUNT);
+
+        let mut buf = [0; SIZE_V2];
+        let m = Meta {
+            version: 2,
+            page_size: 1024,
+            data_size: 4096,
+            checksum: Checksum::None,
+        };
+        m.write(&mut buf).unwrap();
+
+        let loaded = Meta::load(&buf).unwrap();
+        assert_eq!(loaded.version, 2);
+        assert_eq!(loaded.size(), SIZE_V2);
+        assert_eq!(loaded.checksum, Checksum::None);
+        assert!(loaded.outdated());
+
+        let mut buf = [0; SIZE_V1];
+        let m = Meta {
+            version: 1,
+            page_size: 1024,
+            data_size: 4096,
+            checksum: Checksum::Crc64,
+        };
+        m.write(&mut buf).unwrap();
+
+        let loaded = Meta::load(&buf).unwrap();
+        assert_eq!(loaded.version, 1);
+        assert_eq!(loaded.size(), SIZE_V1);
+        assert_eq!(loaded.checksum, Checksum::Crc64);
+        assert!(loaded.outdated());
+
+        // a future version can't be read, version 0 never existed
+        let mut view = meta_v1::View::new(&mut buf[..]);
+        view.version_mut().write(WIDE_VERSION + 1);
+        assert!(matches!(
+            Meta::load(&buf),
+            Err(Error::UnsupportedMetaVersion(v)) if v == WIDE_VERSION + 1
+        ));
+
+        let mut view = meta_v1::View::new(&mut buf[..]);
+        view.version_mut().write(0);
+        assert!(matches!(Meta::load(&buf), Err(Error::InvalidMetaVersion)));
+    }
+
+    #[test]
+    fn crc() {
+        let mut buf = [0; SIZE];
+        let m = Meta {
+            version: VERSION,
+            page_size: 1024,
+            data_size: 4096,
+            checksum: Checksum::Crc64,
+        };
+        m.write(&mut buf).unwrap();
+        assert_eq!(meta::View::new(&buf[..]).page_count().read(), 4);
+        assert!(Meta::load(&buf).is_ok());
+
+        // any changed field is caught, even if the meta still makes sense
+        let mut corrupted = buf;
+        meta::View::new(&mut corrupted[..])
+            .data_size_mut()
+            .write(8192);
+        assert!(matches!(Meta::load(&corrupted), Err(Error::InvalidMetaCrc)));
+
+        // so is a meta that was only partially written
+        let mut torn = buf;
+        torn[SIZE - 8..].fill(0);
+        assert!(matches!(Meta::load(&torn), Err(Error::InvalidMetaCrc)));
     }
 }
@@ -20,18 +20,39 @@
 //! pre-defined values you can set (flags, id)
 //! the value of the id is a u32 that is associated with that page. It is used to
 //! map this page from this address, to that id on the block device (nbd)
-use crate::{Error, Result};
+use crate::{config::Geometry, Error, Result};
 use bytesize::ByteSize;
-use memmap2::MmapMut;
-use std::io::{Error as IoError, ErrorKind};
-use std::{fs::OpenOptions, mem::size_of, ops::Range, os::fd::AsRawFd, path::Path};
+use memmap2::{Advice, Mmap, MmapMut, MmapOptions};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use std::io::{Error as IoError, ErrorKind, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    mem::{align_of, size_of},
+    ops::Range,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt},
+    },
+    path::Path,
+    str::FromStr,
+    time::Instant,
+};
 
 mod header;
-pub use header::{Flags, Header};
+pub use header::{Flags, Header, MAX_PAGE_COUNT, MAX_WIDE_PAGE_COUNT};
+mod mapping;
 mod meta;
+mod multi;
+pub use multi::MultiPageMap;
+
+use mapping::Mapping;
 
 pub const MAX_PAGE_SIZE: ByteSize = ByteSize::mb(5);
 pub const CRC: crc::Crc<u64> = crc::Crc::<u64>::new(&crc::CRC_64_GO_ISO);
+const CRC32C: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
 const FS_NOCOW_FL: i64 = 0x00800000;
 
 pub type Crc = u64;
@@ -40,7 +61,9 @@ pub struct Page<'a> {
     address: usize,
     header: *const Header,
     data: &'a [u8],
-    crc: Crc,
+    // None if the map has no checksum
+    crc: Option<Crc>,
+    checksum: Checksum,
 }
 
 impl<'a> Page<'a> {
@@ -54,20 +77,28 @@ impl<'a> Page<'a> {
         unsafe { &*self.header }
     }
 
-    /// verify if data and crc match
+    /// verify if data and crc match. Always true if the map has no checksum
     pub fn is_crc_ok(&self) -> bool {
-        self.crc == CRC.checksum(self.data())
+        self.crc
+            .map_or(true, |crc| crc == self.checksum.compute(self.data()))
     }
 
-    /// returns crc stored on the page
+    /// returns crc stored on the page (0 if the map has no checksum)
     pub fn crc(&self) -> Crc {
-        self.crc
+        self.crc.unwrap_or_default()
     }
 
     /// data stored on the page at address
     pub fn data(&self) -> &[u8] {
         self.data
     }
+
+    /// shift the page address by base, used when the map is a shard
+    /// of a bigger map
+    pub(crate) fn rebase(mut self, base: usize) -> Self {
+        self.address += base;
+        self
+    }
 }
 
 /// PageMut is a mut page
@@ -75,7 +106,8 @@ pub struct PageMut<'a> {
     address: usize,
     header: *mut Header,
     data: &'a mut [u8],
-    crc: *mut Crc,
+    crc: Option<*mut Crc>,
+    checksum: Checksum,
 }
 
 impl<'a> PageMut<'a> {
@@ -84,6 +116,12 @@ impl<'a> PageMut<'a> {
         self.address
     }
 
+    /// see [`Page::rebase`]
+    pub(crate) fn rebase(mut self, base: usize) -> Self {
+        self.address += base;
+        self
+    }
+
     /// return header associated with page at location
     pub fn header(&self) -> &Header {
         unsafe { &*self.header }
@@ -94,20 +132,24 @@ impl<'a> PageMut<'a> {
         unsafe { &mut *self.header }
     }
 
-    /// verify if data and crc match
+    /// verify if data and crc match. Always true if the map has no checksum
     pub fn is_crc_ok(&self) -> bool {
-        unsafe { *self.crc == CRC.checksum(self.data()) }
+        self.crc.map_or(true, |crc| unsafe {
+            *crc == self.checksum.compute(self.data())
+        })
     }
 
-    /// returns crc stored on the page
+    /// returns crc stored on the page (0 if the map has no checksum)
     pub fn crc(&self) -> Crc {
-        unsafe { *self.crc }
+        self.crc.map_or(0, |crc| unsafe { *crc })
     }
 
-    /// updates crc to match the data
+    /// updates crc to match the data, does nothing if the map has no checksum
     pub fn update_crc(&mut self) {
-        unsafe {
-            *self.crc = CRC.checksum(self.data());
+        if let Some(crc) = self.crc {
+            unsafe {
+                *crc = self.checksum.compute(self.data());
+            }
         }
     }
 
@@ -126,8 +168,174 @@ impl<'a> From<PageMut<'a>> for Page<'a> {
         Self {
             address: value.address,
             data: value.data,
-            crc: value.crc(),
+            crc: value.crc.map(|crc| unsafe { *crc }),
             header: value.header,
+            checksum: value.checksum,
+        }
+    }
+}
+
+/// Watermark tracks the range of page headers (and crc) that
+/// were handed out for modification since the last flush. This
+/// allows flushing only the part of the header and crc sections
+/// that could have changed instead of the full sections.
+///
+/// the range is [lo, hi[ and is empty if lo >= hi
+struct Watermark {
+    lo: AtomicUsize,
+    hi: AtomicUsize,
+}
+
+impl Default for Watermark {
+    fn default() -> Self {
+        Self {
+            lo: AtomicUsize::new(usize::MAX),
+            hi: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Watermark {
+    /// mark header at index as possibly modified
+    fn touch(&self, index: usize) {
+        self.lo.fetch_min(index, Ordering::Relaxed);
+        self.hi.fetch_max(index + 1, Ordering::Relaxed);
+    }
+
+    /// take the current range and reset the watermark
+    fn take(&self) -> Option<Range<usize>> {
+        let lo = self.lo.swap(usize::MAX, Ordering::Relaxed);
+        let hi = self.hi.swap(0, Ordering::Relaxed);
+
+        if lo >= hi {
+            return None;
+        }
+
+        Some(lo..hi)
+    }
+}
+
+/// MapOptions controls how the map file is created and opened
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MapOptions {
+    sparse: bool,
+    nocow: bool,
+    checksum: Option<Checksum>,
+    huge_pages: bool,
+    migrate: bool,
+    prefault: bool,
+    wide_ids: bool,
+}
+
+impl MapOptions {
+    /// if set, the file is not pre-allocated with fallocate but only
+    /// truncated to the full size. This creates a sparse file where disk
+    /// space is only allocated on write which is useful for thin-provisioned
+    /// setups, but a write to the map can then fail (SIGBUS) if the underlying
+    /// filesystem runs out of space.
+    pub fn sparse(mut self, on: bool) -> Self {
+        self.sparse = on;
+        self
+    }
+
+    /// if set, try to disable copy on write on the file. This only
+    /// makes sense on filesystems like btrfs, on other filesystems
+    /// the flag is not supported and is ignored.
+    pub fn nocow(mut self, on: bool) -> Self {
+        self.nocow = on;
+        self
+    }
+
+    /// checksum kept for pages of a new map file (crc64 if not set). An
+    /// existing file always keeps the checksum it was created with, if
+    /// this is set it must match or the file fails to open
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// ask the kernel to back the map with transparent huge pages, this
+    /// reduces tlb misses on huge maps. The map works the same if huge
+    /// pages are not supported (for file maps this depends on the
+    /// filesystem and the kernel).
+    pub fn huge_pages(mut self, on: bool) -> Self {
+        self.huge_pages = on;
+        self
+    }
+
+    /// fault in all pages of the map when it's opened so the first access
+    /// to a page doesn't pay for it. This makes opening a big map a lot
+    /// slower (the whole file is read) but gives smoother latency after
+    pub fn prefault(mut self, on: bool) -> Self {
+        self.prefault = on;
+        self
+    }
+
+    /// if set, an existing file of an older version is upgraded to the
+    /// current version on open (see [`PageMap::with_options`]). Otherwise
+    /// the file is used with the layout it was created with
+    pub fn migrate(mut self, on: bool) -> Self {
+        self.migrate = on;
+        self
+    }
+
+    /// if set, the headers of the map hold 48 bits page ids instead of 32
+    /// (meta version 4, see [`Header`]). A new file is created with wide
+    /// ids and an existing file of the current version is switched to
+    /// them on open, only its meta changes. Older qbd versions can't open
+    /// the file anymore. Files of older versions must be migrated first
+    pub fn wide_ids(mut self, on: bool) -> Self {
+        self.wide_ids = on;
+        self
+    }
+}
+
+/// Checksum is the kind of checksum kept for each page of the map
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// the crc64 of each page is kept in the crc section
+    #[default]
+    Crc64,
+    /// the crc32c of each page is kept in the crc section, it takes the
+    /// same space as crc64 but is cheaper to compute
+    Crc32c,
+    /// no checksum is kept, the file has no crc section and all
+    /// pages are always considered valid. Only makes sense if the
+    /// store already guarantees integrity
+    None,
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "crc64" => Ok(Self::Crc64),
+            "crc32c" => Ok(Self::Crc32c),
+            "none" => Ok(Self::None),
+            _ => Err(format!(
+                "invalid checksum '{s}', expected crc64, crc32c or none"
+            )),
+        }
+    }
+}
+
+impl Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Crc64 => f.write_str("crc64"),
+            Self::Crc32c => f.write_str("crc32c"),
+            Self::None => f.write_str("none"),
+        }
+    }
+}
+
+impl Checksum {
+    /// checksum of data, always 0 for none
+    pub fn compute(self, data: &[u8]) -> Crc {
+        match self {
+            Self::Crc64 => CRC.checksum(data),
+            Self::Crc32c => CRC32C.checksum(data) as Crc,
+            Self::None => 0,
         }
     }
 }
@@ -139,16 +347,174 @@ pub struct PageMap {
     header_rng: Range<usize>,
     crc_rng: Range<usize>,
     data_rng: Range<usize>,
-    map: MmapMut,
+    map: Mapping,
+    touched: Watermark,
+    file: File,
+    options: MapOptions,
+    version: u32,
+    checksum: Checksum,
 }
 
 impl PageMap {
     pub fn new<P: AsRef<Path>>(path: P, data_size: ByteSize, page_size: ByteSize) -> Result<Self> {
+        Self::with_options(path, data_size, page_size, MapOptions::default())
+    }
+
+    /// map of the size and page size of geometry
+    pub fn with_geometry<P: AsRef<Path>>(
+        path: P,
+        geometry: &Geometry,
+        options: MapOptions,
+    ) -> Result<Self> {
+        Self::with_options(path, geometry.disk_size, geometry.page_size, options)
+    }
+
+    pub fn with_options<P: AsRef<Path>>(
+        path: P,
+        data_size: ByteSize,
+        page_size: ByteSize,
+        options: MapOptions,
+    ) -> Result<Self> {
         // we need to have 3 segments in the file.
         // - header segment
-        // - crc segment
+        // - crc segment (unless there is no checksum)
         // - data segment
+        let (pc, ps) = Self::geometry(data_size, page_size)?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+
+        Self::lock(&file, path.as_ref(), FlockArg::LockExclusiveNonblock)?;
+
+        let mut file_size = file.metadata()?.len();
+
+        // the layout of an existing file depends on its meta
+        let m = if file_size == 0 {
+            meta::Meta {
+                version: match options.wide_ids {
+                    true => meta::WIDE_VERSION,
+                    false => meta::VERSION,
+                },
+                data_size: data_size.0,
+                page_size: page_size.0,
+                checksum: options.checksum.unwrap_or_default(),
+            }
+        } else {
+            let m = Self::read_meta(&file, file_size, data_size, page_size)?;
+            match options.checksum {
+                Some(checksum) if checksum != m.checksum => {
+                    return Err(Error::ChecksumMismatch(
+                        path.as_ref().into(),
+                        m.checksum,
+                        checksum,
+                    ));
+                }
+                _ => {}
+            }
+
+            let m = if options.migrate && m.outdated() {
+                let m = Self::migrate(&file, path.as_ref(), pc, ps, m, &options)?;
+                file_size = file.metadata()?.len();
+                m
+            } else {
+                m
+            };
+
+            if options.wide_ids && !m.wide_ids() {
+                Self::widen(&file, path.as_ref(), m)?
+            } else {
+                m
+            }
+        };
+
+        let (header_rng, crc_rng, data_rng) = Self::layout(pc, ps, &m);
+        let full_size = data_rng.end;
+
+        if file_size != 0 && file_size != full_size as u64 {
+            return Err(Error::SizeChanged(path.as_ref().into()));
+        }
+
+        if options.nocow {
+            let v = unsafe { ioctls::fs_ioc_setflags(file.as_raw_fd(), &FS_NOCOW_FL) };
+            if v != 0 {
+                log::debug!("failed to disable COW: {v}");
+            }
+        }
+
+        Self::allocate(&file, full_size, &options)?;
+
+        let mut map = Self::map_mut(&file, &options)?;
+        Self::advise(&map, path.as_ref(), &options);
+
+        if file_size == 0 {
+            // this is a new file. we need to set the meta
+            m.write(&mut map[0..m.size()])?;
+            map.flush_range(0, m.size())?;
+        }
+
+        Ok(PageMap {
+            pc,
+            ps,
+            header_rng,
+            crc_rng,
+            data_rng,
+            map: Mapping::ReadWrite(map),
+            touched: Watermark::default(),
+            file,
+            options,
+            version: m.version,
+            checksum: m.checksum,
+        })
+    }
+
+    /// open an existing map file read-only. The file is never modified, it's
+    /// not allocated and it is mapped read-only so it can be used over images
+    /// we are not allowed to change.
+    ///
+    /// NOTE: calling any of the mut methods (at_mut, grow, ...) on a read-only
+    /// map will panic or fail.
+    pub fn open_read_only<P: AsRef<Path>>(
+        path: P,
+        data_size: ByteSize,
+        page_size: ByteSize,
+    ) -> Result<Self> {
+        let (pc, ps) = Self::geometry(data_size, page_size)?;
+
+        let file = OpenOptions::new().read(true).open(&path)?;
 
+        // readers can share the file but not with a writer
+        Self::lock(&file, path.as_ref(), FlockArg::LockSharedNonblock)?;
+
+        let file_size = file.metadata()?.len();
+        let m = Self::read_meta(&file, file_size, data_size, page_size)?;
+        let (header_rng, crc_rng, data_rng) = Self::layout(pc, ps, &m);
+
+        if file_size != data_rng.end as u64 {
+            return Err(Error::SizeChanged(path.as_ref().into()));
+        }
+
+        let map = unsafe { Mmap::map(&file)? };
+
+        Ok(PageMap {
+            pc,
+            ps,
+            header_rng,
+            crc_rng,
+            data_rng,
+            map: Mapping::ReadOnly(map),
+            touched: Watermark::default(),
+            file,
+            options: MapOptions::default(),
+            version: m.version,
+            checksum: m.checksum,
+        })
+    }
+
+    /// validates data and page size and return (page count, page size)
+    fn geometry(data_size: ByteSize, page_size: ByteSize) -> Result<(usize, usize)> {
         let data_sec_size = data_size.as_u64() as usize;
         let ps = page_size.as_u64() as usize;
 
@@ -176,91 +542,346 @@ impl PageMap {
             return Err(Error::PageCountTooBig);
         }
 
-        let header_sec_size = pc * size_of::<Header>();
-        let crc_sec_size = pc * size_of::<Crc>();
+        Ok((pc, ps))
+    }
 
-        // the final size is the given data size + header + crc
-        let full_size = meta::SIZE + header_sec_size + crc_sec_size + data_sec_size;
+    /// reads and validates the meta of an existing map file
+    fn read_meta(
+        file: &File,
+        file_size: u64,
+        data_size: ByteSize,
+        page_size: ByteSize,
+    ) -> Result<meta::Meta> {
+        // older versions have a shorter meta
+        let mut buf = [0; meta::SIZE];
+        let len = buf.len().min(file_size as usize);
+        file.read_exact_at(&mut buf[..len], 0)?;
+
+        let m = meta::Meta::load(&buf[..len])?;
+
+        if m.page_size != page_size.0 {
+            return Err(Error::InvalidMetaPageSize);
+        }
 
-        let file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(&path)?;
+        if m.data_size != data_size.0 {
+            return Err(Error::InvalidMetaDataSize);
+        }
 
-        let file_size = file.metadata()?.len();
+        Ok(m)
+    }
 
-        if file_size != 0 && file_size != full_size as u64 {
-            return Err(Error::SizeChanged(path.as_ref().into()));
+    /// upgrades the file from the layout of meta m to the current version.
+    /// The old meta is copied to `{path}.meta.v{version}.bak` first. Newer
+    /// metas are bigger so all sections move toward the end of the file,
+    /// the data section moves first so it is not overwritten by the crc
+    /// section and so on. The meta is only updated once the sections are
+    /// flushed at their new place.
+    ///
+    /// WARNING: same as grow, this is not crash safe. If the process dies
+    /// while the sections are moved the file must be restored by hand
+    /// from the backup meta.
+    fn migrate(
+        file: &File,
+        path: &Path,
+        pc: usize,
+        ps: usize,
+        m: meta::Meta,
+        options: &MapOptions,
+    ) -> Result<meta::Meta> {
+        let mut old = vec![0; m.size()];
+        file.read_exact_at(&mut old, 0)?;
+
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".meta.v{}.bak", m.version));
+        let mut f = File::create(&backup)?;
+        f.write_all(&old)?;
+        f.sync_all()?;
+
+        let (old_header, old_crc, old_data) = Self::layout(pc, ps, &m);
+        if file.metadata()?.len() != old_data.end as u64 {
+            return Err(Error::SizeChanged(path.into()));
         }
 
-        unsafe {
-            let v = ioctls::fs_ioc_setflags(file.as_raw_fd(), &FS_NOCOW_FL);
-            if v != 0 {
-                log::error!("failed to disable COW: {v}");
-            }
+        let new = meta::Meta {
+            version: meta::VERSION,
+            page_size: m.page_size,
+            data_size: m.data_size,
+            checksum: m.checksum,
+        };
+        let (header, crc, data) = Self::layout(pc, ps, &new);
+
+        log::info!(
+            "migrating {} from version {} to {}",
+            path.display(),
+            m.version,
+            new.version
+        );
+
+        Self::allocate(file, data.end, options)?;
+        let mut map = unsafe { MmapMut::map_mut(file)? };
+        map.copy_within(old_data, data.start);
+        map.copy_within(old_crc, crc.start);
+        map.copy_within(old_header, header.start);
+        map.flush()?;
+
+        new.write(&mut map[0..new.size()])?;
+        map.flush_range(0, new.size())?;
+
+        Ok(new)
+    }
+
+    /// switches a map of the current version to wide ids. The headers of the
+    /// current version already have the high id bits cleared so only the
+    /// version in the meta changes. Older versions have to be migrated
+    /// first, they keep their ids as they are
+    fn widen(file: &File, path: &Path, m: meta::Meta) -> Result<meta::Meta> {
+        if m.version != meta::VERSION {
+            log::warn!(
+                "{} is version {}, it must be migrated before it can use wide page ids",
+                path.display(),
+                m.version
+            );
+            return Ok(m);
+        }
+
+        let new = meta::Meta {
+            version: meta::WIDE_VERSION,
+            ..m
+        };
+
+        log::info!("switching {} to wide page ids", path.display());
+        let mut buf = vec![0; new.size()];
+        new.write(&mut buf)?;
+        file.write_all_at(&buf, 0)?;
+        file.sync_data()?;
+
+        Ok(new)
+    }
+
+    /// computes the (header, crc, data) sections ranges for a map
+    /// of pc pages each of size ps. The end of the data section is
+    /// also the full size of the file. The crc section is empty if
+    /// the map has no checksum.
+    fn layout(pc: usize, ps: usize, m: &meta::Meta) -> (Range<usize>, Range<usize>, Range<usize>) {
+        Self::layout_at(m.size(), pc, ps, m.checksum)
+    }
+
+    /// layout where the header section starts right after meta_size bytes.
+    /// The header and crc sections are padded to the alignment of their
+    /// types so they can always be viewed as slices of Header and Crc.
+    /// All current meta sizes are already aligned so there is no padding
+    /// in existing files.
+    fn layout_at(
+        meta_size: usize,
+        pc: usize,
+        ps: usize,
+        checksum: Checksum,
+    ) -> (Range<usize>, Range<usize>, Range<usize>) {
+        let header_offset = meta_size.next_multiple_of(align_of::<Header>());
+        let crc_offset =
+            (header_offset + pc * size_of::<Header>()).next_multiple_of(align_of::<Crc>());
+        let crc_len = match checksum {
+            Checksum::Crc64 | Checksum::Crc32c => pc * size_of::<Crc>(),
+            Checksum::None => 0,
+        };
+        let data_offset = crc_offset + crc_len;
+
+        (
+            header_offset..crc_offset,
+            crc_offset..data_offset,
+            data_offset..data_offset + pc * ps,
+        )
+    }
+
+    /// take an advisory lock on the file so two processes never map the
+    /// same file. The lock is released when the file is closed (on drop)
+    fn lock(file: &File, path: &Path, arg: FlockArg) -> Result<()> {
+        match flock(file.as_raw_fd(), arg) {
+            Ok(_) => Ok(()),
+            Err(Errno::EWOULDBLOCK) => Err(Error::Locked(path.into())),
+            Err(err) => Err(IoError::from(err).into()),
+        }
+    }
+
+    /// maps the whole file, with prefault all pages are faulted in before
+    /// the map is returned
+    fn map_mut(file: &File, options: &MapOptions) -> Result<MmapMut> {
+        if !options.prefault {
+            return Ok(unsafe { MmapMut::map_mut(file)? });
+        }
+
+        let start = Instant::now();
+        let map = unsafe { MmapOptions::new().populate().map_mut(file)? };
+        log::info!(
+            "prefaulted {} of map in {:?}",
+            ByteSize(map.len() as u64).to_string_as(true),
+            start.elapsed()
+        );
+        Ok(map)
+    }
+
+    /// request huge pages for the map if enabled in options. It's a hint
+    /// only, so all we can do is log if it's going to work
+    fn advise(map: &MmapMut, path: &Path, options: &MapOptions) {
+        if !options.huge_pages {
+            return;
+        }
+
+        if let Err(err) = map.advise(Advice::HugePage) {
+            log::warn!("huge pages are not supported for {}: {err}", path.display());
+            return;
+        }
+
+        // the selected mode is in brackets, for example `always [madvise] never`
+        let mode = std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled")
+            .ok()
+            .and_then(|modes| {
+                let start = modes.find('[')?;
+                let end = modes.find(']')?;
+                modes.get(start + 1..end).map(String::from)
+            });
+
+        // the advice is only a hint, for a file mapping the filesystem has to
+        // support huge pages too (tmpfs with huge=, ...). What is really used
+        // shows as FilePmdMapped in /proc/<pid>/smaps
+        match mode.as_deref() {
+            Some("always") | Some("madvise") => log::info!(
+                "huge pages advised for {}, the kernel may still use normal pages",
+                path.display()
+            ),
+            Some(mode) => log::warn!(
+                "huge pages requested for {} but transparent huge pages are set to {mode}",
+                path.display()
+            ),
+            None => log::warn!(
+                "huge pages requested for {} but transparent huge pages are not available",
+                path.display()
+            ),
+        }
+    }
+
+    /// make sure the file is at least of size
+    fn allocate(file: &File, size: usize, options: &MapOptions) -> Result<()> {
+        if options.sparse {
+            // the file is only truncated to the full size, space is allocated
+            // by the filesystem on write
+            file.set_len(size as u64)?;
+            return Ok(());
         }
 
         use nix::fcntl::{fallocate, FallocateFlags};
         // we use fallocate to allocate entire map space on disk so we grantee write operations
         // won't fail
-        fallocate(
-            file.as_raw_fd(),
-            FallocateFlags::empty(),
-            0,
-            full_size as i64,
-        )
-        .map_err(|e| IoError::new(ErrorKind::Other, e))?;
+        fallocate(file.as_raw_fd(), FallocateFlags::empty(), 0, size as i64)
+            .map_err(IoError::other)?;
 
-        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(())
+    }
 
-        // validation or initializing meta section
-        if file_size == 0 {
-            // this is a new file. we need to set the meta
-            let m = meta::Meta {
-                version: meta::VERSION,
-                data_size: data_size.0,
-                page_size: page_size.0,
-            };
+    /// grow the map so it can hold data_size worth of pages. Existing pages
+    /// keep their addresses.
+    ///
+    /// Since the 3 sections are contiguous, growing the header and crc sections
+    /// shifts everything after them. The file is extended first, then the data
+    /// section is moved to its new offset (it moves the furthest so it has to
+    /// go first otherwise the crc section would overwrite it), then the crc
+    /// section is moved, and finally the new headers and crc entries are zeroed.
+    /// The meta is only updated after the new layout is flushed to disk.
+    ///
+    /// WARNING: this is not crash safe. If the process dies while the map is
+    /// being rewritten, the file will not open again (size does not match meta).
+    pub fn grow(&mut self, data_size: ByteSize) -> Result<()> {
+        let data_sec_size = data_size.as_u64() as usize;
+        if data_sec_size % self.ps != 0 {
+            return Err(Error::SizeNotMultipleOfPageSize);
+        }
 
-            m.write(&mut map[0..meta::SIZE])?;
-        } else {
-            // we need to validate meta then
-            let m = meta::Meta::load(&map[0..meta::SIZE])?;
-            if m.version != meta::VERSION {
-                return Err(Error::InvalidMetaVersion);
-            }
+        if self.map.is_read_only() {
+            return Err(IoError::from(ErrorKind::PermissionDenied).into());
+        }
 
-            if m.page_size != page_size.0 {
-                return Err(Error::InvalidMetaPageSize);
-            }
+        let pc = data_sec_size / self.ps;
+        if pc < self.pc {
+            return Err(Error::CannotShrink);
+        }
 
-            if m.data_size != data_size.0 {
-                return Err(Error::InvalidMetaDataSize);
-            }
+        if pc == self.pc {
+            return Ok(());
         }
 
-        let header_offset = meta::SIZE;
-        let crc_offset = header_offset + header_sec_size;
-        let data_offset = crc_offset + crc_sec_size;
+        if pc > u32::MAX as usize {
+            return Err(Error::PageCountTooBig);
+        }
 
-        Ok(PageMap {
-            pc,
-            ps,
-            header_rng: Range {
-                start: header_offset,
-                end: crc_offset,
-            },
-            crc_rng: Range {
-                start: crc_offset,
-                end: data_offset,
-            },
-            data_rng: Range {
-                start: data_offset,
-                end: full_size,
-            },
-            map,
-        })
+        // the file keeps its version and checksum
+        let m = meta::Meta {
+            version: self.version,
+            data_size: data_size.0,
+            page_size: self.ps as u64,
+            checksum: self.checksum,
+        };
+
+        let (header_rng, crc_rng, data_rng) = Self::layout(pc, self.ps, &m);
+
+        Self::allocate(&self.file, data_rng.end, &self.options)?;
+        let map = Self::map_mut(&self.file, &self.options)?;
+        if self.options.huge_pages {
+            // best effort, a failure is already logged on open
+            let _ = map.advise(Advice::HugePage);
+        }
+        self.map = Mapping::ReadWrite(map);
+
+        self.map.copy_within(self.data_rng.clone(), data_rng.start);
+        self.map.copy_within(self.crc_rng.clone(), crc_rng.start);
+
+        // new headers and crc must be cleared since they contain
+        // whatever was there before the move. The new data pages are
+        // beyond the old end of the file so they are already zeros
+        self.map[self.header_rng.end..header_rng.end].fill(0);
+        self.map[crc_rng.start + self.crc_rng.len()..crc_rng.end].fill(0);
+
+        self.map.flush()?;
+
+        m.write(&mut self.map[0..m.size()])?;
+        self.map.flush_range(0, m.size())?;
+
+        self.pc = pc;
+        self.header_rng = header_rng;
+        self.crc_rng = crc_rng;
+        self.data_rng = data_rng;
+
+        Ok(())
+    }
+
+    /// checksum kept for the pages of this map
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    /// on disk format version of the map file
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// true if the headers hold wide page ids, see [`MapOptions::wide_ids`]
+    pub fn wide_ids(&self) -> bool {
+        self.version >= meta::WIDE_VERSION
+    }
+
+    /// true if the map was opened with open_read_only
+    pub fn is_read_only(&self) -> bool {
+        self.map.is_read_only()
+    }
+
+    /// full size of the map file (meta, headers, crc and data)
+    pub fn file_size(&self) -> u64 {
+        self.data_rng.end as u64
+    }
+
+    /// actual disk space used by the map file. This is smaller than
+    /// file_size only if the file is sparse
+    pub fn allocated(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.blocks() * 512)
     }
 
     /// capacity of cache returns max number of pages
@@ -273,17 +894,20 @@ impl PageMap {
         self.ps
     }
 
+    // the mmap is page aligned and the layout aligns the sections so
+    // there is never a head or a tail
     fn header(&self) -> &[Header] {
         let (h, header, t) = unsafe { self.map[self.header_rng.clone()].align_to::<Header>() };
-        assert!(h.is_empty(), "h is not empty");
-        assert!(t.is_empty(), "t is not empty");
+        debug_assert!(
+            h.is_empty() && t.is_empty(),
+            "header section is not aligned"
+        );
         header
     }
 
     fn crc(&self) -> &[Crc] {
         let (h, crc, t) = unsafe { self.map[self.crc_rng.clone()].align_to::<Crc>() };
-        assert!(h.is_empty(), "h is not empty");
-        assert!(t.is_empty(), "t is not empty");
+        debug_assert!(h.is_empty() && t.is_empty(), "crc section is not aligned");
         crc
     }
 
@@ -293,15 +917,16 @@ impl PageMap {
 
     fn header_mut(&mut self) -> &mut [Header] {
         let (h, header, t) = unsafe { self.map[self.header_rng.clone()].align_to_mut::<Header>() };
-        assert!(h.is_empty(), "h is not empty");
-        assert!(t.is_empty(), "t is not empty");
+        debug_assert!(
+            h.is_empty() && t.is_empty(),
+            "header section is not aligned"
+        );
         header
     }
 
     fn crc_mut(&mut self) -> &mut [Crc] {
         let (h, crc, t) = unsafe { self.map[self.crc_rng.clone()].align_to_mut::<Crc>() };
-        assert!(h.is_empty(), "h is not empty");
-        assert!(t.is_empty(), "t is not empty");
+        debug_assert!(h.is_empty() && t.is_empty(), "crc section is not aligned");
         crc
     }
 
@@ -364,12 +989,13 @@ impl PageMap {
 
         let data = self.data_at(address);
         let header: *const Header = self.header_at(address);
-        let crc = self.crc_at(address);
+        let crc = self.has_crc().then(|| self.crc_at(address));
         Page {
             address,
             header,
             data,
             crc,
+            checksum: self.checksum,
         }
     }
 
@@ -379,15 +1005,136 @@ impl PageMap {
             panic!("index out of range");
         }
 
+        self.touched.touch(address);
+
         let header: *mut Header = self.header_mut_at(address);
-        let crc: *mut Crc = self.crc_mut_at(address);
+        let crc: Option<*mut Crc> = if self.has_crc() {
+            Some(self.crc_mut_at(address))
+        } else {
+            None
+        };
+        let checksum = self.checksum;
         let data = self.data_mut_at(address);
         PageMut {
             address,
             header,
             data,
             crc,
+            checksum,
+        }
+    }
+
+    #[inline]
+    fn has_crc(&self) -> bool {
+        self.checksum != Checksum::None
+    }
+
+    /// recompute the crc of the page at address from its current data.
+    /// returns true if the stored crc was stale and had to be updated, in
+    /// that case the crc is flushed to disk before returning.
+    pub fn recompute_page(&mut self, address: usize) -> Result<bool> {
+        if !self.update_crc_at(address) {
+            return Ok(false);
         }
+
+        self.flush_crc(address..address + 1)?;
+        Ok(true)
+    }
+
+    /// recompute the crc of all occupied pages from the current data. This
+    /// is only safe if the data is known to be good since a corrupted page
+    /// will then look valid. Returns the number of pages that had a stale crc.
+    pub fn recompute_crcs(&mut self) -> Result<usize> {
+        let mut count = 0;
+        for address in 0..self.pc {
+            if !self.header_at(address).flag(Flags::Occupied) {
+                continue;
+            }
+
+            if self.update_crc_at(address) {
+                count += 1;
+            }
+        }
+
+        if count != 0 {
+            self.flush_crc(0..self.pc)?;
+        }
+
+        Ok(count)
+    }
+
+    /// addresses of all occupied clean pages whose data doesn't match their
+    /// crc. Dirty pages are skipped since their crc is only updated once
+    /// they are evicted. Empty if the map has no checksum
+    pub fn scrub(&self) -> Vec<usize> {
+        if !self.has_crc() {
+            return vec![];
+        }
+
+        (0..self.pc)
+            .filter(|address| {
+                let header = self.header_at(*address);
+                header.flag(Flags::Occupied)
+                    && !header.flag(Flags::Dirty)
+                    && self.checksum.compute(self.data_at(*address)) != self.crc_at(*address)
+            })
+            .collect()
+    }
+
+    /// updates crc of page at address if it doesn't match the data. We don't
+    /// go through at_mut here so checking pages doesn't mark them as touched
+    fn update_crc_at(&mut self, address: usize) -> bool {
+        if address >= self.pc {
+            panic!("index out of range");
+        }
+
+        if !self.has_crc() {
+            return false;
+        }
+
+        let crc = self.checksum.compute(self.data_at(address));
+        if crc == self.crc_at(address) {
+            return false;
+        }
+
+        *self.crc_mut_at(address) = crc;
+        true
+    }
+
+    /// flush crc entries of pages in range and wait until they are on disk
+    fn flush_crc(&self, rng: Range<usize>) -> Result<()> {
+        if !self.has_crc() {
+            return Ok(());
+        }
+
+        self.map
+            .flush_range(
+                self.crc_rng.start + rng.start * size_of::<Crc>(),
+                rng.len() * size_of::<Crc>(),
+            )
+            .map_err(Error::from)
+    }
+
+    /// free the disk space of the data of the page at address, the data
+    /// reads as zeros afterwards. The header is not touched. Note that a
+    /// later write to that page needs the space again, so writing to a
+    /// punched page of a non sparse map can fail if the disk is full
+    pub fn punch(&self, address: usize) -> Result<()> {
+        if self.map.is_read_only() {
+            return Err(IoError::from(ErrorKind::PermissionDenied).into());
+        }
+
+        use nix::fcntl::{fallocate, FallocateFlags};
+        let (start, _) = self.data_block_range(address);
+        fallocate(
+            self.file.as_raw_fd(),
+            FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            (self.data_rng.start + start) as i64,
+            self.ps as i64,
+        )
+        .map_err(IoError::from)?;
+
+        Ok(())
     }
 
     /// flush_page flushes a page and wait for it until it is written to disk
@@ -395,13 +1142,46 @@ impl PageMap {
         self.flush_range(address, 1)
     }
 
+    /// flush only the headers and crc that were modified since last flush
+    fn flush_touched(&self) -> Result<()> {
+        let rng = match self.touched.take() {
+            Some(rng) => rng,
+            None => return Ok(()),
+        };
+
+        log::trace!("flushing headers [{}: {}]", rng.start, rng.len());
+        let result = self
+            .map
+            .flush_range(
+                self.header_rng.start + rng.start * size_of::<Header>(),
+                rng.len() * size_of::<Header>(),
+            )
+            .and_then(|_| {
+                if !self.has_crc() {
+                    return Ok(());
+                }
+
+                self.map.flush_range(
+                    self.crc_rng.start + rng.start * size_of::<Crc>(),
+                    rng.len() * size_of::<Crc>(),
+                )
+            });
+
+        if result.is_err() {
+            // keep the range so the next flush tries again
+            self.touched.touch(rng.start);
+            self.touched.touch(rng.end - 1);
+        }
+
+        result.map_err(Error::from)
+    }
+
     pub fn flush_range(&self, address: usize, count: usize) -> Result<()> {
         let (mut start, _) = self.data_block_range(address);
         start += self.data_rng.start;
         let len = self.ps * count;
 
-        // the header is also flushed but in async way
-        self.map.flush_range(0, self.crc_rng.end)?;
+        self.flush_touched()?;
 
         log::trace!("flushing page {address}/{count} [{start}: {len}]");
         self.map.flush_range(start, len).map_err(Error::from)
@@ -411,13 +1191,19 @@ impl PageMap {
         let (mut start, _) = self.data_block_range(address);
         start += self.data_rng.start;
         let len = self.ps * count;
-        // the header is also flushed but in async way
-        self.map.flush_range(0, self.crc_rng.end)?;
+
+        self.flush_touched()?;
 
         log::trace!("flushing page {address}/{count} [{start}: {len}]");
         self.map.flush_async_range(start, len).map_err(Error::from)
     }
 
+    /// flush the full map and wait until it is written to disk
+    pub fn flush(&self) -> Result<()> {
+        self.touched.take();
+        self.map.flush().map_err(Error::from)
+    }
+
     /// flush a cache to disk
     pub fn flush_async(&self) -> Result<()> {
         // self.map.flush_range(offset, len)
@@ -563,6 +1349,494 @@ mod test {
         assert!(page.data().iter().all(|b| *b == b'D'));
     }
 
+    #[test]
+    fn recompute_crcs() {
+        const PATH: &str = "/tmp/recompute.test";
+        let mut cache = PageMap::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        for address in 0..5 {
+            let mut page = cache.at_mut(address);
+            page.header_mut()
+                .set_page(address as u32)
+                .set(header::Flags::Occupied, true);
+            page.data_mut().fill(address as u8 + 1);
+            page.update_crc();
+        }
+
+        // nothing to repair
+        assert_eq!(cache.recompute_crcs().unwrap(), 0);
+
+        // corrupt the crc of 3 occupied pages and one free page
+        for address in [0, 2, 4, 7] {
+            *cache.crc_mut_at(address) = 0xdead;
+        }
+
+        assert!(!cache.at(2).is_crc_ok());
+
+        // free pages are not touched
+        assert_eq!(cache.recompute_crcs().unwrap(), 3);
+        assert!(cache.iter().take(5).all(|p| p.is_crc_ok()));
+        assert_eq!(cache.crc_at(7), 0xdead);
+
+        // single page repair
+        assert!(cache.recompute_page(7).unwrap());
+        assert!(cache.at(7).is_crc_ok());
+        assert!(!cache.recompute_page(7).unwrap());
+    }
+
+    #[test]
+    fn scrub() {
+        const PATH: &str = "/tmp/map.scrub.test";
+        let mut cache = PageMap::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        for address in 0..5 {
+            let mut page = cache.at_mut(address);
+            page.header_mut()
+                .set_page(address as u32)
+                .set(header::Flags::Occupied, true);
+            page.data_mut().fill(address as u8 + 1);
+            page.update_crc();
+        }
+
+        assert!(cache.scrub().is_empty());
+
+        // flip a byte without updating the crc, of a clean, a dirty and
+        // a free page. Only the clean one is reported
+        cache.at_mut(1).data_mut()[10] ^= 1;
+        cache.at_mut(3).data_mut()[10] ^= 1;
+        cache.at_mut(3).header_mut().set(header::Flags::Dirty, true);
+        cache.at_mut(7).data_mut()[10] ^= 1;
+
+        assert_eq!(cache.scrub(), vec![1]);
+    }
+
+    #[test]
+    fn read_only() {
+        const PATH: &str = "/tmp/read_only.test";
+        let _ = std::fs::remove_file(PATH);
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        // the file must exist
+        assert!(PageMap::open_read_only(PATH, ByteSize::kib(10), ByteSize::kib(1)).is_err());
+
+        let mut map = PageMap::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        let mut page = map.at_mut(3);
+        page.header_mut()
+            .set_page(3)
+            .set(header::Flags::Occupied, true);
+        page.data_mut().fill(b'R');
+        page.update_crc();
+        map.flush_range(3, 1).unwrap();
+        drop(map);
+
+        let mut map = PageMap::open_read_only(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert!(map.is_read_only());
+
+        let page = map.at(3);
+        assert!(page.header().flag(header::Flags::Occupied));
+        assert!(page.data().iter().all(|b| *b == b'R'));
+        assert!(page.is_crc_ok());
+
+        assert!(map.flush_range(3, 1).is_ok());
+        assert!(map.grow(ByteSize::kib(20)).is_err());
+
+        // geometry is still validated
+        assert!(PageMap::open_read_only(PATH, ByteSize::kib(20), ByteSize::kib(1)).is_err());
+    }
+
+    #[test]
+    fn no_checksum() {
+        const PATH: &str = "/tmp/no_checksum.test";
+        let _ = std::fs::remove_file(PATH);
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        let options = MapOptions::default().checksum(Checksum::None);
+        let mut map =
+            PageMap::with_options(PATH, ByteSize::kib(10), ByteSize::kib(1), options).unwrap();
+        assert_eq!(map.checksum(), Checksum::None);
+        assert!(map.crc_rng.is_empty());
+        assert_eq!(
+            map.file_size(),
+            (meta::SIZE + 10 * size_of::<Header>() + 10 * 1024) as u64
+        );
+
+        let mut page = map.at_mut(2);
+        page.header_mut().set_page(2).set(Flags::Occupied, true);
+        page.data_mut().fill(b'N');
+        page.update_crc();
+        // every page is valid
+        assert!(page.is_crc_ok());
+        assert_eq!(page.crc(), 0);
+        map.flush_range(2, 1).unwrap();
+        assert_eq!(map.recompute_crcs().unwrap(), 0);
+        drop(map);
+
+        // the file remembers it has no checksum
+        let mut map = PageMap::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert_eq!(map.checksum(), Checksum::None);
+        assert_eq!(map.version(), meta::VERSION);
+        let page = map.at(2);
+        assert!(page.data().iter().all(|b| *b == b'N'));
+        assert!(page.is_crc_ok());
+
+        map.grow(ByteSize::kib(20)).unwrap();
+        drop(map);
+
+        let map = PageMap::open_read_only(PATH, ByteSize::kib(20), ByteSize::kib(1)).unwrap();
+        assert_eq!(map.checksum(), Checksum::None);
+        assert!(map.at(2).data().iter().all(|b| *b == b'N'));
+    }
+
+    #[test]
+    fn crc32c() {
+        const PATH: &str = "/tmp/map.crc32c.test";
+        let _ = std::fs::remove_file(PATH);
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        let options = MapOptions::default().checksum(Checksum::Crc32c);
+        let mut map =
+            PageMap::with_options(PATH, ByteSize::kib(10), ByteSize::kib(1), options).unwrap();
+        assert_eq!(map.checksum(), Checksum::Crc32c);
+
+        let mut page = map.at_mut(2);
+        page.data_mut().fill(b'C');
+        page.update_crc();
+        assert!(page.is_crc_ok());
+        assert!(page.crc() != 0 && page.crc() <= u32::MAX as Crc);
+        page.data_mut()[0] = b'D';
+        assert!(!page.is_crc_ok());
+        page.update_crc();
+        map.flush_range(2, 1).unwrap();
+        drop(map);
+
+        // the file can't be opened with another checksum
+        for checksum in [Checksum::Crc64, Checksum::None] {
+            let options = MapOptions::default().checksum(checksum);
+            assert!(matches!(
+                PageMap::with_options(PATH, ByteSize::kib(10), ByteSize::kib(1), options),
+                Err(Error::ChecksumMismatch(_, Checksum::Crc32c, c)) if c == checksum
+            ));
+        }
+
+        // but it can without any
+        let map = PageMap::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert_eq!(map.checksum(), Checksum::Crc32c);
+        assert!(map.at(2).is_crc_ok());
+        drop(map);
+
+        let map =
+            PageMap::with_options(PATH, ByteSize::kib(10), ByteSize::kib(1), options).unwrap();
+        assert!(map.at(2).is_crc_ok());
+    }
+
+    #[test]
+    fn v1() {
+        const PATH: &str = "/tmp/map.v1.test";
+        let _ = std::fs::remove_file(PATH);
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        // build a v1 file by hand, meta is 24 bytes and all pages have a crc
+        let m = meta::Meta {
+            version: 1,
+            page_size: 1024,
+            data_size: 4 * 1024,
+            checksum: Checksum::Crc64,
+        };
+        let (_, crc_rng, data_rng) = PageMap::layout(4, 1024, &m);
+        assert_eq!(crc_rng.start, meta::SIZE_V1 + 4 * size_of::<Header>());
+
+        let mut buf = vec![0; data_rng.end];
+        m.write(&mut buf[..meta::SIZE_V1]).unwrap();
+        buf[data_rng.start + 1024..data_rng.start + 2048].fill(b'1');
+        std::fs::write(PATH, buf).unwrap();
+
+        let mut map = PageMap::new(PATH, ByteSize::kib(4), ByteSize::kib(1)).unwrap();
+        assert_eq!(map.version(), 1);
+        assert_eq!(map.checksum(), Checksum::Crc64);
+        assert!(map.at(1).data().iter().all(|b| *b == b'1'));
+
+        // growing keeps the v1 layout
+        map.grow(ByteSize::kib(8)).unwrap();
+        drop(map);
+
+        let map = PageMap::new(PATH, ByteSize::kib(8), ByteSize::kib(1)).unwrap();
+        assert_eq!(map.version(), 1);
+        assert!(map.at(1).data().iter().all(|b| *b == b'1'));
+    }
+
+    #[test]
+    fn migrate() {
+        const PATH: &str = "/tmp/map.migrate.test";
+        const BACKUP: &str = "/tmp/map.migrate.test.meta.v1.bak";
+        let _ = std::fs::remove_file(PATH);
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+            std::fs::remove_file(BACKUP).unwrap();
+        });
+
+        let m = meta::Meta {
+            version: 1,
+            page_size: 1024,
+            data_size: 4 * 1024,
+            checksum: Checksum::Crc64,
+        };
+        let (_, _, data_rng) = PageMap::layout(4, 1024, &m);
+        let mut buf = vec![0; data_rng.end];
+        m.write(&mut buf[..meta::SIZE_V1]).unwrap();
+        std::fs::write(PATH, buf).unwrap();
+
+        let mut map = PageMap::new(PATH, ByteSize::kib(4), ByteSize::kib(1)).unwrap();
+        let mut page = map.at_mut(1);
+        page.data_mut().fill(b'1');
+        page.update_crc();
+        drop(map);
+
+        let options = MapOptions::default().migrate(true);
+        let map = PageMap::with_options(PATH, ByteSize::kib(4), ByteSize::kib(1), options).unwrap();
+        assert_eq!(map.version(), meta::VERSION);
+        assert!(map.at(1).is_crc_ok());
+        assert!(map.at(1).data().iter().all(|b| *b == b'1'));
+        drop(map);
+
+        let backup = std::fs::read(BACKUP).unwrap();
+        assert_eq!(meta::Meta::load(&backup).unwrap().version, 1);
+
+        // already migrated, opens as is
+        let map = PageMap::with_options(PATH, ByteSize::kib(4), ByteSize::kib(1), options).unwrap();
+        assert_eq!(map.version(), meta::VERSION);
+    }
+
+    #[test]
+    fn wide_ids() {
+        const PATH: &str = "/tmp/map.wide_ids.test";
+        let _ = std::fs::remove_file(PATH);
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        // an existing map keeps its pages when switched to wide ids
+        let mut map = PageMap::new(PATH, ByteSize::kib(4), ByteSize::kib(1)).unwrap();
+        assert!(!map.wide_ids());
+        map.at_mut(1).header_mut().set_page(10);
+        map.flush().unwrap();
+        drop(map);
+
+        let options = MapOptions::default().wide_ids(true);
+        let mut map =
+            PageMap::with_options(PATH, ByteSize::kib(4), ByteSize::kib(1), options).unwrap();
+        assert_eq!(map.version(), meta::WIDE_VERSION);
+        assert!(map.wide_ids());
+        assert_eq!(map.at(1).header().page_id(), 10);
+
+        let id = MAX_WIDE_PAGE_COUNT - 1;
+        map.at_mut(2).header_mut().set_page_id(id);
+        map.flush().unwrap();
+        drop(map);
+
+        // the file stays wide without the option
+        let map = PageMap::new(PATH, ByteSize::kib(4), ByteSize::kib(1)).unwrap();
+        assert!(map.wide_ids());
+        assert_eq!(map.at(2).header().page_id(), id);
+        drop(map);
+
+        // a new file is created wide
+        let _ = std::fs::remove_file(PATH);
+        let map = PageMap::with_options(PATH, ByteSize::kib(4), ByteSize::kib(1), options).unwrap();
+        assert_eq!(map.version(), meta::WIDE_VERSION);
+    }
+
+    #[test]
+    fn alignment() {
+        // current meta sizes need no padding so existing files keep their layout
+        let (header, _, _) = PageMap::layout_at(meta::SIZE, 10, 1024, Checksum::Crc64);
+        assert_eq!(header.start, meta::SIZE);
+        let (header, _, _) = PageMap::layout_at(meta::SIZE_V1, 10, 1024, Checksum::Crc64);
+        assert_eq!(header.start, meta::SIZE_V1);
+
+        // a meta that would misalign the headers
+        for meta_size in [1, 20, 27, 33] {
+            let (header, crc, data) = PageMap::layout_at(meta_size, 3, 1024, Checksum::Crc64);
+            assert!(header.start >= meta_size);
+            assert_eq!(header.start % align_of::<Header>(), 0);
+            assert_eq!(crc.start % align_of::<Crc>(), 0);
+
+            // u64 backed buffer so the start of buf is aligned like the mmap
+            let buf = vec![0u64; data.end / 8 + 1];
+            let buf: &[u8] = unsafe { buf.align_to::<u8>().1 };
+            let (h, headers, t) = unsafe { buf[header].align_to::<Header>() };
+            assert!(h.is_empty() && t.is_empty());
+            assert_eq!(headers.len(), 3);
+            let (h, crcs, t) = unsafe { buf[crc].align_to::<Crc>() };
+            assert!(h.is_empty() && t.is_empty());
+            assert_eq!(crcs.len(), 3);
+        }
+    }
+
+    #[test]
+    fn locked() {
+        const PATH: &str = "/tmp/locked.test";
+        let _ = std::fs::remove_file(PATH);
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        let map = PageMap::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert!(matches!(
+            PageMap::new(PATH, ByteSize::kib(10), ByteSize::kib(1)),
+            Err(Error::Locked(_))
+        ));
+        assert!(matches!(
+            PageMap::open_read_only(PATH, ByteSize::kib(10), ByteSize::kib(1)),
+            Err(Error::Locked(_))
+        ));
+        drop(map);
+
+        // readers can share it
+        let ro = PageMap::open_read_only(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        let ro2 = PageMap::open_read_only(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert!(matches!(
+            PageMap::new(PATH, ByteSize::kib(10), ByteSize::kib(1)),
+            Err(Error::Locked(_))
+        ));
+        drop(ro);
+        drop(ro2);
+
+        assert!(PageMap::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).is_ok());
+    }
+
+    #[test]
+    fn watermark() {
+        let mark = Watermark::default();
+        assert!(mark.take().is_none());
+
+        mark.touch(5);
+        mark.touch(2);
+        mark.touch(7);
+        assert_eq!(mark.take(), Some(2..8));
+        // take resets the watermark
+        assert!(mark.take().is_none());
+
+        const PATH: &str = "/tmp/map.watermark.test";
+        let mut cache = PageMap::new(PATH, ByteSize::mib(10), ByteSize::mib(1)).unwrap();
+
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        cache.at_mut(3).header_mut().set(Flags::Dirty, true);
+        cache.at_mut(4).header_mut().set(Flags::Dirty, true);
+        cache.flush_range(3, 2).unwrap();
+        assert!(cache.touched.take().is_none());
+    }
+
+    #[test]
+    fn sparse() {
+        const PATH: &str = "/tmp/map.sparse.test";
+        let cache = PageMap::with_options(
+            PATH,
+            ByteSize::mib(10),
+            ByteSize::mib(1),
+            MapOptions::default().sparse(true),
+        )
+        .unwrap();
+
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        assert_eq!(cache.page_count(), 10);
+        assert_eq!(cache.iter().count(), 10);
+
+        // the logical size is the full size but almost nothing is allocated
+        assert!(cache.file_size() > 10 * 1024 * 1024);
+        assert_eq!(std::fs::metadata(PATH).unwrap().len(), cache.file_size());
+        assert!(cache.allocated().unwrap() < 10 * 1024 * 1024);
+    }
+
+    #[test]
+    fn prefault() {
+        const PATH: &str = "/tmp/map.prefault.test";
+        let _ = std::fs::remove_file(PATH);
+        let options = MapOptions::default().prefault(true);
+        let mut cache =
+            PageMap::with_options(PATH, ByteSize::mib(1), ByteSize::kib(64), options).unwrap();
+
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        cache.at_mut(3).data_mut().fill(3);
+        cache.grow(ByteSize::mib(2)).unwrap();
+        assert_eq!(cache.page_count(), 32);
+        assert!(cache.at(3).data().iter().all(|b| *b == 3));
+    }
+
+    #[test]
+    fn grow() {
+        const PATH: &str = "/tmp/map.grow.test";
+        let mut cache = PageMap::new(PATH, ByteSize::mib(10), ByteSize::mib(1)).unwrap();
+
+        let _d = Defer::new(|| {
+            std::fs::remove_file(PATH).unwrap();
+        });
+
+        for loc in 0..cache.page_count() {
+            let mut page = cache.at_mut(loc);
+            page.data_mut().fill(loc as u8);
+            page.header_mut()
+                .set_page(loc as u32 + 100)
+                .set(Flags::Occupied, true);
+            page.update_crc();
+        }
+
+        assert!(matches!(
+            cache.grow(ByteSize::mib(5)),
+            Err(Error::CannotShrink)
+        ));
+
+        cache.grow(ByteSize::mib(20)).unwrap();
+        assert_eq!(cache.page_count(), 20);
+
+        let check = |cache: &PageMap| {
+            for loc in 0..cache.page_count() {
+                let page = cache.at(loc);
+                if loc < 10 {
+                    assert!(page.header().flag(Flags::Occupied));
+                    assert_eq!(page.header().page(), loc as u32 + 100);
+                    assert!(page.data().iter().all(|v| *v == loc as u8));
+                    assert!(page.is_crc_ok());
+                } else {
+                    assert_eq!(*page.header(), Header::default());
+                    assert_eq!(page.crc(), 0);
+                    assert!(page.data().iter().all(|v| *v == 0));
+                }
+            }
+        };
+
+        check(&cache);
+        drop(cache);
+
+        // open it again with the new size
+        let cache = PageMap::new(PATH, ByteSize::mib(20), ByteSize::mib(1)).unwrap();
+        check(&cache);
+    }
+
     #[test]
     fn test_big() {
         const PATH: &str = "/tmp/map.big.test";
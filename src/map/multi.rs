@@ -0,0 +1,288 @@
+//! MultiPageMap spreads the cache pages over multiple page maps (files)
+//! so the cache can use more than one disk, or go beyond the max file
+//! size of the filesystem.
+//!
+//! Each map (shard) holds a contiguous range of the addresses, the first
+//! shard holds addresses [0, pc0[, the second [pc0, pc0 + pc1[ and so on.
+//! Since pages are assigned to free slots as they are loaded, the working
+//! set ends up spread over all shards anyway.
+use super::{Header, Page, PageMap, PageMut};
+use crate::{Error, Result};
+use bytesize::ByteSize;
+
+pub struct MultiPageMap {
+    shards: Vec<PageMap>,
+    // first address of each shard
+    starts: Vec<usize>,
+    pc: usize,
+}
+
+impl MultiPageMap {
+    /// create a map over shards. All shards must have the same page size
+    pub fn new(shards: Vec<PageMap>) -> Result<Self> {
+        let ps = shards.first().ok_or(Error::ZeroSize)?.page_size();
+        if shards.iter().any(|shard| shard.page_size() != ps) {
+            return Err(Error::InvalidPageSize);
+        }
+
+        let mut map = Self {
+            shards,
+            starts: Vec::default(),
+            pc: 0,
+        };
+        map.update();
+
+        Ok(map)
+    }
+
+    fn update(&mut self) {
+        self.starts.clear();
+        let mut start = 0;
+        for shard in self.shards.iter() {
+            self.starts.push(start);
+            start += shard.page_count();
+        }
+
+        self.pc = start;
+    }
+
+    /// number of shards
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// returns the shard index and the address inside that shard
+    #[inline]
+    pub(crate) fn locate(&self, address: usize) -> (usize, usize) {
+        let shard = self.starts.partition_point(|start| *start <= address) - 1;
+        (shard, address - self.starts[shard])
+    }
+
+    /// address of the slot at local address inside shard, the reverse
+    /// of [`MultiPageMap::locate`]
+    pub(crate) fn address(&self, shard: usize, local: usize) -> usize {
+        self.starts[shard] + local
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pc
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.shards[0].page_size()
+    }
+
+    /// total size of all shard files
+    pub fn file_size(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.file_size()).sum()
+    }
+
+    /// total allocated space of all shard files
+    pub fn allocated(&self) -> Result<u64> {
+        let mut allocated = 0;
+        for shard in self.shards.iter() {
+            allocated += shard.allocated()?;
+        }
+
+        Ok(allocated)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.shards.iter().any(|shard| shard.is_read_only())
+    }
+
+    /// grow the map so it holds data_size in total. The size is split
+    /// evenly over the shards so they can be opened again with the same
+    /// size each. A slot keeps its address inside its shard, but since
+    /// the shards before it grow too its address in the map changes, use
+    /// [`MultiPageMap::locate`] before and [`MultiPageMap::address`] after
+    /// to follow it.
+    pub fn grow(&mut self, data_size: ByteSize) -> Result<()> {
+        let shards = self.shards.len() as u64;
+        if data_size.0 % (self.page_size() as u64 * shards) != 0 {
+            return Err(Error::SizeNotMultipleOfPageSize);
+        }
+
+        let size = data_size.0 / shards;
+        let ps = self.page_size() as u64;
+        if self
+            .shards
+            .iter()
+            .any(|shard| shard.page_count() as u64 * ps > size)
+        {
+            return Err(Error::CannotShrink);
+        }
+
+        // the starts must match the shards even if one of them failed
+        let grown = self
+            .shards
+            .iter_mut()
+            .try_for_each(|shard| shard.grow(ByteSize(size)));
+        self.update();
+        grown
+    }
+
+    pub(crate) fn data_at(&self, address: usize) -> &[u8] {
+        let (shard, address) = self.locate(address);
+        self.shards[shard].data_at(address)
+    }
+
+    pub(crate) fn header_at(&self, address: usize) -> &Header {
+        let (shard, address) = self.locate(address);
+        self.shards[shard].header_at(address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Page> {
+        self.shards
+            .iter()
+            .zip(self.starts.iter())
+            .flat_map(|(shard, start)| {
+                let start = *start;
+                shard.iter().map(move |page| page.rebase(start))
+            })
+    }
+
+    pub fn at(&self, address: usize) -> Page {
+        let (shard, local) = self.locate(address);
+        self.shards[shard].at(local).rebase(self.starts[shard])
+    }
+
+    pub fn at_mut(&mut self, address: usize) -> PageMut {
+        let (shard, local) = self.locate(address);
+        let start = self.starts[shard];
+        self.shards[shard].at_mut(local).rebase(start)
+    }
+
+    /// flush all shards and wait until they are on disk
+    pub fn flush(&self) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush_async(&self) -> Result<()> {
+        for shard in self.shards.iter() {
+            shard.flush_async()?;
+        }
+
+        Ok(())
+    }
+
+    /// flush a range of pages, the range is split over the shards
+    /// it crosses
+    pub fn flush_range_async(&self, mut address: usize, mut count: usize) -> Result<()> {
+        while count > 0 {
+            let (shard, local) = self.locate(address);
+            let map = &self.shards[shard];
+            let len = count.min(map.page_count() - local);
+            map.flush_range_async(local, len)?;
+
+            address += len;
+            count -= len;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<PageMap> for MultiPageMap {
+    fn from(map: PageMap) -> Self {
+        let mut map = Self {
+            shards: vec![map],
+            starts: Vec::default(),
+            pc: 0,
+        };
+        map.update();
+        map
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::map::Flags;
+
+    #[test]
+    fn shards() {
+        const PATHS: [&str; 3] = [
+            "/tmp/multi.0.test",
+            "/tmp/multi.1.test",
+            "/tmp/multi.2.test",
+        ];
+        for path in PATHS {
+            let _ = std::fs::remove_file(path);
+        }
+
+        let shards = vec![
+            PageMap::new(PATHS[0], ByteSize::kib(3), ByteSize::kib(1)).unwrap(),
+            PageMap::new(PATHS[1], ByteSize::kib(2), ByteSize::kib(1)).unwrap(),
+        ];
+
+        let mut map = MultiPageMap::new(shards).unwrap();
+        assert_eq!(map.page_count(), 5);
+        assert_eq!(map.locate(2), (0, 2));
+        assert_eq!(map.locate(3), (1, 0));
+        assert_eq!(map.locate(4), (1, 1));
+
+        for address in 0..map.page_count() {
+            let mut page = map.at_mut(address);
+            assert_eq!(page.address(), address);
+            page.header_mut().set_page(address as u32 + 10);
+            page.header_mut().set(Flags::Occupied, true);
+            page.data_mut().fill(address as u8);
+        }
+
+        map.flush_range_async(1, 4).unwrap();
+
+        for (address, page) in map.iter().enumerate() {
+            assert_eq!(page.address(), address);
+            assert_eq!(page.header().page(), address as u32 + 10);
+            assert!(map.data_at(address).iter().all(|v| *v == address as u8));
+        }
+
+        // all shards grow to the same size
+        let located = map.locate(4);
+        map.grow(ByteSize::kib(8)).unwrap();
+        assert_eq!(map.page_count(), 8);
+        assert_eq!(map.locate(7), (1, 3));
+        assert_eq!(map.address(located.0, located.1), 5);
+        assert_eq!(map.at(5).header().page(), 14);
+        assert!(matches!(
+            map.grow(ByteSize::kib(6)),
+            Err(Error::CannotShrink)
+        ));
+        assert!(matches!(
+            map.grow(ByteSize::kib(9)),
+            Err(Error::SizeNotMultipleOfPageSize)
+        ));
+        drop(map);
+
+        // open again with the grown size
+        let shards = vec![
+            PageMap::new(PATHS[0], ByteSize::kib(4), ByteSize::kib(1)).unwrap(),
+            PageMap::new(PATHS[1], ByteSize::kib(4), ByteSize::kib(1)).unwrap(),
+        ];
+        let map = MultiPageMap::new(shards).unwrap();
+        assert_eq!(map.page_count(), 8);
+        assert_eq!(map.at(2).header().page(), 12);
+        assert_eq!(map.at(5).header().page(), 14);
+        drop(map);
+
+        // shards must have the same page size
+        let shards = vec![
+            PageMap::new(PATHS[0], ByteSize::kib(4), ByteSize::kib(1)).unwrap(),
+            PageMap::new(PATHS[2], ByteSize::kib(4), ByteSize::kib(2)).unwrap(),
+        ];
+        assert!(matches!(
+            MultiPageMap::new(shards),
+            Err(Error::InvalidPageSize)
+        ));
+        assert!(MultiPageMap::new(Vec::default()).is_err());
+
+        for path in PATHS {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! prefix of all metric names. Metrics are registered on first use, so
+//! the prefix has to be set before the device is built and can't change
+//! once any metric is registered.
+use std::sync::OnceLock;
+
+/// prefix used if none is set
+pub const DEFAULT_PREFIX: &str = "nbd_";
+
+static PREFIX: OnceLock<String> = OnceLock::new();
+
+/// set the prefix of all metric names, fails if the prefix is not a valid
+/// metric name or if a metric was already registered
+pub fn set_prefix(prefix: &str) -> anyhow::Result<()> {
+    if !is_valid(prefix) {
+        anyhow::bail!("invalid metrics prefix '{prefix}'");
+    }
+
+    PREFIX
+        .set(prefix.into())
+        .map_err(|_| anyhow::anyhow!("metrics prefix is already set"))
+}
+
+/// full name of metric (name without the prefix)
+pub(crate) fn name(name: &str) -> String {
+    let prefix = PREFIX.get_or_init(|| DEFAULT_PREFIX.into());
+    format!("{prefix}{name}")
+}
+
+/// prometheus names are `[a-zA-Z_:][a-zA-Z0-9_:]*`, an empty prefix is fine
+fn is_valid(prefix: &str) -> bool {
+    prefix.chars().enumerate().all(|(i, c)| {
+        c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn valid() {
+        assert!(is_valid(DEFAULT_PREFIX));
+        assert!(is_valid("tenant_1_"));
+        assert!(is_valid(""));
+        assert!(!is_valid("1qbd_"));
+        assert!(!is_valid("qbd-"));
+    }
+}
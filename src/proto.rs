@@ -0,0 +1,47 @@
+//! constants of the nbd protocol shared by the nbd store (client) and
+//! the nbd server. See https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+
+pub const NBD_MAGIC: u64 = 0x4e42444d41474943;
+pub const IHAVEOPT: u64 = 0x49484156454f5054;
+pub const OPT_REPLY_MAGIC: u64 = 0x0003e889045565a9;
+pub const REQUEST_MAGIC: u32 = 0x25609513;
+pub const REPLY_MAGIC: u32 = 0x67446698;
+
+// handshake flags
+pub const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+// options
+pub const OPT_EXPORT_NAME: u32 = 1;
+pub const OPT_ABORT: u32 = 2;
+pub const OPT_LIST: u32 = 3;
+pub const OPT_INFO: u32 = 6;
+pub const OPT_GO: u32 = 7;
+
+// option replies
+pub const REP_ACK: u32 = 1;
+pub const REP_SERVER: u32 = 2;
+pub const REP_INFO: u32 = 3;
+pub const REP_ERR_UNSUP: u32 = (1 << 31) | 1;
+pub const REP_ERR_INVALID: u32 = (1 << 31) | 3;
+pub const REP_ERR_UNKNOWN: u32 = (1 << 31) | 6;
+
+// info types of REP_INFO
+pub const INFO_EXPORT: u16 = 0;
+pub const INFO_BLOCK_SIZE: u16 = 3;
+
+// transmission flags
+pub const FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const FLAG_READ_ONLY: u16 = 1 << 1;
+pub const FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const FLAG_SEND_FUA: u16 = 1 << 3;
+pub const FLAG_SEND_TRIM: u16 = 1 << 5;
+
+// command flags
+pub const CMD_FLAG_FUA: u16 = 1 << 0;
+
+pub const CMD_READ: u16 = 0;
+pub const CMD_WRITE: u16 = 1;
+pub const CMD_DISC: u16 = 2;
+pub const CMD_FLUSH: u16 = 3;
+pub const CMD_TRIM: u16 = 4;
@@ -0,0 +1,690 @@
+//! nbd server to export a device over tcp to standard nbd clients
+//! (`nbd-client -N name host port`, qemu, or the nbd store of another qbd)
+//! instead of attaching it to a local nbd device.
+//!
+//! The fixed newstyle handshake is implemented with NBD_OPT_EXPORT_NAME,
+//! NBD_OPT_INFO, NBD_OPT_GO and NBD_OPT_LIST. Transmission uses simple
+//! replies only. One client is served at a time, other clients wait until
+//! the current one disconnects. A client that doesn't finish negotiation
+//! within the handshake timeout is dropped so it can't hold up the others.
+//!
+//! The block sizes of the export are sent with NBD_INFO_BLOCK_SIZE so
+//! clients that support it align their requests to the preferred size (the
+//! page size of the device). Those are only hints, a client that ignores
+//! them still works but small or unaligned writes become a read-modify-write
+//! of the pages they touch. Only requests bigger than the max block size
+//! are rejected (EINVAL).
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+use bytesize::ByteSize;
+use nbd_async::{BlockDevice, Control};
+use nix::errno::Errno;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::device::DeviceControl;
+use crate::proto::*;
+
+/// max length of an option sent by a client, names are way shorter
+const MAX_OPTION: u32 = 4096;
+
+/// default max time a client can take to negotiate the export
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// flags of the export sent to the client
+const EXPORT_FLAGS: u16 = FLAG_HAS_FLAGS | FLAG_SEND_FLUSH | FLAG_SEND_FUA | FLAG_SEND_TRIM;
+
+/// flags of a read only export, trim is a write too
+const READ_ONLY_FLAGS: u16 = FLAG_HAS_FLAGS | FLAG_SEND_FLUSH | FLAG_READ_ONLY;
+
+/// the exported device as advertised to the clients
+#[derive(Debug, Clone)]
+pub struct Export {
+    name: String,
+    size: u64,
+    min_block: u32,
+    preferred_block: u32,
+    max_block: u32,
+    read_only: bool,
+    handshake_timeout: Duration,
+}
+
+impl Export {
+    /// export of size with name. A client that asks for the default
+    /// export (empty name) gets this export too
+    pub fn new<N: Into<String>>(name: N, size: ByteSize) -> Self {
+        Self {
+            name: name.into(),
+            size: size.as_u64(),
+            min_block: 1,
+            preferred_block: 4096,
+            max_block: 32 * 1024 * 1024,
+            read_only: false,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// max time a client can take to negotiate the export before it is
+    /// disconnected (10s by default)
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// advertise the export as read only, writes and trims are rejected
+    /// with EPERM before they reach the device
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    fn flags(&self) -> u16 {
+        if self.read_only {
+            READ_ONLY_FLAGS
+        } else {
+            EXPORT_FLAGS
+        }
+    }
+
+    /// block size constraints sent to clients. Requests bigger than max
+    /// are rejected. The preferred size is kept between min and max as
+    /// the protocol requires
+    pub fn with_block_size(mut self, min: u32, preferred: u32, max: u32) -> Self {
+        self.min_block = min;
+        self.max_block = max.max(min);
+        self.preferred_block = preferred.clamp(min, self.max_block);
+        self
+    }
+
+    fn matches(&self, name: &[u8]) -> bool {
+        name.is_empty() || name == self.name.as_bytes()
+    }
+}
+
+/// serves device to clients connecting on listener until a shutdown is
+/// received on control. All control messages are passed to the device
+/// as they come, whether a client is connected or not.
+pub async fn serve<D, C>(
+    listener: TcpListener,
+    export: Export,
+    mut device: D,
+    mut control: C,
+) -> io::Result<()>
+where
+    D: BlockDevice<DeviceControl>,
+    C: Stream<Item = Control<DeviceControl>> + Unpin,
+{
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (mut stream, peer) = accepted?;
+                log::info!("client {peer} connected");
+                let negotiated =
+                    tokio::time::timeout(export.handshake_timeout, handshake(&mut stream, &export));
+                match negotiated.await {
+                    Ok(Ok(true)) => {}
+                    Ok(Ok(false)) => {
+                        log::info!("client {peer} aborted negotiation");
+                        continue;
+                    }
+                    Ok(Err(err)) => {
+                        log::error!("negotiation with client {peer} failed: {err}");
+                        continue;
+                    }
+                    Err(_) => {
+                        log::error!("client {peer} did not negotiate in time, disconnecting");
+                        continue;
+                    }
+                }
+
+                match transmission(stream, &export, &mut device, &mut control).await {
+                    Ok(true) => return Ok(()),
+                    Ok(false) => log::info!("client {peer} disconnected"),
+                    Err(err) => log::error!("client {peer} failed: {err}"),
+                }
+            }
+            msg = control.next() => {
+                let Some(msg) = msg else {
+                    return Ok(());
+                };
+
+                if handle_control(&mut device, &msg).await {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// passes the control message to the device, returns true on shutdown
+async fn handle_control<D>(device: &mut D, msg: &Control<DeviceControl>) -> bool
+where
+    D: BlockDevice<DeviceControl>,
+{
+    // the device already logs the failure
+    let _ = device.control(msg).await;
+    matches!(msg, Control::Shutdown)
+}
+
+async fn option_reply<S>(stream: &mut S, option: u32, kind: u32, data: &[u8]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(20 + data.len());
+    buf.extend_from_slice(&OPT_REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&option.to_be_bytes());
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf).await
+}
+
+/// negotiates the export with the client. Returns true if the client
+/// can start transmission, false if it aborted.
+async fn handshake<S>(stream: &mut S, export: &Export) -> io::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut hello = Vec::with_capacity(18);
+    hello.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+    hello.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    hello.extend_from_slice(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+    stream.write_all(&hello).await?;
+
+    let client = stream.read_u32().await?;
+    let no_zeroes = client & FLAG_NO_ZEROES as u32 != 0;
+
+    loop {
+        if stream.read_u64().await? != IHAVEOPT {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid option magic",
+            ));
+        }
+
+        let option = stream.read_u32().await?;
+        let length = stream.read_u32().await?;
+        if length > MAX_OPTION {
+            return Err(io::Error::new(ErrorKind::InvalidData, "option is too long"));
+        }
+
+        let mut data = vec![0; length as usize];
+        stream.read_exact(&mut data).await?;
+
+        match option {
+            OPT_EXPORT_NAME => {
+                // there is no way to tell the client the export does not
+                // exist other than closing the connection
+                if !export.matches(&data) {
+                    return Err(io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("unknown export '{}'", String::from_utf8_lossy(&data)),
+                    ));
+                }
+
+                let mut buf = Vec::with_capacity(134);
+                buf.extend_from_slice(&export.size.to_be_bytes());
+                buf.extend_from_slice(&export.flags().to_be_bytes());
+                if !no_zeroes {
+                    buf.extend_from_slice(&[0; 124]);
+                }
+                stream.write_all(&buf).await?;
+                return Ok(true);
+            }
+            OPT_ABORT => {
+                option_reply(stream, option, REP_ACK, &[]).await?;
+                return Ok(false);
+            }
+            OPT_LIST => {
+                let mut buf = Vec::with_capacity(4 + export.name.len());
+                buf.extend_from_slice(&(export.name.len() as u32).to_be_bytes());
+                buf.extend_from_slice(export.name.as_bytes());
+                option_reply(stream, option, REP_SERVER, &buf).await?;
+                option_reply(stream, option, REP_ACK, &[]).await?;
+            }
+            OPT_INFO | OPT_GO => {
+                // name length, name, then the requested info types which
+                // are ignored since we always send export and block size
+                let name = match data.get(..4) {
+                    Some(len) => {
+                        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+                        data.get(4..4 + len)
+                    }
+                    None => None,
+                };
+
+                let Some(name) = name else {
+                    option_reply(stream, option, REP_ERR_INVALID, &[]).await?;
+                    continue;
+                };
+
+                if !export.matches(name) {
+                    option_reply(stream, option, REP_ERR_UNKNOWN, &[]).await?;
+                    continue;
+                }
+
+                let mut info = Vec::with_capacity(12);
+                info.extend_from_slice(&INFO_EXPORT.to_be_bytes());
+                info.extend_from_slice(&export.size.to_be_bytes());
+                info.extend_from_slice(&export.flags().to_be_bytes());
+                option_reply(stream, option, REP_INFO, &info).await?;
+
+                let mut info = Vec::with_capacity(14);
+                info.extend_from_slice(&INFO_BLOCK_SIZE.to_be_bytes());
+                info.extend_from_slice(&export.min_block.to_be_bytes());
+                info.extend_from_slice(&export.preferred_block.to_be_bytes());
+                info.extend_from_slice(&export.max_block.to_be_bytes());
+                option_reply(stream, option, REP_INFO, &info).await?;
+
+                option_reply(stream, option, REP_ACK, &[]).await?;
+                if option == OPT_GO {
+                    return Ok(true);
+                }
+            }
+            _ => option_reply(stream, option, REP_ERR_UNSUP, &[]).await?,
+        }
+    }
+}
+
+struct Request {
+    flags: u16,
+    cmd: u16,
+    handle: u64,
+    offset: u64,
+    length: u32,
+    data: Option<Vec<u8>>,
+}
+
+/// reads requests from the client and sends them over tx. This runs in
+/// its own task so control messages are still handled while waiting for
+/// the next request.
+async fn read_requests<R>(reader: R, max: u32, tx: mpsc::Sender<Request>) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    loop {
+        if reader.read_u32().await? != REQUEST_MAGIC {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid request magic",
+            ));
+        }
+
+        let flags = reader.read_u16().await?;
+        let cmd = reader.read_u16().await?;
+        let handle = reader.read_u64().await?;
+        let offset = reader.read_u64().await?;
+        let length = reader.read_u32().await?;
+
+        let data = if cmd == CMD_WRITE {
+            // we can't skip the payload without reading it, so a request
+            // that is too big ends the connection
+            if length > max {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("write of {length} bytes is too big"),
+                ));
+            }
+
+            let mut data = vec![0; length as usize];
+            reader.read_exact(&mut data).await?;
+            Some(data)
+        } else {
+            None
+        };
+
+        let request = Request {
+            flags,
+            cmd,
+            handle,
+            offset,
+            length,
+            data,
+        };
+
+        if tx.send(request).await.is_err() || cmd == CMD_DISC {
+            return Ok(());
+        }
+    }
+}
+
+/// serves the requests of a client until it disconnects. returns true if
+/// a shutdown was received in the meantime.
+async fn transmission<S, D, C>(
+    stream: S,
+    export: &Export,
+    device: &mut D,
+    control: &mut C,
+) -> io::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    D: BlockDevice<DeviceControl>,
+    C: Stream<Item = Control<DeviceControl>> + Unpin,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut writer = BufWriter::new(writer);
+
+    let (tx, mut requests) = mpsc::channel(1);
+    let max = export.max_block;
+    let reader = tokio::spawn(async move {
+        // also fails when the client just closes the connection
+        if let Err(err) = read_requests(reader, max, tx).await {
+            log::debug!("stopped reading requests: {err}");
+        }
+    });
+
+    let result = loop {
+        tokio::select! {
+            request = requests.recv() => {
+                let request = match request {
+                    Some(request) if request.cmd != CMD_DISC => request,
+                    _ => break Ok(false),
+                };
+
+                let (error, data) = match execute(device, export, &request).await {
+                    Ok(data) => (0, data),
+                    Err(err) => (err.raw_os_error().unwrap_or(Errno::EIO as i32) as u32, None),
+                };
+
+                writer.write_u32(REPLY_MAGIC).await?;
+                writer.write_u32(error).await?;
+                writer.write_u64(request.handle).await?;
+                if let Some(data) = data {
+                    writer.write_all(&data).await?;
+                }
+                writer.flush().await?;
+            }
+            msg = control.next() => {
+                let Some(msg) = msg else {
+                    break Ok(true);
+                };
+
+                if handle_control(device, &msg).await {
+                    break Ok(true);
+                }
+            }
+        }
+    };
+
+    reader.abort();
+    result
+}
+
+async fn execute<D>(
+    device: &mut D,
+    export: &Export,
+    request: &Request,
+) -> io::Result<Option<Vec<u8>>>
+where
+    D: BlockDevice<DeviceControl>,
+{
+    let invalid = || io::Error::from_raw_os_error(Errno::EINVAL as i32);
+    if export.read_only && matches!(request.cmd, CMD_WRITE | CMD_TRIM) {
+        return Err(io::Error::from_raw_os_error(Errno::EPERM as i32));
+    }
+
+    let end = request.offset.checked_add(request.length as u64);
+    let in_range = matches!(end, Some(end) if end <= export.size);
+
+    match request.cmd {
+        CMD_READ => {
+            if !in_range || request.length > export.max_block {
+                return Err(invalid());
+            }
+
+            let mut buf = vec![0; request.length as usize];
+            device.read(request.offset, &mut buf).await?;
+            Ok(Some(buf))
+        }
+        CMD_WRITE => {
+            if !in_range {
+                return Err(invalid());
+            }
+
+            let data = request.data.as_deref().unwrap_or_default();
+            device.write(request.offset, data).await?;
+            if request.flags & CMD_FLAG_FUA != 0 {
+                device.flush().await?;
+            }
+
+            Ok(None)
+        }
+        CMD_FLUSH => {
+            device.flush().await?;
+            Ok(None)
+        }
+        CMD_TRIM => {
+            if !in_range {
+                return Err(invalid());
+            }
+
+            device.trim(request.offset, request.length as usize).await?;
+            if request.flags & CMD_FLAG_FUA != 0 {
+                device.flush().await?;
+            }
+
+            Ok(None)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::{NbdStore, Store};
+    use tokio::net::TcpStream;
+
+    /// a device in memory
+    struct Memory {
+        data: Vec<u8>,
+        flushes: usize,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl BlockDevice<DeviceControl> for Memory {
+        async fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        async fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+            let offset = offset as usize;
+            self.data[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+
+        async fn trim(&mut self, offset: u64, len: usize) -> io::Result<()> {
+            let offset = offset as usize;
+            self.data[offset..offset + len].fill(0);
+            Ok(())
+        }
+
+        async fn control(&mut self, _control: &Control<DeviceControl>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// negotiates with NBD_OPT_GO and returns the info replies
+    async fn go(stream: &mut TcpStream, name: &str) -> (u32, Vec<Vec<u8>>) {
+        assert_eq!(stream.read_u64().await.unwrap(), NBD_MAGIC);
+        assert_eq!(stream.read_u64().await.unwrap(), IHAVEOPT);
+        stream.read_u16().await.unwrap();
+        stream.write_u32(3).await.unwrap();
+
+        stream.write_u64(IHAVEOPT).await.unwrap();
+        stream.write_u32(OPT_GO).await.unwrap();
+        stream.write_u32(6 + name.len() as u32).await.unwrap();
+        stream.write_u32(name.len() as u32).await.unwrap();
+        stream.write_all(name.as_bytes()).await.unwrap();
+        stream.write_u16(0).await.unwrap();
+
+        let mut infos = vec![];
+        loop {
+            assert_eq!(stream.read_u64().await.unwrap(), OPT_REPLY_MAGIC);
+            assert_eq!(stream.read_u32().await.unwrap(), OPT_GO);
+            let kind = stream.read_u32().await.unwrap();
+            let len = stream.read_u32().await.unwrap();
+            let mut data = vec![0; len as usize];
+            stream.read_exact(&mut data).await.unwrap();
+            if kind != REP_INFO {
+                return (kind, infos);
+            }
+            infos.push(data);
+        }
+    }
+
+    #[test]
+    fn block_size() {
+        // a page bigger than the max request can't be preferred
+        let export = Export::new("test", ByteSize::mib(1)).with_block_size(4096, 65536, 8192);
+        assert_eq!(export.preferred_block, 8192);
+
+        let export = Export::new("test", ByteSize::mib(1)).with_block_size(4096, 512, 8192);
+        assert_eq!(export.preferred_block, 4096);
+    }
+
+    #[tokio::test]
+    async fn serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let device = Memory {
+            data: vec![0; 10 * 1024],
+            flushes: 0,
+        };
+        let export = Export::new("test", ByteSize::kib(10)).with_block_size(512, 1024, 4096);
+        let (ctl, recv) = mpsc::channel(1);
+        let server = super::serve(
+            listener,
+            export,
+            device,
+            tokio_stream::wrappers::ReceiverStream::new(recv),
+        );
+
+        let client = async {
+            // the nbd store negotiates with NBD_OPT_EXPORT_NAME
+            let mut store = NbdStore::connect(address.as_str(), "test", ByteSize::kib(1))
+                .await
+                .unwrap();
+            assert_eq!(store.size(), ByteSize::kib(10));
+            store.set(3, &[7; 1024]).await.unwrap();
+            let page = store.get(3).await.unwrap().unwrap();
+            assert!(page.iter().all(|v| *v == 7));
+            store.flush().await.unwrap();
+            drop(store);
+
+            let mut stream = TcpStream::connect(address.as_str()).await.unwrap();
+            let (kind, _) = go(&mut stream, "other").await;
+            assert_eq!(kind, REP_ERR_UNKNOWN);
+            drop(stream);
+
+            let mut stream = TcpStream::connect(address.as_str()).await.unwrap();
+            let (kind, infos) = go(&mut stream, "test").await;
+            assert_eq!(kind, REP_ACK);
+            assert_eq!(infos.len(), 2);
+            assert_eq!(&infos[0][2..10], &(10u64 * 1024).to_be_bytes());
+            let flags = u16::from_be_bytes([infos[0][10], infos[0][11]]);
+            assert_ne!(flags & FLAG_SEND_TRIM, 0);
+            assert_eq!(&infos[1][2..6], &512u32.to_be_bytes());
+            assert_eq!(&infos[1][6..10], &1024u32.to_be_bytes());
+            assert_eq!(&infos[1][10..14], &4096u32.to_be_bytes());
+
+            // reads that are too big fail but the connection stays usable
+            for (handle, length) in [(1u64, 8192u32), (2, 1024)] {
+                stream.write_u32(REQUEST_MAGIC).await.unwrap();
+                stream.write_u16(0).await.unwrap();
+                stream.write_u16(CMD_READ).await.unwrap();
+                stream.write_u64(handle).await.unwrap();
+                stream.write_u64(3 * 1024).await.unwrap();
+                stream.write_u32(length).await.unwrap();
+
+                assert_eq!(stream.read_u32().await.unwrap(), REPLY_MAGIC);
+                let error = stream.read_u32().await.unwrap();
+                assert_eq!(stream.read_u64().await.unwrap(), handle);
+                if handle == 1 {
+                    assert_eq!(error, Errno::EINVAL as u32);
+                    continue;
+                }
+
+                assert_eq!(error, 0);
+                let mut data = vec![0; length as usize];
+                stream.read_exact(&mut data).await.unwrap();
+                assert!(data.iter().all(|v| *v == 7));
+            }
+
+            // a trimmed page reads back as zeros
+            for cmd in [CMD_TRIM, CMD_READ] {
+                stream.write_u32(REQUEST_MAGIC).await.unwrap();
+                stream.write_u16(0).await.unwrap();
+                stream.write_u16(cmd).await.unwrap();
+                stream.write_u64(3).await.unwrap();
+                stream.write_u64(3 * 1024).await.unwrap();
+                stream.write_u32(1024).await.unwrap();
+
+                assert_eq!(stream.read_u32().await.unwrap(), REPLY_MAGIC);
+                assert_eq!(stream.read_u32().await.unwrap(), 0);
+                assert_eq!(stream.read_u64().await.unwrap(), 3);
+            }
+            let mut data = vec![0xff; 1024];
+            stream.read_exact(&mut data).await.unwrap();
+            assert!(data.iter().all(|v| *v == 0));
+
+            ctl.send(Control::Shutdown).await.unwrap();
+        };
+
+        let (result, _) = tokio::join!(server, client);
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let device = Memory {
+            data: vec![0; 10 * 1024],
+            flushes: 0,
+        };
+        let export = Export::new("test", ByteSize::kib(10))
+            .with_handshake_timeout(Duration::from_millis(50));
+        let (ctl, recv) = mpsc::channel(1);
+        let server = super::serve(
+            listener,
+            export,
+            device,
+            tokio_stream::wrappers::ReceiverStream::new(recv),
+        );
+
+        let client = async {
+            // connects but never negotiates
+            let mut idle = TcpStream::connect(address.as_str()).await.unwrap();
+
+            // is served once the idle client is dropped
+            let store = NbdStore::connect(address.as_str(), "test", ByteSize::kib(1))
+                .await
+                .unwrap();
+            assert_eq!(store.size(), ByteSize::kib(10));
+            drop(store);
+
+            // the server closed the idle connection
+            let mut buf = vec![];
+            idle.read_to_end(&mut buf).await.unwrap();
+
+            ctl.send(Control::Shutdown).await.unwrap();
+        };
+
+        let (result, _) = tokio::join!(server, client);
+        result.unwrap();
+    }
+}
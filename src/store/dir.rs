@@ -0,0 +1,202 @@
+//! DirStore keeps each page in its own file `{dir}/{index}`. A page that
+//! was never written has no file. This makes it easy to backup (or rsync)
+//! only the pages that changed.
+//!
+//! A page is written to a temporary file first then renamed over the old
+//! one so a crash never leaves a partially written page behind.
+//!
+//! With short pages enabled a page file can also be smaller than the page
+//! size, this is what a CompressPolicy over the store needs to save space.
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use bytesize::ByteSize;
+use tokio::io::AsyncWriteExt;
+
+use super::*;
+
+/// store with a file per page in a directory
+pub struct DirStore {
+    dir: PathBuf,
+    size: ByteSize,
+    page_size: usize,
+    short_pages: bool,
+}
+
+impl DirStore {
+    /// creates the directory if it does not exist, existing page files
+    /// are used as is
+    pub fn new<P: AsRef<Path>>(dir: P, size: ByteSize, page_size: ByteSize) -> Result<Self> {
+        if page_size.as_u64() == 0 {
+            return Err(Error::ZeroSize);
+        }
+
+        if size.as_u64() % page_size.as_u64() != 0 {
+            return Err(Error::SizeNotMultipleOfPageSize);
+        }
+
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir: dir.as_ref().into(),
+            size,
+            page_size: page_size.as_u64() as usize,
+            short_pages: false,
+        })
+    }
+
+    /// accept pages shorter than the page size, see [`Store::short_pages`]
+    pub fn with_short_pages(mut self) -> Self {
+        self.short_pages = true;
+        self
+    }
+
+    fn check_len(&self, len: usize) -> Result<()> {
+        if len > self.page_size || (len < self.page_size && !self.short_pages) {
+            return Err(Error::InvalidPageSize);
+        }
+
+        Ok(())
+    }
+
+    fn path(&self, index: u32) -> PathBuf {
+        self.dir.join(index.to_string())
+    }
+
+    fn check(&self, index: u32) -> Result<()> {
+        if index as u64 >= self.page_count() {
+            return Err(Error::PageIndexOutOfRange);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for DirStore {
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        self.check(index)?;
+        self.check_len(page.len())?;
+
+        let tmp = self.dir.join(format!(".{index}.tmp"));
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(page).await?;
+        // the data must be on disk before the rename, otherwise a crash
+        // can leave an empty page file behind
+        file.sync_all().await?;
+        drop(file);
+
+        tokio::fs::rename(&tmp, self.path(index)).await?;
+        Ok(())
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        self.check(index)?;
+
+        let data = match tokio::fs::read(self.path(index)).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        self.check_len(data.len())?;
+        Ok(Some(Page::Owned(data)))
+    }
+
+    /// renames are only durable once the directory itself is synced
+    async fn flush(&self) -> Result<()> {
+        tokio::fs::File::open(&self.dir).await?.sync_all().await?;
+        Ok(())
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        self.check(index)?;
+
+        match tokio::fs::remove_file(self.path(index)).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn can_discard(&self) -> bool {
+        true
+    }
+
+    fn short_pages(&self) -> bool {
+        self.short_pages
+    }
+
+    fn size(&self) -> ByteSize {
+        self.size
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "dir:{} ({})",
+            self.dir.display(),
+            self.size.to_string_as(true)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn dir() {
+        const PATH: &str = "/tmp/store.dir.test";
+        let _ = std::fs::remove_dir_all(PATH);
+
+        let mut store = DirStore::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert_eq!(store.page_count(), 10);
+        assert!(store.get(3).await.unwrap().is_none());
+
+        store.set(3, &[3; 1024]).await.unwrap();
+        store.flush().await.unwrap();
+        let page = store.get(3).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 3));
+        // no temp file is left behind
+        assert_eq!(std::fs::read_dir(PATH).unwrap().count(), 1);
+
+        assert!(matches!(
+            store.get(10).await,
+            Err(Error::PageIndexOutOfRange)
+        ));
+        assert!(matches!(
+            store.set(10, &[0; 1024]).await,
+            Err(Error::PageIndexOutOfRange)
+        ));
+        assert!(matches!(
+            store.set(0, &[0; 10]).await,
+            Err(Error::InvalidPageSize)
+        ));
+
+        // pages are still there once the store is opened again
+        drop(store);
+        let mut store = DirStore::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert!(store.get(3).await.unwrap().is_some());
+
+        store.discard(3).await.unwrap();
+        assert!(store.get(3).await.unwrap().is_none());
+        // discarding a page that was never written is fine
+        store.discard(4).await.unwrap();
+
+        // short pages are kept as they are
+        let mut store = store.with_short_pages();
+        store.set(0, &[1; 10]).await.unwrap();
+        let page = store.get(0).await.unwrap().unwrap();
+        assert_eq!(&page[..], &[1; 10]);
+        drop(page);
+        assert!(matches!(
+            store.set(0, &[0; 1025]).await,
+            Err(Error::InvalidPageSize)
+        ));
+    }
+}
@@ -1,29 +1,81 @@
-use std::path::Path;
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
 
 use bytesize::ByteSize;
 
-use crate::map::{Flags, PageMap};
+use crate::config::Geometry;
+use crate::map::{Flags, MapOptions, PageMap};
 
 use super::*;
 
-/// persisted storage using BlockMap
+/// persisted storage using PageMap
 pub struct FileStore {
     map: PageMap,
     size: ByteSize,
+    path: PathBuf,
 }
 
 impl FileStore {
     pub fn new<P: AsRef<Path>>(path: P, size: ByteSize, page_size: ByteSize) -> Result<Self> {
+        Self::with_options(path, size, page_size, MapOptions::default())
+    }
+
+    /// store of the size and page size of geometry
+    pub fn with_geometry<P: AsRef<Path>>(
+        path: P,
+        geometry: &Geometry,
+        options: MapOptions,
+    ) -> Result<Self> {
+        Self::with_options(path, geometry.disk_size, geometry.page_size, options)
+    }
+
+    pub fn with_options<P: AsRef<Path>>(
+        path: P,
+        size: ByteSize,
+        page_size: ByteSize,
+        options: MapOptions,
+    ) -> Result<Self> {
         Ok(Self {
-            map: PageMap::new(path, size, page_size).map_err(IoError::from)?,
+            map: PageMap::with_options(&path, size, page_size, options).map_err(IoError::from)?,
             size,
+            path: path.as_ref().into(),
         })
     }
-}
 
-#[async_trait::async_trait]
-impl Store for FileStore {
-    async fn set(&mut self, index: u32, data: &[u8]) -> Result<()> {
+    /// open an existing store file read-only. The file is never modified
+    /// and all writes to the store fail with a permission error.
+    pub fn open_read_only<P: AsRef<Path>>(
+        path: P,
+        size: ByteSize,
+        page_size: ByteSize,
+    ) -> Result<Self> {
+        Ok(Self {
+            map: PageMap::open_read_only(&path, size, page_size).map_err(IoError::from)?,
+            size,
+            path: path.as_ref().into(),
+        })
+    }
+
+    // all operations on a file store are synchronous, so both the async
+    // and sync versions of the store methods are implemented by these
+
+    fn set_sync(&mut self, index: u32, data: &[u8]) -> Result<()> {
+        self.write(index, data)?;
+
+        // this flushes the block immediately, may
+        // be for performance improvements we shouldn't
+        // do that or use async way
+        self.map.flush_page(index as usize)
+    }
+
+    /// writes a page to the map without flushing it
+    fn write(&mut self, index: u32, data: &[u8]) -> Result<()> {
+        if self.map.is_read_only() {
+            return Err(IoError::new(ErrorKind::PermissionDenied, "store is read only").into());
+        }
+
         if data.len() != self.map.page_size() {
             return Err(Error::InvalidPageSize);
         }
@@ -36,22 +88,102 @@ impl Store for FileStore {
             .set(Flags::Occupied, true);
         block.update_crc();
 
-        // this flushes the block immediately, may
-        // be for performance improvements we shouldn't
-        // do that or use async way
-        self.map.flush_page(index as usize)
+        Ok(())
     }
 
-    async fn get(&self, index: u32) -> Result<Option<Page>> {
+    fn get_sync(&self, index: u32) -> Result<Option<Page>> {
         // we access the map directly to avoid a borrow problem
         let header = self.map.header_at(index as usize);
         if !header.flag(Flags::Occupied) {
             return Ok(None);
         }
 
-        let data = self.map.data_at(index as usize);
+        // a corrupted page is an error so a mirror can read it from
+        // another replica instead
+        if !self.map.at(index as usize).is_crc_ok() {
+            return Err(Error::BadCrc(index));
+        }
+
+        Ok(Some(Page::Borrowed(self.map.data_at(index as usize))))
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for FileStore {
+    async fn set(&mut self, index: u32, data: &[u8]) -> Result<()> {
+        self.set_sync(index, data)
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        self.get_sync(index)
+    }
+
+    /// all pages are written first then flushed together
+    async fn set_many(&mut self, index: u32, pages: &[&[u8]]) -> Result<()> {
+        for (i, page) in pages.iter().enumerate() {
+            self.write(index + i as u32, page)?;
+        }
+
+        self.map.flush_range(index as usize, pages.len())
+    }
 
-        Ok(Some(Page::Borrowed(data)))
+    async fn flush(&self) -> Result<()> {
+        self.map.flush()
+    }
+
+    /// checks the crc of every occupied page in place
+    async fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for page in self.map.iter() {
+            if !page.header().flag(Flags::Occupied) {
+                continue;
+            }
+
+            if page.is_crc_ok() {
+                report.good += 1;
+            } else {
+                log::error!(
+                    "page {} of {} has a bad crc",
+                    page.address(),
+                    self.describe()
+                );
+                report.unrecoverable += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// the page is marked free and its space is given back to the filesystem
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        if self.map.is_read_only() {
+            return Err(IoError::new(ErrorKind::PermissionDenied, "store is read only").into());
+        }
+
+        if index as usize >= self.map.page_count() {
+            return Err(Error::PageIndexOutOfRange);
+        }
+
+        self.map
+            .at_mut(index as usize)
+            .header_mut()
+            .set(Flags::Occupied, false)
+            .set(Flags::Dirty, false);
+        self.map.punch(index as usize)?;
+
+        self.map.flush_page(index as usize)
+    }
+
+    fn can_discard(&self) -> bool {
+        true
+    }
+
+    fn try_get_sync(&self, index: u32) -> Option<Result<Option<Page>>> {
+        Some(self.get_sync(index))
+    }
+
+    fn try_set_sync(&mut self, index: u32, page: &[u8]) -> Option<Result<()>> {
+        Some(self.set_sync(index, page))
     }
 
     fn size(&self) -> ByteSize {
@@ -61,4 +193,135 @@ impl Store for FileStore {
     fn page_size(&self) -> usize {
         self.map.page_size()
     }
+
+    fn describe(&self) -> String {
+        let ro = if self.map.is_read_only() { ", ro" } else { "" };
+        format!(
+            "file:{} ({}{ro})",
+            self.path.display(),
+            self.size.to_string_as(true)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_only() {
+        const PATH: &str = "/tmp/store.read_only.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut store = FileStore::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        store.set(2, &[7; 1024]).await.unwrap();
+        drop(store);
+
+        let mut store =
+            FileStore::open_read_only(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+
+        let page = store.get(2).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 7));
+        assert!(store.get(3).await.unwrap().is_none());
+
+        let err = store.set(2, &[1; 1024]).await.unwrap_err();
+        assert!(matches!(err, Error::IO(err) if err.kind() == ErrorKind::PermissionDenied));
+    }
+
+    #[tokio::test]
+    async fn set_many() {
+        const PATH: &str = "/tmp/store.set_many.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut store = FileStore::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        let pages: Vec<Vec<u8>> = (0..3).map(|i| vec![i + 1; 1024]).collect();
+        let pages: Vec<&[u8]> = pages.iter().map(|p| p.as_slice()).collect();
+        store.set_many(4, &pages).await.unwrap();
+
+        for i in 0..3 {
+            let page = store.get(4 + i).await.unwrap().unwrap();
+            assert!(page.iter().all(|v| *v == i as u8 + 1));
+        }
+        assert!(store.get(7).await.unwrap().is_none());
+
+        // a bad page fails the run
+        assert!(store.set_many(0, &[&[0; 1024], &[0; 10]]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn verify() {
+        const PATH: &str = "/tmp/store.verify.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut store = FileStore::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        for index in 0..3 {
+            store.set(index, &[1; 1024]).await.unwrap();
+        }
+
+        store.map.at_mut(1).data_mut().fill(0xff);
+        let report = store.verify().await.unwrap();
+        assert_eq!(report.good, 2);
+        assert_eq!(report.unrecoverable, 1);
+    }
+
+    #[tokio::test]
+    async fn discard() {
+        use std::os::unix::fs::MetadataExt;
+
+        const PATH: &str = "/tmp/store.discard.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut store = FileStore::new(PATH, ByteSize::kib(512), ByteSize::kib(64)).unwrap();
+        for index in 0..8 {
+            store.set(index, &[1; 64 * 1024]).await.unwrap();
+        }
+
+        let blocks = || std::fs::metadata(PATH).unwrap().blocks();
+        let before = blocks();
+
+        store.discard(2).await.unwrap();
+        store.discard(3).await.unwrap();
+        assert!(blocks() < before);
+
+        assert!(store.get(2).await.unwrap().is_none());
+        let page = store.get(4).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 1));
+
+        // the discarded page can be used again
+        store.set(2, &[5; 64 * 1024]).await.unwrap();
+        let page = store.get(2).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 5));
+
+        assert!(store.discard(8).await.is_err());
+    }
+
+    #[test]
+    fn describe() {
+        const PATH: &str = "/tmp/store.describe.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let store = FileStore::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert_eq!(
+            store.describe(),
+            format!("file:{PATH} ({})", ByteSize::kib(10).to_string_as(true))
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_fast_path() {
+        const PATH: &str = "/tmp/store.sync.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut store = FileStore::new(PATH, ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert!(store.try_set_sync(4, &[3; 1024]).unwrap().is_ok());
+
+        // both paths see the same data
+        let page = store.try_get_sync(4).unwrap().unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 3));
+        let page = store.get(4).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 3));
+
+        assert!(store.try_get_sync(5).unwrap().unwrap().is_none());
+        assert!(store.try_set_sync(5, &[3; 10]).unwrap().is_err());
+    }
 }
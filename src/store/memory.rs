@@ -0,0 +1,140 @@
+//! MemoryStore keeps all pages in memory, nothing survives a restart.
+//! Only pages that were set use memory so a big store that is mostly
+//! holes is cheap. Useful for benchmarks and as a scratch device
+use std::collections::HashMap;
+
+use bytesize::ByteSize;
+
+use super::*;
+
+/// store that keeps its pages in memory
+pub struct MemoryStore {
+    pages: HashMap<u32, Vec<u8>>,
+    size: ByteSize,
+    page_size: usize,
+}
+
+impl MemoryStore {
+    pub fn new(size: ByteSize, page_size: ByteSize) -> Result<Self> {
+        if page_size.as_u64() == 0 {
+            return Err(Error::ZeroSize);
+        }
+
+        if size.as_u64() % page_size.as_u64() != 0 {
+            return Err(Error::SizeNotMultipleOfPageSize);
+        }
+
+        Ok(Self {
+            pages: HashMap::default(),
+            size,
+            page_size: page_size.as_u64() as usize,
+        })
+    }
+
+    fn check(&self, index: u32) -> Result<()> {
+        if index as u64 >= self.page_count() {
+            return Err(Error::PageIndexOutOfRange);
+        }
+
+        Ok(())
+    }
+
+    fn set_page(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        self.check(index)?;
+        if page.len() != self.page_size {
+            return Err(Error::InvalidPageSize);
+        }
+
+        match self.pages.get_mut(&index) {
+            Some(data) => data.copy_from_slice(page),
+            None => {
+                self.pages.insert(index, page.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_page(&self, index: u32) -> Result<Option<Page>> {
+        self.check(index)?;
+        Ok(self.pages.get(&index).map(|data| Page::Borrowed(data)))
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for MemoryStore {
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        self.set_page(index, page)
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        self.get_page(index)
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        self.check(index)?;
+        self.pages.remove(&index);
+        Ok(())
+    }
+
+    fn can_discard(&self) -> bool {
+        true
+    }
+
+    fn try_get_sync(&self, index: u32) -> Option<Result<Option<Page>>> {
+        Some(self.get_page(index))
+    }
+
+    fn try_set_sync(&mut self, index: u32, page: &[u8]) -> Option<Result<()>> {
+        Some(self.set_page(index, page))
+    }
+
+    fn size(&self) -> ByteSize {
+        self.size
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn describe(&self) -> String {
+        format!("memory ({})", self.size.to_string_as(true))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory() {
+        let mut store = MemoryStore::new(ByteSize::kib(10), ByteSize::kib(1)).unwrap();
+        assert_eq!(store.page_count(), 10);
+        assert!(store.get(3).await.unwrap().is_none());
+
+        store.set(3, &[3; 1024]).await.unwrap();
+        let page = store.get(3).await.unwrap().unwrap();
+        assert!(matches!(page, Page::Borrowed(_)));
+        assert!(page.iter().all(|v| *v == 3));
+        drop(page);
+
+        assert!(matches!(
+            store.get(10).await,
+            Err(Error::PageIndexOutOfRange)
+        ));
+        assert!(matches!(
+            store.set(10, &[0; 1024]).await,
+            Err(Error::PageIndexOutOfRange)
+        ));
+        assert!(matches!(
+            store.set(0, &[0; 10]).await,
+            Err(Error::InvalidPageSize)
+        ));
+
+        store.discard(3).await.unwrap();
+        assert!(store.get(3).await.unwrap().is_none());
+
+        assert!(MemoryStore::new(ByteSize::kib(10), ByteSize::b(0)).is_err());
+        assert!(MemoryStore::new(ByteSize::b(1500), ByteSize::kib(1)).is_err());
+    }
+}
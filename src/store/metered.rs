@@ -0,0 +1,225 @@
+//! MeteredStore counts and times the operations of the store it wraps
+//! under a `store_path` label, so the load of each backend of a composed
+//! store can be told apart.
+//!
+//! The path is the position of the store in the policy tree, a policy and
+//! the index of the part for each level, followed by the kind of the store.
+//! For example the second file of the device strip is `strip/1/file`. A
+//! store that is made of other stores passes its own path down as the
+//! prefix of theirs, so the hot file of a tiered store that is the first
+//! part of the strip is `strip/0/tiered/0/file`. A dashboard can then match on a prefix to see how a
+//! policy spreads its load, or on the suffix to compare kinds.
+use std::time::Instant;
+
+use bytesize::ByteSize;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Histogram, HistogramVec, IntCounter,
+    IntCounterVec,
+};
+
+use super::*;
+use crate::metrics;
+
+lazy_static! {
+    static ref STORE_OPS: IntCounterVec = register_int_counter_vec!(
+        metrics::name("store_ops"),
+        "number of operations per store and operation",
+        &["store_path", "op"]
+    )
+    .unwrap();
+    static ref STORE_OP_ERRORS: IntCounterVec = register_int_counter_vec!(
+        metrics::name("store_op_errors"),
+        "number of failed operations per store and operation",
+        &["store_path", "op"]
+    )
+    .unwrap();
+    static ref STORE_OP_SECONDS: HistogramVec = register_histogram_vec!(
+        metrics::name("store_op_seconds"),
+        "duration of operations per store and operation",
+        &["store_path", "op"],
+        vec![0.0001, 0.001, 0.010, 0.050, 0.100, 0.250, 0.500]
+    )
+    .unwrap();
+}
+
+/// metrics of a single operation of a store
+struct Meter {
+    ops: IntCounter,
+    errors: IntCounter,
+    seconds: Histogram,
+}
+
+impl Meter {
+    fn new(path: &str, op: &str) -> Self {
+        Self {
+            ops: STORE_OPS.with_label_values(&[path, op]),
+            errors: STORE_OP_ERRORS.with_label_values(&[path, op]),
+            seconds: STORE_OP_SECONDS.with_label_values(&[path, op]),
+        }
+    }
+
+    fn observe<T>(&self, start: Instant, result: &Result<T>) {
+        self.ops.inc();
+        self.seconds.observe(start.elapsed().as_secs_f64());
+        if result.is_err() {
+            self.errors.inc();
+        }
+    }
+}
+
+/// store that records the metrics of inner, see the module docs
+pub struct MeteredStore<S> {
+    inner: S,
+    path: String,
+    get: Meter,
+    get_many: Meter,
+    set: Meter,
+    set_many: Meter,
+    flush: Meter,
+    discard: Meter,
+}
+
+impl<S> MeteredStore<S>
+where
+    S: Store,
+{
+    /// records the operations of inner under path
+    pub fn new<P: Into<String>>(inner: S, path: P) -> Self {
+        let path = path.into();
+        Self {
+            inner,
+            get: Meter::new(&path, "get"),
+            get_many: Meter::new(&path, "get_many"),
+            set: Meter::new(&path, "set"),
+            set_many: Meter::new(&path, "set_many"),
+            flush: Meter::new(&path, "flush"),
+            discard: Meter::new(&path, "discard"),
+            path,
+        }
+    }
+
+    /// the store_path label of this store
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Store for MeteredStore<S>
+where
+    S: Store,
+{
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.set(index, page).await;
+        self.set.observe(start, &result);
+        result
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        let start = Instant::now();
+        let result = self.inner.get(index).await;
+        self.get.observe(start, &result);
+        result
+    }
+
+    async fn get_many(&self, indices: &[u32]) -> Result<Vec<Option<Page>>> {
+        let start = Instant::now();
+        let result = self.inner.get_many(indices).await;
+        self.get_many.observe(start, &result);
+        result
+    }
+
+    async fn set_many(&mut self, index: u32, pages: &[&[u8]]) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.set_many(index, pages).await;
+        self.set_many.observe(start, &result);
+        result
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.flush().await;
+        self.flush.observe(start, &result);
+        result
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.discard(index).await;
+        self.discard.observe(start, &result);
+        result
+    }
+
+    fn can_discard(&self) -> bool {
+        self.inner.can_discard()
+    }
+
+    fn short_pages(&self) -> bool {
+        self.inner.short_pages()
+    }
+
+    async fn verify(&self) -> Result<VerifyReport> {
+        self.inner.verify().await
+    }
+
+    // the fast paths are counted as the operation they replace
+    fn try_get_sync(&self, index: u32) -> Option<Result<Option<Page>>> {
+        let start = Instant::now();
+        let result = self.inner.try_get_sync(index)?;
+        self.get.observe(start, &result);
+        Some(result)
+    }
+
+    fn try_set_sync(&mut self, index: u32, page: &[u8]) -> Option<Result<()>> {
+        let start = Instant::now();
+        let result = self.inner.try_set_sync(index, page)?;
+        self.set.observe(start, &result);
+        Some(result)
+    }
+
+    fn size(&self) -> ByteSize {
+        self.inner.size()
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn describe(&self) -> String {
+        self.inner.describe()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn metered() {
+        const PATH: &str = "test/0/memory";
+        let mut store = MeteredStore::new(InMemory::new(10), PATH);
+        assert_eq!(store.page_count(), 10);
+
+        store.set(1, &[1; 1024]).await.unwrap();
+        // the fast path counts as a set too
+        store.try_set_sync(2, &[2; 1024]).unwrap().unwrap();
+        assert!(store.get(1).await.unwrap().is_some());
+        store.flush().await.unwrap();
+
+        let ops = |op: &str| STORE_OPS.with_label_values(&[PATH, op]).get();
+        assert_eq!(ops("set"), 2);
+        assert_eq!(ops("get"), 1);
+        assert_eq!(ops("flush"), 1);
+        assert_eq!(ops("discard"), 0);
+        assert_eq!(STORE_OP_ERRORS.with_label_values(&[PATH, "set"]).get(), 0);
+
+        let store = store.into_inner();
+        assert_eq!(store.mem.len(), 2);
+    }
+}
@@ -2,12 +2,26 @@
 use std::io::Error as IoError;
 use std::ops::Deref;
 
+mod dir;
 mod file;
+mod memory;
+mod metered;
+mod nbd;
 pub mod policy;
+#[cfg(feature = "s3")]
+mod s3;
+mod tiered;
 
+#[cfg(feature = "s3")]
+pub use self::s3::{Credentials, Region, S3Store};
 use crate::{Error, Result};
 use bytesize::ByteSize;
+pub use dir::DirStore;
 pub use file::FileStore;
+pub use memory::MemoryStore;
+pub use metered::MeteredStore;
+pub use nbd::NbdStore;
+pub use tiered::TieredStore;
 
 /// Data is like built in Cow but read only
 /// this allow stores to return data with no copy
@@ -36,19 +50,209 @@ impl<'a> From<Page<'a>> for Vec<u8> {
     }
 }
 
+/// result of [`Store::verify`], holes are not counted
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// pages that read back fine
+    pub good: u64,
+    /// pages with a bad copy that can still be read from another copy
+    pub corrupt: u64,
+    /// pages that can't be read correctly from any copy
+    pub unrecoverable: u64,
+}
+
+impl std::ops::AddAssign for VerifyReport {
+    fn add_assign(&mut self, other: Self) {
+        self.good += other.good;
+        self.corrupt += other.corrupt;
+        self.unrecoverable += other.unrecoverable;
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Store: Send + Sync + 'static {
     /// set a page it the store
     async fn set(&mut self, index: u32, page: &[u8]) -> Result<()>;
 
-    /// get a page from the store
+    /// get a page from the store. Returns None exactly when the page is a
+    /// hole, it was never set (or it was discarded). A page that was set
+    /// is always returned, even if it's all zeros, so all stores agree on
+    /// what a hole is. Stores that can't tell (a remote nbd export) never
+    /// return None
     async fn get(&self, index: u32) -> Result<Option<Page>>;
 
+    /// get the pages at indices, in the same order. Stores that pay a
+    /// round trip per call should override this to read all pages at
+    /// once, by default each page is read on its own.
+    async fn get_many(&self, indices: &[u32]) -> Result<Vec<Option<Page>>> {
+        let mut pages = Vec::with_capacity(indices.len());
+        for index in indices {
+            pages.push(self.get(*index).await?);
+        }
+
+        Ok(pages)
+    }
+
+    /// set a run of contiguous pages starting at index. Stores that can
+    /// write multiple pages at once cheaper than one by one should
+    /// override this, by default each page is set on its own.
+    async fn set_many(&mut self, index: u32, pages: &[&[u8]]) -> Result<()> {
+        for (i, page) in pages.iter().enumerate() {
+            self.set(index + i as u32, page).await?;
+        }
+
+        Ok(())
+    }
+
+    /// make sure all pages set so far are persisted. A page is only
+    /// durable once flush returned, the cache doesn't consider a page
+    /// clean before that. Stores that don't buffer writes have nothing to do
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// the page at index is not needed anymore, stores that can free the
+    /// space it uses should do so, the page is then a hole and get returns
+    /// None. Stores that can't free pages keep returning the old content.
+    /// By default nothing is done.
+    async fn discard(&mut self, _index: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// true if discard really frees pages, so a discarded page is a hole
+    /// that reads as zeros. By default stores can't do that
+    fn can_discard(&self) -> bool {
+        false
+    }
+
+    /// true if set also takes pages shorter than page_size and get returns
+    /// them with the length they were written with. Variable length data
+    /// (see CompressPolicy) is then stored without padding
+    fn short_pages(&self) -> bool {
+        false
+    }
+
+    /// checks the integrity of every page of the store. By default each page
+    /// is read with get and a failed read is an unrecoverable page, stores
+    /// that can do better (check a crc, compare copies) override this. This
+    /// reads the whole store so it's meant for offline audits
+    async fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for index in 0..self.page_count() {
+            match self.get(index as u32).await {
+                Ok(Some(_)) => report.good += 1,
+                Ok(None) => {}
+                Err(err) => {
+                    log::error!("page {index} of {} is unreadable: {err}", self.describe());
+                    report.unrecoverable += 1;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// synchronous fast path of get for stores that can serve a page
+    /// without waiting on anything (for example local files). This avoids
+    /// the boxed future of get. Returns None if the store has no fast path
+    /// and the caller should use get instead.
+    fn try_get_sync(&self, _index: u32) -> Option<Result<Option<Page>>> {
+        None
+    }
+
+    /// synchronous fast path of set, same as try_get_sync
+    fn try_set_sync(&mut self, _index: u32, _page: &[u8]) -> Option<Result<()>> {
+        None
+    }
+
     /// size of the store
     fn size(&self) -> ByteSize;
 
     /// size of the page
     fn page_size(&self) -> usize;
+
+    /// human readable description of the store, used for logging
+    fn describe(&self) -> String {
+        format!("store ({})", self.size().to_string_as(true))
+    }
+
+    /// number of pages in the store. A partial page at the end
+    /// of the store is not counted, and a store with page size
+    /// of 0 has no pages
+    fn page_count(&self) -> u64 {
+        match self.page_size() {
+            0 => 0,
+            ps => self.size().as_u64() / ps as u64,
+        }
+    }
+}
+
+/// a boxed store is a store, this allows building a policy
+/// over different types of stores
+#[async_trait::async_trait]
+impl<S> Store for Box<S>
+where
+    S: Store + ?Sized,
+{
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        self.as_mut().set(index, page).await
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        self.as_ref().get(index).await
+    }
+
+    async fn get_many(&self, indices: &[u32]) -> Result<Vec<Option<Page>>> {
+        self.as_ref().get_many(indices).await
+    }
+
+    async fn set_many(&mut self, index: u32, pages: &[&[u8]]) -> Result<()> {
+        self.as_mut().set_many(index, pages).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.as_ref().flush().await
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        self.as_mut().discard(index).await
+    }
+
+    fn can_discard(&self) -> bool {
+        self.as_ref().can_discard()
+    }
+
+    fn short_pages(&self) -> bool {
+        self.as_ref().short_pages()
+    }
+
+    async fn verify(&self) -> Result<VerifyReport> {
+        self.as_ref().verify().await
+    }
+
+    fn try_get_sync(&self, index: u32) -> Option<Result<Option<Page>>> {
+        self.as_ref().try_get_sync(index)
+    }
+
+    fn try_set_sync(&mut self, index: u32, page: &[u8]) -> Option<Result<()>> {
+        self.as_mut().try_set_sync(index, page)
+    }
+
+    fn size(&self) -> ByteSize {
+        self.as_ref().size()
+    }
+
+    fn page_size(&self) -> usize {
+        self.as_ref().page_size()
+    }
+
+    fn describe(&self) -> String {
+        self.as_ref().describe()
+    }
+
+    fn page_count(&self) -> u64 {
+        self.as_ref().page_count()
+    }
 }
 
 #[cfg(test)]
@@ -62,6 +266,8 @@ mod test {
 
     pub struct InMemory {
         pub mem: HashMap<u32, Vec<u8>>,
+        /// (index, count) of every set_many call
+        pub runs: Vec<(u32, usize)>,
         cap: usize,
     }
 
@@ -69,6 +275,7 @@ mod test {
         pub fn new(cap: usize) -> Self {
             Self {
                 mem: HashMap::with_capacity(cap),
+                runs: Vec::default(),
                 cap,
             }
         }
@@ -84,6 +291,32 @@ mod test {
             Ok(self.mem.get(&index).map(|d| Page::Borrowed(&d)))
         }
 
+        async fn set_many(&mut self, index: u32, pages: &[&[u8]]) -> Result<()> {
+            self.runs.push((index, pages.len()));
+            for (i, page) in pages.iter().enumerate() {
+                self.mem.insert(index + i as u32, Vec::from(*page));
+            }
+            Ok(())
+        }
+
+        async fn discard(&mut self, index: u32) -> Result<()> {
+            self.mem.remove(&index);
+            Ok(())
+        }
+
+        fn can_discard(&self) -> bool {
+            true
+        }
+
+        fn try_get_sync(&self, index: u32) -> Option<Result<Option<Page>>> {
+            Some(Ok(self.mem.get(&index).map(|d| Page::Borrowed(d))))
+        }
+
+        fn try_set_sync(&mut self, index: u32, page: &[u8]) -> Option<Result<()>> {
+            self.mem.insert(index, Vec::from(page));
+            Some(Ok(()))
+        }
+
         fn size(&self) -> ByteSize {
             ByteSize((self.cap * self.page_size()) as u64)
         }
@@ -92,4 +325,82 @@ mod test {
             1024
         }
     }
+
+    /// a store that only reports a size, used to test geometry
+    struct Fixed(u64, usize);
+
+    #[async_trait::async_trait]
+    impl Store for Fixed {
+        async fn set(&mut self, _index: u32, _page: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get(&self, _index: u32) -> Result<Option<Page>> {
+            Ok(None)
+        }
+
+        fn size(&self) -> ByteSize {
+            ByteSize(self.0)
+        }
+
+        fn page_size(&self) -> usize {
+            self.1
+        }
+    }
+
+    #[test]
+    fn page_count() {
+        assert_eq!(InMemory::new(10).page_count(), 10);
+        assert_eq!(Fixed(4096, 1024).page_count(), 4);
+        // partial page at the end is not counted
+        assert_eq!(Fixed(4097, 1024).page_count(), 4);
+        assert_eq!(Fixed(1023, 1024).page_count(), 0);
+        // zero page size must not panic
+        assert_eq!(Fixed(4096, 0).page_count(), 0);
+        assert_eq!(Fixed(0, 1024).page_count(), 0);
+    }
+
+    /// the hole contract of Store::get
+    async fn holes<S: Store>(mut store: S) {
+        let name = store.describe();
+        let data = vec![0; store.page_size()];
+        assert!(store.get(0).await.unwrap().is_none(), "{name}");
+
+        // a page of zeros is not a hole
+        store.set(0, &data).await.unwrap();
+        assert!(store.get(0).await.unwrap().is_some(), "{name}");
+        assert!(store.get(1).await.unwrap().is_none(), "{name}");
+
+        store.discard(0).await.unwrap();
+        assert!(store.get(0).await.unwrap().is_none(), "{name}");
+    }
+
+    #[tokio::test]
+    async fn hole_semantics() {
+        const FILE: &str = "/tmp/store.holes.test";
+        const DIR: &str = "/tmp/store.holes.dir.test";
+        const INDEX: &str = "/tmp/store.holes.index.test";
+        let _ = std::fs::remove_file(FILE);
+        let _ = std::fs::remove_dir_all(DIR);
+        let _ = std::fs::remove_file(INDEX);
+
+        holes(InMemory::new(10)).await;
+        holes(MemoryStore::new(ByteSize::kib(10), ByteSize::kib(1)).unwrap()).await;
+        holes(FileStore::new(FILE, ByteSize::kib(10), ByteSize::kib(1)).unwrap()).await;
+        holes(DirStore::new(DIR, ByteSize::kib(10), ByteSize::kib(1)).unwrap()).await;
+        holes(TieredStore::new(InMemory::new(2), InMemory::new(10), INDEX).unwrap()).await;
+        holes(policy::ConcatPolicy::new(vec![InMemory::new(5), InMemory::new(5)]).unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn get_many() {
+        let mut store = InMemory::new(10);
+        store.set(2, &[2; 1024]).await.unwrap();
+        store.set(7, &[7; 1024]).await.unwrap();
+
+        let pages = store.get_many(&[7, 3, 2]).await.unwrap();
+        let found: Vec<Option<u8>> = pages.iter().map(|p| p.as_ref().map(|p| p[0])).collect();
+        assert_eq!(found, vec![Some(7), None, Some(2)]);
+        assert!(store.get_many(&[]).await.unwrap().is_empty());
+    }
 }
@@ -0,0 +1,330 @@
+//! NbdStore uses a remote nbd export as a store. Pages are read and written
+//! at `index * page_size` of the export. This allows putting a qbd in front
+//! of any nbd server, including another qbd.
+//!
+//! Only the basic protocol is implemented (newstyle handshake with
+//! NBD_OPT_EXPORT_NAME and simple replies) and requests are sent one at a
+//! time over a single connection.
+//!
+//! The protocol has no way to tell if a block was ever written, so every
+//! page of the export is returned on get (zeros if it was never written).
+use std::io::{Error as IoError, ErrorKind};
+
+use bytesize::ByteSize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use super::*;
+use crate::proto::*;
+
+struct Connection {
+    stream: BufStream<TcpStream>,
+    handle: u64,
+}
+
+impl Connection {
+    /// connects to the export, returns the connection, the export size
+    /// and the transmission flags
+    async fn open(address: &str, export: &str) -> std::io::Result<(Self, u64, u16)> {
+        let mut stream = BufStream::new(TcpStream::connect(address).await?);
+
+        if stream.read_u64().await? != NBD_MAGIC || stream.read_u64().await? != IHAVEOPT {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "not a newstyle nbd server",
+            ));
+        }
+
+        let server = stream.read_u16().await?;
+        let no_zeroes = server & FLAG_NO_ZEROES != 0;
+        let mut client = (server & FLAG_FIXED_NEWSTYLE) as u32;
+        if no_zeroes {
+            client |= FLAG_NO_ZEROES as u32;
+        }
+
+        stream.write_u32(client).await?;
+        stream.write_u64(IHAVEOPT).await?;
+        stream.write_u32(OPT_EXPORT_NAME).await?;
+        stream.write_u32(export.len() as u32).await?;
+        stream.write_all(export.as_bytes()).await?;
+        stream.flush().await?;
+
+        // the server just closes the connection if the export does not exist
+        let size = stream.read_u64().await?;
+        let flags = stream.read_u16().await?;
+        if !no_zeroes {
+            let mut zeroes = [0; 124];
+            stream.read_exact(&mut zeroes).await?;
+        }
+
+        Ok((Self { stream, handle: 0 }, size, flags))
+    }
+
+    /// sends a request and waits for its reply. The outer error means the
+    /// connection is broken, the inner one is an error returned by the server
+    async fn exchange(
+        &mut self,
+        cmd: u16,
+        offset: u64,
+        length: u32,
+        data: Option<&[u8]>,
+    ) -> std::io::Result<Result<Option<Vec<u8>>>> {
+        self.handle = self.handle.wrapping_add(1);
+
+        self.stream.write_u32(REQUEST_MAGIC).await?;
+        self.stream.write_u16(0).await?;
+        self.stream.write_u16(cmd).await?;
+        self.stream.write_u64(self.handle).await?;
+        self.stream.write_u64(offset).await?;
+        self.stream.write_u32(length).await?;
+        if let Some(data) = data {
+            self.stream.write_all(data).await?;
+        }
+        self.stream.flush().await?;
+
+        if self.stream.read_u32().await? != REPLY_MAGIC {
+            return Err(IoError::new(ErrorKind::InvalidData, "invalid reply magic"));
+        }
+
+        let error = self.stream.read_u32().await?;
+        if self.stream.read_u64().await? != self.handle {
+            return Err(IoError::new(ErrorKind::InvalidData, "unexpected reply"));
+        }
+
+        if error != 0 {
+            return Ok(Err(IoError::from_raw_os_error(error as i32).into()));
+        }
+
+        if cmd != CMD_READ {
+            return Ok(Ok(None));
+        }
+
+        let mut buf = vec![0; length as usize];
+        self.stream.read_exact(&mut buf).await?;
+        Ok(Ok(Some(buf)))
+    }
+}
+
+/// store over a remote nbd export
+pub struct NbdStore {
+    address: String,
+    export: String,
+    size: ByteSize,
+    page_size: usize,
+    flags: u16,
+    // None if the connection was lost, next request reconnects
+    conn: Mutex<Option<Connection>>,
+}
+
+impl NbdStore {
+    /// connect to export on the nbd server at address (host:port). The
+    /// size of the store is the size of the export
+    pub async fn connect<A: Into<String>, E: Into<String>>(
+        address: A,
+        export: E,
+        page_size: ByteSize,
+    ) -> Result<Self> {
+        let address = address.into();
+        let export = export.into();
+        let (conn, size, flags) = Connection::open(&address, &export).await?;
+
+        Ok(Self {
+            address,
+            export,
+            size: ByteSize::b(size),
+            page_size: page_size.as_u64() as usize,
+            flags,
+            conn: Mutex::new(Some(conn)),
+        })
+    }
+
+    /// sends the request, if the connection is broken it reconnects and
+    /// tries once more. If that fails too the error is returned and the
+    /// next request will try to reconnect again.
+    async fn call(
+        &self,
+        cmd: u16,
+        index: u32,
+        length: u32,
+        data: Option<&[u8]>,
+    ) -> Result<Option<Vec<u8>>> {
+        let offset = index as u64 * self.page_size as u64;
+        let mut conn = self.conn.lock().await;
+        let mut retried = false;
+        loop {
+            let result = async {
+                if conn.is_none() {
+                    let (fresh, _, _) = Connection::open(&self.address, &self.export).await?;
+                    log::info!("reconnected to nbd {}", self.address);
+                    *conn = Some(fresh);
+                }
+
+                conn.as_mut()
+                    .unwrap()
+                    .exchange(cmd, offset, length, data)
+                    .await
+            }
+            .await;
+
+            match result {
+                Ok(result) => return result,
+                Err(err) => {
+                    *conn = None;
+                    if retried {
+                        return Err(IoError::new(
+                            ErrorKind::NotConnected,
+                            format!("connection to nbd {} lost: {err}", self.address),
+                        )
+                        .into());
+                    }
+
+                    log::warn!("connection to nbd {} lost: {err}, retrying", self.address);
+                    retried = true;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for NbdStore {
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        if self.flags & FLAG_READ_ONLY != 0 {
+            return Err(IoError::new(ErrorKind::PermissionDenied, "store is read only").into());
+        }
+
+        if index as u64 >= self.page_count() {
+            return Err(Error::PageIndexOutOfRange);
+        }
+
+        if page.len() != self.page_size {
+            return Err(Error::InvalidPageSize);
+        }
+
+        self.call(CMD_WRITE, index, page.len() as u32, Some(page))
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        if index as u64 >= self.page_count() {
+            return Err(Error::PageIndexOutOfRange);
+        }
+
+        let data = self
+            .call(CMD_READ, index, self.page_size as u32, None)
+            .await?;
+        Ok(data.map(Page::Owned))
+    }
+
+    async fn flush(&self) -> Result<()> {
+        if self.flags & FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+
+        self.call(CMD_FLUSH, 0, 0, None).await?;
+        Ok(())
+    }
+
+    fn size(&self) -> ByteSize {
+        self.size
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "nbd:{}/{} ({})",
+            self.address,
+            self.export,
+            self.size.to_string_as(true)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// serves a 10 pages export from memory, every connection is dropped
+    /// after `requests` requests
+    async fn serve(listener: TcpListener, requests: usize) {
+        let mut data = vec![0u8; 10 * 1024];
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufStream::new(stream);
+            stream.write_u64(NBD_MAGIC).await.unwrap();
+            stream.write_u64(IHAVEOPT).await.unwrap();
+            stream
+                .write_u16(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)
+                .await
+                .unwrap();
+            stream.flush().await.unwrap();
+
+            assert_eq!(stream.read_u32().await.unwrap(), 3);
+            assert_eq!(stream.read_u64().await.unwrap(), IHAVEOPT);
+            assert_eq!(stream.read_u32().await.unwrap(), OPT_EXPORT_NAME);
+            let len = stream.read_u32().await.unwrap();
+            let mut name = vec![0; len as usize];
+            stream.read_exact(&mut name).await.unwrap();
+            assert_eq!(name, b"test");
+
+            stream.write_u64(data.len() as u64).await.unwrap();
+            stream.write_u16(FLAG_SEND_FLUSH).await.unwrap();
+            stream.flush().await.unwrap();
+
+            for _ in 0..requests {
+                assert_eq!(stream.read_u32().await.unwrap(), REQUEST_MAGIC);
+                stream.read_u16().await.unwrap();
+                let cmd = stream.read_u16().await.unwrap();
+                let handle = stream.read_u64().await.unwrap();
+                let offset = stream.read_u64().await.unwrap() as usize;
+                let length = stream.read_u32().await.unwrap() as usize;
+                let range = offset..offset + length;
+                if cmd == CMD_WRITE {
+                    stream.read_exact(&mut data[range.clone()]).await.unwrap();
+                }
+
+                stream.write_u32(REPLY_MAGIC).await.unwrap();
+                stream.write_u32(0).await.unwrap();
+                stream.write_u64(handle).await.unwrap();
+                if cmd == CMD_READ {
+                    stream.write_all(&data[range]).await.unwrap();
+                }
+                stream.flush().await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn nbd() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve(listener, 2));
+
+        let mut store = NbdStore::connect(address, "test", ByteSize::kib(1))
+            .await
+            .unwrap();
+        assert_eq!(store.size(), ByteSize::kib(10));
+        assert_eq!(store.page_count(), 10);
+
+        for index in 0..10 {
+            store.set(index, &[index as u8; 1024]).await.unwrap();
+        }
+
+        // the server drops the connection every 2 requests
+        // so this also goes over reconnecting
+        for index in 0..10 {
+            let page = store.get(index).await.unwrap().unwrap();
+            assert!(page.iter().all(|v| *v == index as u8));
+        }
+
+        store.flush().await.unwrap();
+        assert!(store.get(10).await.is_err());
+        assert!(store.set(0, &[0; 10]).await.is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::store::{Page, Store, VerifyReport};
+use crate::{Error, Result};
+use bytesize::ByteSize;
+
+/// size of the length prefix stored in front of each page
+pub const COMPRESS_OVERHEAD: usize = 4;
+
+/// CompressPolicy wraps a single store and compresses pages with zstd
+/// before they are written to it.
+///
+/// Each page is stored as a length prefix followed by the compressed data,
+/// a page that doesn't get smaller is stored as is with a zero length.
+/// This means the inner store needs pages that are COMPRESS_OVERHEAD bytes
+/// bigger than the pages of this store.
+///
+/// Space is only saved if the inner store keeps short pages (see
+/// [`Store::short_pages`], a DirStore for example). Other stores only take
+/// full pages so the compressed data is padded with zeros, then it only
+/// helps if the padding is not stored (a compressing file system)
+pub struct CompressPolicy<S> {
+    inner: S,
+    page_size: usize,
+    level: i32,
+}
+
+impl<S> CompressPolicy<S>
+where
+    S: Store,
+{
+    /// pages of inner must be COMPRESS_OVERHEAD bytes bigger than the
+    /// wanted page size
+    pub fn new(inner: S) -> Result<Self> {
+        if inner.page_size() <= COMPRESS_OVERHEAD {
+            return Err(Error::InvalidPageSize);
+        }
+
+        Ok(Self {
+            page_size: inner.page_size() - COMPRESS_OVERHEAD,
+            inner,
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        })
+    }
+
+    /// zstd compression level, higher is smaller but slower
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn inner(self) -> S {
+        self.inner
+    }
+
+    fn compress(&self, page: &[u8]) -> Result<Vec<u8>> {
+        let mut data = vec![0; self.inner.page_size()];
+        let compressed = zstd::bulk::compress(page, self.level)?;
+        if compressed.len() >= self.page_size {
+            data[COMPRESS_OVERHEAD..].copy_from_slice(page);
+            return Ok(data);
+        }
+
+        data[..COMPRESS_OVERHEAD].copy_from_slice(&(compressed.len() as u32).to_le_bytes());
+        data[COMPRESS_OVERHEAD..COMPRESS_OVERHEAD + compressed.len()].copy_from_slice(&compressed);
+        if self.inner.short_pages() {
+            data.truncate(COMPRESS_OVERHEAD + compressed.len());
+        }
+
+        Ok(data)
+    }
+
+    fn decompress(&self, index: u32, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < COMPRESS_OVERHEAD || data.len() > self.inner.page_size() {
+            return Err(Error::InvalidPageSize);
+        }
+
+        let (len, data) = data.split_at(COMPRESS_OVERHEAD);
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        if len == 0 {
+            // uncompressed pages are always full
+            if data.len() != self.page_size {
+                return Err(Error::InvalidPageSize);
+            }
+
+            return Ok(data.into());
+        }
+
+        if len > data.len() {
+            return Err(Error::BadCompressedPage(index));
+        }
+
+        match zstd::bulk::decompress(&data[..len], self.page_size) {
+            Ok(page) if page.len() == self.page_size => Ok(page),
+            _ => Err(Error::BadCompressedPage(index)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Store for CompressPolicy<S>
+where
+    S: Store,
+{
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        if page.len() != self.page_size {
+            return Err(Error::InvalidPageSize);
+        }
+
+        let data = self.compress(page)?;
+        self.inner.set(index, &data).await
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        match self.inner.get(index).await? {
+            Some(data) => Ok(Some(Page::Owned(self.decompress(index, &data)?))),
+            None => Ok(None),
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        self.inner.discard(index).await
+    }
+
+    fn can_discard(&self) -> bool {
+        self.inner.can_discard()
+    }
+
+    async fn verify(&self) -> Result<VerifyReport> {
+        self.inner.verify().await
+    }
+
+    fn size(&self) -> ByteSize {
+        ByteSize(self.inner.page_count() * self.page_size as u64)
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn describe(&self) -> String {
+        format!("compress({})[{}]", self.level, self.inner.describe())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::{DirStore, MemoryStore};
+
+    #[tokio::test]
+    async fn compress() {
+        let inner = MemoryStore::new(
+            ByteSize::b(10 * (1024 + COMPRESS_OVERHEAD as u64)),
+            ByteSize::b(1024 + COMPRESS_OVERHEAD as u64),
+        )
+        .unwrap();
+        let mut store = CompressPolicy::new(inner).unwrap();
+        assert_eq!(store.page_size(), 1024);
+        assert_eq!(store.page_count(), 10);
+
+        // pseudo random data does not compress so it's stored as is
+        let mut seed: u32 = 1;
+        let random: Vec<u8> = (0..1024)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        store.set(0, &random).await.unwrap();
+        store.set(1, &[0; 1024]).await.unwrap();
+
+        let page = store.get(0).await.unwrap().unwrap();
+        assert_eq!(&page[..], &random[..]);
+        let page = store.get(1).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 0));
+        assert!(store.get(2).await.unwrap().is_none());
+
+        assert!(matches!(
+            store.set(0, &[0; 10]).await,
+            Err(Error::InvalidPageSize)
+        ));
+
+        let mut inner = store.inner();
+        let stored = inner.get(1).await.unwrap().unwrap();
+        let len = u32::from_le_bytes(stored[..4].try_into().unwrap());
+        assert!(len > 0 && len < 100);
+
+        // a broken compressed page is an error, not garbage
+        let mut broken: Vec<u8> = stored.into();
+        broken[COMPRESS_OVERHEAD] ^= 0xff;
+        inner.set(1, &broken).await.unwrap();
+        let store = CompressPolicy::new(inner).unwrap();
+        assert!(matches!(
+            store.get(1).await,
+            Err(Error::BadCompressedPage(1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn compress_short_pages() {
+        const PATH: &str = "/tmp/compress.short_pages.test";
+        let _ = std::fs::remove_dir_all(PATH);
+
+        let inner = DirStore::new(
+            PATH,
+            ByteSize::b(10 * (1024 + COMPRESS_OVERHEAD as u64)),
+            ByteSize::b(1024 + COMPRESS_OVERHEAD as u64),
+        )
+        .unwrap()
+        .with_short_pages();
+        let mut store = CompressPolicy::new(inner).unwrap();
+
+        store.set(1, &[1; 1024]).await.unwrap();
+        let page = store.get(1).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 1));
+        drop(page);
+
+        // only the compressed data is written
+        let len = std::fs::metadata(format!("{PATH}/1")).unwrap().len();
+        assert!(len < 100);
+    }
+}
@@ -1,9 +1,20 @@
-use crate::store::{Page, Store};
-use crate::{Error, Result};
+use crate::store::{Page, Store, VerifyReport};
+use crate::{Error, PolicyError, Result};
 use bytesize::ByteSize;
+use std::io::ErrorKind;
+use std::path::Path;
 
 /// ConcatStore takes multiple stores and makes them
 /// act like a single big store where size = sum(sizes)
+///
+/// parts can use a different page size than the concat as long as one
+/// is a multiple of the other. A concat page then either spans multiple
+/// pages of the part, or is a slice of a single page of the part (which
+/// makes a set a read-modify-write of the part page).
+///
+/// NOTE: in the second case pages that share a part page also share its
+/// hole state, once one of them is set the others read as zeros instead
+/// of None.
 pub struct ConcatPolicy<S> {
     parts: Vec<S>,
     ps: usize,
@@ -13,17 +24,87 @@ impl<S> ConcatPolicy<S>
 where
     S: Store,
 {
+    /// concat with the page size of the first part
     pub fn new(parts: Vec<S>) -> Result<Self> {
         if parts.is_empty() {
             return Err(Error::ZeroSize);
         }
 
         let ps = parts[0].page_size();
-        if !parts.iter().all(|f| f.page_size() == ps) {
+        Self::with_page_size(parts, ps)
+    }
+
+    /// concat that serves pages of page_size whatever the page size of
+    /// the parts, see [`ConcatPolicy`]
+    pub fn with_page_size(parts: Vec<S>, page_size: usize) -> Result<Self> {
+        if parts.is_empty() {
+            return Err(Error::ZeroSize);
+        }
+
+        let valid = |ps: usize| ps != 0 && (ps % page_size == 0 || page_size % ps == 0);
+        if page_size == 0 || !parts.iter().all(|f| valid(f.page_size())) {
             return Err(Error::InvalidPageSize);
         }
 
-        Ok(Self { parts, ps })
+        Ok(Self {
+            parts,
+            ps: page_size,
+        })
+    }
+
+    /// same as new but also records the geometry (page size and page count
+    /// of each part) in a sidecar file at path. If the file already exists
+    /// the parts must match the recorded geometry exactly, otherwise a part
+    /// that was recreated with a different size will silently shift the
+    /// indices of all the parts after it.
+    pub fn with_geometry<P: AsRef<Path>>(parts: Vec<S>, path: P) -> Result<Self> {
+        let policy = Self::new(parts)?;
+        let path = path.as_ref();
+
+        let geometry = policy.geometry();
+        match std::fs::read_to_string(path) {
+            Ok(recorded) if recorded == geometry => {}
+            Ok(_) => return Err(PolicyError::GeometryChanged(path.into()).into()),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                std::fs::write(path, geometry)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        Ok(policy)
+    }
+
+    /// geometry as written in the sidecar file. page size on the first line
+    /// then one line with the page count of each part
+    fn geometry(&self) -> String {
+        let mut geometry = format!("{}\n", self.ps);
+        for part in self.parts.iter() {
+            geometry.push_str(&format!("{}\n", part.page_count()));
+        }
+
+        geometry
+    }
+
+    /// number of concat pages in part, a tail of the part that is smaller
+    /// than a concat page is not used
+    fn pages(&self, part: &S) -> u64 {
+        part.page_count() * part.page_size() as u64 / self.ps as u64
+    }
+
+    /// returns the part that holds page index and the index of the page
+    /// inside that part (in concat pages)
+    fn locate(&self, index: u32) -> Result<(usize, u64)> {
+        let mut index = index as u64;
+        for (i, part) in self.parts.iter().enumerate() {
+            let pages = self.pages(part);
+            if index < pages {
+                return Ok((i, index));
+            }
+
+            index -= pages;
+        }
+
+        Err(Error::PageIndexOutOfRange)
     }
 }
 
@@ -33,40 +114,164 @@ where
     S: Store,
 {
     async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
-        let mut index = index as usize;
-        for store in self.parts.iter_mut() {
-            let bc = store.size().0 as usize / self.ps;
-            if index < bc {
-                return store.set(index as u32, page).await;
-            }
+        let (i, index) = self.locate(index)?;
+        let ps = self.ps;
+        let part = &mut self.parts[i];
+        let pps = part.page_size();
+        if pps == ps {
+            return part.set(index as u32, page).await;
+        }
 
-            index -= bc;
+        if page.len() != ps {
+            return Err(Error::InvalidPageSize);
         }
 
-        Err(Error::PageIndexOutOfRange)
+        if pps < ps {
+            let count = (ps / pps) as u64;
+            let pages: Vec<&[u8]> = page.chunks(pps).collect();
+            return part.set_many((index * count) as u32, &pages).await;
+        }
+
+        // the page is a slice of a part page
+        let count = (pps / ps) as u64;
+        let physical = (index / count) as u32;
+        let offset = (index % count) as usize * ps;
+        let mut data: Vec<u8> = match part.get(physical).await? {
+            Some(data) => data.into(),
+            None => vec![0; pps],
+        };
+        data.resize(pps, 0);
+        data[offset..offset + ps].copy_from_slice(page);
+
+        part.set(physical, &data).await
     }
 
     async fn get(&self, index: u32) -> Result<Option<Page>> {
-        let mut index = index as usize;
-        for store in self.parts.iter() {
-            let bc = store.size().0 as usize / self.ps;
-            if index < bc {
-                return store.get(index as u32).await;
+        let (i, index) = self.locate(index)?;
+        let ps = self.ps;
+        let part = &self.parts[i];
+        let pps = part.page_size();
+        if pps == ps {
+            return part.get(index as u32).await;
+        }
+
+        // missing part pages (or the missing tail of a short one) read as zeros
+        let copy = |dest: &mut [u8], src: &[u8]| {
+            let len = dest.len().min(src.len());
+            dest[..len].copy_from_slice(&src[..len]);
+        };
+
+        if pps < ps {
+            let count = (ps / pps) as u64;
+            let mut data: Option<Vec<u8>> = None;
+            for j in 0..count {
+                if let Some(page) = part.get((index * count + j) as u32).await? {
+                    let data = data.get_or_insert_with(|| vec![0; ps]);
+                    let offset = j as usize * pps;
+                    copy(&mut data[offset..offset + pps], &page[..]);
+                }
             }
 
-            index -= bc;
+            return Ok(data.map(Page::Owned));
         }
 
-        Err(Error::PageIndexOutOfRange)
+        let count = (pps / ps) as u64;
+        let offset = (index % count) as usize * ps;
+        let page = part.get((index / count) as u32).await?;
+        Ok(page.map(|page| {
+            let mut data = vec![0; ps];
+            copy(&mut data, page.get(offset..).unwrap_or_default());
+            Page::Owned(data)
+        }))
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        let (i, index) = self.locate(index)?;
+        let ps = self.ps;
+        let part = &mut self.parts[i];
+        let pps = part.page_size();
+        if pps > ps {
+            // only a slice of a part page, it can't be freed
+            return Ok(());
+        }
+
+        let count = (ps / pps) as u64;
+        for j in 0..count {
+            part.discard((index * count + j) as u32).await?;
+        }
+
+        Ok(())
+    }
+
+    /// parts with bigger pages than the concat hold slices that can't be
+    /// freed
+    fn can_discard(&self) -> bool {
+        self.parts
+            .iter()
+            .all(|part| part.page_size() <= self.ps && part.can_discard())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        for part in self.parts.iter() {
+            part.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// reports are in pages of the parts
+    async fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for part in self.parts.iter() {
+            report += part.verify().await?;
+        }
+
+        Ok(report)
+    }
+
+    // the fast paths are only used for parts with the same page size
+
+    fn try_get_sync(&self, index: u32) -> Option<Result<Option<Page>>> {
+        let (i, index) = match self.locate(index) {
+            Ok(location) => location,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let part = &self.parts[i];
+        if part.page_size() != self.ps {
+            return None;
+        }
+
+        part.try_get_sync(index as u32)
+    }
+
+    fn try_set_sync(&mut self, index: u32, page: &[u8]) -> Option<Result<()>> {
+        let (i, index) = match self.locate(index) {
+            Ok(location) => location,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let ps = self.ps;
+        let part = &mut self.parts[i];
+        if part.page_size() != ps {
+            return None;
+        }
+
+        part.try_set_sync(index as u32, page)
     }
 
     fn size(&self) -> ByteSize {
-        self.parts.iter().fold(ByteSize(0), |t, i| t + i.size())
+        let pages: u64 = self.parts.iter().map(|part| self.pages(part)).sum();
+        ByteSize(pages * self.ps as u64)
     }
 
     fn page_size(&self) -> usize {
         self.ps
     }
+
+    fn describe(&self) -> String {
+        super::describe_parts("concat", &self.parts)
+    }
 }
 
 #[cfg(test)]
@@ -105,4 +310,86 @@ mod test {
         // this then should be at index 0
         assert!(mem.get(&0).is_some());
     }
+
+    #[tokio::test]
+    async fn test_concat_scaled() {
+        // each concat page spans 2 pages of the parts
+        let mut store =
+            ConcatPolicy::with_page_size(vec![InMemory::new(10), InMemory::new(5)], 2048).unwrap();
+        assert_eq!(store.page_count(), 7);
+        assert_eq!(store.size(), ByteSize::kib(14));
+
+        let mut data = [1; 2048];
+        data[1024..].fill(2);
+        store.set(5, &data).await.unwrap();
+        assert_eq!(Vec::from(store.get(5).await.unwrap().unwrap()), data);
+        assert!(store.get(6).await.unwrap().is_none());
+        assert!(store.get(7).await.is_err());
+
+        let mem = &store.parts[1].mem;
+        assert!(mem[&0].iter().all(|v| *v == 1));
+        assert!(mem[&1].iter().all(|v| *v == 2));
+
+        // each concat page is half a page of the parts
+        let mut store =
+            ConcatPolicy::with_page_size(vec![InMemory::new(10), InMemory::new(5)], 512).unwrap();
+        assert_eq!(store.page_count(), 30);
+
+        store.set(21, &[3; 512]).await.unwrap();
+        assert_eq!(
+            Vec::from(store.get(21).await.unwrap().unwrap()),
+            vec![3u8; 512]
+        );
+        // the other half of the part page reads as zeros
+        assert_eq!(
+            Vec::from(store.get(20).await.unwrap().unwrap()),
+            vec![0u8; 512]
+        );
+
+        let page = &store.parts[1].mem[&0];
+        assert!(page[..512].iter().all(|v| *v == 0));
+        assert!(page[512..].iter().all(|v| *v == 3));
+
+        assert!(ConcatPolicy::with_page_size(vec![InMemory::new(10)], 1000).is_err());
+    }
+
+    #[test]
+    fn test_concat_describe() {
+        let store = ConcatPolicy::new(vec![InMemory::new(10), InMemory::new(20)]).unwrap();
+        let size = |pages: u64| ByteSize::kib(pages).to_string_as(true);
+
+        assert_eq!(
+            store.describe(),
+            format!("concat[store ({}), store ({})]", size(10), size(20))
+        );
+    }
+
+    #[test]
+    fn test_concat_geometry() {
+        const PATH: &str = "/tmp/concat.geometry.test";
+        // start from clean slate
+        let _ = std::fs::remove_file(PATH);
+
+        let parts = vec![InMemory::new(10), InMemory::new(20)];
+        assert!(ConcatPolicy::with_geometry(parts, PATH).is_ok());
+
+        // same composition can be opened again
+        let parts = vec![InMemory::new(10), InMemory::new(20)];
+        assert!(ConcatPolicy::with_geometry(parts, PATH).is_ok());
+
+        // first part got smaller
+        let parts = vec![InMemory::new(5), InMemory::new(20)];
+        assert!(matches!(
+            ConcatPolicy::with_geometry(parts, PATH),
+            Err(Error::PolicyError(PolicyError::GeometryChanged(_)))
+        ));
+
+        // parts were reordered
+        let parts = vec![InMemory::new(20), InMemory::new(10)];
+        assert!(ConcatPolicy::with_geometry(parts, PATH).is_err());
+
+        // a new part was added
+        let parts = vec![InMemory::new(10), InMemory::new(20), InMemory::new(10)];
+        assert!(ConcatPolicy::with_geometry(parts, PATH).is_err());
+    }
 }
@@ -0,0 +1,140 @@
+use crate::store::{Page, Store, VerifyReport};
+use crate::Result;
+use bytesize::ByteSize;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// DelayPolicy wraps a store and delays every get and set by a fixed
+/// duration plus an optional random jitter. It's only useful for testing
+/// the cache against a store with realistic latency.
+pub struct DelayPolicy<S> {
+    inner: S,
+    delay: Duration,
+    jitter: Duration,
+    // used to generate the jitter, no need for a real random number generator
+    state: RandomState,
+    counter: AtomicU64,
+}
+
+impl<S> DelayPolicy<S>
+where
+    S: Store,
+{
+    pub fn new(inner: S, delay: Duration) -> Self {
+        Self {
+            inner,
+            delay,
+            jitter: Duration::ZERO,
+            state: RandomState::new(),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// add a random delay in [0, jitter] to each operation
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn inner(self) -> S {
+        self.inner
+    }
+
+    fn delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+
+        let mut hasher = self.state.build_hasher();
+        hasher.write_u64(self.counter.fetch_add(1, Ordering::Relaxed));
+        let jitter = hasher.finish() % (self.jitter.as_nanos() as u64 + 1);
+
+        self.delay + Duration::from_nanos(jitter)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Store for DelayPolicy<S>
+where
+    S: Store,
+{
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        tokio::time::sleep(self.delay()).await;
+        self.inner.set(index, page).await
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        tokio::time::sleep(self.delay()).await;
+        self.inner.get(index).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        self.inner.discard(index).await
+    }
+
+    fn can_discard(&self) -> bool {
+        self.inner.can_discard()
+    }
+
+    fn short_pages(&self) -> bool {
+        self.inner.short_pages()
+    }
+
+    async fn verify(&self) -> Result<VerifyReport> {
+        self.inner.verify().await
+    }
+
+    fn size(&self) -> ByteSize {
+        self.inner.size()
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "delay({:?}+{:?})[{}]",
+            self.delay,
+            self.jitter,
+            self.inner.describe()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::InMemory;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_delay() {
+        let mut store = DelayPolicy::new(InMemory::new(10), Duration::from_millis(20))
+            .with_jitter(Duration::from_millis(10));
+
+        let start = Instant::now();
+        store.set(1, &[1; 1024]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let start = Instant::now();
+        let page = store.get(1).await.unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(page.iter().all(|v| *v == 1));
+
+        // no sync fast path that could skip the delay
+        assert!(store.try_get_sync(1).is_none());
+
+        for _ in 0..100 {
+            let delay = store.delay();
+            assert!(delay >= Duration::from_millis(20));
+            assert!(delay <= Duration::from_millis(30));
+        }
+    }
+}
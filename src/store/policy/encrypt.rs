@@ -0,0 +1,178 @@
+use crate::store::{Page, Store, VerifyReport};
+use crate::{Error, Result};
+use aes_gcm_siv::{
+    aead::{AeadInPlace, KeyInit},
+    Aes256GcmSiv, Nonce, Tag,
+};
+use bytesize::ByteSize;
+
+/// size of the auth tag stored after each page
+pub const ENCRYPT_OVERHEAD: usize = 16;
+
+/// EncryptPolicy wraps a single store and encrypts pages with
+/// AES-256-GCM-SIV before they are written to it.
+///
+/// The nonce of a page is derived from its index so a page is always
+/// encrypted the same way and nothing but the key has to be kept. GCM-SIV
+/// stays safe when a nonce is used again (which happens on every rewrite
+/// of a page), the only thing it leaks is that the same data was written
+/// to the same page again. Since the nonce depends on the index a page
+/// copied to another index fails to decrypt too.
+///
+/// The auth tag is stored after the encrypted page, so the inner store
+/// needs pages that are ENCRYPT_OVERHEAD bytes bigger than the pages of
+/// this store
+pub struct EncryptPolicy<S> {
+    inner: S,
+    cipher: Aes256GcmSiv,
+    page_size: usize,
+}
+
+impl<S> EncryptPolicy<S>
+where
+    S: Store,
+{
+    /// pages of inner must be ENCRYPT_OVERHEAD bytes bigger than the
+    /// wanted page size
+    pub fn new(inner: S, key: &[u8; 32]) -> Result<Self> {
+        if inner.page_size() <= ENCRYPT_OVERHEAD {
+            return Err(Error::InvalidPageSize);
+        }
+
+        Ok(Self {
+            page_size: inner.page_size() - ENCRYPT_OVERHEAD,
+            cipher: Aes256GcmSiv::new(key.into()),
+            inner,
+        })
+    }
+
+    pub fn inner(self) -> S {
+        self.inner
+    }
+
+    fn nonce(index: u32) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[..4].copy_from_slice(&index.to_le_bytes());
+        nonce
+    }
+
+    fn encrypt(&self, index: u32, page: &[u8]) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.inner.page_size());
+        data.extend_from_slice(page);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&Self::nonce(index), b"", &mut data)
+            .map_err(|_| anyhow::anyhow!("failed to encrypt page {index}"))?;
+        data.extend_from_slice(&tag);
+
+        Ok(data)
+    }
+
+    fn decrypt(&self, index: u32, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() != self.inner.page_size() {
+            return Err(Error::InvalidPageSize);
+        }
+
+        let (data, tag) = data.split_at(self.page_size);
+        let mut page = Vec::from(data);
+        self.cipher
+            .decrypt_in_place_detached(&Self::nonce(index), b"", &mut page, Tag::from_slice(tag))
+            .map_err(|_| Error::BadAuthTag(index))?;
+
+        Ok(page)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Store for EncryptPolicy<S>
+where
+    S: Store,
+{
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        if page.len() != self.page_size {
+            return Err(Error::InvalidPageSize);
+        }
+
+        let data = self.encrypt(index, page)?;
+        self.inner.set(index, &data).await
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        match self.inner.get(index).await? {
+            Some(data) => Ok(Some(Page::Owned(self.decrypt(index, &data)?))),
+            None => Ok(None),
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        self.inner.discard(index).await
+    }
+
+    fn can_discard(&self) -> bool {
+        self.inner.can_discard()
+    }
+
+    async fn verify(&self) -> Result<VerifyReport> {
+        self.inner.verify().await
+    }
+
+    fn size(&self) -> ByteSize {
+        ByteSize(self.inner.page_count() * self.page_size as u64)
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn describe(&self) -> String {
+        format!("encrypt[{}]", self.inner.describe())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[tokio::test]
+    async fn encrypt() {
+        const PAGE: u64 = 1024 + ENCRYPT_OVERHEAD as u64;
+        let inner = MemoryStore::new(ByteSize::b(10 * PAGE), ByteSize::b(PAGE)).unwrap();
+        let mut store = EncryptPolicy::new(inner, &[7; 32]).unwrap();
+        assert_eq!(store.page_size(), 1024);
+        assert_eq!(store.page_count(), 10);
+
+        store.set(1, &[1; 1024]).await.unwrap();
+        store.set(2, &[1; 1024]).await.unwrap();
+        let page = store.get(1).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 1));
+        drop(page);
+        assert!(store.get(3).await.unwrap().is_none());
+
+        let mut inner = store.inner();
+        let one: Vec<u8> = inner.get(1).await.unwrap().unwrap().into();
+        let two: Vec<u8> = inner.get(2).await.unwrap().unwrap().into();
+        // same data on another page encrypts differently
+        assert_ne!(one, two);
+        assert!(!one[..1024].iter().all(|v| *v == 1));
+
+        // a changed byte or a page moved to another index fails
+        let mut tampered = one.clone();
+        tampered[10] ^= 1;
+        inner.set(1, &tampered).await.unwrap();
+        inner.set(2, &one).await.unwrap();
+        let store = EncryptPolicy::new(inner, &[7; 32]).unwrap();
+        assert!(matches!(store.get(1).await, Err(Error::BadAuthTag(1))));
+        assert!(matches!(store.get(2).await, Err(Error::BadAuthTag(2))));
+
+        // so does the wrong key
+        let mut inner = store.inner();
+        inner.set(1, &one).await.unwrap();
+        let store = EncryptPolicy::new(inner, &[8; 32]).unwrap();
+        assert!(matches!(store.get(1).await, Err(Error::BadAuthTag(1))));
+    }
+}
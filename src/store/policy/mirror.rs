@@ -1,10 +1,25 @@
-use crate::store::{Page, Store};
-use crate::{Error, PolicyError, Result};
+use crate::store::{Page, Store, VerifyReport};
+use crate::{metrics, Error, PolicyError, Result};
 use anyhow::Context;
 use bytesize::ByteSize;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
 use std::sync::Arc;
 use tokio::task::JoinSet;
 
+lazy_static! {
+    static ref MIRROR_READ_FALLBACKS: IntCounter = register_int_counter!(
+        metrics::name("mirror_read_fallbacks"),
+        "number of reads served by another replica after a replica failed or returned a corrupted page"
+    )
+    .unwrap();
+    static ref MIRROR_WRITE_DEGRADED: IntCounter = register_int_counter!(
+        metrics::name("mirror_write_degraded"),
+        "number of writes that failed on a replica but still reached the write quorum"
+    )
+    .unwrap();
+}
+
 use tokio::sync::mpsc::Sender as Channel;
 use tokio::sync::oneshot::Sender as OneShotSender;
 
@@ -18,6 +33,13 @@ enum Request {
         index: u32,
         reply_on: OneShotSender<Result<Option<Vec<u8>>>>,
     },
+    Flush {
+        reply_on: OneShotSender<Result<()>>,
+    },
+    Discard {
+        index: u32,
+        reply_on: OneShotSender<Result<()>>,
+    },
 }
 
 fn mirror<S: Store>(mut store: S) -> Channel<Request> {
@@ -38,6 +60,12 @@ fn mirror<S: Store>(mut store: S) -> Channel<Request> {
                     let result = store.set(index, &page).await;
                     let _ = reply_on.send(result);
                 }
+                Request::Flush { reply_on } => {
+                    let _ = reply_on.send(store.flush().await);
+                }
+                Request::Discard { index, reply_on } => {
+                    let _ = reply_on.send(store.discard(index).await);
+                }
             }
         }
     });
@@ -49,17 +77,39 @@ fn mirror<S: Store>(mut store: S) -> Channel<Request> {
 /// act like a single mirrored stores where size = size of a single instance
 /// on writing the data must be written to the 2 stores at the same time
 /// on read, the data is retrieved from the first store that answers
+/// successfully. A replica that fails (or has a bad crc for the page)
+/// is skipped
+///
+/// a write succeeds once write_quorum replicas have it (all of them by
+/// default), the other replicas keep writing in the background and their
+/// failures are only logged. A replica that failed a write can then serve
+/// an old version of the page until it is rewritten
 pub struct MirrorPolicy {
     bs: usize,
     size: ByteSize,
     channels: Vec<Channel<Request>>,
+    write_quorum: usize,
+    // parts are moved to their own tasks so we keep the description
+    description: String,
+    can_discard: bool,
 }
 
 impl MirrorPolicy {
     pub fn new<S: Store>(parts: Vec<S>) -> Result<Self> {
+        let write_quorum = parts.len();
+        Self::with_write_quorum(parts, write_quorum)
+    }
+
+    /// a write succeeds once write_quorum of the parts have it
+    pub fn with_write_quorum<S: Store>(parts: Vec<S>, write_quorum: usize) -> Result<Self> {
         if parts.is_empty() {
             return Err(Error::ZeroSize);
         }
+
+        if write_quorum == 0 || write_quorum > parts.len() {
+            return Err(PolicyError::InvalidWriteQuorum(write_quorum, parts.len()).into());
+        }
+
         let size = parts[0].size();
         if !parts.iter().all(|f| f.size() == size) {
             return Err(PolicyError::StoresNotSameSize.into());
@@ -70,20 +120,99 @@ impl MirrorPolicy {
             return Err(Error::InvalidPageSize);
         }
 
+        let description = super::describe_parts("mirror", &parts);
+        let can_discard = parts.iter().all(|part| part.can_discard());
         let mut channels = vec![];
         for sub in parts {
             let ch = mirror(sub);
             channels.push(ch);
         }
 
-        Ok(Self { bs, size, channels })
+        Ok(Self {
+            bs,
+            size,
+            channels,
+            write_quorum,
+            description,
+            can_discard,
+        })
+    }
+
+    /// reads the page from all replicas, unlike get it waits for all of them
+    async fn get_all(&self, index: u32) -> Result<Vec<Result<Option<Vec<u8>>>>> {
+        let mut set = JoinSet::new();
+        for sub in self.channels.iter() {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+
+            let request = Request::Get {
+                index,
+                reply_on: tx,
+            };
+
+            if sub.send(request).await.is_err() {
+                log::error!("failed to send request to store");
+                continue;
+            }
+
+            set.spawn(rx);
+        }
+
+        let mut results = vec![];
+        while let Some(result) = set.join_next().await {
+            let result = result
+                .context("joining get request")?
+                .context("receive response from mirrored store")?;
+
+            results.push(result);
+        }
+
+        Ok(results)
+    }
+
+    /// sends the request built by request to all replicas and waits for all
+    /// of them. Like a set, it fails if less than write_quorum replicas
+    /// succeeded
+    async fn broadcast<F>(&self, op: &str, request: F) -> Result<()>
+    where
+        F: Fn(OneShotSender<Result<()>>) -> Request,
+    {
+        let mut set = JoinSet::new();
+        for sub in self.channels.iter() {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+
+            if sub.send(request(tx)).await.is_err() {
+                log::error!("failed to send {op} request to store");
+                continue;
+            }
+
+            set.spawn(rx);
+        }
+
+        let mut acked = 0;
+        while let Some(result) = set.join_next().await {
+            match result {
+                Ok(Ok(Ok(_))) => acked += 1,
+                Ok(Ok(Err(err))) => log::error!("failed to {op} a replica: {err:#}"),
+                _ => log::error!("replica never answered the {op} request"),
+            }
+        }
+
+        if acked < self.write_quorum {
+            return Err(anyhow::anyhow!(
+                "{op} succeeded on {acked} replicas, quorum is {}",
+                self.write_quorum
+            )
+            .into());
+        }
+
+        Ok(())
     }
 }
 
 #[async_trait::async_trait]
 impl Store for MirrorPolicy {
     async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
-        if index as u64 >= self.size.0 {
+        if index as u64 >= self.page_count() {
             return Err(Error::PageIndexOutOfRange);
         }
 
@@ -110,21 +239,60 @@ impl Store for MirrorPolicy {
         // the first Result is the join_next() result itself
         // inside that the result of `rx;await`
         // then the final result from the actual called operation
-        while let Some(result) = set.join_next().await {
+        let mut failed = self.channels.len() - set.len();
+        let mut acked = 0;
+        while acked < self.write_quorum {
+            let Some(result) = set.join_next().await else {
+                break;
+            };
+
             // result is 3 layers of result since each can fail separated
             let result = result
                 .context("joining set request")?
                 .context("receive response from mirrored store")?;
 
-            result?;
+            match result {
+                Ok(_) => acked += 1,
+                Err(err) if self.channels.len() - failed - 1 < self.write_quorum => {
+                    return Err(err)
+                }
+                Err(err) => {
+                    log::error!("failed to write page {index} to a replica: {err:#}");
+                    MIRROR_WRITE_DEGRADED.inc();
+                    failed += 1;
+                }
+            }
+        }
+
+        if acked < self.write_quorum {
+            return Err(anyhow::anyhow!(
+                "page {index} written to {acked} replicas, quorum is {}",
+                self.write_quorum
+            )
+            .into());
+        }
+
+        // the quorum has the page, the rest is written in the background
+        if !set.is_empty() {
+            tokio::spawn(async move {
+                while let Some(result) = set.join_next().await {
+                    match result {
+                        Ok(Ok(Ok(_))) => continue,
+                        Ok(Ok(Err(err))) => {
+                            log::error!("failed to write page {index} to a replica: {err:#}")
+                        }
+                        _ => log::error!("replica never answered the write of page {index}"),
+                    }
+                    MIRROR_WRITE_DEGRADED.inc();
+                }
+            });
         }
 
-        // all write operation succeeded
         Ok(())
     }
 
     async fn get(&self, index: u32) -> Result<Option<Page>> {
-        if index as u64 >= self.size.0 {
+        if index as u64 >= self.page_count() {
             return Err(Error::PageIndexOutOfRange);
         }
 
@@ -148,6 +316,8 @@ impl Store for MirrorPolicy {
         // the first Result is the join_next() result itself
         // inside that the result of `rx;await`
         // then the final result from the actual called operation
+        let mut failed = 0;
+        let mut corrupted = 0;
         while let Some(result) = set.join_next().await {
             // result is 3 layers of result since each can fail separated
             let result = result
@@ -157,17 +327,72 @@ impl Store for MirrorPolicy {
             match result {
                 Err(err) => {
                     log::error!("store return error: {:#}", err);
+                    failed += 1;
+                    if matches!(err, Error::BadCrc(_)) {
+                        corrupted += 1;
+                    }
                     continue;
                 }
                 Ok(result) => {
+                    if failed > 0 {
+                        MIRROR_READ_FALLBACKS.inc();
+                    }
                     return Ok(result.map(Page::Owned));
                 }
             }
         }
 
-        return Err(
-            anyhow::anyhow!("all stores failed to answer the request, please check logs").into(),
-        );
+        if corrupted > 0 && corrupted == failed {
+            return Err(Error::BadCrc(index));
+        }
+
+        Err(anyhow::anyhow!("all stores failed to answer the request, please check logs").into())
+    }
+
+    /// all mirrors must be flushed
+    async fn flush(&self) -> Result<()> {
+        self.broadcast("flush", |reply_on| Request::Flush { reply_on })
+            .await
+    }
+
+    /// every page is read from all replicas. A page is corrupt if a replica
+    /// fails or doesn't have the same data as the others, but at least one
+    /// replica can still read it
+    async fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for index in 0..self.page_count() as u32 {
+            let results = self.get_all(index).await?;
+            let pages: Vec<&Option<Vec<u8>>> = results.iter().flatten().collect();
+
+            if pages.is_empty() {
+                log::error!("page {index} can't be read from any replica");
+                report.unrecoverable += 1;
+                continue;
+            }
+
+            let agree = pages.iter().all(|page| *page == pages[0]);
+            if agree && pages.len() == self.channels.len() {
+                if pages[0].is_some() {
+                    report.good += 1;
+                }
+                continue;
+            }
+
+            log::error!("replicas of page {index} don't agree");
+            report.corrupt += 1;
+        }
+
+        Ok(report)
+    }
+
+    /// the page is discarded on all mirrors
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        if index as u64 >= self.page_count() {
+            return Err(Error::PageIndexOutOfRange);
+        }
+
+        self.broadcast("discard", |reply_on| Request::Discard { index, reply_on })
+            .await
     }
 
     fn size(&self) -> ByteSize {
@@ -177,4 +402,158 @@ impl Store for MirrorPolicy {
     fn page_size(&self) -> usize {
         self.bs
     }
+
+    fn can_discard(&self) -> bool {
+        self.can_discard
+    }
+
+    fn describe(&self) -> String {
+        self.description.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::InMemory;
+    use std::time::Duration;
+
+    /// a replica where every page is corrupted
+    struct Corrupted;
+
+    #[async_trait::async_trait]
+    impl Store for Corrupted {
+        async fn set(&mut self, _index: u32, _page: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get(&self, index: u32) -> Result<Option<Page>> {
+            Err(Error::BadCrc(index))
+        }
+
+        fn size(&self) -> ByteSize {
+            ByteSize::kib(10)
+        }
+
+        fn page_size(&self) -> usize {
+            1024
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupted_replica() {
+        let parts: Vec<Box<dyn Store>> = vec![Box::new(Corrupted), Box::new(InMemory::new(10))];
+        let mut store = MirrorPolicy::new(parts).unwrap();
+
+        store.set(1, &[1; 1024]).await.unwrap();
+        let page = store.get(1).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 1));
+
+        // all replicas are corrupted
+        let parts: Vec<Box<dyn Store>> = vec![Box::new(Corrupted), Box::new(Corrupted)];
+        let store = MirrorPolicy::new(parts).unwrap();
+        assert!(matches!(store.get(1).await, Err(Error::BadCrc(1))));
+    }
+
+    #[tokio::test]
+    async fn verify() {
+        let mut store = MirrorPolicy::new(vec![InMemory::new(10), InMemory::new(10)]).unwrap();
+        store.set(1, &[1; 1024]).await.unwrap();
+        store.set(2, &[2; 1024]).await.unwrap();
+        let report = store.verify().await.unwrap();
+        assert_eq!(report.good, 2);
+        assert_eq!(report.corrupt + report.unrecoverable, 0);
+
+        // every page can still be read from the good replica
+        let parts: Vec<Box<dyn Store>> = vec![Box::new(Corrupted), Box::new(InMemory::new(10))];
+        let report = MirrorPolicy::new(parts).unwrap().verify().await.unwrap();
+        assert_eq!((report.corrupt, report.unrecoverable), (10, 0));
+
+        let parts: Vec<Box<dyn Store>> = vec![Box::new(Corrupted), Box::new(Corrupted)];
+        let report = MirrorPolicy::new(parts).unwrap().verify().await.unwrap();
+        assert_eq!((report.corrupt, report.unrecoverable), (0, 10));
+    }
+
+    /// a replica that fails every write
+    struct Failing;
+
+    #[async_trait::async_trait]
+    impl Store for Failing {
+        async fn set(&mut self, index: u32, _page: &[u8]) -> Result<()> {
+            Err(anyhow::anyhow!("failed to write page {index}").into())
+        }
+
+        async fn get(&self, index: u32) -> Result<Option<Page>> {
+            Err(anyhow::anyhow!("failed to read page {index}").into())
+        }
+
+        async fn flush(&self) -> Result<()> {
+            Err(anyhow::anyhow!("failed to flush").into())
+        }
+
+        async fn discard(&mut self, index: u32) -> Result<()> {
+            Err(anyhow::anyhow!("failed to discard page {index}").into())
+        }
+
+        fn size(&self) -> ByteSize {
+            ByteSize::kib(10)
+        }
+
+        fn page_size(&self) -> usize {
+            1024
+        }
+    }
+
+    #[tokio::test]
+    async fn write_quorum() {
+        let parts = || -> Vec<Box<dyn Store>> {
+            vec![
+                Box::new(InMemory::new(10)),
+                Box::new(Failing),
+                Box::new(InMemory::new(10)),
+            ]
+        };
+
+        // all replicas must have the page by default
+        let mut store = MirrorPolicy::new(parts()).unwrap();
+        assert!(store.set(1, &[1; 1024]).await.is_err());
+
+        let mut store = MirrorPolicy::with_write_quorum(parts(), 2).unwrap();
+        let degraded = MIRROR_WRITE_DEGRADED.get();
+        store.set(1, &[1; 1024]).await.unwrap();
+        let page = store.get(1).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 1));
+        drop(page);
+        // the failure is counted in the background if the quorum was
+        // reached before the failed replica answered
+        for _ in 0..100 {
+            if MIRROR_WRITE_DEGRADED.get() > degraded {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(MIRROR_WRITE_DEGRADED.get() > degraded);
+
+        // one good replica is not enough
+        let weak: Vec<Box<dyn Store>> = vec![
+            Box::new(Failing),
+            Box::new(InMemory::new(10)),
+            Box::new(Failing),
+        ];
+        let mut store = MirrorPolicy::with_write_quorum(weak, 2).unwrap();
+        assert!(store.set(1, &[1; 1024]).await.is_err());
+        assert!(store.flush().await.is_err());
+        assert!(store.discard(1).await.is_err());
+
+        // flush and discard follow the same quorum
+        let mut store = MirrorPolicy::new(parts()).unwrap();
+        assert!(store.flush().await.is_err());
+        assert!(store.discard(1).await.is_err());
+        let mut store = MirrorPolicy::with_write_quorum(parts(), 2).unwrap();
+        store.flush().await.unwrap();
+        store.discard(1).await.unwrap();
+
+        assert!(MirrorPolicy::with_write_quorum(parts(), 0).is_err());
+        assert!(MirrorPolicy::with_write_quorum(parts(), 4).is_err());
+    }
 }
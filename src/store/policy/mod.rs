@@ -4,18 +4,34 @@
 //!
 //! for example a ConcatStore appends 2 or more stores together so that
 //! they appear as a bigger single store.
+mod compress;
 mod concat;
+mod delay;
+mod encrypt;
 mod mirror;
+mod retry;
 mod strip;
+mod throttle;
 
 use bytesize::ByteSize;
+pub use compress::{CompressPolicy, COMPRESS_OVERHEAD};
 pub use concat::ConcatPolicy;
+pub use delay::DelayPolicy;
+pub use encrypt::{EncryptPolicy, ENCRYPT_OVERHEAD};
 pub use mirror::MirrorPolicy;
+pub use retry::RetryPolicy;
 pub use strip::StripPolicy;
+pub use throttle::ThrottlePolicy;
 
-use super::{Page, Store};
+use super::{Page, Store, VerifyReport};
 use crate::Result;
 
+/// describes a policy over parts as `name[part, part, ...]`
+fn describe_parts<S: Store>(name: &str, parts: &[S]) -> String {
+    let parts: Vec<String> = parts.iter().map(|part| part.describe()).collect();
+    format!("{name}[{}]", parts.join(", "))
+}
+
 pub enum Policy<S>
 where
     S: Store,
@@ -23,6 +39,10 @@ where
     Concat(ConcatPolicy<S>),
     Strip(StripPolicy<S>),
     Mirror(MirrorPolicy),
+    Throttle(ThrottlePolicy<S>),
+    Compress(CompressPolicy<S>),
+    Encrypt(EncryptPolicy<S>),
+    Retry(RetryPolicy<S>),
 }
 
 impl<S> Policy<S>
@@ -42,6 +62,30 @@ where
     pub fn mirror(parts: Vec<S>) -> Result<Self> {
         Ok(Self::Mirror(MirrorPolicy::new(parts)?))
     }
+
+    /// build a new throttle policy that limits concurrent
+    /// operations on inner store to max_inflight
+    pub fn throttle(inner: S, max_inflight: usize) -> Result<Self> {
+        Ok(Self::Throttle(ThrottlePolicy::new(inner, max_inflight)?))
+    }
+
+    /// build a new compress policy over inner, see [`CompressPolicy`]
+    /// for the page size inner needs
+    pub fn compress(inner: S) -> Result<Self> {
+        Ok(Self::Compress(CompressPolicy::new(inner)?))
+    }
+
+    /// build a new encrypt policy over inner with key, see
+    /// [`EncryptPolicy`] for the page size inner needs
+    pub fn encrypt(inner: S, key: &[u8; 32]) -> Result<Self> {
+        Ok(Self::Encrypt(EncryptPolicy::new(inner, key)?))
+    }
+
+    /// build a new retry policy that tries failed operations on inner
+    /// up to max_attempts times, see [`RetryPolicy`]
+    pub fn retry(inner: S, max_attempts: usize) -> Result<Self> {
+        Ok(Self::Retry(RetryPolicy::new(inner, max_attempts)?))
+    }
 }
 
 #[async_trait::async_trait]
@@ -55,6 +99,10 @@ where
             Self::Concat(inner) => inner.set(index, page).await,
             Self::Strip(inner) => inner.set(index, page).await,
             Self::Mirror(inner) => inner.set(index, page).await,
+            Self::Throttle(inner) => inner.set(index, page).await,
+            Self::Compress(inner) => inner.set(index, page).await,
+            Self::Encrypt(inner) => inner.set(index, page).await,
+            Self::Retry(inner) => inner.set(index, page).await,
         }
     }
 
@@ -64,6 +112,115 @@ where
             Self::Concat(inner) => inner.get(index).await,
             Self::Strip(inner) => inner.get(index).await,
             Self::Mirror(inner) => inner.get(index).await,
+            Self::Throttle(inner) => inner.get(index).await,
+            Self::Compress(inner) => inner.get(index).await,
+            Self::Encrypt(inner) => inner.get(index).await,
+            Self::Retry(inner) => inner.get(index).await,
+        }
+    }
+
+    async fn get_many(&self, indices: &[u32]) -> Result<Vec<Option<Page>>> {
+        match self {
+            Self::Concat(inner) => inner.get_many(indices).await,
+            Self::Strip(inner) => inner.get_many(indices).await,
+            Self::Mirror(inner) => inner.get_many(indices).await,
+            Self::Throttle(inner) => inner.get_many(indices).await,
+            Self::Compress(inner) => inner.get_many(indices).await,
+            Self::Encrypt(inner) => inner.get_many(indices).await,
+            Self::Retry(inner) => inner.get_many(indices).await,
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        match self {
+            Self::Concat(inner) => inner.flush().await,
+            Self::Strip(inner) => inner.flush().await,
+            Self::Mirror(inner) => inner.flush().await,
+            Self::Throttle(inner) => inner.flush().await,
+            Self::Compress(inner) => inner.flush().await,
+            Self::Encrypt(inner) => inner.flush().await,
+            Self::Retry(inner) => inner.flush().await,
+        }
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        match self {
+            Self::Concat(inner) => inner.discard(index).await,
+            Self::Strip(inner) => inner.discard(index).await,
+            Self::Mirror(inner) => inner.discard(index).await,
+            Self::Throttle(inner) => inner.discard(index).await,
+            Self::Compress(inner) => inner.discard(index).await,
+            Self::Encrypt(inner) => inner.discard(index).await,
+            Self::Retry(inner) => inner.discard(index).await,
+        }
+    }
+
+    fn can_discard(&self) -> bool {
+        match self {
+            Self::Concat(inner) => inner.can_discard(),
+            Self::Strip(inner) => inner.can_discard(),
+            Self::Mirror(inner) => inner.can_discard(),
+            Self::Throttle(inner) => inner.can_discard(),
+            Self::Compress(inner) => inner.can_discard(),
+            Self::Encrypt(inner) => inner.can_discard(),
+            Self::Retry(inner) => inner.can_discard(),
+        }
+    }
+
+    /// only policies that pass pages through unchanged keep short pages
+    fn short_pages(&self) -> bool {
+        match self {
+            Self::Throttle(inner) => inner.short_pages(),
+            Self::Retry(inner) => inner.short_pages(),
+            _ => false,
+        }
+    }
+
+    async fn verify(&self) -> Result<VerifyReport> {
+        match self {
+            Self::Concat(inner) => inner.verify().await,
+            Self::Strip(inner) => inner.verify().await,
+            Self::Mirror(inner) => inner.verify().await,
+            Self::Throttle(inner) => inner.verify().await,
+            Self::Compress(inner) => inner.verify().await,
+            Self::Encrypt(inner) => inner.verify().await,
+            Self::Retry(inner) => inner.verify().await,
+        }
+    }
+
+    async fn set_many(&mut self, index: u32, pages: &[&[u8]]) -> Result<()> {
+        match self {
+            Self::Concat(inner) => inner.set_many(index, pages).await,
+            Self::Strip(inner) => inner.set_many(index, pages).await,
+            Self::Mirror(inner) => inner.set_many(index, pages).await,
+            Self::Throttle(inner) => inner.set_many(index, pages).await,
+            Self::Compress(inner) => inner.set_many(index, pages).await,
+            Self::Encrypt(inner) => inner.set_many(index, pages).await,
+            Self::Retry(inner) => inner.set_many(index, pages).await,
+        }
+    }
+
+    fn try_get_sync(&self, index: u32) -> Option<Result<Option<Page>>> {
+        match self {
+            Self::Concat(inner) => inner.try_get_sync(index),
+            Self::Strip(inner) => inner.try_get_sync(index),
+            Self::Mirror(inner) => inner.try_get_sync(index),
+            Self::Throttle(inner) => inner.try_get_sync(index),
+            Self::Compress(inner) => inner.try_get_sync(index),
+            Self::Encrypt(inner) => inner.try_get_sync(index),
+            Self::Retry(inner) => inner.try_get_sync(index),
+        }
+    }
+
+    fn try_set_sync(&mut self, index: u32, page: &[u8]) -> Option<Result<()>> {
+        match self {
+            Self::Concat(inner) => inner.try_set_sync(index, page),
+            Self::Strip(inner) => inner.try_set_sync(index, page),
+            Self::Mirror(inner) => inner.try_set_sync(index, page),
+            Self::Throttle(inner) => inner.try_set_sync(index, page),
+            Self::Compress(inner) => inner.try_set_sync(index, page),
+            Self::Encrypt(inner) => inner.try_set_sync(index, page),
+            Self::Retry(inner) => inner.try_set_sync(index, page),
         }
     }
 
@@ -73,6 +230,10 @@ where
             Self::Concat(inner) => inner.size(),
             Self::Strip(inner) => inner.size(),
             Self::Mirror(inner) => inner.size(),
+            Self::Throttle(inner) => inner.size(),
+            Self::Compress(inner) => inner.size(),
+            Self::Encrypt(inner) => inner.size(),
+            Self::Retry(inner) => inner.size(),
         }
     }
 
@@ -82,6 +243,22 @@ where
             Self::Concat(inner) => inner.page_size(),
             Self::Strip(inner) => inner.page_size(),
             Self::Mirror(inner) => inner.page_size(),
+            Self::Throttle(inner) => inner.page_size(),
+            Self::Compress(inner) => inner.page_size(),
+            Self::Encrypt(inner) => inner.page_size(),
+            Self::Retry(inner) => inner.page_size(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Concat(inner) => inner.describe(),
+            Self::Strip(inner) => inner.describe(),
+            Self::Mirror(inner) => inner.describe(),
+            Self::Throttle(inner) => inner.describe(),
+            Self::Compress(inner) => inner.describe(),
+            Self::Encrypt(inner) => inner.describe(),
+            Self::Retry(inner) => inner.describe(),
         }
     }
 }
@@ -0,0 +1,263 @@
+use crate::store::{Page, Store, VerifyReport};
+use crate::{Error, Result};
+use bytesize::ByteSize;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+/// RetryPolicy wraps a single store and retries a get or set that failed
+/// with an error that can go away on its own (timeouts, broken connections,
+/// or errors of remote backends) up to max_attempts times. The wait before
+/// each retry starts at backoff and doubles after every attempt. Errors that
+/// fail the same way every time (a bad index or page size, a read only
+/// store) are returned right away.
+///
+/// this is useful for remote backends where a request can fail
+/// because of a short network issue
+pub struct RetryPolicy<S> {
+    inner: S,
+    max_attempts: usize,
+    backoff: Duration,
+}
+
+impl<S> RetryPolicy<S>
+where
+    S: Store,
+{
+    /// max_attempts includes the first try
+    pub fn new(inner: S, max_attempts: usize) -> Result<Self> {
+        if max_attempts == 0 {
+            return Err(Error::ZeroSize);
+        }
+
+        Ok(Self {
+            inner,
+            max_attempts,
+            backoff: Duration::from_millis(100),
+        })
+    }
+
+    /// wait before the first retry, doubled after every attempt
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn inner(self) -> S {
+        self.inner
+    }
+
+    /// how long to wait before trying again after attempt failed with err,
+    /// None if err has to be returned
+    fn retry_after(&self, attempt: usize, err: &Error) -> Option<Duration> {
+        if attempt >= self.max_attempts || !is_transient(err) {
+            return None;
+        }
+
+        Some(
+            self.backoff
+                .saturating_mul(2u32.saturating_pow(attempt as u32 - 1)),
+        )
+    }
+}
+
+/// true if err can go away on its own
+fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::IO(err) => matches!(
+            err.kind(),
+            ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::WouldBlock
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof
+        ),
+        Error::Other(_) => true,
+        _ => false,
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Store for RetryPolicy<S>
+where
+    S: Store,
+{
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            let err = match self.inner.set(index, page).await {
+                Ok(_) => return Ok(()),
+                Err(err) => err,
+            };
+
+            let Some(wait) = self.retry_after(attempt, &err) else {
+                return Err(err);
+            };
+
+            log::warn!(
+                "failed to set page {index} (attempt {attempt}): {err}, retrying in {wait:?}"
+            );
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        let mut attempt = 1;
+        loop {
+            let err = match self.inner.get(index).await {
+                Ok(page) => return Ok(page),
+                Err(err) => err,
+            };
+
+            let Some(wait) = self.retry_after(attempt, &err) else {
+                return Err(err);
+            };
+
+            log::warn!(
+                "failed to get page {index} (attempt {attempt}): {err}, retrying in {wait:?}"
+            );
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        self.inner.discard(index).await
+    }
+
+    fn can_discard(&self) -> bool {
+        self.inner.can_discard()
+    }
+
+    fn short_pages(&self) -> bool {
+        self.inner.short_pages()
+    }
+
+    async fn verify(&self) -> Result<VerifyReport> {
+        self.inner.verify().await
+    }
+
+    fn size(&self) -> ByteSize {
+        self.inner.size()
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "retry({}, {:?})[{}]",
+            self.max_attempts,
+            self.backoff,
+            self.inner.describe()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::InMemory;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// fails the first failures operations with err
+    struct Flaky {
+        inner: InMemory,
+        failures: usize,
+        attempts: AtomicUsize,
+        err: fn() -> Error,
+    }
+
+    impl Flaky {
+        fn new(failures: usize, err: fn() -> Error) -> Self {
+            Self {
+                inner: InMemory::new(10),
+                failures,
+                attempts: AtomicUsize::new(0),
+                err,
+            }
+        }
+
+        fn attempt(&self) -> Result<()> {
+            if self.attempts.fetch_add(1, Ordering::Relaxed) < self.failures {
+                return Err((self.err)());
+            }
+
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Store for Flaky {
+        async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+            self.attempt()?;
+            self.inner.set(index, page).await
+        }
+
+        async fn get(&self, index: u32) -> Result<Option<Page>> {
+            self.attempt()?;
+            self.inner.get(index).await
+        }
+
+        fn size(&self) -> ByteSize {
+            self.inner.size()
+        }
+
+        fn page_size(&self) -> usize {
+            self.inner.page_size()
+        }
+    }
+
+    fn io_err() -> Error {
+        std::io::Error::from(ErrorKind::TimedOut).into()
+    }
+
+    fn read_only_err() -> Error {
+        std::io::Error::from(ErrorKind::PermissionDenied).into()
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let flaky = Flaky::new(2, io_err);
+        let mut store = RetryPolicy::new(flaky, 3)
+            .unwrap()
+            .with_backoff(Duration::from_millis(1));
+
+        store.set(1, &[1; 1024]).await.unwrap();
+        assert_eq!(store.inner.attempts.load(Ordering::Relaxed), 3);
+
+        let page = store.get(1).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 1));
+        drop(page);
+        assert_eq!(store.inner.attempts.load(Ordering::Relaxed), 4);
+
+        // gives up after max attempts
+        let mut store = RetryPolicy::new(Flaky::new(3, io_err), 3)
+            .unwrap()
+            .with_backoff(Duration::from_millis(1));
+        assert!(matches!(store.set(1, &[1; 1024]).await, Err(Error::IO(_))));
+        assert_eq!(store.inner.attempts.load(Ordering::Relaxed), 3);
+
+        // permanent errors are not retried
+        let store = RetryPolicy::new(Flaky::new(1, || Error::PageIndexOutOfRange), 3).unwrap();
+        assert!(matches!(
+            store.get(1).await,
+            Err(Error::PageIndexOutOfRange)
+        ));
+        assert_eq!(store.inner.attempts.load(Ordering::Relaxed), 1);
+
+        // so are io errors that happen again on every try
+        let mut store = RetryPolicy::new(Flaky::new(1, read_only_err), 3).unwrap();
+        assert!(matches!(store.set(1, &[1; 1024]).await, Err(Error::IO(_))));
+        assert_eq!(store.inner.attempts.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::store::{Page, Store};
+use crate::store::{Page, Store, VerifyReport};
 use crate::{Error, PolicyError, Result};
 use bytesize::ByteSize;
 
@@ -50,7 +50,7 @@ where
     S: Store,
 {
     async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
-        if index as u64 >= self.size.0 {
+        if index as u64 >= self.page_count() {
             return Err(Error::PageIndexOutOfRange);
         }
 
@@ -61,7 +61,7 @@ where
     }
 
     async fn get(&self, index: u32) -> Result<Option<Page>> {
-        if index as u64 >= self.size.0 {
+        if index as u64 >= self.page_count() {
             return Err(Error::PageIndexOutOfRange);
         }
 
@@ -71,6 +71,61 @@ where
         self.parts[outer].get(inner as u32).await
     }
 
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        if index as u64 >= self.page_count() {
+            return Err(Error::PageIndexOutOfRange);
+        }
+
+        let outer = index as usize % self.parts.len();
+        let inner = index as usize / self.parts.len();
+
+        self.parts[outer].discard(inner as u32).await
+    }
+
+    fn can_discard(&self) -> bool {
+        self.parts.iter().all(|part| part.can_discard())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        for part in self.parts.iter() {
+            part.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// reports are in pages of the parts
+    async fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for part in self.parts.iter() {
+            report += part.verify().await?;
+        }
+
+        Ok(report)
+    }
+
+    fn try_get_sync(&self, index: u32) -> Option<Result<Option<Page>>> {
+        if index as u64 >= self.page_count() {
+            return Some(Err(Error::PageIndexOutOfRange));
+        }
+
+        let outer = index as usize % self.parts.len();
+        let inner = index as usize / self.parts.len();
+
+        self.parts[outer].try_get_sync(inner as u32)
+    }
+
+    fn try_set_sync(&mut self, index: u32, page: &[u8]) -> Option<Result<()>> {
+        if index as u64 >= self.page_count() {
+            return Some(Err(Error::PageIndexOutOfRange));
+        }
+
+        let outer = index as usize % self.parts.len();
+        let inner = index as usize / self.parts.len();
+
+        self.parts[outer].try_set_sync(inner as u32, page)
+    }
+
     fn size(&self) -> ByteSize {
         self.size
     }
@@ -78,4 +133,31 @@ where
     fn page_size(&self) -> usize {
         self.bs
     }
+
+    fn describe(&self) -> String {
+        super::describe_parts("strip", &self.parts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::store::InMemory;
+
+    #[tokio::test]
+    async fn test_strip_bounds() {
+        let mut store = StripPolicy::new(vec![InMemory::new(10), InMemory::new(10)]).unwrap();
+        assert_eq!(store.page_count(), 20);
+
+        assert!(store.get(19).await.is_ok());
+        assert!(store.get(20).await.is_err());
+
+        let data: [u8; 1024] = [70; 1024];
+        assert!(store.set(19, &data).await.is_ok());
+        assert!(store.set(20, &data).await.is_err());
+
+        // page 19 is the 10th page of the second store
+        assert!(store.parts[1].mem.get(&9).is_some());
+    }
 }
@@ -0,0 +1,149 @@
+use crate::store::{Page, Store, VerifyReport};
+use crate::{Error, Result};
+use anyhow::Context;
+use bytesize::ByteSize;
+use tokio::sync::Semaphore;
+
+/// ThrottlePolicy wraps a single store and makes sure that
+/// no more than max_inflight operations are running against
+/// that store at the same time. The rest of the operations
+/// are queued until a slot is free.
+///
+/// this is useful for slow backends that can't handle
+/// a lot of concurrent requests
+pub struct ThrottlePolicy<S> {
+    inner: S,
+    permits: Semaphore,
+    max_inflight: usize,
+}
+
+impl<S> ThrottlePolicy<S>
+where
+    S: Store,
+{
+    pub fn new(inner: S, max_inflight: usize) -> Result<Self> {
+        if max_inflight == 0 {
+            return Err(Error::ZeroSize);
+        }
+
+        Ok(Self {
+            inner,
+            permits: Semaphore::new(max_inflight),
+            max_inflight,
+        })
+    }
+
+    pub fn inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Store for ThrottlePolicy<S>
+where
+    S: Store,
+{
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .context("acquire store permit")?;
+
+        self.inner.set(index, page).await
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .context("acquire store permit")?;
+
+        self.inner.get(index).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        self.inner.discard(index).await
+    }
+
+    fn can_discard(&self) -> bool {
+        self.inner.can_discard()
+    }
+
+    fn short_pages(&self) -> bool {
+        self.inner.short_pages()
+    }
+
+    async fn verify(&self) -> Result<VerifyReport> {
+        self.inner.verify().await
+    }
+
+    fn size(&self) -> ByteSize {
+        self.inner.size()
+    }
+
+    fn page_size(&self) -> usize {
+        self.inner.page_size()
+    }
+
+    fn describe(&self) -> String {
+        format!("throttle({})[{}]", self.max_inflight, self.inner.describe())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Slow {
+        inflight: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Store for Slow {
+        async fn set(&mut self, _index: u32, _page: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get(&self, _index: u32) -> Result<Option<Page>> {
+            let current = self.inflight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(current, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.inflight.fetch_sub(1, Ordering::SeqCst);
+            Ok(None)
+        }
+
+        fn size(&self) -> ByteSize {
+            ByteSize::kib(10)
+        }
+
+        fn page_size(&self) -> usize {
+            1024
+        }
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        assert!(ThrottlePolicy::new(Slow::default(), 0).is_err());
+
+        let store = ThrottlePolicy::new(Slow::default(), 2).unwrap();
+        assert_eq!(store.size(), ByteSize::kib(10));
+        assert_eq!(store.page_size(), 1024);
+
+        let (a, b, c, d) = tokio::join!(store.get(0), store.get(1), store.get(2), store.get(3));
+        assert!(a.is_ok() && b.is_ok() && c.is_ok() && d.is_ok());
+
+        let inner = store.inner();
+        assert_eq!(inner.max.load(Ordering::SeqCst), 2);
+        assert_eq!(inner.inflight.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,207 @@
+//! S3Store keeps each page in its own object `{prefix}/{index}` of an s3
+//! bucket (or anything that speaks s3, like minio). A page that was never
+//! written has no object. Every get and set is a request so this is only
+//! useful as cold storage behind a big cache, usually with a retry policy
+//! in front of it.
+use ::s3::Bucket;
+pub use ::s3::{creds::Credentials, Region};
+use bytesize::ByteSize;
+
+use super::*;
+
+/// store with an object per page in an s3 bucket
+pub struct S3Store {
+    bucket: Bucket,
+    prefix: String,
+    size: ByteSize,
+    page_size: usize,
+}
+
+impl S3Store {
+    /// the bucket must exist, existing page objects are used as is
+    pub fn new(
+        bucket: &str,
+        prefix: &str,
+        region: Region,
+        credentials: Credentials,
+        size: ByteSize,
+        page_size: ByteSize,
+    ) -> Result<Self> {
+        if page_size.as_u64() == 0 {
+            return Err(Error::ZeroSize);
+        }
+
+        if size.as_u64() % page_size.as_u64() != 0 {
+            return Err(Error::SizeNotMultipleOfPageSize);
+        }
+
+        // path style works with every s3 implementation, virtual hosts
+        // need a dns entry per bucket
+        let bucket = Bucket::new(bucket, region, credentials)
+            .map_err(anyhow::Error::from)?
+            .with_path_style();
+
+        Ok(Self {
+            bucket,
+            prefix: prefix.trim_matches('/').into(),
+            size,
+            page_size: page_size.as_u64() as usize,
+        })
+    }
+
+    fn key(&self, index: u32) -> String {
+        if self.prefix.is_empty() {
+            return index.to_string();
+        }
+
+        format!("{}/{index}", self.prefix)
+    }
+
+    fn check(&self, index: u32) -> Result<()> {
+        if index as u64 >= self.page_count() {
+            return Err(Error::PageIndexOutOfRange);
+        }
+
+        Ok(())
+    }
+}
+
+/// request errors are `Error::Other` so a retry policy retries them
+fn request_err(op: &str, key: &str, code: u16) -> Error {
+    anyhow::anyhow!("s3 {op} of {key} failed with status {code}").into()
+}
+
+#[async_trait::async_trait]
+impl Store for S3Store {
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        self.check(index)?;
+        if page.len() != self.page_size {
+            return Err(Error::InvalidPageSize);
+        }
+
+        let key = self.key(index);
+        let response = self
+            .bucket
+            .put_object(&key, page)
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        match response.status_code() {
+            200..=299 => Ok(()),
+            code => Err(request_err("put", &key, code)),
+        }
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        self.check(index)?;
+
+        let key = self.key(index);
+        let response = self
+            .bucket
+            .get_object(&key)
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        match response.status_code() {
+            200..=299 => {}
+            404 => return Ok(None),
+            code => return Err(request_err("get", &key, code)),
+        }
+
+        let data = response.bytes();
+        if data.len() != self.page_size {
+            return Err(Error::InvalidPageSize);
+        }
+
+        Ok(Some(Page::Owned(data.to_vec())))
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        self.check(index)?;
+
+        let key = self.key(index);
+        let response = self
+            .bucket
+            .delete_object(&key)
+            .await
+            .map_err(anyhow::Error::from)?;
+
+        // deleting an object that does not exist is not an error
+        match response.status_code() {
+            200..=299 | 404 => Ok(()),
+            code => Err(request_err("delete", &key, code)),
+        }
+    }
+
+    fn can_discard(&self) -> bool {
+        true
+    }
+
+    fn size(&self) -> ByteSize {
+        self.size
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "s3:{}/{} ({})",
+            self.bucket.name(),
+            self.prefix,
+            self.size.to_string_as(true)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// runs against the minio (or s3) endpoint in QBD_S3_TEST_ENDPOINT with
+    /// the credentials in the AWS_* env vars. The bucket (QBD_S3_TEST_BUCKET,
+    /// qbd-test by default) must exist. Skipped if no endpoint is set
+    #[tokio::test]
+    async fn s3() {
+        let Ok(endpoint) = std::env::var("QBD_S3_TEST_ENDPOINT") else {
+            return;
+        };
+        let bucket = std::env::var("QBD_S3_TEST_BUCKET").unwrap_or_else(|_| "qbd-test".into());
+        let region = Region::Custom {
+            region: "us-east-1".into(),
+            endpoint,
+        };
+
+        let mut store = S3Store::new(
+            &bucket,
+            "store.s3.test",
+            region,
+            Credentials::from_env().unwrap(),
+            ByteSize::kib(10),
+            ByteSize::kib(1),
+        )
+        .unwrap();
+        assert_eq!(store.page_count(), 10);
+
+        store.discard(3).await.unwrap();
+        assert!(store.get(3).await.unwrap().is_none());
+
+        store.set(3, &[3; 1024]).await.unwrap();
+        let page = store.get(3).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 3));
+        drop(page);
+
+        assert!(matches!(
+            store.get(10).await,
+            Err(Error::PageIndexOutOfRange)
+        ));
+        assert!(matches!(
+            store.set(0, &[0; 10]).await,
+            Err(Error::InvalidPageSize)
+        ));
+
+        store.discard(3).await.unwrap();
+        assert!(store.get(3).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,383 @@
+//! TieredStore puts a small fast store (hot) in front of a big slow one
+//! (cold). Pages are written to hot, once hot is full the least recently
+//! used page of hot is moved down to cold to make room. A read of a page
+//! that is only in cold brings it back to hot.
+//!
+//! Which hot slot holds which page is kept in memory and in an index file
+//! with an entry per hot slot, so hot pages survive a restart. Only the
+//! entry of a slot that changes is written. A slot is only reused after
+//! its old page is safe in cold and the entry without it is on disk,
+//! otherwise a crash could map a page to the data of another one. Other
+//! entries are synced on flush. The lru order of hot is not kept, after a
+//! restart the pages are taken in slot order.
+use std::{
+    fs::{File, OpenOptions},
+    io::ErrorKind,
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use bytesize::ByteSize;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use tokio::sync::Mutex;
+
+use super::*;
+use crate::metrics;
+
+lazy_static! {
+    static ref TIER_PROMOTIONS: IntCounter = register_int_counter!(
+        metrics::name("tier_promotions"),
+        "number of pages moved from the cold to the hot store"
+    )
+    .unwrap();
+    static ref TIER_DEMOTIONS: IntCounter = register_int_counter!(
+        metrics::name("tier_demotions"),
+        "number of pages moved from the hot to the cold store"
+    )
+    .unwrap();
+    static ref TIER_HOT_PAGES: IntGauge = register_int_gauge!(
+        metrics::name("tier_hot_pages"),
+        "number of pages in the hot store"
+    )
+    .unwrap();
+}
+
+/// size of an index entry, the page in the slot plus one or 0 if the slot
+/// is free
+const ENTRY_SIZE: u64 = 8;
+
+struct Tiers<H, C> {
+    hot: H,
+    cold: C,
+    // page -> hot slot holding it
+    index: LruCache<u32, u32>,
+    free: Vec<u32>,
+    // the index file
+    file: File,
+}
+
+impl<H, C> Tiers<H, C>
+where
+    H: Store,
+    C: Store,
+{
+    /// writes the index entry of slot. An entry that frees a slot is synced
+    /// right away since the slot is reused next
+    fn persist(&self, slot: u32, page: Option<u32>) -> Result<()> {
+        let entry = page.map_or(0, |page| page as u64 + 1);
+        self.file
+            .write_all_at(&entry.to_le_bytes(), slot as u64 * ENTRY_SIZE)?;
+        if page.is_none() {
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// returns a free hot slot, if hot is full its least recently used
+    /// page is moved to cold first
+    async fn take_slot(&mut self) -> Result<u32> {
+        if let Some(slot) = self.free.pop() {
+            return Ok(slot);
+        }
+
+        // hot has at least one page so the index can't be empty here
+        let (page, slot) = self
+            .index
+            .peek_lru()
+            .map(|(page, slot)| (*page, *slot))
+            .ok_or(Error::ZeroSize)?;
+        let data: Vec<u8> = match self.hot.get(slot).await? {
+            Some(data) => data.into(),
+            None => vec![0; self.cold.page_size()],
+        };
+
+        log::trace!("moving page {page} from hot slot {slot} to cold");
+        self.cold.set(page, &data).await?;
+        self.cold.flush().await?;
+        self.persist(slot, None)?;
+        self.index.pop(&page);
+        TIER_DEMOTIONS.inc();
+        Ok(slot)
+    }
+
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        if let Some(slot) = self.index.get(&index) {
+            return self.hot.set(*slot, page).await;
+        }
+
+        let slot = self.take_slot().await?;
+        let result = match self.hot.set(slot, page).await {
+            Ok(_) => self.persist(slot, Some(index)),
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            self.free.push(slot);
+            return Err(err);
+        }
+
+        self.index.put(index, slot);
+        TIER_HOT_PAGES.set(self.index.len() as i64);
+        Ok(())
+    }
+
+    async fn get(&mut self, index: u32) -> Result<Option<Vec<u8>>> {
+        if let Some(slot) = self.index.get(&index) {
+            return Ok(self.hot.get(*slot).await?.map(Vec::from));
+        }
+
+        let data: Vec<u8> = match self.cold.get(index).await? {
+            Some(data) => data.into(),
+            None => return Ok(None),
+        };
+
+        // the page is still in cold, so failing to bring it to hot
+        // doesn't fail the read
+        match self.set(index, &data).await {
+            Ok(_) => TIER_PROMOTIONS.inc(),
+            Err(err) => log::warn!("failed to move page {index} to the hot store: {err}"),
+        }
+
+        Ok(Some(data))
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        if let Some(slot) = self.index.peek(&index).copied() {
+            self.hot.discard(slot).await?;
+            // same as with moving a page to cold, the slot can only be
+            // used again once the index is updated
+            self.persist(slot, None)?;
+            self.index.pop(&index);
+            self.free.push(slot);
+            TIER_HOT_PAGES.set(self.index.len() as i64);
+        }
+
+        self.cold.discard(index).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        // the index can only point to slots that are on disk
+        self.hot.flush().await?;
+        self.cold.flush().await?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+/// store with a hot and a cold tier, see the module docs
+pub struct TieredStore<H, C> {
+    tiers: Mutex<Tiers<H, C>>,
+    size: ByteSize,
+    page_size: usize,
+    describe: String,
+    // a discarded page is dropped from hot, so it's up to cold
+    can_discard: bool,
+}
+
+impl<H, C> TieredStore<H, C>
+where
+    H: Store,
+    C: Store,
+{
+    /// the store has the size of cold, the hot index is kept at path
+    /// and loaded from there if it exists
+    pub fn new<P: AsRef<Path>>(hot: H, cold: C, path: P) -> Result<Self> {
+        if hot.page_size() != cold.page_size() {
+            return Err(Error::InvalidPageSize);
+        }
+
+        let slots = hot.page_count().min(u32::MAX as u64) as u32;
+        if slots == 0 {
+            return Err(Error::ZeroSize);
+        }
+
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        let invalid = |what: String| -> Error {
+            IoError::new(
+                ErrorKind::InvalidData,
+                format!("{what} in hot index {}", path.display()),
+            )
+            .into()
+        };
+
+        let size = slots as u64 * ENTRY_SIZE;
+        match file.metadata()?.len() {
+            0 => file.set_len(size)?,
+            len if len != size => {
+                return Err(invalid(format!(
+                    "{} entries for a hot store of {slots} pages",
+                    len / ENTRY_SIZE
+                )))
+            }
+            _ => {}
+        }
+
+        let mut entries = vec![0; size as usize];
+        file.read_exact_at(&mut entries, 0)?;
+
+        let mut index = LruCache::unbounded();
+        // lowest slot goes last so it's used first
+        let mut free = vec![];
+        for (slot, entry) in entries.chunks_exact(ENTRY_SIZE as usize).enumerate().rev() {
+            let entry = u64::from_le_bytes(entry.try_into().unwrap());
+            if entry == 0 {
+                free.push(slot as u32);
+                continue;
+            }
+
+            let page = entry - 1;
+            if page >= cold.page_count() || index.put(page as u32, slot as u32).is_some() {
+                return Err(invalid(format!("invalid entry of slot {slot}")));
+            }
+        }
+        TIER_HOT_PAGES.set(index.len() as i64);
+
+        Ok(Self {
+            size: cold.size(),
+            page_size: cold.page_size(),
+            describe: format!("tiered[{}, {}]", hot.describe(), cold.describe()),
+            can_discard: cold.can_discard(),
+            tiers: Mutex::new(Tiers {
+                hot,
+                cold,
+                index,
+                free,
+                file,
+            }),
+        })
+    }
+
+    /// returns the hot and cold stores
+    pub fn into_inner(self) -> (H, C) {
+        let tiers = self.tiers.into_inner();
+        (tiers.hot, tiers.cold)
+    }
+
+    fn check(&self, index: u32) -> Result<()> {
+        if index as u64 >= self.page_count() {
+            return Err(Error::PageIndexOutOfRange);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<H, C> Store for TieredStore<H, C>
+where
+    H: Store,
+    C: Store,
+{
+    async fn set(&mut self, index: u32, page: &[u8]) -> Result<()> {
+        self.check(index)?;
+        if page.len() != self.page_size {
+            return Err(Error::InvalidPageSize);
+        }
+
+        self.tiers.get_mut().set(index, page).await
+    }
+
+    async fn get(&self, index: u32) -> Result<Option<Page>> {
+        self.check(index)?;
+        let data = self.tiers.lock().await.get(index).await?;
+        Ok(data.map(Page::Owned))
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.tiers.lock().await.flush().await
+    }
+
+    async fn discard(&mut self, index: u32) -> Result<()> {
+        self.check(index)?;
+        self.tiers.get_mut().discard(index).await
+    }
+
+    fn can_discard(&self) -> bool {
+        self.can_discard
+    }
+
+    /// both tiers are verified, pages of hot are counted as hot slots
+    async fn verify(&self) -> Result<VerifyReport> {
+        let tiers = self.tiers.lock().await;
+        let mut report = tiers.hot.verify().await?;
+        report += tiers.cold.verify().await?;
+        Ok(report)
+    }
+
+    fn size(&self) -> ByteSize {
+        self.size
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn describe(&self) -> String {
+        self.describe.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn tiered() {
+        const PATH: &str = "/tmp/store.tiered.test";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut store = TieredStore::new(InMemory::new(2), InMemory::new(10), PATH).unwrap();
+        assert_eq!(store.page_count(), 10);
+
+        for index in 0..3 {
+            store.set(index, &[index as u8; 1024]).await.unwrap();
+        }
+
+        // 0 was moved to cold to make room for 2
+        let (hot, cold) = {
+            let tiers = store.tiers.get_mut();
+            (tiers.index.len(), tiers.cold.mem.len())
+        };
+        assert_eq!((hot, cold), (2, 1));
+
+        // reading 0 brings it back and moves 1 down
+        let page = store.get(0).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 0));
+        drop(page);
+        assert!(store.tiers.get_mut().cold.mem.contains_key(&1));
+        assert!(store.get(5).await.unwrap().is_none());
+
+        store.flush().await.unwrap();
+        let (hot, cold) = store.into_inner();
+
+        // the hot pages are still found after opening the store again
+        let store = TieredStore::new(hot, cold, PATH).unwrap();
+        let page = store.get(2).await.unwrap().unwrap();
+        assert!(page.iter().all(|v| *v == 2));
+        drop(page);
+        let (_, cold) = store.into_inner();
+        assert!(!cold.mem.contains_key(&2));
+
+        // entries are written as they change, not only on flush
+        let _ = std::fs::remove_file(PATH);
+        let mut store = TieredStore::new(InMemory::new(2), InMemory::new(10), PATH).unwrap();
+        store.set(7, &[7; 1024]).await.unwrap();
+        let (hot, cold) = store.into_inner();
+        let store = TieredStore::new(hot, cold, PATH).unwrap();
+        assert!(store.tiers.lock().await.index.contains(&7));
+
+        // the index has to match the hot store
+        assert!(TieredStore::new(InMemory::new(3), InMemory::new(10), PATH).is_err());
+        assert!(TieredStore::new(InMemory::new(0), InMemory::new(10), PATH).is_err());
+    }
+}